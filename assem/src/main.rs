@@ -1,63 +1,443 @@
 #![warn(clippy::all)]
 
 use std::env;
-use std::fs::OpenOptions;
-use std::io::{self, BufRead, BufReader, Write};
-use std::path::Path;
-use std::str::FromStr;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 
-use grumpy::isa::*;
-use grumpy::assemble::*;
 use grumpy::*;
 
 fn main() -> io::Result<()> {
-    // Read input file (command line argument at index 1).
-    let args: Vec<String> = env::args().collect();
-    let file = OpenOptions::new().read(true).open(&args[1]).expect("Error getting input");
-    let reader = BufReader::new(file);
-
-    let mut inp: Vec<isa::PInstr> = Vec::new();
-    for line in reader.lines(){
-        inp.push(isa::PInstr::from_str(&line?)?);
-    }
-    // Convert file contents to vector of (labeled) instructions.
-    let mut assembled_inp: Vec<Instr> = Vec::new();
-    match assemble::assemble(&inp){
-        Ok(T) => assembled_inp = T,
-        Err(E) => std::process::exit(1),
-    }
-
-    let mut temp = &mut args[1].chars();
-    temp.next_back();
-    temp.next_back();
-    let v = temp.as_str();
-
-    let mut buffer = OpenOptions::new().write(true).create(true).open(v.to_owned() + ".o").expect("Error creating output file");
-
-
-    // Resolve labels, converting the vector of labeled instructions
-    // to a vector of assembled instructions.
-    let mut pc: u32 = 0;
-    for (count, i) in assembled_inp.iter().enumerate(){
-        if count == assembled_inp.len() - 1{
-            match i{
-                Instr::Push(i) => match *i{
-                    Val::Vloc(u) => pc = u,
-                    _ => (),
-                },
-                _ => ()
+    let argv: Vec<String> = env::args().skip(1).collect();
+    let option = |name: &str| argv.iter().enumerate().find_map(|(n, a)| match a.strip_prefix(&format!("{}=", name)) {
+        Some(f) => Some(f.to_string()),
+        None if a == name => Some(argv.get(n + 1).cloned().unwrap_or_default()),
+        None => None,
+    });
+    // `-o path` names the output file, rather than the input with the
+    // output's extension; `-o -` is stdout.
+    let output = option("-o");
+    // `--listing path` also writes an assembly listing: each line of
+    // source with its pc and bytes (`-` for stdout).
+    let listing = option("--listing");
+    // `--max-errors N` reports up to N lines that do not parse, rather
+    // than only the first (0 for all of them).
+    let max_errors = match option("--max-errors").map(|n| n.parse::<usize>()) {
+        None => 1,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            eprintln!("error: --max-errors expects a number");
+            std::process::exit(1);
+        }
+    };
+    // `-D NAME=VALUE` (or `-DNAME=VALUE`, `--define NAME=VALUE`) defines
+    // a constant as `.equ` does, which the program may not define again;
+    // `-D NAME` is `NAME=1`. There may be any number of them.
+    let defines: Vec<&str> = argv.iter().enumerate()
+        .filter_map(|(n, a)| match a.strip_prefix("-D").or_else(|| a.strip_prefix("--define=")) {
+            _ if a == "-D" || a == "--define" => argv.get(n + 1).map(String::as_str),
+            d => d,
+        })
+        .collect();
+    let defines: Vec<expand::Numbered> = defines.into_iter()
+        .map(|d| expand::parse_define(d).unwrap_or_else(|e| {
+            eprintln!("error: -D {}: {}", d, e);
+            std::process::exit(1);
+        }))
+        .collect();
+    // Read the input files (the arguments that are not flags or flags'
+    // values), or stdin for `-`.
+    let args: Vec<&String> = argv.iter().enumerate()
+        .filter(|(n, a)| (!a.starts_with('-') || *a == "-")
+                && (*n == 0 || !["--format", "--max-errors", "--message-format", "--listing", "-o", "-D", "--define"].contains(&argv[n - 1].as_str())))
+        .map(|(_, a)| a)
+        .collect();
+    // `--metadata` records in an object file how it was built (see
+    // `object::build_metadata`): the input, the assembler and the flags,
+    // but not where the outputs went. With `--reproducible`, only what
+    // is the same wherever the same input is built.
+    let metadata = env::args().any(|a| a == "--metadata");
+    // `-g` (or `--debug`) gives an object file a debug section, mapping
+    // each instruction back to its line of source.
+    let debug = env::args().any(|a| a == "-g" || a == "--debug");
+    let reproducible = env::args().any(|a| a == "--reproducible");
+    let is_output = |a: &str| a == "-o" || a == "--listing" || a.starts_with("-o=") || a.starts_with("--listing=");
+    let flags: Vec<&str> = argv.iter().enumerate()
+        .filter(|(n, a)| !args.contains(a) && !is_output(a) && (*n == 0 || !is_output(&argv[n - 1])))
+        .map(|(_, a)| a.as_str())
+        .collect();
+    let ignore_case = env::args().any(|a| a == "--ignore-case");
+    let verbose = env::args().any(|a| a == "-v" || a == "--verbose");
+    // `--format bin|json|hex|...` (or `--format=json`) picks the output
+    // encoding; `--json` is `--format json`.
+    let format = match option("--format") {
+        Some(f) => f,
+        None if env::args().any(|a| a == "--json") => "json".to_string(),
+        None => "bin".to_string(),
+    };
+    if !FORMATS.contains(&format.as_str()) {
+        let (last, rest) = FORMATS.split_last().unwrap();
+        eprintln!("error: unknown format '{}' (expected {} or {})", format, rest.join(", "), last);
+        std::process::exit(1);
+    }
+    let hexdump = env::args().any(|a| a == "--hexdump");
+    if hexdump && format != "bin" {
+        eprintln!("error: --hexdump cannot be used with --format {} (--format hex writes the dump to a file)", format);
+        std::process::exit(1);
+    }
+    let compress = env::args().any(|a| a == "--compress");
+    // The output is an object file, rather than the plain layout.
+    let object_file = (format == "bin" && compress && !hexdump) || format == "base64";
+    let disassemble = env::args().any(|a| a == "-d" || a == "--disassemble");
+    // `--force` writes a binary object file to stdout even if it is a
+    // terminal.
+    let force = env::args().any(|a| a == "--force");
+    // `--warn` reports likely mistakes; `--warn=error` fails on them.
+    let deny_warnings = env::args().any(|a| a == "--warn=error");
+    let warn = deny_warnings || env::args().any(|a| a == "--warn");
+    // `--verify` checks the assembled program is fit to load (see
+    // `object::verify_object`), failing if it is not.
+    let verify = env::args().any(|a| a == "--verify");
+    // `--check` parses and assembles each input, reporting everything
+    // wrong with it, but writes nothing (not even to `-o`).
+    let check = env::args().any(|a| a == "--check");
+    // `--message-format=json` prints diagnostics as JSON lines, for
+    // editors, rather than as text.
+    let json_messages = match option("--message-format").as_deref() {
+        None | Some("human") => false,
+        Some("json") => true,
+        Some(f) => {
+            eprintln!("error: unknown message format '{}' (expected human or json)", f);
+            std::process::exit(1);
+        }
+    };
+    let endianness = if env::args().any(|a| a == "--little-endian") {
+        Endianness::Little
+    } else {
+        Endianness::Big
+    };
+    // `assem diff OLD NEW` compares two programs instruction by
+    // instruction, like diff(1): exit status 1 if they differ.
+    if args.len() == 3 && args[0] == "diff" {
+        let diffs = diff::diff_bytes(&fs::read(args[1])?, &fs::read(args[2])?, endianness)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        io::stdout().write_all(diff::unified(&diffs, args[1], args[2]).as_bytes())?;
+        std::process::exit(if diffs.is_empty() { 0 } else { 1 });
+    }
+    // With no input file, read piped assembly from stdin, as for `-`.
+    let stdin = match args.first() {
+        Some(a) => *a == "-",
+        None if !io::stdin().is_terminal() => true,
+        None => {
+            eprintln!("error: no input file (use - to read stdin)");
+            std::process::exit(1);
+        }
+    };
+    let input = Path::new(if stdin { "<stdin>" } else { args[0].as_str() });
+    // `-d`/`--disassemble` reads an object file, or the plain output,
+    // and prints its entry pc and instructions as assembly text that
+    // assembles back to the same program, to stdout unless `-o` names
+    // a file. Labels come from its symbol section, or are `L<n>`.
+    if disassemble {
+        let bytes = if stdin {
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes)?;
+            bytes
+        } else {
+            match fs::read(input) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    eprintln!("error: {}: {}", input.display(), e);
+                    std::process::exit(1);
+                }
             }
-
+        };
+        let text = match disassemble::disassemble_program(&bytes, endianness) {
+            Ok((entry, listing)) => format!("// entry pc {}\n{}", entry, listing),
+            Err(e) => {
+                eprintln!("{}: error: {}", input.display(), e);
+                std::process::exit(1);
+            }
+        };
+        match output.as_deref() {
+            None | Some("-") => io::stdout().write_all(text.as_bytes())?,
+            Some(o) => write_file(Path::new(o), &[input], text.as_bytes())?,
+        }
+        std::process::exit(0);
+    }
+    let options = Options { ignore_case, max_errors, defines, warn, deny_warnings, verify, json_messages, object_file, endianness };
+    // Each input's diagnostics, then its result on stdout; a bad file
+    // does not stop the others. The exit status is 1 if any failed.
+    if check {
+        let inputs: Vec<&str> = if stdin && args.is_empty() { vec!["-"] } else { args.iter().map(|a| a.as_str()).collect() };
+        let mut failed = 0;
+        for &a in &inputs {
+            let (path, from_stdin) = if a == "-" { (Path::new("<stdin>"), true) } else { (Path::new(a), false) };
+            match assemble_input(&[path], from_stdin, &options) {
+                Ok(_) => println!("{}: ok", path.display()),
+                Err(()) => {
+                    failed += 1;
+                    println!("{}: failed", path.display());
+                }
+            }
+        }
+        if inputs.len() > 1 {
+            println!("checked {} files: {} ok, {} failed", inputs.len(), inputs.len() - failed, failed);
+        }
+        std::process::exit(if failed == 0 { 0 } else { 1 });
+    }
+    // Several inputs are read one after the other as one program, which
+    // has no one input to name the output after.
+    let inputs: Vec<&Path> = if stdin { vec![input] } else { args.iter().map(Path::new).collect() };
+    if inputs.len() > 1 {
+        if args.iter().any(|a| *a == "-") {
+            eprintln!("error: stdin (-) cannot be one of several inputs");
+            std::process::exit(1);
+        }
+        if output.is_none() {
+            eprintln!("error: -o is required with more than one input file");
+            std::process::exit(1);
         }
     }
-    assembled_inp.pop();
+    let Assembled { mut program, symbols, expanded, sources } = match assemble_input(&inputs, stdin, &options) {
+        Ok(assembled) => assembled,
+        Err(()) => std::process::exit(1),
+    };
+    if metadata {
+        program.metadata = object::build_metadata(input, &flags, reproducible);
+    }
+    if debug {
+        let lines = assemble::line_numbers(&expanded).map_err(io::Error::other)?;
+        program.lines = object::LineTable::new(&lines, &sources);
+    }
+    if let Some(path) = &listing {
+        let text = assemble::source_listing(&expanded, &sources, endianness)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        match path.as_str() {
+            "-" => io::stdout().write_all(text.as_bytes())?,
+            path => write_file(Path::new(path), &inputs, text.as_bytes())?,
+        }
+    }
+    if verbose{
+        eprintln!("{}", assemble::Stats::new(program.instrs(), &symbols));
+    }
 
-    let pc_bites = pc.to_be_bytes();
-    buffer.write(&pc_bites).unwrap();
-    for i in assembled_inp{
-        let data = Instr::to_bytes(&i);
-        buffer.write(&data).unwrap();
+    // Name generated source after the input, or the output read from
+    // stdin or several inputs.
+    let name = match output.as_deref() {
+        Some(o) if (inputs.len() > 1 || stdin) && o != "-" => o,
+        _ if inputs.len() > 1 || stdin => "program",
+        _ => args[0].as_str(),
+    };
+    // Encode the whole program into one buffer, in the headerless
+    // layout GrumpyVM loads (or another format), and write it at once.
+    // Every format but `bin` is text, ending with a newline. Only an
+    // object file carries the data the loader sets up.
+    if !program.data.values.is_empty() && !object_file {
+        eprintln!("{}: error: the program has data (from .datastring), which only an object file holds \
+                   (use --compress or --format base64)", input.display());
+        std::process::exit(1);
     }
+    let legacy = program.to_legacy_bytes(endianness);
+    let (data, extension) = match format.as_str() {
+        "bin" if compress && !hexdump => (program.encode_compressed(endianness), ".o"),
+        "bin" => (legacy, ".o"),
+        "json" => (json::to_json(&program).into_bytes(), ".json"),
+        "hex" => {
+            let dump = disassemble::hexdump_with(&legacy, endianness).map_err(|e| io::Error::other(e.to_string()))?;
+            (dump.into_bytes(), ".hexdump")
+        }
+        "base64" => (format!("{}\n", base64::encode_base64(&program)).into_bytes(), ".b64"),
+        "rust" => (emit::rust_source(&emit::identifier(name), &legacy).into_bytes(), ".rs"),
+        "c" => (emit::c_source(&emit::identifier(name), &legacy).into_bytes(), ".h"),
+        "ihex" => (ihex::encode_ihex(&program, endianness, 16).into_bytes(), ".hex"),
+        _ => unreachable!("checked against FORMATS"),
+    };
+    // Show the bytes that would be written, rather than writing them.
+    if hexdump {
+        let dump = disassemble::hexdump_with(&data, endianness).map_err(|e| io::Error::other(e.to_string()))?;
+        io::stdout().write_all(dump.as_bytes())?;
+        std::process::exit(0);
+    }
+    // Read from stdin, with no file to name the output after, or with
+    // `-o -`, write to stdout; nothing else is printed there. Raw bytes
+    // would garble a terminal, so they need `--force`; text is fine.
+    if output.as_deref() == Some("-") || (stdin && output.is_none()) {
+        if format == "bin" && !force && io::stdout().is_terminal() {
+            eprintln!("error: not writing a binary object file to a terminal (use --force, or redirect stdout)");
+            std::process::exit(1);
+        }
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&data)?;
+        stdout.flush()?;
+        std::process::exit(0);
+    }
+    let path = match &output {
+        Some(o) => PathBuf::from(o),
+        None => emit::output_path(input, &extension[1..]),
+    };
+    write_file(&path, &inputs, &data)?;
 
     std::process::exit(0);
 }
+
+/// The names `--format` takes: the plain output (or an object file, with
+/// `--compress`), then the text formats.
+const FORMATS: [&str; 7] = ["bin", "json", "hex", "base64", "rust", "c", "ihex"];
+
+/// How to read and check each program, from the command line.
+struct Options {
+    ignore_case: bool,
+    max_errors: usize,
+    defines: Vec<expand::Numbered>,
+    warn: bool,
+    deny_warnings: bool,
+    verify: bool,
+    json_messages: bool,
+    /// Whether the output is an object file, which `.align` does not
+    /// know the layout of.
+    object_file: bool,
+    endianness: Endianness,
+}
+
+/// A program `assemble_input` assembled, with what it was assembled
+/// from.
+struct Assembled {
+    program: object::ObjectFile,
+    symbols: assemble::Symbols,
+    /// The program before assembly, as `expand::expand_with_data`
+    /// returned it.
+    expanded: Vec<expand::Numbered>,
+    sources: expand::Sources,
+}
+
+/// Print a diagnostic to stderr, as text or as a line of JSON.
+fn report(diagnostic: &expand::Diagnostic, options: &Options) {
+    if options.json_messages {
+        eprintln!("{}", json::diagnostic_to_json(diagnostic));
+    } else {
+        eprintln!("{}", diagnostic);
+    }
+}
+
+/// Parse `inputs` one after the other, or stdin (named `inputs[0]`) if
+/// `stdin`, and assemble them as one program, printing its diagnostics
+/// (see `report`): errors as `file.s:LINE: error: message`, naming the
+/// input or included file a line came from, and parse errors with the
+/// column and the line with a caret under the bad token. Errors about
+/// the whole program name the first input. Fails if there were errors,
+/// once they are printed.
+fn assemble_input(inputs: &[&Path], stdin: bool, options: &Options) -> Result<Assembled, ()> {
+    let Options { ignore_case, max_errors, ref defines, .. } = *options;
+    let input = inputs[0];
+    // Parse the inputs, inlining any .include'd files.
+    let parsed = if stdin {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)
+            .and_then(|_| expand::parse_reader_sources(text.as_bytes(), input, ignore_case, max_errors))
+    } else {
+        expand::parse_all_sources(inputs, ignore_case, max_errors)
+    };
+    let (inp, sources) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            match e.get_ref().and_then(|e| e.downcast_ref::<expand::ParseErrors>()) {
+                Some(errors) => {
+                    errors.errors.iter().for_each(|e| report(&e.diagnostic(), options));
+                    // With room for more, look past the bad lines.
+                    if max_errors == 0 || errors.errors.len() < max_errors {
+                        let recovered = defines.iter().cloned().chain(errors.recovered.iter().cloned()).collect();
+                        let errors = expand::ParseErrors { recovered, ..errors.clone() };
+                        if let Some(e) = errors.later_error() {
+                            report(&expand::Diagnostic::locate_error(&errors.sources, input, &e), options);
+                        }
+                    }
+                }
+                None => {
+                    // Errors reading an input name it; other files'
+                    // errors (of an .include) keep their name.
+                    let message = e.to_string();
+                    let (input, message) = inputs.iter()
+                        .find_map(|i| message.strip_prefix(&format!("{}: ", i.display())).map(|m| (*i, m)))
+                        .unwrap_or((input, &message));
+                    report(&expand::Diagnostic::error(input, None, message), options);
+                }
+            }
+            return Err(());
+        }
+    };
+    // Lower directives such as .while to plain pseudo-instructions, and
+    // convert them to instructions, resolving labels.
+    let inp: Vec<expand::Numbered> = defines.iter().cloned().chain(inp).collect();
+    let assembled = expand::expand_with_data(&inp, options.endianness)
+        .and_then(|(expanded, data)| assemble::assemble_numbered(&expanded).map(|assembled| (expanded, data, assembled)));
+    let (expanded, data, (assembled_inp, symbols)) = match assembled {
+        Ok(t) => t,
+        Err(e) => {
+            report(&expand::Diagnostic::locate_error(&sources, input, &e), options);
+            return Err(());
+        }
+    };
+    if options.object_file {
+        if let Some((line, _)) = inp.iter().find(|(_, i)| matches!(i, isa::PInstr::PDirective(isa::Directive::Align(_)))) {
+            let e = expand::ExpandError {
+                line: *line,
+                message: ".align only aligns the plain output, not an object file".to_string(),
+                previous: None,
+            };
+            report(&expand::Diagnostic::locate_error(&sources, input, &e), options);
+            return Err(());
+        }
+    }
+
+    // Split off the entry pc, which the assembler pushes last.
+    let program = object::ObjectFile { data, ..object::ObjectFile::from_program(assembled_inp, &symbols) };
+    if options.warn {
+        let mut warnings: Vec<expand::Diagnostic> = assemble::check_heap_operands(&expanded).iter()
+            .map(|w| expand::Diagnostic::locate(&sources, input, w.line, &w.message))
+            .collect();
+        if let Err(e) = assemble::check_has_halt(program.instrs()) {
+            warnings.push(expand::Diagnostic::error(input, None, &e));
+        }
+        let severity = if options.deny_warnings { expand::Severity::Error } else { expand::Severity::Warning };
+        warnings.iter().for_each(|w| report(&expand::Diagnostic { severity, ..w.clone() }, options));
+        if options.deny_warnings && !warnings.is_empty() {
+            return Err(());
+        }
+    }
+    if options.verify {
+        if let Err(errors) = object::verify_object(&program) {
+            errors.iter().for_each(|e| report(&expand::Diagnostic::error(input, None, &e.to_string()), options));
+            return Err(());
+        }
+    }
+    Ok(Assembled { program, symbols, expanded, sources })
+}
+
+/// Write `data` to `path`, creating its directory if need be, but never
+/// over one of `inputs`; exits with an error message if it cannot.
+fn write_file(path: &Path, inputs: &[&Path], data: &[u8]) -> io::Result<()> {
+    if inputs.iter().any(|input| emit::same_file(path, input)) {
+        eprintln!("error: not writing {}: it is the input file", path.display());
+        std::process::exit(1);
+    }
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("error: cannot create directory {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+    let file = match File::create(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("error: cannot create {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    // Buffered, so that writes stay batched if output is ever streamed;
+    // flushed explicitly so a failed write is reported, not dropped.
+    let mut buffer = BufWriter::new(file);
+    buffer.write_all(data)?;
+    buffer.flush()
+}
+
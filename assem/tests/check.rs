@@ -0,0 +1,61 @@
+//! `assem --check`: every input parsed and assembled, with all its
+//! diagnostics, and nothing written.
+
+mod common;
+
+use common::{assem, temp_dir};
+use std::fs;
+use std::path::Path;
+
+/// The names of the files in `dir`, sorted.
+fn files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_check() {
+    let dir = temp_dir("check");
+    fs::write(dir.join("good.s"), "push Lf\ncall\nhalt\nLf:\nret\n").unwrap();
+    fs::write(dir.join("also.s"), "push 1\npop\nhalt\n").unwrap();
+    fs::write(dir.join("bad.s"), "push 1\npsh 2\nLf:\nLf:\nhalt\n").unwrap();
+    fs::write(dir.join("undefined.s"), "push LATER\nhalt\n").unwrap();
+    let before = files(&dir);
+
+    let out = assem(&dir, &["--check", "good.s", "also.s"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "good.s: ok\nalso.s: ok\nchecked 2 files: 2 ok, 0 failed\n");
+    assert!(out.stderr.is_empty());
+
+    // Past a bad file, with every error in each, and `-o` ignored.
+    let out = assem(&dir, &["--check", "bad.s", "good.s", "undefined.s", "--max-errors", "0", "-o", "out.o"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(),
+               "bad.s: failed\ngood.s: ok\nundefined.s: failed\nchecked 3 files: 1 ok, 2 failed\n");
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let errors: Vec<&str> = stderr.lines().filter(|l| l.contains(": error: ")).collect();
+    assert_eq!(errors, ["bad.s:2:1: error: could not parse instruction 'psh 2'; did you mean 'push'?",
+                        "bad.s:4: error: label 'Lf' is defined more than once (first defined at bad.s:3)",
+                        "undefined.s:1: error: undefined label 'LATER'"]);
+
+    // A file that cannot be read fails like any other.
+    let out = assem(&dir, &["--check", "missing.s", "good.s"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8(out.stdout).unwrap().starts_with("missing.s: failed\ngood.s: ok\n"));
+
+    // Warnings only fail with `--warn=error`, and `--verify` passes an
+    // assembled program.
+    let out = assem(&dir, &["--check", "--warn", "--verify", "good.s"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "good.s: ok\n");
+    fs::write(dir.join("nohalt.s"), "push 1\npop\n").unwrap();
+    assert!(assem(&dir, &["--check", "--warn", "nohalt.s"]).status.success());
+    assert_eq!(assem(&dir, &["--check", "--warn=error", "nohalt.s"]).status.code(), Some(1));
+    fs::remove_file(dir.join("nohalt.s")).unwrap();
+
+    assert_eq!(files(&dir), before);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,38 @@
+//! `assem -D NAME=VALUE`: constants given on the command line, which
+//! the program uses as if it had defined them with `.equ`.
+
+mod common;
+
+use common::{assem, temp_dir};
+use std::fs;
+use std::process::Output;
+
+#[test]
+fn test_define() {
+    let dir = temp_dir("defines");
+    for (file, src) in [("expected.s", "push 256\npush 0\nalloc\npush true\nhalt\n"),
+                        ("prog.s", "push STACK\npush 0\nalloc\npush ON\nhalt\n"),
+                        ("debug.s", "push DEBUG\nhalt\n"), ("one.s", "push 1\nhalt\n"),
+                        ("twice.s", ".equ STACK 512\npush STACK\nhalt\n")] {
+        fs::write(dir.join(file), src).unwrap();
+    }
+    let expected = assem(&dir, &["expected.s", "-o", "-"]).stdout;
+    for args in [&["-D", "STACK=256", "-D", "ON=true"][..], &["-DSTACK=256", "--define", "ON=true"],
+                 &["--define=ON=true", "-D", "STACK=256"]] {
+        let out = assem(&dir, &[&["prog.s", "-o", "-"], args].concat());
+        assert!(out.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&out.stderr));
+        assert_eq!(out.stdout, expected, "{:?}", args);
+    }
+    // `-D NAME` alone is `NAME=1`.
+    assert_eq!(assem(&dir, &["debug.s", "-D", "DEBUG", "-o", "-"]).stdout, assem(&dir, &["one.s", "-o", "-"]).stdout);
+
+    let stderr = |out: Output| {
+        assert!(!out.status.success());
+        String::from_utf8(out.stderr).unwrap()
+    };
+    assert_eq!(stderr(assem(&dir, &["prog.s", "-D", "STACK=256"])), "prog.s:4: error: undefined constant 'ON'\n");
+    assert_eq!(stderr(assem(&dir, &["twice.s", "-D", "STACK=256"])),
+               "twice.s:1: error: constant 'STACK' is already defined on the command line\n");
+    assert_eq!(stderr(assem(&dir, &["prog.s", "-D", "STACK=lots"])), "error: -D STACK=lots: could not parse value 'lots'\n");
+    fs::remove_dir_all(dir).unwrap();
+}
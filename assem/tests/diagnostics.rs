@@ -0,0 +1,87 @@
+//! The diagnostics `assem` prints to stderr for programs that do not
+//! assemble: `file.s:LINE: error: message`, and nothing written.
+
+mod common;
+
+use common::{assem, temp_dir};
+use std::fs;
+use std::process::Output;
+
+/// Whether `assem` succeeded, and its stderr, which is all it printed.
+fn errors(out: Output) -> (bool, String) {
+    assert!(out.stdout.is_empty());
+    (out.status.success(), String::from_utf8(out.stderr).unwrap())
+}
+
+#[test]
+fn test_parse_error() {
+    let dir = temp_dir("parse-error");
+    let path = dir.join("prog.s");
+    fs::write(&path, "push 1\npop\n  psh 2\nhalt\n").unwrap();
+    let (ok, stderr) = errors(assem(&dir, &["prog.s"]));
+    assert!(!ok);
+    assert_eq!(stderr, "prog.s:3:3: error: could not parse instruction 'psh 2'; did you mean 'push'?\n  psh 2\n  ^\n");
+    assert!(!dir.join("prog.o").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_later_errors() {
+    let dir = temp_dir("later-errors");
+    let path = dir.join("prog.s");
+    fs::write(dir.join("lib.s"), "Lf:\nret\n.endwhile\n").unwrap();
+
+    // Errors from directives and from resolving labels, in the file
+    // they are in, where one was included.
+    fs::write(&path, "push Lf\ncall\n.include \"lib.s\"\nhalt\n").unwrap();
+    assert_eq!(errors(assem(&dir, &["prog.s"])), (false, "lib.s:3: error: .endwhile without a matching .while\n".to_string()));
+    fs::write(&path, "Lf:\npush 1\nLf:\nhalt\n").unwrap();
+    assert_eq!(errors(assem(&dir, &["prog.s"])),
+               (false, "prog.s:3: error: label 'Lf' is defined more than once (first defined at prog.s:1)\n".to_string()));
+    fs::write(&path, "Lf:\npush Lf+3\n").unwrap();
+    assert_eq!(errors(assem(&dir, &["prog.s"])).1, "prog.s:2: error: 'Lf+3' is outside the program (0..=1)\n");
+    fs::write(&path, "push 1\n.align 8\nhalt\n").unwrap();
+    assert_eq!(errors(assem(&dir, &["prog.s", "--compress"])),
+               (false, "prog.s:2: error: .align only aligns the plain output, not an object file\n".to_string()));
+    assert_eq!(errors(assem(&dir, &["prog.s"])), (true, String::new()));
+
+    // After parse errors, with room for more.
+    fs::write(&path, "pop 1\nLf:\nhalt\nLf:\n").unwrap();
+    let (ok, stderr) = errors(assem(&dir, &["prog.s", "--max-errors", "0"]));
+    assert!(!ok);
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert!(lines[0].starts_with("prog.s:1:"), "{}", stderr);
+    assert_eq!(lines[3], "prog.s:4: error: label 'Lf' is defined more than once (first defined at prog.s:2)");
+
+    // Warnings do not change the exit status, unless they are errors.
+    fs::write(&path, "push 1\npop\n").unwrap();
+    assert_eq!(errors(assem(&dir, &["prog.s", "--warn"])),
+               (true, "prog.s: warning: program has no 'halt' and will run off its end\n".to_string()));
+    assert!(!errors(assem(&dir, &["prog.s", "--warn=error"])).0);
+    fs::write(&path, "push 2\npush undef\nalloc\npush 0\nset\nhalt\n").unwrap();
+    assert_eq!(errors(assem(&dir, &["prog.s", "--warn"])),
+               (true, "prog.s:5: warning: 'set' pops 3 values, but only 2 pushed before it\n".to_string()));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_json_messages() {
+    let dir = temp_dir("json-messages");
+    let path = dir.join("prog.s");
+    fs::write(&path, "push 1\n  peek x\nLf:\nLf:\npush 2\n").unwrap();
+    let (ok, stderr) = errors(assem(&dir, &["prog.s", "--message-format=json", "--max-errors", "0", "--warn"]));
+    assert!(!ok);
+    assert_eq!(stderr.lines().collect::<Vec<_>>(), [
+        r#"{"file": "prog.s", "line": 2, "col": 8, "severity": "error", "message": "could not parse integer 'x'"}"#,
+        r#"{"file": "prog.s", "line": 4, "col": null, "severity": "error", "message": "label 'Lf' is defined more than once (first defined at prog.s:3)"}"#,
+    ]);
+
+    fs::write(&path, "push 1\npop\n").unwrap();
+    let (ok, stderr) = errors(assem(&dir, &["prog.s", "--message-format", "json", "--warn"]));
+    assert!(ok);
+    assert_eq!(stderr, "{\"file\": \"prog.s\", \"line\": null, \"col\": null, \"severity\": \"warning\", \
+                        \"message\": \"program has no 'halt' and will run off its end\"}\n");
+    assert_eq!(errors(assem(&dir, &["prog.s", "--message-format=xml"])),
+               (false, "error: unknown message format 'xml' (expected human or json)\n".to_string()));
+    fs::remove_dir_all(dir).unwrap();
+}
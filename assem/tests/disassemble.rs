@@ -0,0 +1,69 @@
+//! `assem -d` end to end: assembling each program of the test corpus,
+//! and one with scoped and generated labels, disassembling it and
+//! assembling the text again gives back the same bytes.
+
+mod common;
+
+use common::{assem, corpus, temp_dir};
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn test_round_trip() {
+    let dir = temp_dir("disassemble");
+    for source in corpus() {
+        let expected = fs::read(source.with_extension("expected")).unwrap();
+        // The plain output, with no symbols, and an object file with them.
+        for flag in ["--little-endian", "--compress"] {
+            let (object, text, again) = (dir.join("prog.o"), dir.join("prog.s"), dir.join("again.o"));
+            let o = Path::new("-o");
+            assert!(assem(&dir, &[&source, Path::new(flag), o, &object]).status.success());
+            let out = assem(&dir, &[Path::new("-d"), &object, Path::new(flag)]);
+            assert!(out.status.success(), "{}: {}", source.display(), String::from_utf8_lossy(&out.stderr));
+            fs::write(&text, &out.stdout).unwrap();
+            assert!(assem(&dir, &[&text, o, &again]).status.success(), "{}", String::from_utf8_lossy(&out.stdout));
+            assert_eq!(fs::read(&again).unwrap(), expected, "{} {}", source.display(), flag);
+
+            // Or to a file.
+            assert!(assem(&dir, &[Path::new("--disassemble"), &object, Path::new(flag), o, &text]).status.success());
+            assert_eq!(fs::read(&text).unwrap(), out.stdout);
+        }
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// A program whose symbols include scoped labels and the labels of
+/// `.while`, which are not labels that can be written in source.
+const SCOPED: &str = "Lmain:\n    push 3\n.while\n    peek 0\n    push 0\n    binary <\n.do\n    push Ldec\n    call\n\
+                      .endwhile\n    halt\nLdec:\n.Lx:\n    push -1\n    binary +\n    push .Lx\n    pop\n    swap; ret\n";
+
+#[test]
+fn test_round_trip_scoped() {
+    let dir = temp_dir("disassemble-scoped");
+    let (source, object, text) = (dir.join("prog.s"), dir.join("prog.o"), dir.join("again.s"));
+    let (expected, again) = (dir.join("expected.o"), dir.join("again.o"));
+    let o = Path::new("-o");
+    fs::write(&source, SCOPED).unwrap();
+    assert!(assem(&dir, &[&source, o, &expected]).status.success());
+    assert!(assem(&dir, &[&source, Path::new("--compress"), o, &object]).status.success());
+    let out = assem(&dir, &[Path::new("-d"), &object, o, &text]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let out = assem(&dir, &[&text, o, &again]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(fs::read(&again).unwrap(), fs::read(&expected).unwrap());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_corrupt() {
+    let dir = temp_dir("disassemble-corrupt");
+    let path = dir.join("prog.o");
+    // An entry pc, `push 7` and `push` cut short.
+    fs::write(&path, [0, 0, 0, 2, 0x00, 0x01, 0, 0, 0, 7, 0x00, 0x01, 0]).unwrap();
+    let out = assem(&dir, &[Path::new("-d"), &path]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert_eq!(String::from_utf8(out.stderr).unwrap(),
+               format!("{}: error: byte 0xa (instr #1): truncated i32 (needs 4 bytes, 1 left)\n", path.display()));
+    fs::remove_dir_all(dir).unwrap();
+}
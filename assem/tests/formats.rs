@@ -0,0 +1,113 @@
+//! `assem --format`: each output encoding of the same program, with
+//! the extension it is written with by default.
+
+mod common;
+
+use common::temp_dir;
+use grumpy::Endianness;
+use grumpy::object::ObjectFile;
+use std::fs;
+use std::process::Output;
+
+#[test]
+fn test_formats() {
+    let dir = temp_dir("formats");
+    fs::write(dir.join("prog.s"), "push 42\nhalt\n").unwrap();
+    let assem = |args: &[&str]| -> Output {
+        common::assem(&dir, &[&["prog.s"], args].concat())
+    };
+    let formats: [(&str, &str, &[u8]); 7] = [
+        ("bin", "prog.o", &[0, 0, 0, 2, 0x00, 0x01]),
+        ("json", "prog.json", b"{\n"),
+        ("hex", "prog.hexdump", b"      00 00 00 02"),
+        ("base64", "prog.b64", b"R1JQ"),
+        ("rust", "prog.rs", b"pub const PROG: &[u8] = &["),
+        ("c", "prog.h", b"#ifndef PROG_H"),
+        ("ihex", "prog.hex", b":0B000000"),
+    ];
+    for (format, file, start) in formats {
+        let out = assem(&["--format", format, "-o", "-"]);
+        assert!(out.status.success(), "{}: {}", format, String::from_utf8_lossy(&out.stderr));
+        assert!(out.stdout.starts_with(start), "{}: {:?}", format, String::from_utf8_lossy(&out.stdout));
+        if format != "bin" {
+            assert!(String::from_utf8(out.stdout.clone()).unwrap().ends_with('\n'), "{}", format);
+        }
+        // The same, named after the input.
+        assert!(assem(&[&format!("--format={}", format)]).status.success());
+        assert_eq!(fs::read(dir.join(file)).unwrap(), out.stdout, "{}", format);
+    }
+    assert_eq!(assem(&[]).status.code(), Some(0));
+    assert_eq!(fs::read(dir.join("prog.o")).unwrap()[..4], [0, 0, 0, 2]);
+
+    let out = assem(&["--format", "xml"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stderr).unwrap(),
+               "error: unknown format 'xml' (expected bin, json, hex, base64, rust, c or ihex)\n");
+    let out = assem(&["--format", "json", "--hexdump"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stderr).unwrap(),
+               "error: --hexdump cannot be used with --format json (--format hex writes the dump to a file)\n");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_data_formats() {
+    // Only an object file has a data segment for the text of a .datastring.
+    let dir = temp_dir("data-formats");
+    fs::write(dir.join("prog.s"), ".datastring \"hi\"\nget\nhalt\n").unwrap();
+    let assem = |args: &[&str]| -> Output {
+        common::assem(&dir, &[&["prog.s"], args].concat())
+    };
+    for args in [&["--compress"][..], &["--format", "base64"]] {
+        let out = assem(args);
+        assert!(out.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&out.stderr));
+    }
+    assert!(fs::read(dir.join("prog.o")).unwrap().starts_with(b"GRPY"));
+    for args in [&[][..], &["--format", "json"], &["--compress", "--hexdump"]] {
+        let out = assem(args);
+        assert_eq!(out.status.code(), Some(1), "{:?}", args);
+        assert_eq!(String::from_utf8(out.stderr).unwrap(),
+                   "prog.s: error: the program has data (from .datastring), which only an object file holds \
+                    (use --compress or --format base64)\n");
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_metadata() {
+    let dir = temp_dir("metadata");
+    fs::write(dir.join("prog.s"), "push 42\nhalt\n").unwrap();
+    let read = |args: &[&str]| -> ObjectFile {
+        let out = common::assem(&dir, &[&["prog.s", "-o", "-", "--compress"], args].concat());
+        assert!(out.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&out.stderr));
+        ObjectFile::read(&out.stdout[..], Endianness::Big).unwrap()
+    };
+    assert!(read(&[]).metadata.is_empty());
+    let metadata = read(&["--metadata", "--reproducible", "-DN=1"]).metadata;
+    assert_eq!(metadata["source"], "prog.s");
+    assert_eq!(metadata["assembler"], format!("grumpy {}", env!("CARGO_PKG_VERSION")));
+    assert_eq!(metadata["options"], "--compress --metadata --reproducible -DN=1");
+    assert!(!metadata.contains_key("time"));
+    let metadata = read(&["--metadata"]).metadata;
+    assert!(metadata["source"].ends_with("prog.s") && metadata["source"] != "prog.s");
+    assert!(metadata.contains_key("time"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_debug_lines() {
+    let dir = temp_dir("debug-lines");
+    fs::write(dir.join("prog.s"), "push 1\n\nL0:\npush 2\nswap\nhalt\n").unwrap();
+    let read = |args: &[&str]| -> ObjectFile {
+        let out = common::assem(&dir, &[&["prog.s", "-o", "-", "--compress"], args].concat());
+        assert!(out.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&out.stderr));
+        ObjectFile::read(&out.stdout[..], Endianness::Big).unwrap()
+    };
+    assert_eq!(read(&[]).line_for_pc(0), None);
+    for flag in ["-g", "--debug"] {
+        let program = read(&[flag]);
+        let lines: Vec<_> = (0..4).map(|pc| program.line_for_pc(pc)).collect();
+        assert_eq!(lines, [Some(("prog.s", 1)), Some(("prog.s", 4)), Some(("prog.s", 5)), Some(("prog.s", 6))]);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,44 @@
+//! `assem main.s runtime.s -o prog.o`: several inputs assembled as one
+//! program, as if concatenated, with each file named in its
+//! diagnostics.
+
+mod common;
+
+use common::{assem, temp_dir};
+use std::fs;
+
+#[test]
+fn test_inputs() {
+    let dir = temp_dir("inputs");
+    let (main, runtime) = ("push 20\nLmain:\npush Ldouble\ncall\nhalt\n", "Ldouble:\nvar 0; var 0\nbinary +\nret\n");
+    fs::write(dir.join("main.s"), main).unwrap();
+    fs::write(dir.join("runtime.s"), runtime).unwrap();
+    fs::write(dir.join("all.s"), format!("{}{}", main, runtime)).unwrap();
+
+    // A call from one file to a label in the next.
+    let out = assem(&dir, &["main.s", "runtime.s", "-o", "prog.o"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(assem(&dir, &["all.s"]).status.success());
+    assert_eq!(fs::read(dir.join("prog.o")).unwrap(), fs::read(dir.join("all.o")).unwrap());
+
+    // Errors name the file and its own line; a label both define names
+    // both.
+    fs::write(dir.join("main.s"), "push 20\nLdouble:\nhalt\n").unwrap();
+    let out = assem(&dir, &["runtime.s", "main.s", "-o", "prog.o"]);
+    assert!(!out.status.success());
+    assert_eq!(String::from_utf8(out.stderr).unwrap(),
+               "main.s:2: error: label 'Ldouble' is defined more than once (first defined at runtime.s:1)\n");
+    fs::write(dir.join("main.s"), "push 20\npsh Ldouble\nhalt\n").unwrap();
+    let out = assem(&dir, &["runtime.s", "main.s", "-o", "prog.o"]);
+    assert!(String::from_utf8(out.stderr).unwrap().starts_with("main.s:2:1: error: "));
+
+    // The output cannot be named after one of them.
+    fs::write(dir.join("main.s"), main).unwrap();
+    let out = assem(&dir, &["main.s", "runtime.s"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stderr).unwrap(), "error: -o is required with more than one input file\n");
+    let out = assem(&dir, &["main.s", "runtime.s", "-o", "runtime.s"]);
+    assert!(String::from_utf8(out.stderr).unwrap().ends_with("it is the input file\n"));
+    assert_eq!(fs::read_to_string(dir.join("runtime.s")).unwrap(), runtime);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,34 @@
+//! `assem --listing`: the listing of the golden test in `grumpy`,
+//! written beside the object file, which is the same as without it.
+
+mod common;
+
+use common::{assem, temp_dir};
+use std::fs;
+use std::path::Path;
+
+const SOURCE: &str = "// Double 21.\n.equ N 21\nLmain:\n    push N\n    push Ldouble  // not defined yet\n    call\n    halt\n\n\
+                      Ldouble: var 0; var 0\n    binary +\n    swap; ret\n";
+
+#[test]
+fn test_listing() {
+    let dir = temp_dir("listing");
+    let path = dir.join("prog.s");
+    fs::write(&path, SOURCE).unwrap();
+    let golden = fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("../grumpy/tests/golden/listing.txt")).unwrap();
+
+    assert!(assem(&dir, &["prog.s", "-o", "plain.o"]).status.success());
+    let out = assem(&dir, &["prog.s", "--listing", "out/prog.lst"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(fs::read_to_string(dir.join("out/prog.lst")).unwrap(), golden);
+    assert_eq!(fs::read(dir.join("prog.o")).unwrap(), fs::read(dir.join("plain.o")).unwrap());
+
+    // To stdout, or not at all if the program does not assemble.
+    let out = assem(&dir, &["prog.s", "--listing", "-", "-o", "again.o"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), golden);
+    fs::write(&path, "push Lf\nhalt\n").unwrap();
+    let out = assem(&dir, &["prog.s", "--listing", "-"]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,54 @@
+//! Drive `assem` as a filter, `assem - -o -`, with piped stdin and
+//! stdout, and compare with what it writes to files.
+
+mod common;
+
+use common::{assem, assem_piped, corpus, temp_dir};
+use std::fs;
+
+#[test]
+fn test_pipe_corpus() {
+    for source in corpus() {
+        let out = assem_piped(&["-", "-o", "-"], &fs::read(&source).unwrap());
+        assert!(out.status.success(), "{}: {}", source.display(), String::from_utf8_lossy(&out.stderr));
+        assert_eq!(out.stdout, fs::read(source.with_extension("expected")).unwrap(), "{}", source.display());
+        assert!(out.stderr.is_empty(), "{}", source.display());
+    }
+}
+
+#[test]
+fn test_pipe_matches_file() {
+    let dir = temp_dir("stdio");
+    let source = &corpus()[0];
+    let text = fs::read(source).unwrap();
+    fs::write(dir.join("prog.s"), &text).unwrap();
+    for format in [&["--little-endian"][..], &["--compress"], &["--format", "base64"]] {
+        let file = dir.join("prog.s");
+        assert!(assem(&dir, &[&["prog.s", "-o", "prog.out"], format].concat()).status.success());
+        let written = fs::read(dir.join("prog.out")).unwrap();
+        // From a file to stdout, from stdin to stdout, and from stdin
+        // with no input argument at all.
+        let mut args = vec![file.to_str().unwrap(), "-o", "-"];
+        args.extend(format);
+        assert_eq!(assem_piped(&args, b"").stdout, written);
+        args.splice(..3, ["-"]);
+        assert_eq!(assem_piped(&args, &text).stdout, written);
+        assert_eq!(assem_piped(&args[1..], &text).stdout, written);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_pipe_keeps_stdout_clean() {
+    let source = &corpus()[0];
+    let out = assem_piped(&["-v", "--warn", "-o", "-"], &fs::read(source).unwrap());
+    assert_eq!(out.stdout, fs::read(source.with_extension("expected")).unwrap());
+    assert!(!out.stderr.is_empty());
+
+    let out = assem_piped(&["-", "--max-errors", "0"], b"push 1\npsh 2\nhalt\nfrob\n");
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.starts_with("<stdin>:2:1: error: "), "{}", stderr);
+    assert!(stderr.contains("<stdin>:4:1: error: "), "{}", stderr);
+}
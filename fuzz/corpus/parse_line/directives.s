@@ -0,0 +1,14 @@
+.equ N 10
+.global Lmain
+.local Lhelper
+Lmain: push N; push Lend+1; push .Lloop
+.Lloop:
+.while; push true; .do; .break; .endwhile
+.times 2 { pop; peek 0 }
+.string "a \"quoted\"\n string"
+.byte 1 2 0xff
+.i32 -7
+.align 4
+push @3; push tt; push undef; push -2147483648
+unary neg; binary ==; var 0; store 1; setframe 2 // comment
+Lend: halt
@@ -0,0 +1,8 @@
+setframe 0
+push Lmain
+call
+halt
+Lmain:
+push true
+unary neg
+ret
@@ -0,0 +1,7 @@
+setframe 0
+push Lmain
+call
+halt
+Lmain:
+push 4
+ret
@@ -0,0 +1,22 @@
+//! Throws arbitrary text at the line parser, which must return `Ok` or
+//! `Err` for every input rather than panic.
+
+#![no_main]
+
+use grumpy::isa::{lowercase_opcodes, PInstr};
+use libfuzzer_sys::fuzz_target;
+use std::str::FromStr;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    for line in text.lines() {
+        let _ = PInstr::from_str(line);
+        if let Err(e) = PInstr::parse_line(line) {
+            // The column must point into the line.
+            let _ = e.caret(line);
+        }
+        let _ = PInstr::parse_line(&lowercase_opcodes(line));
+    }
+});
@@ -1,40 +1,746 @@
-use crate::isa::{*, Instr::*, PInstr::*, Val::*};
-use std::collections::HashMap;
+use crate::isa::{*, PInstr::*};
+use crate::{byte_len, Endianness, ToBytes};
+use crate::expand::{expand, ExpandError, Numbered, Sources};
+use crate::ParseError;
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt::{self, Display};
+
+/// Advance the program counter past one instruction. Labels and the
+/// object file header address instructions with a `u32`, so a program
+/// may hold at most `u32::MAX` instructions.
+///
+/// A data byte (`Instr::Byte`) counts as one instruction, so a `.i32`
+/// takes four pc slots. The pc is therefore an index into the list of
+/// instructions and data bytes, not a byte offset into the object file.
+fn next_pc(pc: u32) -> Result<u32, String> {
+    pc.checked_add(1)
+        .ok_or_else(|| format!("program too large: more than {} instructions", u32::MAX))
+}
+
+/// Whether a label may be referenced from other modules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    /// Private to its module (the default).
+    Local,
+    /// Exported with `.global`.
+    Global,
+}
+
+impl Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Visibility::Local  => write!(f, "local"),
+            Visibility::Global => write!(f, "global"),
+        }
+    }
+}
+
+/// A label's address and visibility.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub pc: u32,
+    pub visibility: Visibility,
+}
+
+/// Label addresses, ordered by label so that anything printed or
+/// serialized from them is the same on every run.
+pub type Symbols = BTreeMap<Label, Symbol>;
+
 /// Translate an assembly program to an equivalent bytecode program.
 pub fn assemble(pinstrs : &[PInstr]) -> Result<Vec<Instr>, String> {
+    assemble_with_symbols(pinstrs).map(|(instrs, _)| instrs)
+}
+
+/// Like `assemble`, but also return the address of every label.
+pub fn assemble_with_symbols(pinstrs : &[PInstr]) -> Result<(Vec<Instr>, Symbols), String> {
+    assemble_at(pinstrs).map_err(|(_, message)| message)
+}
+
+/// Like `assemble_with_symbols`, for a numbered program such as
+/// `expand::expand` returns. An error gives the line of the
+/// pseudo-instruction it is about, as an `ExpandError` does, and for a
+/// label defined twice, the line of its first definition.
+pub fn assemble_numbered(numbered: &[Numbered]) -> Result<(Vec<Instr>, Symbols), ExpandError> {
+    let pinstrs: Vec<PInstr> = numbered.iter().map(|(_, i)| i.clone()).collect();
+    assemble_at(&pinstrs).map_err(|(n, message)| {
+        let previous = match &pinstrs[n] {
+            PLabel(l) => pinstrs[..n].iter().position(|i| matches!(i, PLabel(m) if m == l)).map(|k| numbered[k].0),
+            _ => None,
+        };
+        ExpandError { line: numbered[n].0, message, previous }
+    })
+}
+
+/// `assemble_with_symbols`, failing with the position in `pinstrs` of
+/// the pseudo-instruction at fault.
+fn assemble_at(pinstrs : &[PInstr]) -> Result<(Vec<Instr>, Symbols), (usize, String)> {
     let mut assembled_inp : Vec<Instr> = Vec::new();
+    let (labels, pc) = resolve_labels(pinstrs)?;
+
+    for (n, i) in pinstrs.iter().enumerate() {
+        match i{
+            PPush(t) => match labels.get(t) {
+                Some(k) => assembled_inp.push(Instr::Push(Val::Vloc(*k))),
+                None => return Err((n, format!("undefined label '{}'", t))),
+            },
+            PPushOffset(t, offset) => {
+                let target = offset_target(&labels, t, *offset, pc).map_err(|e| (n, e))?;
+                assembled_inp.push(Instr::Push(Val::Vloc(target)));
+            }
+            PI(s) => assembled_inp.push(*s),
+            _ => ()
+        }
+    }
+    let count: u32 = pc;
+    assembled_inp.push(Instr::Push(Val::Vloc(count)));
+    Ok((assembled_inp, symbols(pinstrs, labels)?))
+}
+
+/// Errors raised by `assemble_lines`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssembleError {
+    /// A line, numbered from 1, that could not be parsed.
+    Parse { line: usize, error: ParseError },
+    /// A directive used wrongly.
+    Expand(ExpandError),
+    /// A program that parses but cannot be assembled, such as one that
+    /// defines a label twice.
+    Assemble(String),
+}
+
+impl Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssembleError::Parse { line, error } => write!(f, "line {}: {}", line, error),
+            AssembleError::Expand(e) => write!(f, "{}", e),
+            AssembleError::Assemble(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl error::Error for AssembleError {}
+
+/// Assemble a program given as lines of source, such as a tool
+/// generates them, without collecting the text first. Each line is
+/// parsed as it arrives; directives are expanded and labels resolved
+/// once the last has. Returns the instructions (without the count that
+/// `assemble` appends) and the entry pc.
+pub fn assemble_lines<I: IntoIterator<Item = String>>(lines: I) -> Result<(Vec<Instr>, u32), AssembleError> {
+    let mut numbered = Vec::new();
+    for (n, line) in lines.into_iter().enumerate() {
+        let pinstrs = PInstr::parse_line(&line).map_err(|error| AssembleError::Parse { line: n + 1, error })?;
+        numbered.extend(pinstrs.into_iter().map(|i| (n + 1, i)));
+    }
+    let pinstrs: Vec<PInstr> = expand(&numbered).map_err(AssembleError::Expand)?
+        .into_iter()
+        .map(|(_, i)| i)
+        .collect();
+    let mut instrs = assemble(&pinstrs).map_err(AssembleError::Assemble)?;
+    let entry = match instrs.pop() {
+        Some(Instr::Push(Val::Vloc(pc))) => pc,
+        _ => unreachable!("assemble appends the instruction count"),
+    };
+    Ok((instrs, entry))
+}
+
+/// Resolve `push label+offset`. The result must be a location in the
+/// program: from 0 up to and including `end`, the address just past its
+/// last instruction (where a label at the very end points).
+fn offset_target(labels: &HashMap<Label, u32>, label: &Label, offset: i32, end: u32)
+                 -> Result<u32, String> {
+    let addr = labels.get(label).ok_or_else(|| format!("undefined label '{}'", label))?;
+    i64::from(*addr).checked_add(i64::from(offset))
+        .filter(|t| (0..=i64::from(end)).contains(t))
+        .map(|t| t as u32)
+        .ok_or_else(|| format!("'{}{:+}' is outside the program (0..={})", label, offset, end))
+}
+
+/// Check a program's `.global` and `.local` declarations, returning the
+/// visibility of every label they mention. Declaring a label that is
+/// never defined, or declaring one label both ways, is an error.
+pub fn declared_visibility(pinstrs : &[PInstr]) -> Result<HashMap<Label, Visibility>, String> {
+    visibility(pinstrs).map_err(|(_, message)| message)
+}
+
+/// `declared_visibility`, failing with the position of the declaration
+/// at fault.
+fn visibility(pinstrs : &[PInstr]) -> Result<HashMap<Label, Visibility>, (usize, String)> {
+    let defined: Vec<&Label> = pinstrs.iter()
+        .filter_map(|i| match i { PLabel(l) => Some(l), _ => None })
+        .collect();
+    let mut declared = HashMap::new();
+    for (n, i) in pinstrs.iter().enumerate() {
+        let (label, visibility) = match i {
+            PGlobal(l) => (l, Visibility::Global),
+            PLocal(l) => (l, Visibility::Local),
+            _ => continue,
+        };
+        if !defined.contains(&label) {
+            return Err((n, format!("label '{}' is declared {} but never defined", label, visibility)));
+        }
+        match declared.insert(label.clone(), visibility) {
+            Some(v) if v != visibility =>
+                return Err((n, format!("label '{}' is declared both local and global", label))),
+            _ => (),
+        }
+    }
+    Ok(declared)
+}
+
+/// Tag resolved label addresses with their declared visibility.
+fn symbols(pinstrs : &[PInstr], labels: HashMap<Label, u32>) -> Result<Symbols, (usize, String)> {
+    let declared = visibility(pinstrs)?;
+    Ok(labels.into_iter()
+        .map(|(label, pc)| {
+            let visibility = declared.get(&label).copied().unwrap_or(Visibility::Local);
+            (label, Symbol { pc, visibility })
+        })
+        .collect())
+}
+
+/// First pass: find the address of every label, and the number of
+/// instructions in the program. An error gives the position of the
+/// pseudo-instruction at fault.
+fn resolve_labels(pinstrs : &[PInstr]) -> Result<(HashMap<Label, u32>, u32), (usize, String)> {
     let mut pc: u32 = 0;
     let mut labels = HashMap::<String, u32>::new();
-    let mut is_label = true;
 
-    for i in pinstrs{
+    for (n, i) in pinstrs.iter().enumerate() {
         match i {
-            PInstr::PLabel(t) => is_label = true,
-            _ => is_label = false,
+            PI(Instr::Push(v)) if v.is_internal() =>
+                return Err((n, format!("'{}' is internal to the VM and cannot appear in a program", v))),
+            PDirective(_) | PRel(_) | PPushConst(_) =>
+                return Err((n, format!("'{}' must be expanded before assembly", i))),
+            PLabel(t) if t.starts_with('.') =>
+                return Err((n, format!("'{}' must be expanded before assembly", i))),
+            PLabel(t) if labels.contains_key(t) =>
+                return Err((n, format!("label '{}' is defined more than once", t))),
+            PLabel(t) => {
+                labels.insert(t.clone(), pc);
+            }
+            _ if i.emits_instruction() => pc = next_pc(pc).map_err(|e| (n, e))?,
+            _ => (),
         }
-        if is_label{
-            let string: &str = &i.to_string();
-            let last_off: &str = &string[..string.len() - 1];
-            labels.insert(last_off.to_string(), pc);
+    }
+    Ok((labels, pc))
+}
+
+/// The source line of every instruction of an expanded program (see
+/// `expand::expand`), as `(pc, line)` pairs in order of pc: each line
+/// covers the instructions from its pc up to the next pair's. Labels
+/// take up no pc, so the instructions after one are on the line that
+/// follows it, and code that a directive expands to is on the line of
+/// the directive.
+pub fn line_numbers(numbered: &[Numbered]) -> Result<Vec<(u32, usize)>, String> {
+    let mut pc: u32 = 0;
+    let mut lines: Vec<(u32, usize)> = Vec::new();
+
+    for (line, i) in numbered {
+        if !i.emits_instruction() {
+            continue;
         }
-        else{
-            pc = pc + 1;
+        if lines.last().is_none_or(|(_, l)| l != line) {
+            lines.push((pc, *line));
         }
+        pc = next_pc(pc)?;
     }
+    Ok(lines)
+}
+
+/// An assembly listing of an expanded program (see `expand::expand`)
+/// read as `sources`: every line of source, in order, after the pc and
+/// the bytes of its first instruction. A line that only defines a
+/// label has the label's pc and no bytes, and one with no code has
+/// neither. The other instructions of a line, from a directive that
+/// expands to several, follow on lines of their own with no text.
+///
+/// ```text
+/// 0000  00 04 00 00 00 02   push Lf
+/// 0001  0f                  halt
+/// 0002                      Lf:
+/// ```
+///
+/// No pass drops code between expanding and assembling, so every line
+/// with code in `sources` has its pc.
+pub fn source_listing(expanded: &[Numbered], sources: &Sources, endianness: Endianness)
+                      -> Result<String, ExpandError> {
+    let (instrs, symbols) = assemble_numbered(expanded)?;
+    // The pc of each line, and the pcs of its instructions.
+    let mut rows: HashMap<usize, (u32, Vec<u32>)> = HashMap::new();
+    let mut pc: u32 = 0;
+    for (line, i) in expanded {
+        match i {
+            PLabel(l) => { rows.entry(*line).or_insert((symbols[l].pc, Vec::new())); }
+            _ if i.emits_instruction() => {
+                rows.entry(*line).or_insert((pc, Vec::new())).1.push(pc);
+                pc += 1;
+            }
+            _ => (),
+        }
+    }
+
+    let hex = |pc: u32| instrs[pc as usize].to_bytes_with(endianness).iter()
+        .map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+    let mut lines: Vec<(String, String, &str)> = Vec::new();
+    for line in 1..=sources.len() {
+        let text = sources.text(line).unwrap_or("");
+        match rows.get(&line) {
+            Some((pc, pcs)) => {
+                lines.push((format!("{:04}", pc), pcs.first().map_or(String::new(), |&pc| hex(pc)), text));
+                lines.extend(pcs.iter().skip(1).map(|&pc| (format!("{:04}", pc), hex(pc), "")));
+            }
+            None => lines.push((String::new(), String::new(), text)),
+        }
+    }
+    let width = lines.iter().map(|(_, hex, _)| hex.len()).max().unwrap_or(0);
+    Ok(lines.iter()
+        .map(|(pc, hex, text)| format!("{:4}  {:<width$}   {}", pc, hex, text, width = width).trim_end().to_string() + "\n")
+        .collect())
+}
+
+/// A label reference left unresolved by `assemble_relocatable`: the
+/// index of the `Push` instruction to patch, and the label it pushes.
+pub type Relocation = (usize, Label);
+
+/// A module assembled without knowing where it will be loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocatable {
+    /// The instructions, with every label push encoded as `push 0`.
+    pub instrs: Vec<Instr>,
+    /// The pushes to patch once the module's position is known.
+    pub relocations: Vec<Relocation>,
+    /// The module's labels, relative to the start of the module.
+    pub symbols: Symbols,
+}
+
+/// Assemble a program for loading at an address decided later. Unlike
+/// `assemble`, label references are left as placeholders (so they may
+/// name labels defined in other modules), and no trailing instruction
+/// count is appended. A placeholder holds the reference's offset
+/// (`push Lfoo+4` becomes `push 4`), as a two's complement `Vloc`.
+pub fn assemble_relocatable(pinstrs : &[PInstr]) -> Result<Relocatable, String> {
+    let (labels, _) = resolve_labels(pinstrs).map_err(|(_, message)| message)?;
+    let mut instrs = Vec::new();
+    let mut relocations = Vec::new();
 
     for i in pinstrs {
-        match i{
+        match i {
             PPush(t) => {
-                match labels.get(t){
-                    Some(K) => assembled_inp.push(Instr::Push(Val::Vloc(*K))),
-                    None => (),
-                }
+                relocations.push((instrs.len(), t.clone()));
+                instrs.push(Instr::Push(Val::Vloc(0)));
             }
-            PI(s) => assembled_inp.push(*s),
+            PPushOffset(t, n) => {
+                relocations.push((instrs.len(), t.clone()));
+                instrs.push(Instr::Push(Val::Vloc(*n as u32)));
+            }
+            PI(s) => instrs.push(*s),
             _ => ()
         }
     }
-    let count: u32 = pc;
-    assembled_inp.push(Instr::Push(Val::Vloc(count)));
-    Ok(assembled_inp)
+    let symbols = symbols(pinstrs, labels).map_err(|(_, message)| message)?;
+    Ok(Relocatable { instrs, relocations, symbols })
+}
+
+/// Patch the placeholder pushes of a relocatable module loaded at
+/// `base_offset`. Each relocated label resolves to `base_offset` plus
+/// its address in `symbols`, plus the offset held in the placeholder.
+pub fn apply_relocations(instrs: &mut [Instr], relocs: &[Relocation],
+                         base_offset: u32, symbols: &Symbols) -> Result<(), String> {
+    for (index, label) in relocs {
+        let addr = symbols.get(label)
+            .ok_or_else(|| format!("undefined label '{}'", label))?.pc;
+        match instrs.get_mut(*index) {
+            Some(Instr::Push(v)) => {
+                let offset = match v { Val::Vloc(n) => *n as i32, _ => 0 };
+                *v = Val::Vloc(base_offset.checked_add(addr)
+                    .and_then(|t| t.checked_add_signed(offset))
+                    .ok_or_else(|| format!("label '{}' relocated outside the address space", label))?);
+            }
+            _ => return Err(format!("relocation for '{}' does not point at a push", label)),
+        }
+    }
+    Ok(())
+}
+
+/// Check that a program (without the trailing entry push) has a `halt`
+/// somewhere, without which it can only end by running off its last
+/// instruction. `ret` from the entry function is not enough: it returns
+/// to nowhere. Data bytes that happen to encode `halt` don't count.
+pub fn check_has_halt(instrs: &[Instr]) -> Result<(), String> {
+    if instrs.contains(&Instr::Halt) {
+        Ok(())
+    } else {
+        Err("program has no 'halt' and will run off its end".to_string())
+    }
+}
+
+/// Check that each `alloc`, `set` and `get` of an expanded program
+/// (see `expand::expand`) comes after enough pushes to supply the
+/// operands it pops (see `Instr::stack_effect`): `alloc` a size and an
+/// initial value, `set` an array, an index and a value, and `get` an
+/// array and an index. Returns a warning, at its line, for each that
+/// does not.
+///
+/// This is a best-effort check of straight-line code, and conservative:
+/// values are only counted from the start of the program or the last
+/// label, since a jump may bring anything on the stack to a label, so
+/// operands pushed before a label are not seen. Counting stops, until
+/// the next label, at an instruction that pops more than was counted.
+/// It says nothing about the values' types, or whether an index is in
+/// bounds.
+pub fn check_heap_operands(expanded: &[Numbered]) -> Vec<ExpandError> {
+    let mut warnings = Vec::new();
+    let mut depth = Some(0);
+    for (line, i) in expanded {
+        let (pops, pushes) = match i {
+            PLabel(_) => {
+                depth = Some(0);
+                continue;
+            }
+            PI(instr) => instr.stack_effect(),
+            _ if i.emits_instruction() => (0, 1),
+            _ => continue,
+        };
+        if let (PI(instr @ (Instr::Alloc | Instr::Set | Instr::Get)), Some(d)) = (i, depth) {
+            if d < pops {
+                warnings.push(ExpandError {
+                    line: *line,
+                    message: format!("'{}' pops {} values, but only {} pushed before it", instr, pops, d),
+                    previous: None,
+                });
+            }
+        }
+        depth = depth.and_then(|d| d.checked_sub(pops)).map(|d| d + pushes);
+    }
+    warnings
+}
+
+/// Render a symbol table as text, one `address visibility label` entry
+/// per line.
+pub fn format_symbols(symbols: &Symbols) -> String {
+    symbols.iter()
+        .map(|(label, sym)| format!("{} {} {}\n", sym.pc, sym.visibility, label))
+        .collect()
+}
+
+/// Summary of an assembled program, as printed by `assem --verbose`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// Number of instructions.
+    pub instrs: usize,
+    /// Number of labels.
+    pub labels: usize,
+    /// Size of the object file, including the 4-byte pc header.
+    pub bytes: usize,
+    /// The pc written in the object file header.
+    pub pc: u32,
+}
+
+impl Stats {
+    /// Compute the statistics of a program, given its instructions
+    /// (without the trailing count `assemble` appends) and symbols.
+    pub fn new(instrs: &[Instr], symbols: &Symbols) -> Stats {
+        Stats {
+            instrs: instrs.len(),
+            labels: symbols.len(),
+            bytes: 4 + byte_len(instrs),
+            pc: instrs.len() as u32,
+        }
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = |n: usize| if n == 1 { "" } else { "s" };
+        write!(f, "{} instruction{}, {} label{}, {} bytes, entry pc {}",
+               self.instrs, s(self.instrs), self.labels, s(self.labels), self.bytes, self.pc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_pc_overflow() {
+        assert_eq!(next_pc(0), Ok(1));
+        assert_eq!(next_pc(u32::MAX - 1), Ok(u32::MAX));
+        assert!(next_pc(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn test_symbols_deterministic() {
+        let src = "Lz:; Ly:; push Lx; Lx:; Lw:; halt; Lv:; Lu:; Lt:; ret; .global Lu";
+        let pinstrs = PInstr::parse_line(src).unwrap();
+        let (_, first) = assemble_with_symbols(&pinstrs).unwrap();
+        let (_, second) = assemble_with_symbols(&pinstrs).unwrap();
+        assert_eq!(format_symbols(&first), format_symbols(&second));
+        assert_eq!(format_symbols(&first), concat!("2 local Lt\n2 global Lu\n2 local Lv\n1 local Lw\n",
+                           "1 local Lx\n0 local Ly\n0 local Lz\n"));
+    }
+
+    #[test]
+    fn test_consecutive_labels() {
+        // Labels take up no pc, so a run of them all name the next
+        // instruction, and pushes of any of them are the same.
+        let src = "push 0\nLstart:\nLmain:\npush Lstart\npush Lmain\nbranch\nLend:\nLdone:\nhalt\n";
+        let pinstrs: Vec<PInstr> = src.lines().flat_map(|l| PInstr::parse_line(l).unwrap()).collect();
+        assert_eq!(pinstrs.iter().filter(|i| i.is_label()).count(), 4);
+        let (instrs, symbols) = assemble_with_symbols(&pinstrs).unwrap();
+        assert_eq!((symbols["Lstart"].pc, symbols["Lmain"].pc), (1, 1));
+        assert_eq!((symbols["Lend"].pc, symbols["Ldone"].pc), (4, 4));
+        assert_eq!(instrs[1], instrs[2]);
+        assert_eq!(instrs[..5], [Instr::Push(Val::Vi32(0)), Instr::Push(Val::Vloc(1)), Instr::Push(Val::Vloc(1)),
+                                 Instr::Branch, Instr::Halt]);
+    }
+
+    #[test]
+    fn test_label_before_instruction() {
+        // A label sharing a line with an instruction names that
+        // instruction, the same as on a line of its own.
+        let same_line = PInstr::parse_line("push 0; Lloop: push 1; push Lloop; branch").unwrap();
+        let own_line: Vec<PInstr> = "push 0\nLloop:\npush 1\npush Lloop\nbranch".lines()
+            .flat_map(|l| PInstr::parse_line(l).unwrap()).collect();
+        assert_eq!(same_line, own_line);
+        let (instrs, symbols) = assemble_with_symbols(&same_line).unwrap();
+        assert_eq!((symbols["Lloop"].pc, instrs[2]), (1, Instr::Push(Val::Vloc(1))));
+    }
+
+    #[test]
+    fn test_visibility() {
+        let src = ".global Lf; Lf:; ret; Lg:; ret; .local Lg";
+        let (_, symbols) = assemble_with_symbols(&PInstr::parse_line(src).unwrap()).unwrap();
+        assert_eq!(symbols["Lf"].visibility, Visibility::Global);
+        assert_eq!(symbols["Lg"].visibility, Visibility::Local);
+
+        let undefined = PInstr::parse_line(".global Lh; Lf:; ret").unwrap();
+        assert_eq!(assemble(&undefined).unwrap_err(),
+                   "label 'Lh' is declared global but never defined");
+        let both = PInstr::parse_line(".global Lf; .local Lf; Lf:; ret").unwrap();
+        assert_eq!(assemble(&both).unwrap_err(),
+                   "label 'Lf' is declared both local and global");
+    }
+
+    #[test]
+    fn test_check_has_halt() {
+        let assembled = |src| assemble(&PInstr::parse_line(src).unwrap()).unwrap();
+        assert_eq!(check_has_halt(&assembled("push Lmain; call; halt; Lmain:; push 1; ret")), Ok(()));
+        assert_eq!(check_has_halt(&assembled("Lmain:; push 1; push 2; binary +; ret")),
+                   Err("program has no 'halt' and will run off its end".to_string()));
+        assert!(check_has_halt(&[]).is_err());
+        assert!(check_has_halt(&[Instr::Byte(0x0F)]).is_err());
+    }
+
+    #[test]
+    fn test_check_heap_operands() {
+        let warnings = |src: &str| -> Vec<(usize, String)> {
+            let numbered = expand(&crate::expand::parse_numbered(src).unwrap()).unwrap();
+            check_heap_operands(&numbered).into_iter().map(|e| (e.line, e.message)).collect()
+        };
+        assert_eq!(warnings("push 2\npush undef\nalloc\npush 0\npush 7\nset\nhalt"), []);
+        assert_eq!(warnings("setframe 0\npush 2; push 0; alloc; store 0\nvar 0; push 1; get\nhalt"), []);
+        // `set` with no array under its index and value.
+        assert_eq!(warnings("push 2\npush undef\nalloc\npop\npush 0\npush 7\nset\nhalt"),
+                   [(7, "'set' pops 3 values, but only 2 pushed before it".to_string())]);
+        assert_eq!(warnings("Lf:\npush 0\nget\nret"), [(3, "'get' pops 2 values, but only 1 pushed before it".to_string())]);
+        // Past a label, what was pushed before it is not counted, and
+        // after popping more than was counted, nothing is checked.
+        assert_eq!(warnings("push 1; push 2\nLf:\nalloc\nhalt").len(), 1);
+        assert_eq!(warnings("Lf:\nbinary +\nget\nret"), []);
+    }
+
+    #[test]
+    fn test_stats() {
+        let src = "push Lmain; call; halt; Lmain:; push 3; ret";
+        let (mut instrs, symbols) = assemble_with_symbols(&PInstr::parse_line(src).unwrap()).unwrap();
+        instrs.pop();
+        assert_eq!(Stats::new(&instrs, &symbols).to_string(),
+                   "5 instructions, 1 label, 19 bytes, entry pc 5");
+
+        let (mut instrs, symbols) = assemble_with_symbols(&[]).unwrap();
+        instrs.pop();
+        assert_eq!(Stats::new(&instrs, &symbols).to_string(),
+                   "0 instructions, 0 labels, 4 bytes, entry pc 0");
+
+        let (mut instrs, symbols) = assemble_with_symbols(&PInstr::parse_line("halt").unwrap()).unwrap();
+        instrs.pop();
+        assert_eq!(Stats::new(&instrs, &symbols).to_string(),
+                   "1 instruction, 0 labels, 5 bytes, entry pc 1");
+    }
+
+    #[test]
+    fn test_relocation() {
+        let src = "push Lloop; Lloop:; var 0; push _Ldone; branch; push Lloop; branch; _Ldone:; ret";
+        let module = assemble_relocatable(&PInstr::parse_line(src).unwrap()).unwrap();
+        assert_eq!(module.instrs[0], Instr::Push(Val::Vloc(0)));
+        assert_eq!(module.relocations, vec![(0, "Lloop".to_string()),
+                                            (2, "_Ldone".to_string()),
+                                            (4, "Lloop".to_string())]);
+
+        for base in [0, 100] {
+            let mut instrs = module.instrs.clone();
+            apply_relocations(&mut instrs, &module.relocations, base, &module.symbols).unwrap();
+            for (index, label) in &module.relocations {
+                let target = match instrs[*index] {
+                    Instr::Push(Val::Vloc(u)) => u,
+                    _ => panic!("relocated instruction is not a push"),
+                };
+                let expected = match label.as_str() {
+                    "Lloop" => Instr::Var(0),
+                    _ => Instr::Ret,
+                };
+                assert_eq!(module.instrs[(target - base) as usize], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_relocation_errors() {
+        let module = assemble_relocatable(&PInstr::parse_line("push Lext; call").unwrap()).unwrap();
+        let mut instrs = module.instrs.clone();
+        assert!(apply_relocations(&mut instrs, &module.relocations, 0, &module.symbols).is_err());
+
+        let ext = Symbol { pc: 1, visibility: Visibility::Global };
+        let symbols: Symbols = vec![("Lext".to_string(), ext)].into_iter().collect();
+        assert!(apply_relocations(&mut instrs, &module.relocations, u32::MAX, &symbols).is_err());
+        apply_relocations(&mut instrs, &module.relocations, 7, &symbols).unwrap();
+        assert_eq!(instrs[0], Instr::Push(Val::Vloc(8)));
+    }
+
+    #[test]
+    fn test_label_offsets() {
+        let src = "push Ltable+2; branch; Ltable:; push 1; push 2; push 3; halt; Lend:";
+        let instrs = assemble(&PInstr::parse_line(src).unwrap()).unwrap();
+        assert_eq!(instrs[0], Instr::Push(Val::Vloc(4)));
+
+        let src = "push Lend-1; halt; Lend:; push Lend+0";
+        let instrs = assemble(&PInstr::parse_line(src).unwrap()).unwrap();
+        assert_eq!(instrs[0], Instr::Push(Val::Vloc(1)));
+        assert_eq!(instrs[2], Instr::Push(Val::Vloc(2)));
+
+        // The program ends at 2, just past `halt`.
+        let end = "Lstart:; push Lstart+2; halt";
+        assert!(assemble(&PInstr::parse_line(end).unwrap()).is_ok());
+        let past = "Lstart:; push Lstart+3; halt";
+        assert_eq!(assemble(&PInstr::parse_line(past).unwrap()).unwrap_err(),
+                   "'Lstart+3' is outside the program (0..=2)");
+        let before = "Lstart:; push Lstart-1; halt";
+        assert!(assemble(&PInstr::parse_line(before).unwrap()).is_err());
+        let undefined = "push Lnone+1; halt";
+        assert_eq!(assemble(&PInstr::parse_line(undefined).unwrap()).unwrap_err(),
+                   "undefined label 'Lnone'");
+    }
+
+    #[test]
+    fn test_relocation_offsets() {
+        let module = assemble_relocatable(&PInstr::parse_line("push Lf-1; Lf:; push Lf+2; ret").unwrap()).unwrap();
+        assert_eq!(module.instrs[0], Instr::Push(Val::Vloc(-1i32 as u32)));
+        let mut instrs = module.instrs.clone();
+        apply_relocations(&mut instrs, &module.relocations, 10, &module.symbols).unwrap();
+        assert_eq!(instrs[0], Instr::Push(Val::Vloc(10)));
+        assert_eq!(instrs[1], Instr::Push(Val::Vloc(13)));
+        let mut instrs = module.instrs.clone();
+        let at_zero = vec![("Lf".to_string(), Symbol { pc: 0, visibility: Visibility::Local })];
+        assert!(apply_relocations(&mut instrs, &module.relocations, 0,
+                                  &at_zero.into_iter().collect()).is_err());
+    }
+
+    #[test]
+    fn test_line_numbers() {
+        let src = "push 1\nLloop:\n\n  .while; push true; .do\n    .break\n  .endwhile\n.string \"hi\"\nhalt";
+        let numbered = crate::expand::expand(&crate::expand::parse_numbered(src).unwrap()).unwrap();
+        let lines = line_numbers(&numbered).unwrap();
+        // The loop test is on line 4, its .break on 5 and back edge on 6,
+        // and the string routine (at 13) on the line of its .string.
+        assert_eq!(lines, vec![(0, 1), (1, 4), (5, 5), (8, 6), (11, 7), (12, 8), (13, 7)]);
+        let instrs = assemble(&numbered.into_iter().map(|(_, i)| i).collect::<Vec<_>>()).unwrap();
+        assert_eq!(instrs[12], Instr::Halt);
+        assert_eq!(instrs.len(), 13 + 12 + 1);
+        assert_eq!(line_numbers(&[]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_assemble_lines() {
+        let lines: Vec<String> = (1..=3).map(|n| format!("push {}", n))
+            .chain(vec!["push Lf".to_string(), "call".to_string(), "halt".to_string()])
+            .chain(vec!["Lf:".to_string(), ".while; push false; .do; .endwhile".to_string(), "ret".to_string()])
+            .collect();
+        let (instrs, entry) = assemble_lines(lines).unwrap();
+        assert_eq!(entry, 14);
+        assert_eq!(instrs.len(), 14);
+        assert_eq!(instrs[..4], [Instr::Push(Val::Vi32(1)), Instr::Push(Val::Vi32(2)),
+                                 Instr::Push(Val::Vi32(3)), Instr::Push(Val::Vloc(6))]);
+        assert_eq!(instrs[13], Instr::Ret);
+        assert_eq!(assemble_lines(Vec::new()).unwrap(), (vec![], 0));
+
+        let err = assemble_lines(vec!["push 1".to_string(), "peek x".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: could not parse integer 'x'");
+        let err = assemble_lines(vec!["push 1".to_string(), ".endwhile".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: .endwhile without a matching .while");
+        let err = assemble_lines(vec!["Lf:".to_string(), "Lf:".to_string()]).unwrap_err();
+        assert_eq!(err, AssembleError::Assemble("label 'Lf' is defined more than once".to_string()));
+        // With no object file to carry it, the data would be lost.
+        let err = assemble_lines(vec!["halt".to_string(), ".datastring \"x\"".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: .datastring needs a data segment, which only an object file has");
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let pinstrs = PInstr::parse_line("Lf:; ret; Lg:; ret; Lf:; halt").unwrap();
+        assert_eq!(assemble(&pinstrs).unwrap_err(), "label 'Lf' is defined more than once");
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let pinstrs = PInstr::parse_line("push Lf; call; halt; Lg:; ret").unwrap();
+        assert_eq!(assemble(&pinstrs).unwrap_err(), "undefined label 'Lf'");
+        let numbered = crate::expand::parse_numbered("halt\nLg:\npush Lg\npush Lf").unwrap();
+        assert_eq!(assemble_numbered(&numbered).unwrap_err().to_string(), "line 4: undefined label 'Lf'");
+    }
+
+    #[test]
+    fn test_assemble_numbered() {
+        let numbered = crate::expand::parse_numbered("Lf:\npush 1; ret\n.global Lg\nLf:\nhalt").unwrap();
+        let err = assemble_numbered(&numbered).unwrap_err();
+        assert_eq!(err.to_string(), "line 4: label 'Lf' is defined more than once");
+        assert_eq!(err.previous, Some(1));
+        let numbered = crate::expand::parse_numbered("Lf:\npush 1; ret\n.global Lg\nhalt").unwrap();
+        assert_eq!(assemble_numbered(&numbered).unwrap_err().line, 3);
+        let numbered = crate::expand::parse_numbered("Lf:\nhalt\n\npush Lf+5").unwrap();
+        assert_eq!(assemble_numbered(&numbered).unwrap_err().line, 4);
+        let numbered = crate::expand::parse_numbered("Lf:\nhalt").unwrap();
+        let pinstrs: Vec<PInstr> = numbered.iter().map(|(_, i)| i.clone()).collect();
+        assert_eq!(assemble_numbered(&numbered).unwrap(), assemble_with_symbols(&pinstrs).unwrap());
+    }
+
+    #[test]
+    fn test_source_listing() {
+        let src = "// Double 21.\n\
+                   .equ N 21\n\
+                   Lmain:\n    push N\n    push Ldouble  // not defined yet\n    call\n    halt\n\n\
+                   Ldouble: var 0; var 0\n    binary +\n    swap; ret\n";
+        let (numbered, sources) = crate::expand::parse_reader_sources(src.as_bytes(), std::path::Path::new("prog.s"),
+                                                                      false, 1).unwrap();
+        let expanded = expand(&numbered).unwrap();
+        assert_eq!(source_listing(&expanded, &sources, Endianness::Big).unwrap(),
+                   include_str!("../tests/golden/listing.txt"));
+    }
+
+    #[test]
+    fn test_internal_vals_rejected() {
+        for v in [Val::Vsize(1), Val::Vaddr(1)] {
+            let pinstrs = vec![PI(Instr::Push(v)), PI(Instr::Halt)];
+            assert!(assemble(&pinstrs).is_err());
+            assert!(assemble_relocatable(&pinstrs).is_err());
+        }
+    }
+
+    #[test]
+    fn test_vloc_literal_range() {
+        assert_eq!(Val::from_str("4294967295").unwrap(), Val::Vloc(u32::MAX));
+        assert!(Val::from_str("4294967296").is_err());
+        assert!(PInstr::from_str("push 99999999999").is_err());
+    }
 }
@@ -0,0 +1,156 @@
+use crate::object::{ObjectError, ObjectFile};
+use crate::Endianness;
+use std::{error, fmt};
+
+/// The standard base64 alphabet (RFC 4648).
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Errors raised while decoding base64, at a byte offset of the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Base64Error {
+    /// A character outside the alphabet (other than whitespace).
+    InvalidCharacter { offset: usize, found: char },
+    /// `=` padding that is misplaced, missing (the text must be a whole
+    /// number of 4-character groups) or preceded by stray bits.
+    BadPadding { offset: usize },
+}
+
+impl fmt::Display for Base64Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Base64Error::InvalidCharacter { offset, found } =>
+                write!(f, "byte {}: {:?} is not a base64 character", offset, found),
+            Base64Error::BadPadding { offset } => write!(f, "byte {}: invalid base64 padding", offset),
+        }
+    }
+}
+
+impl error::Error for Base64Error {}
+
+/// Encode `bytes` as standard base64, padded with `=`, on one line.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |g, (i, b)| g | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            text.push(if i <= chunk.len() {
+                ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char
+            } else {
+                '='
+            });
+        }
+    }
+    text
+}
+
+/// Decode standard base64, ignoring whitespace (so that wrapped text
+/// reads back). Padding is required, and the bits it leaves over must
+/// be zero, so that every byte string has one encoding.
+pub fn decode(text: &str) -> Result<Vec<u8>, Base64Error> {
+    let chars: Vec<(usize, u8)> = text.bytes().enumerate().filter(|(_, b)| !b.is_ascii_whitespace()).collect();
+    if !chars.len().is_multiple_of(4) {
+        return Err(Base64Error::BadPadding { offset: text.len() });
+    }
+    let mut bytes = Vec::with_capacity(chars.len() / 4 * 3);
+    for (n, group) in chars.chunks(4).enumerate() {
+        let last = n == chars.len() / 4 - 1;
+        let padding = group.iter().rev().take_while(|(_, c)| *c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err(Base64Error::BadPadding { offset: group[4 - padding].0 });
+        }
+        let mut bits = 0u32;
+        for (offset, c) in &group[..4 - padding] {
+            let value = ALPHABET.iter().position(|a| a == c).ok_or_else(|| {
+                let found = text[*offset..].chars().next().unwrap();
+                match found {
+                    '=' => Base64Error::BadPadding { offset: *offset },
+                    found => Base64Error::InvalidCharacter { offset: *offset, found },
+                }
+            })?;
+            bits = bits << 6 | value as u32;
+        }
+        bits <<= 6 * padding;
+        let len = 3 - padding;
+        if bits & (0xff_ffff >> (8 * len)) != 0 {
+            return Err(Base64Error::BadPadding { offset: group[3 - padding].0 });
+        }
+        bytes.extend(bits.to_be_bytes()[1..=len].iter());
+    }
+    Ok(bytes)
+}
+
+/// A program as one line of text, for passing it where binary is
+/// awkward: the base64 of its object file (see `ObjectFile::encode`),
+/// big-endian.
+pub fn encode_base64(program: &ObjectFile) -> String {
+    encode(&program.encode(Endianness::Big))
+}
+
+/// Read a program written by `encode_base64`. Whitespace in the text is
+/// ignored.
+pub fn decode_base64(text: &str) -> Result<ObjectFile, ObjectError> {
+    let bytes = decode(text).map_err(ObjectError::Base64)?;
+    ObjectFile::from_bytes(&bytes, Endianness::Big)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::{Binop::*, Instr::*, Unop::*, Val::*};
+    use crate::object::{DataSegment, LineTable, Metadata, SymbolTable};
+    use crate::Profile;
+
+    #[test]
+    fn test_rfc_vectors() {
+        let vectors = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="),
+                       ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")];
+        for (plain, encoded) in vectors {
+            assert_eq!(encode(plain.as_bytes()), encoded);
+            assert_eq!(decode(encoded).unwrap(), plain.as_bytes());
+        }
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&all)).unwrap(), all);
+        assert_eq!(decode(" Zm9v\n YmFy\r\n\tZg==\n").unwrap(), b"foobarf");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode("Zm9"), Err(Base64Error::BadPadding { offset: 3 }));
+        assert_eq!(decode("Zg=\n"), Err(Base64Error::BadPadding { offset: 4 }));
+        assert_eq!(decode("Z==="), Err(Base64Error::BadPadding { offset: 1 }));
+        assert_eq!(decode("Zg==Zm8="), Err(Base64Error::BadPadding { offset: 2 }));
+        assert_eq!(decode("Z=g="), Err(Base64Error::BadPadding { offset: 1 }));
+        // 'h' leaves a stray bit after the one byte 'Zg' encodes.
+        assert_eq!(decode("Zh=="), Err(Base64Error::BadPadding { offset: 1 }));
+        assert_eq!(decode("Zm-v"), Err(Base64Error::InvalidCharacter { offset: 2, found: '-' }));
+        assert_eq!(decode("Zé=").unwrap_err().to_string(), "byte 1: 'é' is not a base64 character");
+    }
+
+    #[test]
+    fn test_program_round_trip() {
+        let mut instrs: Vec<_> = [Vunit, Vi32(-7), Vbool(true), Vbool(false), Vloc(3), Vundef, Vsize(2), Vaddr(9)]
+            .iter().map(|v| Push(*v)).collect();
+        // Not `Byte`: data is raw bytes, which decode as instructions.
+        instrs.extend([Pop, Peek(1), Unary(Neg), Swap, Alloc, Set, Get, Var(2), Store(3), SetFrame(4),
+                       Call, Ret, Branch, Halt]);
+        instrs.extend([Add, Mul, Sub, Div, Lt, Eq].iter().map(|b| Binary(*b)));
+        let symbols: SymbolTable = vec![("Lmain".to_string(), 3)].into_iter().collect();
+        let program = ObjectFile { entry: 14, instrs, symbols, profile: Profile::Fixed,
+                                     lines: LineTable::default(), metadata: Metadata::new(),
+                                     data: DataSegment::default() };
+
+        let text = encode_base64(&program);
+        assert!(text.bytes().all(|b| ALPHABET.contains(&b) || b == b'='), "{}", text);
+        assert_eq!(decode_base64(&text).unwrap(), program);
+        let wrapped: String = text.as_bytes().chunks(76)
+            .map(|l| format!("{}\n", std::str::from_utf8(l).unwrap()))
+            .collect();
+        assert_eq!(decode_base64(&wrapped).unwrap(), program);
+
+        let truncated = &text[..text.len() - 1];
+        assert_eq!(decode_base64(truncated),
+                   Err(ObjectError::Base64(Base64Error::BadPadding { offset: truncated.len() })));
+        // Valid base64, but not of an object file.
+        assert!(matches!(decode_base64("Zm9vYmFy"), Err(ObjectError::BadMagic { .. })));
+    }
+}
@@ -0,0 +1,169 @@
+//! A small LZ77 compressor for object files (see
+//! `ObjectFile::encode_compressed`), without dependencies. Programs
+//! repeat the same few instructions with the same operands, which
+//! back-references to earlier bytes capture well.
+//!
+//! The compressed data is a sequence of runs, each starting with a
+//! control byte. Below 0x80, it is a literal run: the next (control + 1)
+//! bytes are copied as they are. From 0x80, it is a match: (control -
+//! 0x80 + `MIN_MATCH`) bytes are copied from a big-endian u16 distance
+//! back in the output, which may overlap the bytes being written.
+
+use std::{error, fmt};
+
+/// The shortest match worth writing; a match takes three bytes.
+const MIN_MATCH: usize = 4;
+
+/// The longest match a control byte can hold.
+const MAX_MATCH: usize = MIN_MATCH + 0x7f;
+
+/// The longest literal run a control byte can hold.
+const MAX_LITERALS: usize = 0x80;
+
+/// The furthest back a match can reach.
+const MAX_DISTANCE: usize = u16::MAX as usize;
+
+/// The number of bits of the hash of `MIN_MATCH` bytes that finds
+/// where they last occurred.
+const HASH_BITS: u32 = 12;
+
+/// How many earlier occurrences of the same hash to try for a match.
+/// Operands full of zero bytes repeat often, so the most recent one
+/// is seldom the longest.
+const MAX_CANDIDATES: usize = 32;
+
+/// Compressed data that does not decompress: a run, at the given byte
+/// offset of the data, is cut short or reaches back before the start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressError {
+    pub offset: usize,
+}
+
+impl fmt::Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {:#x}: corrupt compressed data", self.offset)
+    }
+}
+
+impl error::Error for CompressError {}
+
+/// Compress `bytes`, greedily taking the longest match among the
+/// recent earlier occurrences of the next `MIN_MATCH` bytes.
+pub fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() / 2);
+    // One more than the last position each hash was seen at, and for
+    // each position, one more than the one before it with its hash.
+    let mut head = vec![0usize; 1 << HASH_BITS];
+    let mut prev = vec![0usize; bytes.len()];
+    let mut literals = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= bytes.len() {
+        let mut best = (0, 0);
+        let mut next = insert(bytes, &mut head, &mut prev, i);
+        for _ in 0..MAX_CANDIDATES {
+            let c = match next.checked_sub(1).filter(|c| i - c <= MAX_DISTANCE) {
+                Some(c) => c,
+                None => break,
+            };
+            let len = bytes[i..].iter().zip(&bytes[c..]).take(MAX_MATCH).take_while(|(a, b)| a == b).count();
+            if len > best.0 {
+                best = (len, i - c);
+            }
+            next = prev[c];
+        }
+        let (len, distance) = best;
+        if len < MIN_MATCH {
+            i += 1;
+            continue;
+        }
+        write_literals(&mut out, &bytes[literals..i]);
+        out.push(0x80 | (len - MIN_MATCH) as u8);
+        out.extend((distance as u16).to_be_bytes());
+        for p in i + 1..(i + len).min(bytes.len() + 1 - MIN_MATCH) {
+            insert(bytes, &mut head, &mut prev, p);
+        }
+        i += len;
+        literals = i;
+    }
+    write_literals(&mut out, &bytes[literals..]);
+    out
+}
+
+/// Decompress data written by `compress`.
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, CompressError> {
+    let mut out = Vec::with_capacity(bytes.len() * 2);
+    let mut at = 0;
+    while let Some(&control) = bytes.get(at) {
+        let error = CompressError { offset: at };
+        if (control as usize) < MAX_LITERALS {
+            let run = bytes.get(at + 1..at + 2 + control as usize).ok_or(error)?;
+            out.extend(run);
+            at += 1 + run.len();
+        } else {
+            let len = (control & 0x7f) as usize + MIN_MATCH;
+            let distance = match bytes.get(at + 1..at + 3) {
+                Some(d) => u16::from_be_bytes([d[0], d[1]]) as usize,
+                None => return Err(error),
+            };
+            let start = out.len().checked_sub(distance).filter(|_| distance > 0).ok_or(error)?;
+            // Byte by byte, as the match may run into what it writes.
+            for k in start..start + len {
+                out.push(out[k]);
+            }
+            at += 3;
+        }
+    }
+    Ok(out)
+}
+
+/// Record that the `MIN_MATCH` bytes at `p` occur there, returning one
+/// more than where they (or bytes with their hash) last did, or 0.
+fn insert(bytes: &[u8], head: &mut [usize], prev: &mut [usize], p: usize) -> usize {
+    let h = hash(&bytes[p..]);
+    prev[p] = head[h];
+    head[h] = p + 1;
+    prev[p]
+}
+
+/// Append `literals` as as many literal runs as they need.
+fn write_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(MAX_LITERALS) {
+        out.push((run.len() - 1) as u8);
+        out.extend(run);
+    }
+}
+
+/// The hash of the `MIN_MATCH` bytes `bytes` starts with.
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (word.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let repetitive: Vec<u8> = (0..500).flat_map(|n| [0x00, 0x01, 0x00, 0x00, 0x00, n as u8 % 4, 0x0A, 0x03]).collect();
+        let varied: Vec<u8> = (0..1000u32).map(|n| (n.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        let inputs = [vec![], vec![7], vec![1, 2, 3], vec![0; 1000], (0..=255).collect(), repetitive.clone(), varied];
+        for input in &inputs {
+            assert_eq!(&decompress(&compress(input)).unwrap(), input);
+        }
+        assert!(compress(&repetitive).len() * 6 < repetitive.len(), "{}", compress(&repetitive).len());
+        // A run of one byte is a literal, then a match on itself.
+        assert_eq!(compress(&[9; 10]), [0x00, 9, 0x85, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_decompress_errors() {
+        // Reaching back before the start, or not at all.
+        assert_eq!(decompress(&[0x80, 0x00, 0x01]), Err(CompressError { offset: 0 }));
+        assert_eq!(decompress(&[0x00, 9, 0x80, 0x00, 0x00]), Err(CompressError { offset: 2 }));
+        // Runs cut short.
+        assert_eq!(decompress(&[0x02, 1, 2]), Err(CompressError { offset: 0 }));
+        assert_eq!(decompress(&[0x00, 9, 0x80, 0x00]), Err(CompressError { offset: 2 }));
+        assert_eq!(CompressError { offset: 2 }.to_string(), "byte 0x2: corrupt compressed data");
+    }
+}
@@ -0,0 +1,344 @@
+//! Differences between two programs at the level of instructions,
+//! rather than bytes (see `diff`).
+
+use crate::disassemble::decode_program;
+use crate::isa::{Instr, Label, Val};
+use crate::object::{ObjectError, ObjectFile};
+use crate::Endianness;
+use std::collections::{BTreeSet, HashMap};
+
+/// One difference between an old and a new program. Pcs are those of
+/// the program they belong to; for an instruction only one program
+/// has, the other pc is where it would be.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Diff {
+    /// The programs start at different instructions.
+    Entry { old: u32, new: u32 },
+    /// An instruction was replaced by another.
+    Changed { old_pc: u32, new_pc: u32, old: Instr, new: Instr },
+    /// An instruction of the old program is not in the new one.
+    Removed { old_pc: u32, new_pc: u32, instr: Instr },
+    /// An instruction of the new program is not in the old one.
+    Inserted { old_pc: u32, new_pc: u32, instr: Instr },
+    /// A symbol was added, removed, or names a different instruction.
+    Symbol { label: Label, old: Option<u32>, new: Option<u32> },
+}
+
+/// How the instructions of the two programs line up.
+enum Step {
+    Same,
+    Removed,
+    Inserted,
+}
+
+/// An instruction of either program or both, with its pc in each: that
+/// of the instruction it replaces or lines up with, or where it would be.
+#[derive(Clone, Copy)]
+enum Line {
+    Both(usize, usize),
+    Old(usize, usize),
+    New(usize, usize),
+}
+
+/// The differences between two programs, in order of pc, then the
+/// symbols in order of label.
+///
+/// The instructions are lined up by a longest common subsequence, so
+/// an inserted instruction is reported once, not as a change to every
+/// one after it. Locations are compared through that alignment: a push
+/// of a location, the entry pc or a symbol only differs if it does not
+/// name the instruction the old one's location lines up with.
+pub fn diff(old: &ObjectFile, new: &ObjectFile) -> Vec<Diff> {
+    let lines = line_up(&align(&old.instrs, &new.instrs));
+    let mut map = HashMap::new();
+    map.insert(old.instrs.len() as u32, new.instrs.len() as u32);
+    for line in &lines {
+        if let Line::Both(i, j) = *line {
+            map.insert(i as u32, j as u32);
+        }
+    }
+    let moved = |old: u32, new: u32| map.get(&old) != Some(&new);
+
+    let mut diffs = Vec::new();
+    if moved(old.entry, new.entry) {
+        diffs.push(Diff::Entry { old: old.entry, new: new.entry });
+    }
+    for line in lines {
+        let (old_pc, new_pc) = match line {
+            Line::Both(i, j) | Line::Old(i, j) | Line::New(i, j) => (i as u32, j as u32),
+        };
+        match line {
+            Line::Both(i, j) => {
+                let changed = match (old.instrs[i], new.instrs[j]) {
+                    (Instr::Push(Val::Vloc(a)), Instr::Push(Val::Vloc(b))) => moved(a, b),
+                    (a, b) => a != b,
+                };
+                if changed {
+                    diffs.push(Diff::Changed { old_pc, new_pc, old: old.instrs[i], new: new.instrs[j] });
+                }
+            }
+            Line::Old(i, _) => diffs.push(Diff::Removed { old_pc, new_pc, instr: old.instrs[i] }),
+            Line::New(_, j) => diffs.push(Diff::Inserted { old_pc, new_pc, instr: new.instrs[j] }),
+        }
+    }
+
+    let labels: BTreeSet<&Label> = old.symbols.keys().chain(new.symbols.keys()).collect();
+    for label in labels {
+        let (a, b) = (old.symbols.get(label).copied(), new.symbols.get(label).copied());
+        let same = match (a, b) {
+            (Some(a), Some(b)) => !moved(a, b),
+            _ => false,
+        };
+        if !same {
+            diffs.push(Diff::Symbol { label: label.clone(), old: a, new: b });
+        }
+    }
+    diffs
+}
+
+/// Like `diff`, but for two encoded programs, each either an object
+/// file (see `ObjectFile::from_bytes`) or the headerless layout.
+pub fn diff_bytes(old: &[u8], new: &[u8], endianness: Endianness) -> Result<Vec<Diff>, ObjectError> {
+    Ok(diff(&decode_program(old, endianness)?, &decode_program(new, endianness)?))
+}
+
+/// Render differences in the style of a unified diff, after `---` and
+/// `+++` lines naming the programs: a hunk, headed `@@ -old +new @@`
+/// with the pcs it starts at, for each run of instructions that
+/// differ, and the entry pc and symbols as `entry` and `symbol` lines.
+/// No differences render as nothing.
+pub fn unified(diffs: &[Diff], old_name: &str, new_name: &str) -> String {
+    if diffs.is_empty() {
+        return String::new();
+    }
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    // The pcs that an instruction continuing the current hunk would be at.
+    let mut next = None;
+    for d in diffs {
+        let hunk = match *d {
+            Diff::Changed { old_pc, new_pc, .. } => Some((old_pc, new_pc, 1, 1)),
+            Diff::Removed { old_pc, new_pc, .. } => Some((old_pc, new_pc, 1, 0)),
+            Diff::Inserted { old_pc, new_pc, .. } => Some((old_pc, new_pc, 0, 1)),
+            Diff::Entry { .. } | Diff::Symbol { .. } => None,
+        };
+        if let Some((old_pc, new_pc, old_len, new_len)) = hunk {
+            if next != Some((old_pc, new_pc)) {
+                out.push_str(&format!("@@ -{} +{} @@\n", old_pc, new_pc));
+            }
+            next = Some((old_pc + old_len, new_pc + new_len));
+        }
+        match d {
+            Diff::Entry { old, new } => out.push_str(&format!("-entry {}\n+entry {}\n", old, new)),
+            Diff::Changed { old, new, .. } => out.push_str(&format!("-{}\n+{}\n", old, new)),
+            Diff::Removed { instr, .. } => out.push_str(&format!("-{}\n", instr)),
+            Diff::Inserted { instr, .. } => out.push_str(&format!("+{}\n", instr)),
+            Diff::Symbol { label, old, new } => {
+                if let Some(pc) = old {
+                    out.push_str(&format!("-symbol {} {}\n", label, pc));
+                }
+                if let Some(pc) = new {
+                    out.push_str(&format!("+symbol {} {}\n", label, pc));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Whether two instructions line up, counting any two pushes of
+/// locations as alike (`diff` compares where they point afterwards).
+fn alike(a: &Instr, b: &Instr) -> bool {
+    match (a, b) {
+        (Instr::Push(Val::Vloc(_)), Instr::Push(Val::Vloc(_))) => true,
+        _ => a == b,
+    }
+}
+
+/// Line up two instruction sequences by a longest common subsequence.
+/// The instructions they start and end with in common are set aside
+/// first; the rest takes time proportional to the product of their
+/// lengths, but space only to their sum (Hirschberg's algorithm).
+fn align(old: &[Instr], new: &[Instr]) -> Vec<Step> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| alike(a, b)).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| alike(a, b)).count();
+    let mut steps = Vec::with_capacity(old.len().max(new.len()));
+    steps.extend((0..prefix).map(|_| Step::Same));
+    split(&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix], &mut steps);
+    steps.extend((0..suffix).map(|_| Step::Same));
+    steps
+}
+
+/// Append the steps lining up `old` and `new` to `steps`: split `old`
+/// in half, and `new` where a longest common subsequence crosses from
+/// one half to the other, and line up each side in turn.
+fn split(old: &[Instr], new: &[Instr], steps: &mut Vec<Step>) {
+    match old {
+        [] => steps.extend(new.iter().map(|_| Step::Inserted)),
+        [a] => match new.iter().position(|b| alike(a, b)) {
+            Some(j) => {
+                steps.extend((0..j).map(|_| Step::Inserted));
+                steps.push(Step::Same);
+                steps.extend((j + 1..new.len()).map(|_| Step::Inserted));
+            }
+            None => {
+                steps.push(Step::Removed);
+                steps.extend(new.iter().map(|_| Step::Inserted));
+            }
+        },
+        _ if new.is_empty() => steps.extend(old.iter().map(|_| Step::Removed)),
+        _ => {
+            let (front, back) = old.split_at(old.len() / 2);
+            let ahead = lcs_lengths(front.iter(), new.iter());
+            let behind = lcs_lengths(back.iter().rev(), new.iter().rev());
+            let m = new.len();
+            let j = (0..=m).rev().max_by_key(|&j| ahead[j] + behind[m - j]).unwrap_or(0);
+            split(front, &new[..j], steps);
+            split(back, &new[j..], steps);
+        }
+    }
+}
+
+/// The length of the longest common subsequence of `old` and each
+/// prefix of `new`, shortest first, keeping one row of the table.
+fn lcs_lengths<'a>(old: impl Iterator<Item = &'a Instr>, new: impl Iterator<Item = &'a Instr> + Clone) -> Vec<u32> {
+    let mut row = vec![0u32; new.clone().count() + 1];
+    for a in old {
+        // The row above's entry to the left of the one being replaced.
+        let mut diagonal = 0;
+        for (j, b) in new.clone().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if alike(a, b) { diagonal + 1 } else { above.max(row[j]) };
+            diagonal = above;
+        }
+    }
+    row
+}
+
+/// Pair off the instructions removed and inserted between two that
+/// line up, in order, as replacements; what is left over of either is
+/// only in its program.
+fn line_up(steps: &[Step]) -> Vec<Line> {
+    let mut lines = Vec::with_capacity(steps.len());
+    let (mut old_pc, mut new_pc) = (0, 0);
+    let (mut removed, mut inserted) = (0, 0);
+    for step in steps.iter().map(Some).chain(Some(None)) {
+        match step {
+            Some(Step::Removed) => removed += 1,
+            Some(Step::Inserted) => inserted += 1,
+            Some(Step::Same) | None => {
+                let paired = removed.min(inserted);
+                lines.extend((0..paired).map(|k| Line::Both(old_pc + k, new_pc + k)));
+                lines.extend((paired..removed).map(|k| Line::Old(old_pc + k, new_pc + paired)));
+                lines.extend((paired..inserted).map(|k| Line::New(old_pc + paired, new_pc + k)));
+                old_pc += removed;
+                new_pc += inserted;
+                removed = 0;
+                inserted = 0;
+                if step.is_some() {
+                    lines.push(Line::Both(old_pc, new_pc));
+                    old_pc += 1;
+                    new_pc += 1;
+                }
+            }
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::assemble_with_symbols;
+    use crate::isa::{Instr::*, PInstr, Val::*};
+
+    fn program(src: &str) -> ObjectFile {
+        let (instrs, symbols) = assemble_with_symbols(&PInstr::parse_line(src).unwrap()).unwrap();
+        ObjectFile::from_program(instrs, &symbols)
+    }
+
+    const SRC: &str = "push Lf; call; halt; Lf:; push 2; push 3; binary +; ret";
+
+    #[test]
+    fn test_identical() {
+        assert_eq!(diff(&program(SRC), &program(SRC)), []);
+        assert_eq!(unified(&[], "a.o", "b.o"), "");
+    }
+
+    #[test]
+    fn test_changed_operand() {
+        let diffs = diff(&program(SRC), &program(&SRC.replace("push 3", "push 4")));
+        assert_eq!(diffs, [Diff::Changed { old_pc: 4, new_pc: 4, old: Push(Vi32(3)), new: Push(Vi32(4)) }]);
+        assert_eq!(unified(&diffs, "a.o", "b.o"), "--- a.o\n+++ b.o\n@@ -4 +4 @@\n-push 3\n+push 4\n");
+    }
+
+    #[test]
+    fn test_inserted() {
+        // The push of Lf and the symbol moved, but still name the same
+        // instruction, so only the insertion is a difference.
+        let old = program(SRC);
+        let new = program(&SRC.replace("halt;", "pop; halt;"));
+        let diffs = diff(&old, &new);
+        assert_eq!(diffs, [Diff::Inserted { old_pc: 2, new_pc: 2, instr: Pop }]);
+        assert_eq!(diff(&new, &old), [Diff::Removed { old_pc: 2, new_pc: 2, instr: Pop }]);
+
+        // A run of changes, then an insertion, is one hunk.
+        let new = program(&SRC.replace("push 2; push 3", "push 5; push 6; pop"));
+        let diffs = diff(&old, &new);
+        assert_eq!(diffs, [Diff::Changed { old_pc: 3, new_pc: 3, old: Push(Vi32(2)), new: Push(Vi32(5)) },
+                           Diff::Changed { old_pc: 4, new_pc: 4, old: Push(Vi32(3)), new: Push(Vi32(6)) },
+                           Diff::Inserted { old_pc: 5, new_pc: 5, instr: Pop }]);
+        assert_eq!(unified(&diffs, "a", "b"), "--- a\n+++ b\n@@ -3 +3 @@\n-push 2\n+push 5\n-push 3\n+push 6\n+pop\n");
+    }
+
+    #[test]
+    fn test_locations_and_symbols() {
+        let old = program(SRC);
+        // Lf now names the `push 3`, and the entry moved.
+        let mut new = program(&SRC.replace("Lf:; push 2;", "push 2; Lf:;"));
+        new.entry = 2;
+        new.symbols.insert("Lg".into(), 0);
+        let diffs = diff(&old, &new);
+        assert_eq!(diffs, [Diff::Entry { old: 7, new: 2 },
+                           Diff::Changed { old_pc: 0, new_pc: 0, old: Push(Vloc(3)), new: Push(Vloc(4)) },
+                           Diff::Symbol { label: "Lf".into(), old: Some(3), new: Some(4) },
+                           Diff::Symbol { label: "Lg".into(), old: None, new: Some(0) }]);
+        assert_eq!(unified(&diffs, "a", "b"),
+                   "--- a\n+++ b\n-entry 7\n+entry 2\n@@ -0 +0 @@\n-push @3\n+push @4\n\
+                    -symbol Lf 3\n+symbol Lf 4\n+symbol Lg 0\n");
+    }
+
+    #[test]
+    fn test_diff_bytes() {
+        let (old, new) = (program(SRC), program(&SRC.replace("ret", "halt")));
+        let e = Endianness::Little;
+        let expected = diff(&old, &new);
+        assert_eq!(expected, [Diff::Changed { old_pc: 6, new_pc: 6, old: Ret, new: Halt }]);
+        assert_eq!(diff_bytes(&old.encode(e), &new.encode(e), e).unwrap(), expected);
+        // The headerless layout has no symbols.
+        assert_eq!(diff_bytes(&old.encode(e), &new.to_legacy_bytes(e), e).unwrap()[1..],
+                   [Diff::Symbol { label: "Lf".into(), old: Some(3), new: None }]);
+        assert_eq!(diff_bytes(&old.to_legacy_bytes(e), &new.to_legacy_bytes(e), e).unwrap(), expected);
+        assert!(matches!(diff_bytes(&[0, 0, 0, 9, 0xff], &new.encode(e), e), Err(ObjectError::Decode(_))));
+    }
+
+    #[test]
+    fn test_align() {
+        let steps = |old: &[Instr], new: &[Instr]| -> String {
+            align(old, new).iter().map(|s| match s { Step::Same => '=', Step::Removed => '-', Step::Inserted => '+' }).collect()
+        };
+        assert_eq!(steps(&[], &[]), "");
+        assert_eq!(steps(&[Pop, Swap], &[]), "--");
+        assert_eq!(steps(&[], &[Pop]), "+");
+        assert_eq!(steps(&[Push(Vloc(1)), Pop], &[Push(Vloc(2)), Pop]), "==");
+        // Split in the middle, with the common ends set aside.
+        assert_eq!(steps(&[Halt, Pop, Swap, Ret, Pop, Halt], &[Halt, Swap, Pop, Call, Ret, Halt]), "=-=-=++=");
+        assert_eq!(steps(&[Pop, Swap, Pop, Swap], &[Swap, Pop, Swap, Pop]), "-===+");
+
+        // Changes throughout a longer program.
+        let old: Vec<Instr> = (0..3000).map(|k| Push(Vi32(k))).collect();
+        let new: Vec<Instr> = (0..3000).map(|k| Push(Vi32(if k % 100 == 50 { -k } else { k }))).collect();
+        let steps = align(&old, &new);
+        assert_eq!(steps.len(), 3030);
+        assert_eq!(steps.iter().filter(|s| matches!(s, Step::Same)).count(), 2970);
+    }
+}
@@ -0,0 +1,637 @@
+use crate::isa::{is_label_name, Instr, Instr::*, Val::*};
+use crate::object::{read_object, ObjectError, ObjectFile, ReadOptions, SymbolTable, MAGIC};
+use crate::{DecodeError, Endianness, FromBytes, Profile, ToBytes};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read};
+use std::{error, fmt};
+
+/// A decoding failure, at the given byte offset into the object file:
+/// the start of the instruction that failed to decode, with its index,
+/// or of the header field or section that did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisassembleError {
+    pub offset: usize,
+    pub instr: Option<usize>,
+    pub error: DecodeError,
+}
+
+impl fmt::Display for DisassembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.instr {
+            Some(n) => write!(f, "byte {:#x} (instr #{}): {}", self.offset, n, self.error),
+            None => write!(f, "byte {:#x}: {}", self.offset, self.error),
+        }
+    }
+}
+
+impl error::Error for DisassembleError {}
+
+/// Decode a big-endian object file: the entry pc in the 4-byte header,
+/// and the instructions that follow it.
+pub fn decode(bytes: &[u8]) -> Result<(u32, Vec<Instr>), DisassembleError> {
+    decode_with(bytes, Endianness::Big)
+}
+
+/// Decode an object file written with the given byte order. The entry
+/// pc may be at most the number of instructions, which catches most
+/// files read with the wrong byte order.
+pub fn decode_with(bytes: &[u8], endianness: Endianness) -> Result<(u32, Vec<Instr>), DisassembleError> {
+    decode_with_profile(bytes, endianness, Profile::Fixed)
+}
+
+/// Like `decode_with`, for instructions whose operands are encoded in
+/// the given profile. The header is always fixed width.
+pub fn decode_with_profile(bytes: &[u8], endianness: Endianness, profile: Profile)
+                           -> Result<(u32, Vec<Instr>), DisassembleError> {
+    let (entry, mut offset) = u32::from_bytes_with(bytes, endianness)
+        .map_err(|error| DisassembleError { offset: 0, instr: None, error })?;
+    let mut instrs = Vec::new();
+    while offset < bytes.len() {
+        let (i, n) = Instr::from_bytes_profile(&bytes[offset..], endianness, profile)
+            .map_err(|error| DisassembleError { offset, instr: Some(instrs.len()), error })?;
+        instrs.push(i);
+        offset += n;
+    }
+    if entry as usize > instrs.len() {
+        let error = DecodeError::EntryOutOfRange { entry, instrs: instrs.len() };
+        return Err(DisassembleError { offset: 0, instr: None, error });
+    }
+    Ok((entry, instrs))
+}
+
+/// Bytes asked of the reader at a time: a little more than the longest
+/// instruction (`push` of an address, 10 bytes).
+const READ_CHUNK: usize = 16;
+
+/// Decodes an object file in the headerless layout as it is read,
+/// rather than all at once: the entry pc when created, then one
+/// instruction per item, holding no more than one instruction's worth
+/// of bytes. A clean end of input ends the iteration; input that ends
+/// in the middle of an instruction gives a `Truncated` error, after
+/// which iteration stops, as it does after any other error. Errors
+/// give the offset and index of the instruction, as `decode_with`'s do.
+///
+/// Unlike `decode_with`, the entry pc is not checked against the
+/// length of the program, which is not known until the end.
+pub struct Decoder<R> {
+    reader: R,
+    endianness: Endianness,
+    entry: u32,
+    buf: Vec<u8>,
+    offset: usize,
+    count: usize,
+    done: bool,
+    io_error: Option<io::Error>,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Read the header of a big-endian object file from `reader`.
+    pub fn new(reader: R) -> io::Result<Self> {
+        Decoder::new_with(reader, Endianness::Big)
+    }
+
+    /// Read the header of an object file written with the given byte
+    /// order. A header cut short is an `UnexpectedEof` error.
+    pub fn new_with(mut reader: R, endianness: Endianness) -> io::Result<Self> {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let (entry, offset) = u32::from_bytes_with(&header, endianness).map_err(io::Error::other)?;
+        Ok(Decoder { reader, endianness, entry, buf: Vec::with_capacity(READ_CHUNK), offset, count: 0,
+                     done: false, io_error: None })
+    }
+
+    /// The entry pc from the header.
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    /// The byte offset into the file of the next instruction.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The error that stopped reading, if the reader failed. The
+    /// instruction it interrupted was reported as truncated.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        self.io_error.as_ref()
+    }
+
+    /// Read more bytes into the buffer, returning false at end of input.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    return Ok(true);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Stop, reporting `error` at the current instruction.
+    fn fail(&mut self, error: DecodeError) -> DisassembleError {
+        self.done = true;
+        DisassembleError { offset: self.offset, instr: Some(self.count), error }
+    }
+}
+
+impl<R: Read> Iterator for Decoder<R> {
+    type Item = Result<Instr, DisassembleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut truncated = DecodeError::Truncated { what: "instruction", needed: 1, available: 0 };
+        loop {
+            if !self.buf.is_empty() {
+                match Instr::from_bytes_with(&self.buf, self.endianness) {
+                    Ok((i, n)) => {
+                        self.buf.drain(..n);
+                        self.offset += n;
+                        self.count += 1;
+                        return Some(Ok(i));
+                    }
+                    Err(e @ DecodeError::Truncated { .. }) => truncated = e,
+                    Err(e) => return Some(Err(self.fail(e))),
+                }
+            }
+            match self.fill() {
+                Ok(true) => continue,
+                Ok(false) if self.buf.is_empty() => {
+                    self.done = true;
+                    return None;
+                }
+                Ok(false) => {}
+                Err(e) => self.io_error = Some(e),
+            }
+            return Some(Err(self.fail(truncated)));
+        }
+    }
+}
+
+/// Size of the entry pc that starts the headerless layout.
+pub const HEADER_BYTES: usize = 4;
+
+/// The byte offset, in the headerless layout, of the instruction at
+/// `pc`: the header and the instructions before it. The pc just past
+/// the last instruction is the end of the file. `None` if `pc` is
+/// beyond that.
+pub fn pc_to_offset(instrs: &[Instr], pc: u32) -> Option<usize> {
+    let before = instrs.get(..pc as usize)?;
+    Some(HEADER_BYTES + before.iter().map(ToBytes::byte_len).sum::<usize>())
+}
+
+/// The pc of the instruction starting at byte `offset` of the headerless
+/// layout, the inverse of `pc_to_offset`. `None` if no instruction (nor
+/// the end of the file) starts there, such as in the header or in the
+/// middle of an instruction.
+pub fn offset_to_pc(instrs: &[Instr], offset: usize) -> Option<u32> {
+    let mut at = HEADER_BYTES;
+    for (pc, i) in instrs.iter().enumerate() {
+        if at >= offset {
+            return if at == offset { Some(pc as u32) } else { None };
+        }
+        at += i.byte_len();
+    }
+    if at == offset { Some(instrs.len() as u32) } else { None }
+}
+
+/// Assembly text for decoded instructions, one per line.
+pub fn listing(instrs: &[Instr]) -> String {
+    instrs.iter().map(|i| format!("{}\n", i)).collect()
+}
+
+/// Turn a big-endian object file back into assembly text, one
+/// instruction per line, returned together with the entry pc.
+pub fn disassemble(bytes: &[u8]) -> Result<(u32, String), DisassembleError> {
+    let (entry, instrs) = decode(bytes)?;
+    Ok((entry, listing(&instrs)))
+}
+
+/// Like `disassemble`, but with a label `L<n>` at every location `n`
+/// that the program pushes or that the header names (see
+/// `listing_with_labels`).
+pub fn disassemble_with_labels(bytes: &[u8]) -> Result<(u32, String), DisassembleError> {
+    let (entry, instrs) = decode(bytes)?;
+    Ok((entry, listing_with_labels(entry, &instrs)))
+}
+
+/// A hex dump of a big-endian object file (see `hexdump_with`).
+pub fn hexdump(bytes: &[u8]) -> Result<String, DecodeError> {
+    hexdump_with(bytes, Endianness::Big)
+}
+
+/// A dump of an object file in the headerless layout, for reading its
+/// bytes: a line for the header, then one per instruction with its pc,
+/// its bytes in hex and its assembly text.
+///
+/// ```text
+///       00 00 00 02         entry pc 2
+/// 0000  00 01 00 00 00 2a   push 42
+/// 0001  0f                  halt
+/// ```
+///
+/// Bytes that do not decode are dumped as an instruction `??`, with the
+/// reason: an unknown opcode takes up one byte, and the dump goes on
+/// with the next, while a truncated instruction takes up the rest of
+/// the file. Only a header too short to hold the entry pc is an error.
+pub fn hexdump_with(bytes: &[u8], endianness: Endianness) -> Result<String, DecodeError> {
+    let (entry, mut offset) = u32::from_bytes_with(bytes, endianness)?;
+    let mut lines = vec![(String::new(), &bytes[..offset], format!("entry pc {}", entry))];
+    while offset < bytes.len() {
+        let (text, n) = match Instr::from_bytes_with(&bytes[offset..], endianness) {
+            Ok((i, n)) => (i.to_string(), n),
+            Err(e @ DecodeError::Truncated { .. }) => (format!("?? // {}", e), bytes.len() - offset),
+            Err(e) => (format!("?? // {}", e), 1),
+        };
+        lines.push((format!("{:04}", lines.len() - 1), &bytes[offset..offset + n], text));
+        offset += n;
+    }
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+    let width = lines.iter().map(|(_, bytes, _)| 3 * bytes.len() - 1).max().unwrap_or(0);
+    Ok(lines.iter()
+        .map(|(pc, bytes, text)| format!("{:4}  {:<width$}   {}\n", pc, hex(bytes), text, width = width))
+        .collect())
+}
+
+/// Like `disassemble_with_labels`, but for an object file (see
+/// `object::write_object`), using the names in its symbol section.
+pub fn disassemble_object(bytes: &[u8], endianness: Endianness) -> Result<(u32, String), ObjectError> {
+    let (entry, instrs, symbols) = read_object(bytes, endianness, ReadOptions::default())?;
+    Ok((entry, listing_with_symbols(entry, &instrs, &symbols)))
+}
+
+/// Decode what `assem` writes: an object file (see `object::MAGIC`),
+/// or else the headerless layout, which has no symbols.
+pub fn decode_program(bytes: &[u8], endianness: Endianness) -> Result<ObjectFile, ObjectError> {
+    if bytes.starts_with(&MAGIC) {
+        return ObjectFile::from_bytes(bytes, endianness);
+    }
+    let (entry, instrs) = decode_with(bytes, endianness).map_err(ObjectError::Decode)?;
+    Ok(ObjectFile { entry, instrs, ..ObjectFile::default() })
+}
+
+/// Like `disassemble_object`, for anything `decode_program` reads:
+/// with the names in its symbol section if it has one, and `L<n>`
+/// labels otherwise.
+pub fn disassemble_program(bytes: &[u8], endianness: Endianness) -> Result<(u32, String), ObjectError> {
+    let program = decode_program(bytes, endianness)?;
+    Ok((program.entry, listing_with_symbols(program.entry, &program.instrs, &program.symbols)))
+}
+
+/// Assembly text for decoded instructions, with a label `L<n>` at
+/// every location `n` that they push or that is the entry pc, and the
+/// pushes written `push L<n>`. Pushes of locations outside the program
+/// stay numbers, with a comment. Re-assembling the text gives back the
+/// original instructions.
+pub fn listing_with_labels(entry: u32, instrs: &[Instr]) -> String {
+    listing_with_symbols(entry, instrs, &SymbolTable::new())
+}
+
+/// Like `listing_with_labels`, but with the names in `symbols` at their
+/// locations, and pushes of a named location written with its first
+/// name. Names that could not be read back as labels, such as scoped
+/// (`Lf.Lx`) and generated (`_Lwhile_0`) ones, are left out. Other
+/// locations still get `L<n>` (with `x`s appended if a symbol already
+/// has that name).
+pub fn listing_with_symbols(entry: u32, instrs: &[Instr], symbols: &SymbolTable) -> String {
+    let end = instrs.len();
+    let in_range = |n: u32| (n as usize) <= end;
+    let mut names: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    for (name, pc) in symbols.iter().filter(|(name, pc)| in_range(**pc) && is_label_name(name)) {
+        names.entry(*pc).or_default().push(name.clone());
+    }
+    let targets: BTreeSet<u32> = instrs.iter()
+        .filter_map(|i| match i { Push(Vloc(n)) => Some(*n), _ => None })
+        .chain(Some(entry))
+        .filter(|n| in_range(*n) && !names.contains_key(n))
+        .collect();
+    for n in targets {
+        let mut name = format!("L{}", n);
+        while symbols.contains_key(&name) {
+            name.push('x');
+        }
+        names.insert(n, vec![name]);
+    }
+
+    let labels = |pc: usize| names.get(&(pc as u32)).into_iter().flatten().map(|l| format!("{}:\n", l));
+    let mut text = String::new();
+    for (pc, i) in instrs.iter().enumerate() {
+        text.extend(labels(pc));
+        text += &match i {
+            Push(Vloc(n)) if in_range(*n) => format!("push {}\n", names[n][0]),
+            Push(Vloc(n)) => format!("{} // location {} is outside the program\n", i, n),
+            _ => format!("{}\n", i),
+        };
+    }
+    text.extend(labels(end));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::assemble;
+    use crate::isa::{Binop::*, PInstr};
+    use crate::object::write_object;
+    use crate::ToBytes;
+    use std::{fs, path::Path};
+
+    /// Assemble source text into an object file, as `assem` does.
+    fn object(src: &str) -> Vec<u8> {
+        let pinstrs: Vec<PInstr> = src.lines().flat_map(|l| PInstr::parse_line(l).unwrap()).collect();
+        let mut instrs = assemble(&pinstrs).unwrap();
+        let entry = match instrs.pop() {
+            Some(Push(Vloc(pc))) => pc,
+            _ => unreachable!(),
+        };
+        crate::object::write_legacy(entry, &instrs, Endianness::Big)
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let (entry, text) = disassemble(&object("push 2; push -3; binary *; setframe 0; halt")).unwrap();
+        assert_eq!(entry, 5);
+        assert_eq!(text, "push 2\npush -3\nbinary *\nsetframe 0\nhalt\n");
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let corpus = ["halt",
+                      "push Lend; push 7; branch; Lend:",
+                      "push @1; push @4294967295; pop; halt",
+                      "push tt; push undef; push true; push false; pop; pop; pop; pop; halt",
+                      "push 2147483647; push -2147483648; binary <; unary neg; halt",
+                      "push 3; push 0; alloc; peek 0; push 1; push 5; set; push 1; get; halt",
+                      "setframe 2; var 0; store 1; swap; call; ret; branch; halt"];
+        for src in corpus {
+            let bytes = object(src);
+            let (_, text) = disassemble(&bytes).unwrap();
+            assert_eq!(object(&text), bytes, "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_decode_corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|e| e != "expected") {
+                continue;
+            }
+            let bytes = fs::read(&path).unwrap();
+            let (pc, instrs) = decode(&bytes).unwrap();
+            assert_eq!(pc as usize, instrs.len(), "{}", path.display());
+            assert_eq!(crate::object::write_legacy(pc, &instrs, Endianness::Big), bytes, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let bytes = object("push 42; push Lend; push true; branch; push -1; unary neg; Lend:; setframe 0; halt");
+        assert_eq!(hexdump(&bytes).unwrap(), include_str!("../tests/golden/program.hexdump"));
+
+        // An unknown opcode, then a truncated push.
+        let dump = hexdump(&[0, 0, 0, 0, 0x0f, 0xee, 0x00, 0x01, 0x00]).unwrap();
+        assert_eq!(dump, "      00 00 00 00   entry pc 0\n\
+                          0000  0f            halt\n\
+                          0001  ee            ?? // unknown instruction opcode 0xee\n\
+                          0002  00 01 00      ?? // truncated i32 (needs 4 bytes, 1 left)\n");
+        assert_eq!(hexdump(&[0, 0]), Err(DecodeError::Truncated { what: "u32", needed: 4, available: 2 }));
+        let little = hexdump_with(&[1, 0, 0, 0, 0x0f], Endianness::Little).unwrap();
+        assert_eq!(little.lines().next(), Some("      01 00 00 00   entry pc 1"));
+    }
+
+    #[test]
+    fn test_disassemble_with_labels() {
+        let bytes = object("push Lend; push Lf; call; branch; Lf:; ret; Lend:");
+        let (_, text) = disassemble_with_labels(&bytes).unwrap();
+        assert_eq!(text, "push L5\npush L4\ncall\nbranch\nL4:\nret\nL5:\n");
+        assert_eq!(object(&text), bytes);
+
+        // 2147483648 is too large for an i32, so it parses as a location.
+        let bytes = object("push 2147483648; halt");
+        let (_, text) = disassemble_with_labels(&bytes).unwrap();
+        assert_eq!(text, "push @2147483648 // location 2147483648 is outside the program\nhalt\nL2:\n");
+        assert_eq!(object(&text), bytes);
+    }
+
+    #[test]
+    fn test_labels_round_trip_corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|e| e != "expected") {
+                continue;
+            }
+            let bytes = fs::read(&path).unwrap();
+            let (_, text) = disassemble_with_labels(&bytes).unwrap();
+            assert_eq!(object(&text), bytes, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_decode_endianness() {
+        let src = "push 1; push Lf; binary +; Lf:; var 258; halt";
+        let pinstrs: Vec<PInstr> = PInstr::parse_line(src).unwrap();
+        let mut instrs = assemble(&pinstrs).unwrap();
+        instrs.pop();
+        let mut little = Vec::new();
+        (instrs.len() as u32).encode_into_with(&mut little, Endianness::Little);
+        for i in &instrs {
+            i.encode_into_with(&mut little, Endianness::Little);
+        }
+        assert_ne!(little, object(src));
+        assert_eq!(decode_with(&little, Endianness::Little).unwrap(), (5, instrs));
+
+        // Read big-endian, the header says 0x05000000 instructions.
+        let err = decode(&little).unwrap_err();
+        assert_eq!(err.to_string(),
+                   "byte 0x0: entry pc 83886080 is past the end of the program (5 instructions)");
+        assert!(decode_with(&object(src), Endianness::Little).is_err());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let truncated = |what, needed, available| DecodeError::Truncated { what, needed, available };
+        assert_eq!(decode(&[0, 0]).unwrap_err(),
+                   DisassembleError { offset: 0, instr: None, error: truncated("u32", 4, 2) });
+        let mut bytes = object("push 1; binary +; halt");
+        assert_eq!(decode(&bytes).unwrap().1, vec![Push(Vi32(1)), Binary(Add), Halt]);
+        bytes[10] = 0x42;
+        let err = decode(&bytes).unwrap_err();
+        assert_eq!(err, DisassembleError {
+            offset: 10,
+            instr: Some(1),
+            error: DecodeError::UnknownOpcode { what: "instruction", opcode: 0x42 },
+        });
+        assert_eq!(err.to_string(), "byte 0xa (instr #1): unknown instruction opcode 0x42");
+        bytes.truncate(8);
+        assert_eq!(decode(&bytes).unwrap_err(),
+                   DisassembleError { offset: 4, instr: Some(0), error: truncated("i32", 4, 2) });
+    }
+
+    #[test]
+    fn test_decode_error_offsets() {
+        // 200 instructions of 1, 2, 5 or 6 bytes, so that offsets and
+        // indices part ways.
+        let src: Vec<String> = (0..50).map(|n| format!("push {}; pop; binary +; var {}", n, n)).collect();
+        let bytes = object(&src.join("; "));
+        let (_, instrs) = decode(&bytes).unwrap();
+        let mut starts = vec![4];
+        starts.extend(instrs.iter().scan(4, |at, i| { *at += i.byte_len(); Some(*at) }));
+        for n in [0, 1, 2, 3, 103, 150, 199] {
+            let mut corrupt = bytes.clone();
+            corrupt[starts[n]] = 0x93;
+            let err = decode(&corrupt).unwrap_err();
+            assert_eq!((err.offset, err.instr), (starts[n], Some(n)));
+            assert_eq!(err.error, DecodeError::UnknownOpcode { what: "instruction", opcode: 0x93 });
+            assert_eq!(err.to_string(),
+                       format!("byte {:#x} (instr #{}): unknown instruction opcode 0x93", starts[n], n));
+        }
+        assert_eq!(decode(&bytes[..starts[103] + 3]).unwrap_err().to_string(),
+                   format!("byte {:#x} (instr #103): truncated u32 (needs 4 bytes, 2 left)", starts[103]));
+        let mut corrupt = bytes.clone();
+        corrupt[starts[102] + 1] = 0x09;
+        assert_eq!(decode(&corrupt).unwrap_err(), DisassembleError {
+            offset: starts[102],
+            instr: Some(102),
+            error: DecodeError::UnknownOpcode { what: "binop", opcode: 0x09 },
+        });
+    }
+
+    #[test]
+    fn test_pc_offsets() {
+        // 6, 1, 6, 1, 2 and 1 bytes.
+        let instrs = [Push(Vi32(1)), Pop, Push(Vloc(0)), Pop, Binary(crate::isa::Binop::Add), Halt];
+        let offsets: Vec<Option<usize>> = (0..=7).map(|pc| pc_to_offset(&instrs, pc)).collect();
+        assert_eq!(offsets, [Some(4), Some(10), Some(11), Some(17), Some(18), Some(20), Some(21), None]);
+        for pc in 0..=6 {
+            assert_eq!(offset_to_pc(&instrs, pc_to_offset(&instrs, pc).unwrap()), Some(pc));
+        }
+        // The end of the program is the end of the file.
+        let bytes = crate::object::write_legacy(0, &instrs, Endianness::Big);
+        assert_eq!(pc_to_offset(&instrs, 6), Some(bytes.len()));
+        // Offsets in the header, inside an instruction or past the end
+        // start no instruction.
+        for offset in [0, 3, 5, 9, 12, 19, 22, 100] {
+            assert_eq!(offset_to_pc(&instrs, offset), None, "{}", offset);
+        }
+        assert_eq!((pc_to_offset(&[], 0), offset_to_pc(&[], HEADER_BYTES)), (Some(HEADER_BYTES), Some(0)));
+        assert_eq!(offset_to_pc(&[], 0), None);
+    }
+
+    /// A reader handing out one byte per call.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((b, rest)) if !buf.is_empty() => {
+                    buf[0] = *b;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decoder() {
+        let bytes = object("push 2; push Lend; push true; branch; peek 1; binary *; Lend:; setframe 0; halt");
+        let (entry, instrs) = decode(&bytes).unwrap();
+
+        let decoder = Decoder::new(io::Cursor::new(&bytes)).unwrap();
+        assert_eq!(decoder.entry(), entry);
+        assert_eq!(decoder.collect::<Result<Vec<_>, _>>().unwrap(), instrs);
+
+        let mut decoder = Decoder::new(Trickle(&bytes)).unwrap();
+        assert_eq!(decoder.offset(), 4);
+        assert_eq!(decoder.next(), Some(Ok(Push(Vi32(2)))));
+        assert_eq!(decoder.offset(), 10);
+        assert_eq!(decoder.by_ref().collect::<Result<Vec<_>, _>>().unwrap(), instrs[1..]);
+        assert_eq!(decoder.offset(), bytes.len());
+        assert_eq!(decoder.next(), None);
+
+        let little = crate::object::write_legacy(entry, &instrs, Endianness::Little);
+        let decoder = Decoder::new_with(Trickle(&little), Endianness::Little).unwrap();
+        assert_eq!(decoder.collect::<Result<Vec<_>, _>>().unwrap(), instrs);
+    }
+
+    #[test]
+    fn test_decoder_errors() {
+        let truncated = |what, needed, available| DecodeError::Truncated { what, needed, available };
+        let bytes = object("push 1; binary +; halt");
+        // Cut off in the middle of the push's operand.
+        let mut decoder = Decoder::new(Trickle(&bytes[..7])).unwrap();
+        let err = decoder.next().unwrap().unwrap_err();
+        assert_eq!(err, DisassembleError { offset: 4, instr: Some(0), error: truncated("i32", 4, 1) });
+        assert_eq!(decoder.next(), None);
+        assert!(decoder.io_error().is_none());
+        // Cut off after a whole instruction: it is decoded, then the next
+        // is truncated.
+        let mut decoder = Decoder::new(io::Cursor::new(&bytes[..11])).unwrap();
+        assert_eq!(decoder.next(), Some(Ok(Push(Vi32(1)))));
+        let err = DisassembleError { offset: 10, instr: Some(1), error: truncated("binop", 1, 0) };
+        assert_eq!(decoder.next(), Some(Err(err)));
+        assert_eq!(decoder.next(), None);
+
+        let mut bad = bytes.clone();
+        bad[10] = 0x42;
+        let errors: Vec<_> = Decoder::new(io::Cursor::new(&bad)).unwrap().collect();
+        let err = DisassembleError {
+            offset: 10,
+            instr: Some(1),
+            error: DecodeError::UnknownOpcode { what: "instruction", opcode: 0x42 },
+        };
+        assert_eq!(errors, vec![Ok(Push(Vi32(1))), Err(err)]);
+
+        let err = Decoder::new(io::Cursor::new(&bytes[..3])).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(Decoder::new(io::Cursor::new(&bytes[..4])).unwrap().next(), None);
+    }
+
+    #[test]
+    fn test_listing_with_symbols() {
+        let instrs = assemble(&PInstr::parse_line("push Lend; push Lf; call; branch; Lf:; ret; Lend:").unwrap()).unwrap();
+        let instrs = &instrs[..instrs.len() - 1];
+        let symbols: SymbolTable = vec![("Lf".to_string(), 4), ("L5".to_string(), 0), ("Lg".to_string(), 4)]
+            .into_iter().collect();
+        assert_eq!(listing_with_symbols(5, instrs, &symbols),
+                   "L5:\npush L5x\npush Lf\ncall\nbranch\nLf:\nLg:\nret\nL5x:\n");
+        assert_eq!(listing_with_symbols(5, instrs, &SymbolTable::new()), listing_with_labels(5, instrs));
+        // Names that are not labels in source fall back to numbers.
+        let generated: SymbolTable = vec![("Lf.Lx".to_string(), 4), ("_Lwhile_0".to_string(), 0), ("_Lg".to_string(), 5)]
+            .into_iter().collect();
+        assert_eq!(listing_with_symbols(5, instrs, &generated),
+                   "push _Lg\npush L4\ncall\nbranch\nL4:\nret\n_Lg:\n");
+
+        let bytes = write_object(5, instrs, Some(&symbols), Endianness::Big);
+        assert_eq!(disassemble_object(&bytes, Endianness::Big).unwrap().1,
+                   listing_with_symbols(5, instrs, &symbols));
+        let stripped = crate::object::strip_symbols(&bytes, Endianness::Big).unwrap();
+        assert_eq!(disassemble_object(&stripped, Endianness::Big).unwrap().1,
+                   "push L5\npush L4\ncall\nbranch\nL4:\nret\nL5:\n");
+    }
+
+    #[test]
+    fn test_disassemble_program() {
+        let instrs = assemble(&PInstr::parse_line("push Lf; call; halt; Lf:; ret").unwrap()).unwrap();
+        let instrs = &instrs[..instrs.len() - 1];
+        let symbols: SymbolTable = vec![("Lf".to_string(), 3)].into_iter().collect();
+        for e in [Endianness::Big, Endianness::Little] {
+            let object = write_object(4, instrs, Some(&symbols), e);
+            assert_eq!(disassemble_program(&object, e).unwrap(), (4, "push Lf\ncall\nhalt\nLf:\nret\nL4:\n".to_string()));
+            let legacy = crate::object::write_legacy(4, instrs, e);
+            assert_eq!(disassemble_program(&legacy, e).unwrap(), (4, "push L3\ncall\nhalt\nL3:\nret\nL4:\n".to_string()));
+            assert_eq!(decode_program(&legacy, e).unwrap().instrs, instrs);
+        }
+        assert!(matches!(disassemble_program(&[0, 0, 0, 9], Endianness::Big), Err(ObjectError::Decode(_))));
+    }
+}
@@ -0,0 +1,131 @@
+use std::path::{Path, PathBuf};
+
+/// Bytes per line of an emitted array.
+const BYTES_PER_LINE: usize = 12;
+
+/// The elements of an array of `bytes`, a line each of
+/// `BYTES_PER_LINE`, indented by four spaces.
+fn elements(bytes: &[u8]) -> String {
+    bytes.chunks(BYTES_PER_LINE)
+        .map(|line| {
+            let line: Vec<String> = line.iter().map(|b| format!("{:#04x},", b)).collect();
+            format!("    {}\n", line.join(" "))
+        })
+        .collect()
+}
+
+/// Rust source defining a constant `name` holding `bytes`, such as an
+/// object file (with its entry pc first), to embed a program in a host
+/// binary:
+///
+/// ```text
+/// pub const PROGRAM: &[u8] = &[
+///     0x00, 0x00, 0x00, 0x01, 0x0f,
+/// ];
+/// ```
+///
+/// `name` must be a Rust identifier.
+pub fn rust_source(name: &str, bytes: &[u8]) -> String {
+    format!("pub const {}: &[u8] = &[\n{}];\n", name, elements(bytes))
+}
+
+/// A C header defining an array `name` holding `bytes`, and its length
+/// `name_LEN`, guarded by `name_H`. `name` must be a C identifier.
+pub fn c_source(name: &str, bytes: &[u8]) -> String {
+    format!("#ifndef {name}_H\n#define {name}_H\n\n\
+             static const unsigned char {name}[] = {{\n{elements}}};\n\
+             static const unsigned long {name}_LEN = {len};\n\n\
+             #endif\n",
+            name = name, elements = elements(bytes), len = bytes.len())
+}
+
+/// An identifier for the program read from `path`: its file name up to
+/// the first `.`, upper case, with anything but letters and digits
+/// replaced by `_`, and a `_` in front if it would start with a digit.
+pub fn identifier(path: &str) -> String {
+    let stem = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let stem = stem.split('.').next().unwrap_or(stem);
+    let mut name: String = stem.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// Where `assem` writes the output for `input` by default: `input` with
+/// the extension of its file name replaced by `extension` (such as
+/// `"o"`), or given it if it has none. Dots in directory names are
+/// left alone.
+pub fn output_path(input: &Path, extension: &str) -> PathBuf {
+    input.with_extension(extension)
+}
+
+/// Whether two paths name the same file: they are equal, or both exist
+/// and resolve to one file.
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    a == b || matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::{Instr::*, PInstr, Val::*};
+    use crate::object::write_legacy;
+    use crate::{Endianness, ToBytes};
+
+    fn object() -> Vec<u8> {
+        let pinstrs = PInstr::parse_line("push Lf; call; halt; Lf:; push 2; push -3; binary *; ret").unwrap();
+        let mut instrs = crate::assemble::assemble(&pinstrs).unwrap();
+        instrs.pop();
+        write_legacy(instrs.len() as u32, &instrs, Endianness::Big)
+    }
+
+    // The golden file, which must compile.
+    include!("../tests/golden/program.rs.in");
+
+    #[test]
+    fn test_rust_source() {
+        let bytes = object();
+        assert_eq!(rust_source("PROGRAM", &bytes), include_str!("../tests/golden/program.rs.in"));
+        assert_eq!(PROGRAM, &bytes[..]);
+        let expected: Vec<u8> = [7u32.to_bytes(), Push(Vloc(3)).to_bytes(), Call.to_bytes()].concat();
+        assert_eq!(PROGRAM[..expected.len()], expected[..]);
+        assert_eq!(rust_source("EMPTY", &[]), "pub const EMPTY: &[u8] = &[\n];\n");
+    }
+
+    #[test]
+    fn test_c_source() {
+        assert_eq!(c_source("PROGRAM", &object()), include_str!("../tests/golden/program.h"));
+    }
+
+    #[test]
+    fn test_identifier() {
+        assert_eq!(identifier("tests/applam2.s"), "APPLAM2");
+        assert_eq!(identifier("C:\\progs\\my-prog.v2.s"), "MY_PROG");
+        assert_eq!(identifier("2fast.s"), "_2FAST");
+        assert_eq!(identifier(".s"), "_");
+    }
+
+    #[test]
+    fn test_output_path() {
+        let cases = [("prog.s", "prog.o"), ("prog.asm", "prog.o"), ("prog", "prog.o"), ("tests/fib.s", "tests/fib.o"),
+                     ("build.v2/prog", "build.v2/prog.o"), ("build.v2/prog.s", "build.v2/prog.o"),
+                     ("./prog.v1.s", "./prog.v1.o")];
+        for (input, output) in cases {
+            assert_eq!(output_path(Path::new(input), "o"), Path::new(output), "{}", input);
+        }
+        assert_eq!(output_path(Path::new("prog.s"), "json"), Path::new("prog.json"));
+        // An input that already has the output's extension maps to itself.
+        assert!(same_file(&output_path(Path::new("prog.o"), "o"), Path::new("prog.o")));
+
+        let dir = crate::temp_dir("output-path");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.s"), "halt\n").unwrap();
+        assert!(same_file(&dir.join("sub/../a.s"), &dir.join("a.s")));
+        assert!(!same_file(&dir.join("a.s"), &dir.join("a.o")));
+        assert!(!same_file(&dir.join("sub/../b.s"), &dir.join("b.s")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1290 @@
+use crate::isa::{lowercase_opcodes, parse_constant, Directive::*, Instr::*, Label, PInstr, PInstr::*, Unop::*, Val, Val::*};
+use crate::object::DataSegment;
+use crate::{Endianness, ParseError, ToBytes};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use std::{error, fmt, fs, io};
+
+/// A pseudo-instruction together with the (1-based) source line it was
+/// parsed from.
+pub type Numbered = (usize, PInstr);
+
+/// An error in the use of a directive, at the given source line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpandError {
+    pub line: usize,
+    pub message: String,
+    /// The line of the earlier definition the error is about, as for a
+    /// label defined twice.
+    pub previous: Option<usize>,
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for ExpandError {}
+
+/// A line of a file that did not parse: its path, its number in the
+/// file and its text, which the error's caret points into.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineError {
+    pub path: PathBuf,
+    pub line: usize,
+    pub text: String,
+    pub error: crate::ParseError,
+}
+
+impl LineError {
+    /// The error as a diagnostic, with the line and a caret under the
+    /// bad token.
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic {
+            caret: self.error.caret(&self.text),
+            column: self.error.column.map(|c| c + 1),
+            ..Diagnostic::error(&self.path, Some(self.line), &self.error.to_string())
+        }
+    }
+
+    /// Where the error is, as `path:line:column`, or `path:line` if it
+    /// has no column.
+    pub fn location(&self) -> String {
+        match self.error.column {
+            Some(c) => format!("{}:{}:{}", self.path.display(), self.line, c + 1),
+            None => format!("{}:{}", self.path.display(), self.line),
+        }
+    }
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location(), self.error)?;
+        match self.error.caret(&self.text) {
+            Some(caret) => write!(f, "\n{}", caret),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for LineError {}
+
+/// Whether a diagnostic stops a program being written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Something wrong with a program, for reporting: in a file, at a
+/// (1-based) line and column where they are known. Displayed as
+/// `file.s:LINE:COLUMN: error: message`, then the caret if it has one;
+/// `json::diagnostic_to_json` writes it for tools.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+    /// The source line, and under it a caret at the column.
+    pub caret: Option<String>,
+}
+
+impl Diagnostic {
+    /// An error in `path`, at `line` if known.
+    pub fn error(path: &Path, line: Option<usize>, message: &str) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            path: path.to_path_buf(),
+            line,
+            column: None,
+            message: message.to_string(),
+            caret: None,
+        }
+    }
+
+    /// A warning about `path`, at `line` if known.
+    pub fn warning(path: &Path, line: Option<usize>, message: &str) -> Diagnostic {
+        Diagnostic { severity: Severity::Warning, ..Diagnostic::error(path, line, message) }
+    }
+
+    /// An error at `line` of a program read by `parse_sources` (or a
+    /// function like it), in the file `sources` finds it in; or in
+    /// `path`, with no line, if it finds none (as for line 0).
+    pub fn locate(sources: &Sources, path: &Path, line: usize, message: &str) -> Diagnostic {
+        match sources.file_line(line) {
+            Some((file, n)) => Diagnostic::error(file, Some(n), message),
+            None => Diagnostic::error(path, None, message),
+        }
+    }
+
+    /// Like `locate`, for an error from expanding or assembling the
+    /// program, which also names where anything it conflicts with was
+    /// defined: `main.s:3: error: label 'Lf' is defined more than once
+    /// (first defined at lib.s:1)`.
+    pub fn locate_error(sources: &Sources, path: &Path, error: &ExpandError) -> Diagnostic {
+        match error.previous.and_then(|line| sources.file_line(line)) {
+            Some((file, n)) => {
+                let message = format!("{} (first defined at {}:{})", error.message, file.display(), n);
+                Diagnostic::locate(sources, path, error.line, &message)
+            }
+            None => Diagnostic::locate(sources, path, error.line, &error.message),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        if let Some(column) = self.column {
+            write!(f, ":{}", column)?;
+        }
+        write!(f, ": {}: {}", self.severity, self.message)?;
+        match &self.caret {
+            Some(caret) => write!(f, "\n{}", caret),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The lines that did not parse, in the order they were read: by line
+/// number within each file, with an included file's at its `.include`.
+/// `parse_file_max_errors` fails with an `io::Error` wrapping these.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseErrors {
+    pub errors: Vec<LineError>,
+    /// What was read, with each bad statement skipped or replaced (see
+    /// `PInstr::parse_line_recovering`), up to where reading stopped.
+    /// The labels of bad lines are kept, so that it can be checked for
+    /// errors beyond the parse errors; it must never be written out.
+    /// Its lines are numbered in one sequence, as `parse_sources` does.
+    pub recovered: Vec<Numbered>,
+    /// The files `recovered` was read from.
+    pub sources: Sources,
+}
+
+impl ParseErrors {
+    /// The first error in the recovered program that is not a parse
+    /// error, from expanding its directives or resolving its labels,
+    /// so that one bad line does not hide a mistake further on. Its
+    /// line is numbered as in `recovered`.
+    pub fn later_error(&self) -> Option<ExpandError> {
+        let expanded = match expand_with_data(&self.recovered, Endianness::Big) {
+            Ok((expanded, _)) => expanded,
+            Err(e) => return Some(e),
+        };
+        crate::assemble::assemble_numbered(&expanded).err()
+    }
+}
+
+impl fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (n, e) in self.errors.iter().enumerate() {
+            if n > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ParseErrors {}
+
+/// The parse errors found so far, and how many to find before giving up.
+struct Errors {
+    found: Vec<LineError>,
+    max: usize,
+}
+
+impl Errors {
+    fn new(max: usize) -> Errors {
+        Errors { found: Vec::new(), max }
+    }
+
+    fn is_full(&self) -> bool {
+        self.max != 0 && self.found.len() >= self.max
+    }
+
+    /// Record `error`, unless enough have been found already.
+    fn push(&mut self, error: LineError) {
+        if !self.is_full() {
+            self.found.push(error);
+        }
+    }
+
+    /// The program read, or if there were errors, them and what was
+    /// recovered as an `io::Error`.
+    fn finish(self, numbered: Vec<Numbered>, sources: Sources) -> io::Result<(Vec<Numbered>, Sources)> {
+        if self.found.is_empty() {
+            Ok((numbered, sources))
+        } else {
+            Err(io::Error::other(ParseErrors { errors: self.found, recovered: numbered, sources }))
+        }
+    }
+}
+
+fn error(line: usize, message: &str) -> ExpandError {
+    ExpandError { line, message: message.to_string(), previous: None }
+}
+
+/// Number the lines of a source text and parse each of them.
+pub fn parse_numbered(src: &str) -> Result<Vec<Numbered>, crate::ParseError> {
+    let mut numbered = Vec::new();
+    for (n, line) in src.lines().enumerate() {
+        for i in PInstr::parse_line(line)? {
+            numbered.push((n + 1, i));
+        }
+    }
+    Ok(numbered)
+}
+
+/// Read and parse an assembly file, replacing each `.include "path"`
+/// with the contents of the named file, found relative to the directory
+/// of the file that includes it. Lines are numbered within their own
+/// file. With `ignore_case`, opcodes may be written in any case (see
+/// `isa::lowercase_opcodes`).
+pub fn parse_file(path: &Path, ignore_case: bool) -> io::Result<Vec<Numbered>> {
+    parse_file_max_errors(path, ignore_case, 1)
+}
+
+/// Like `parse_file`, but carry on past lines that do not parse, up to
+/// `max_errors` of them (0 for no limit), and fail with them all (see
+/// `ParseErrors`). Errors reading files still stop at once.
+pub fn parse_file_max_errors(path: &Path, ignore_case: bool, max_errors: usize) -> io::Result<Vec<Numbered>> {
+    let (numbered, sources) = parse_sources_max_errors(path, ignore_case, max_errors)?;
+    Ok(sources.local(numbered))
+}
+
+/// Like `parse_file`, but number the lines of all the files read in
+/// one sequence, as if the included files were pasted in, so that a
+/// line number (which `expand` and `assemble::line_numbers` carry
+/// through) is enough to find the source file. The returned `Sources`
+/// does that.
+pub fn parse_sources(path: &Path, ignore_case: bool) -> io::Result<(Vec<Numbered>, Sources)> {
+    parse_sources_max_errors(path, ignore_case, 1)
+}
+
+/// Like `parse_sources`, but carry on past up to `max_errors` lines
+/// that do not parse, as `parse_file_max_errors` does.
+pub fn parse_sources_max_errors(path: &Path, ignore_case: bool, max_errors: usize)
+                                -> io::Result<(Vec<Numbered>, Sources)> {
+    parse_all_sources(&[path], ignore_case, max_errors)
+}
+
+/// Like `parse_sources_max_errors`, but read each of `paths` in turn
+/// into one program, as if they were one file: their lines are numbered
+/// in one sequence, and `max_errors` counts the errors in all of them.
+pub fn parse_all_sources(paths: &[&Path], ignore_case: bool, max_errors: usize)
+                         -> io::Result<(Vec<Numbered>, Sources)> {
+    let mut out = Vec::new();
+    let mut sources = Sources::default();
+    let mut errors = Errors::new(max_errors);
+    for path in paths {
+        include(path, ignore_case, &mut Vec::new(), &mut sources, &mut out, &mut errors)?;
+    }
+    errors.finish(out, sources)
+}
+
+/// Like `parse_file`, but read the source from `reader`. `path` names
+/// it in errors, and `.include`s are found relative to its directory.
+/// Errors reading a line (including invalid UTF-8) give its number.
+pub fn parse_reader(reader: impl BufRead, path: &Path, ignore_case: bool) -> io::Result<Vec<Numbered>> {
+    let (numbered, sources) = parse_reader_sources(reader, path, ignore_case, 1)?;
+    Ok(sources.local(numbered))
+}
+
+/// Like `parse_sources_max_errors`, but read the source from `reader`,
+/// named `path`, as `parse_reader` does.
+pub fn parse_reader_sources(reader: impl BufRead, path: &Path, ignore_case: bool, max_errors: usize)
+                            -> io::Result<(Vec<Numbered>, Sources)> {
+    let mut out = Vec::new();
+    let mut sources = Sources::default();
+    let mut errors = Errors::new(max_errors);
+    parse_lines(reader, path, ignore_case, &mut Vec::new(), &mut sources, &mut out, &mut errors)?;
+    errors.finish(out, sources)
+}
+
+/// The files a program was read from by `parse_sources`, and which of
+/// them each of its lines came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sources {
+    /// The files, in the order they were first read.
+    pub files: Vec<PathBuf>,
+    /// Runs of lines read one after the other from the same file: the
+    /// first line's number in the sequence, the file's index, and the
+    /// first line's number in the file.
+    runs: Vec<(usize, usize, usize)>,
+    /// The text of each line read so far, in sequence.
+    texts: Vec<String>,
+}
+
+impl Sources {
+    /// Number the next line, line `n` of `path` with text `text`, in
+    /// the sequence.
+    fn next(&mut self, path: &Path, n: usize, text: &str) -> usize {
+        let file = match self.files.iter().position(|f| f == path) {
+            Some(file) => file,
+            None => {
+                self.files.push(path.to_path_buf());
+                self.files.len() - 1
+            }
+        };
+        self.texts.push(text.to_string());
+        let line = self.texts.len();
+        match self.runs.last() {
+            Some(&(first, f, first_n)) if f == file && first_n + (line - first) == n => (),
+            _ => self.runs.push((line, file, n)),
+        }
+        line
+    }
+
+    /// The number of lines read.
+    pub fn len(&self) -> usize {
+        self.texts.len()
+    }
+
+    /// Whether no lines were read.
+    pub fn is_empty(&self) -> bool {
+        self.texts.is_empty()
+    }
+
+    /// The text of line `line` of the sequence, as it was written.
+    pub fn text(&self, line: usize) -> Option<&str> {
+        self.texts.get(line.checked_sub(1)?).map(String::as_str)
+    }
+
+    /// The index of the file that line `line` of the sequence came
+    /// from, and its number in that file.
+    pub fn locate(&self, line: usize) -> Option<(usize, usize)> {
+        if line == 0 || line > self.len() {
+            return None;
+        }
+        let run = self.runs.partition_point(|&(first, _, _)| first <= line) - 1;
+        let (first, file, first_n) = self.runs[run];
+        Some((file, first_n + (line - first)))
+    }
+
+    /// The file that line `line` of the sequence came from, and its
+    /// number in that file.
+    pub fn file_line(&self, line: usize) -> Option<(&Path, usize)> {
+        self.locate(line).map(|(file, n)| (self.files[file].as_path(), n))
+    }
+
+    /// Renumber lines within their own file.
+    fn local(&self, numbered: Vec<Numbered>) -> Vec<Numbered> {
+        numbered.into_iter().map(|(n, i)| (self.locate(n).map_or(n, |(_, n)| n), i)).collect()
+    }
+}
+
+/// Append the parsed lines of `path` to `out`. `stack` holds the files
+/// currently being included, to detect cycles.
+fn include(path: &Path, ignore_case: bool, stack: &mut Vec<PathBuf>, sources: &mut Sources,
+           out: &mut Vec<Numbered>, errors: &mut Errors) -> io::Result<()> {
+    let context = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+    let canonical = path.canonicalize().map_err(context)?;
+    if stack.contains(&canonical) {
+        let cycle: Vec<String> = stack.iter().chain(Some(&canonical))
+            .map(|p| p.display().to_string())
+            .collect();
+        return Err(io::Error::other(format!("include cycle: {}", cycle.join(" -> "))));
+    }
+    let file = fs::File::open(path).map_err(context)?;
+    stack.push(canonical);
+    parse_lines(BufReader::new(file), path, ignore_case, stack, sources, out, errors)?;
+    stack.pop();
+    Ok(())
+}
+
+/// Append the parsed lines of `reader`, the contents of `path`, to `out`,
+/// and the lines that do not parse to `errors`, stopping once it is full.
+fn parse_lines(reader: impl BufRead, path: &Path, ignore_case: bool, stack: &mut Vec<PathBuf>,
+               sources: &mut Sources, out: &mut Vec<Numbered>, errors: &mut Errors) -> io::Result<()> {
+    for (n, line) in reader.lines().enumerate() {
+        if errors.is_full() {
+            break;
+        }
+        let text = line.map_err(|e| io::Error::new(e.kind(), format!("{}:{}: {}", path.display(), n + 1, e)))?;
+        let line = if ignore_case { lowercase_opcodes(&text) } else { text.clone() };
+        // A bad line is parsed again, recovering what can be, so that
+        // its labels are still defined for the lines after it.
+        let pinstrs = match PInstr::parse_line(&line) {
+            Ok(pinstrs) => pinstrs,
+            Err(_) => {
+                let (pinstrs, bad) = PInstr::parse_line_recovering(&line);
+                for error in bad {
+                    errors.push(LineError { path: path.to_path_buf(), line: n + 1, text: line.clone(), error });
+                }
+                pinstrs
+            }
+        };
+        let line_number = sources.next(path, n + 1, &text);
+        for i in pinstrs {
+            match i {
+                PDirective(Include(file)) => {
+                    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                    include(&dir.join(file), ignore_case, stack, sources, out, errors)?;
+                }
+                i => out.push((line_number, i)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves scoped labels (`.Lname:`, `push .Lname` and `push .Lname+N`)
+/// by qualifying them with their scope, the closest preceding label
+/// written in source (one not starting with `.` or `_`): `.Lx` after
+/// `Lf:` is `Lf.Lx`.
+/// No label written in source contains a `.`, so this never clashes.
+#[derive(Default)]
+pub struct Scopes {
+    scope: Option<Label>,
+    defined: HashSet<Label>,
+    used: Vec<(usize, Label)>,
+}
+
+impl Scopes {
+    fn qualify(&self, name: &str) -> Result<Label, String> {
+        match &self.scope {
+            Some(scope) => Ok(format!("{}.{}", scope, name)),
+            None => Err(format!("scoped label '.{}' is not after any label", name)),
+        }
+    }
+
+    /// Resolve the scoped labels in one pseudo-instruction, the `n`th
+    /// of the program.
+    pub fn resolve(&mut self, n: usize, i: &PInstr) -> Result<PInstr, String> {
+        match i {
+            PLabel(l) => match l.strip_prefix('.') {
+                Some(name) => {
+                    let label = self.qualify(name)?;
+                    if !self.defined.insert(label.clone()) {
+                        return Err(format!("scoped label '{}' is defined twice", label));
+                    }
+                    Ok(PLabel(label))
+                }
+                None => {
+                    if !l.starts_with('_') {
+                        self.scope = Some(l.clone());
+                    }
+                    Ok(i.clone())
+                }
+            },
+            PRel(name) => {
+                let label = self.qualify(name)?;
+                self.used.push((n, label.clone()));
+                Ok(PPush(label))
+            }
+            PPushOffset(l, offset) => match l.strip_prefix('.') {
+                Some(name) => {
+                    let label = self.qualify(name)?;
+                    self.used.push((n, label.clone()));
+                    Ok(PPushOffset(label, *offset))
+                }
+                None => Ok(i.clone()),
+            },
+            _ => Ok(i.clone()),
+        }
+    }
+
+    /// Check that every scoped label used was defined in its scope,
+    /// returning the position of the first that was not.
+    pub fn finish(self) -> Result<(), (usize, String)> {
+        let Scopes { defined, used, .. } = self;
+        match used.into_iter().find(|(_, l)| !defined.contains(l)) {
+            Some((n, label)) => Err((n, format!("undefined scoped label '{}'", label))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The most statements `.times` blocks may unroll to, in all.
+const MAX_UNROLLED: usize = 1 << 20;
+
+/// Unroll `.times` blocks, copying each body the given number of times.
+/// Copies keep the line numbers of the body. A body may not define
+/// labels, since each copy would define them again, and the program may
+/// not grow past `MAX_UNROLLED` statements.
+fn unroll(pinstrs: &[Numbered]) -> Result<Vec<Numbered>, ExpandError> {
+    // The open blocks: line, repeat count, and body so far.
+    let mut blocks: Vec<(usize, u32, Vec<Numbered>)> = vec![(0, 1, Vec::new())];
+    for (line, i) in pinstrs {
+        match i {
+            PDirective(Times(n)) => blocks.push((*line, *n, Vec::new())),
+            PDirective(EndTimes) if blocks.len() > 1 => {
+                let (start, n, body) = blocks.pop().unwrap();
+                let parent = &mut blocks.last_mut().unwrap().2;
+                match body.len().checked_mul(n as usize).and_then(|len| len.checked_add(parent.len())) {
+                    Some(len) if len <= MAX_UNROLLED => (),
+                    _ => return Err(error(start, &format!(
+                        ".times block (lines {}-{}) unrolls to more than {} statements", start, line, MAX_UNROLLED))),
+                }
+                for _ in 0..n {
+                    parent.extend(body.iter().cloned());
+                }
+            }
+            PDirective(EndTimes) => return Err(error(*line, "} without a matching .times")),
+            PLabel(l) if blocks.len() > 1 =>
+                return Err(error(*line, &format!("label '{}' defined inside a .times block", l))),
+            _ => blocks.last_mut().unwrap().2.push((*line, i.clone())),
+        }
+    }
+    match blocks.pop() {
+        Some((line, _, _)) if !blocks.is_empty() =>
+            Err(error(line, ".times block is never closed with }")),
+        Some((_, _, out)) => Ok(out),
+        None => unreachable!(),
+    }
+}
+
+/// Collect the constants defined with `.equ`, which may be used before
+/// their definition.
+fn constants(pinstrs: &[Numbered]) -> Result<HashMap<String, Val>, ExpandError> {
+    let mut constants = HashMap::new();
+    let mut lines = HashMap::new();
+    for (line, i) in pinstrs {
+        if let PDirective(Equ(name, v)) = i {
+            constants.insert(name.clone(), *v);
+            match lines.insert(name, *line) {
+                Some(0) => return Err(error(*line, &format!("constant '{}' is already defined on the command line", name))),
+                Some(previous) => return Err(ExpandError {
+                    previous: Some(previous),
+                    ..error(*line, &format!("constant '{}' is defined more than once", name))
+                }),
+                None => (),
+            }
+        }
+    }
+    Ok(constants)
+}
+
+/// Parse a constant given on the command line, as `assem -D` takes it:
+/// `NAME=VALUE`, or `NAME` alone for `NAME=1`. It is a `.equ` on line
+/// 0, to go before the program's lines; `expand` then substitutes it as
+/// any other, and rejects a `.equ` of the same name in the program.
+pub fn parse_define(define: &str) -> Result<Numbered, ParseError> {
+    let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+    Ok((0, PDirective(Equ(parse_constant(name)?, Val::from_str(value)?))))
+}
+
+/// An open `.while` loop.
+struct Loop {
+    line: usize,
+    top: Label,
+    exit: Label,
+    in_body: bool,
+}
+
+/// Push `true` and jump unconditionally to `target`.
+fn jump(line: usize, target: &Label) -> Vec<Numbered> {
+    vec![(line, PI(Push(Vbool(true)))), (line, PPush(target.clone())), (line, PI(Branch))]
+}
+
+/// The routine for one pooled string: allocate an array with one
+/// element per character, fill it with the character codes, and return
+/// its address.
+fn string_routine(line: usize, label: &Label, text: &str) -> Vec<Numbered> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = vec![
+        (line, PLabel(label.clone())),
+        (line, PI(Push(Vi32(chars.len() as i32)))),
+        (line, PI(Push(Vi32(0)))),
+        (line, PI(Alloc)),
+    ];
+    for (i, c) in chars.into_iter().enumerate() {
+        out.push((line, PI(Peek(0))));
+        out.push((line, PI(Push(Vi32(i as i32)))));
+        out.push((line, PI(Push(Vi32(c as i32)))));
+        out.push((line, PI(Set)));
+    }
+    out.push((line, PI(Ret)));
+    out
+}
+
+/// Size of the header that precedes the instructions in the plain
+/// output (see `object::write_legacy`).
+const HEADER_BYTES: usize = 4;
+
+/// The largest alignment `.align` takes.
+const MAX_ALIGN: u32 = 4096;
+
+/// The number of bytes an expanded pseudo-instruction takes up in the
+/// plain output, with fixed-width operands. A label push always
+/// becomes `push` of a `Vloc`.
+fn encoded_len(i: &PInstr) -> usize {
+    match i {
+        PI(instr) => instr.byte_len(),
+        PPush(_) | PPushOffset(..) => Push(Vloc(0)).byte_len(),
+        _ => 0,
+    }
+}
+
+/// Lower directives to plain pseudo-instructions, which inherit the line
+/// number of the directive they came from.
+///
+/// A loop is written
+///
+/// ```text
+/// .while
+///   <condition, leaving a bool on the stack>
+/// .do
+///   <body, which may use .break>
+/// .endwhile
+/// ```
+///
+/// and becomes
+///
+/// ```text
+/// top:  <condition>; unary neg; push exit; branch
+///       <body>; push true; push top; branch
+/// exit:
+/// ```
+///
+/// where `.break` is `push true; push exit; branch`.
+///
+/// `.string "text"` becomes `push` of a label in a string pool placed
+/// after the rest of the program (so it moves no other instruction).
+/// Each distinct string gets one zero-argument routine there, which
+/// returns the string as a new heap array of character codes; call it
+/// like any other function (`setframe 0; swap; call`).
+///
+/// `.datastring "text"` instead puts the text in the data segment (see
+/// `object::DataSegment`), which only `expand_with_data` returns: its
+/// length, then the code of each character, under a data label
+/// `_Ldata_N`. Identical strings share one copy. In the code it becomes
+/// `var 0; push k`, the address of the data array and the index of the
+/// length, so that `get` reads the length and character `i` is at
+/// `k + 1 + i`. `var 0` is the array only in the program's first
+/// frame, where the loader left it; a function needs it passed in.
+/// `expand` returns an error for it, as the data would be lost.
+///
+/// `.byte` and `.i32` become one `Instr::Byte` per byte of data, which
+/// is written out as is: `.i32` in the byte order of the output, which
+/// `expand_with_data` takes (`expand` lays it out big-endian, the
+/// default). `.align n`, for a power of two `n` up to
+/// `MAX_ALIGN`, becomes as many zero bytes as it takes to bring the
+/// offset in the plain output (header included) to a multiple of `n`.
+/// Nothing else is aligned: not an object file, whose code comes after
+/// other fields, nor varint or compressed code.
+///
+/// `.times n { ... }` blocks are unrolled first, so each copy of a loop
+/// in the body gets its own labels.
+///
+/// `push NAME` becomes `push` of the value given by `.equ NAME value`.
+///
+/// `.jumptable L0 L1 ...` becomes `push L0; push L1; ...`, one pc per
+/// label, and each label must be defined somewhere in the program.
+///
+/// Scoped labels are qualified by their scope (see `Scopes`), so
+/// `push .Lx` becomes an ordinary `push` of a label such as `Lf.Lx`.
+///
+/// Generated labels contain a `_` after their first character, which
+/// labels written in source cannot, so they never clash with the
+/// program's own labels.
+pub fn expand(pinstrs: &[Numbered]) -> Result<Vec<Numbered>, ExpandError> {
+    let (out, data) = expand_with_data(pinstrs, Endianness::Big)?;
+    match pinstrs.iter().find(|(_, i)| matches!(i, PDirective(DataStr(_)))) {
+        Some((line, _)) if !data.values.is_empty() =>
+            Err(error(*line, ".datastring needs a data segment, which only an object file has")),
+        _ => Ok(out),
+    }
+}
+
+/// Like `expand`, for a program written with the given byte order, but
+/// also return the data segment of `.datastring`, which the program's
+/// object file must carry.
+pub fn expand_with_data(pinstrs: &[Numbered], endianness: Endianness) -> Result<(Vec<Numbered>, DataSegment), ExpandError> {
+    let pinstrs = &unroll(pinstrs)?;
+    let constants = constants(pinstrs)?;
+    let mut out = Vec::new();
+    let mut loops: Vec<Loop> = Vec::new();
+    let mut count = 0;
+    let mut pool: Vec<(usize, Label, String)> = Vec::new();
+    let mut pooled = HashMap::<String, Label>::new();
+    let mut data = DataSegment::default();
+    let mut in_data = HashMap::<String, i32>::new();
+    let mut scopes = Scopes::default();
+    let mut tables = Vec::new();
+    // The offset in the plain output of the end of `out[..measured]`.
+    let (mut offset, mut measured) = (HEADER_BYTES, 0);
+
+    for (n, (line, i)) in pinstrs.iter().enumerate() {
+        let line = *line;
+        let i = &scopes.resolve(n, i).map_err(|m| error(line, &m))?;
+        match i {
+            PDirective(While) => {
+                let l = Loop {
+                    line,
+                    top: format!("_Lwhile_{}", count),
+                    exit: format!("_Lendwhile_{}", count),
+                    in_body: false,
+                };
+                count += 1;
+                out.push((line, PLabel(l.top.clone())));
+                loops.push(l);
+            }
+            PDirective(Do) => match loops.last_mut() {
+                Some(l) if !l.in_body => {
+                    l.in_body = true;
+                    out.push((line, PI(Unary(Neg))));
+                    out.push((line, PPush(l.exit.clone())));
+                    out.push((line, PI(Branch)));
+                }
+                Some(_) => return Err(error(line, "second .do in the same .while loop")),
+                None => return Err(error(line, ".do outside of a .while loop")),
+            },
+            PDirective(Break) => match loops.last() {
+                Some(l) if l.in_body => out.extend(jump(line, &l.exit)),
+                Some(_) => return Err(error(line, ".break in a .while condition")),
+                None => return Err(error(line, ".break outside of a .while loop")),
+            },
+            PDirective(EndWhile) => match loops.pop() {
+                Some(l) if l.in_body => {
+                    out.extend(jump(line, &l.top));
+                    out.push((line, PLabel(l.exit)));
+                }
+                Some(l) => return Err(error(l.line, ".while loop has no .do")),
+                None => return Err(error(line, ".endwhile without a matching .while")),
+            },
+            PDirective(Str(text)) => {
+                let label = pooled.entry(text.clone()).or_insert_with(|| {
+                    let label = format!("_Lstr_{}", pool.len());
+                    pool.push((line, label.clone(), text.clone()));
+                    label
+                });
+                out.push((line, PPush(label.clone())));
+            }
+            PDirective(DataStr(text)) => {
+                let index = *in_data.entry(text.clone()).or_insert_with(|| {
+                    let index = data.values.len();
+                    data.symbols.insert(format!("_Ldata_{}", data.symbols.len()), index as u32);
+                    data.values.push(Vi32(text.chars().count() as i32));
+                    data.values.extend(text.chars().map(|c| Vi32(c as i32)));
+                    index as i32
+                });
+                out.push((line, PI(Var(0))));
+                out.push((line, PI(Push(Vi32(index)))));
+            }
+            PDirective(Bytes(bs)) => out.extend(bs.iter().map(|b| (line, PI(Byte(*b))))),
+            PDirective(Equ(..)) => (),
+            PPushConst(name) => match constants.get(name) {
+                Some(v) => out.push((line, PI(Push(*v)))),
+                None => return Err(error(line, &format!("undefined constant '{}'", name))),
+            },
+            PDirective(Include(_)) =>
+                return Err(error(line, ".include is only supported when reading files (see parse_file)")),
+            PDirective(Align(n)) if !n.is_power_of_two() || *n > MAX_ALIGN =>
+                return Err(error(line, &format!(".align {}: alignment must be a power of two up to {}", n, MAX_ALIGN))),
+            PDirective(Align(n)) => {
+                offset += out[measured..].iter().map(|(_, i)| encoded_len(i)).sum::<usize>();
+                measured = out.len();
+                let padding = (*n as usize - offset % *n as usize) % *n as usize;
+                out.extend((0..padding).map(|_| (line, PI(Byte(0)))));
+            }
+            PDirective(I32(n)) => out.extend(n.to_bytes_with(endianness).into_iter().map(|b| (line, PI(Byte(b))))),
+            PDirective(JumpTable(labels)) => {
+                tables.extend(labels.iter().map(|l| (line, l.clone())));
+                out.extend(labels.iter().map(|l| (line, PPush(l.clone()))));
+            }
+            _ => out.push((line, i.clone())),
+        }
+    }
+
+    if let Some(l) = loops.pop() {
+        return Err(error(l.line, ".while loop is never closed with .endwhile"));
+    }
+    scopes.finish().map_err(|(n, m)| error(pinstrs[n].0, &m))?;
+    let defined: HashSet<&Label> = out.iter().filter_map(|(_, i)| match i { PLabel(l) => Some(l), _ => None }).collect();
+    if let Some((line, label)) = tables.iter().find(|(_, l)| !defined.contains(l)) {
+        return Err(error(*line, &format!("undefined label '{}' in .jumptable", label)));
+    }
+    for (line, label, text) in &pool {
+        out.extend(string_routine(*line, label, text));
+    }
+    Ok((out, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::assemble;
+    use crate::temp_dir;
+
+    fn expand_src(src: &str) -> Result<Vec<PInstr>, ExpandError> {
+        let numbered = parse_numbered(src).unwrap();
+        Ok(expand(&numbered)?.into_iter().map(|(_, i)| i).collect())
+    }
+
+    #[test]
+    fn test_nested_while() {
+        let src = ".while
+                   push true
+                   .do
+                   .while
+                   push false
+                   .do
+                   .break
+                   pop
+                   .endwhile
+                   push 1
+                   .endwhile
+                   halt";
+        let instrs = assemble(&expand_src(src).unwrap()).unwrap();
+        // Outer loop: condition at 0, exit test at 1-3, back edge at 16-18.
+        assert_eq!(instrs[1], Unary(Neg));
+        assert_eq!(instrs[2], Push(Vloc(19)));
+        assert_eq!(instrs[17], Push(Vloc(0)));
+        assert_eq!(instrs[19], Halt);
+        // Inner loop: condition at 4, exit test at 5-7, back edge at 12-14.
+        assert_eq!(instrs[6], Push(Vloc(15)));
+        assert_eq!(instrs[13], Push(Vloc(4)));
+        assert_eq!(instrs[4], Push(Vbool(false)));
+        // .break leaves the inner loop only.
+        assert_eq!(&instrs[8..11], &[Push(Vbool(true)), Push(Vloc(15)), Branch]);
+        assert_eq!(instrs[15], Push(Vi32(1)));
+    }
+
+    #[test]
+    fn test_expanded_lines() {
+        let numbered = parse_numbered("push 0\n.while\npush true\n.do\n.endwhile").unwrap();
+        let lines: Vec<usize> = expand(&numbered).unwrap().iter().map(|(n, _)| *n).collect();
+        assert_eq!(lines, vec![1, 2, 3, 4, 4, 4, 5, 5, 5, 5]);
+    }
+
+    #[test]
+    fn test_string_pool() {
+        let src = r#"setframe 0
+                     .string "hi"
+                     swap
+                     call
+                     .string "yo"
+                     .string "hi"
+                     halt"#;
+        let pinstrs = expand_src(src).unwrap();
+        // Two distinct strings, so two routines in the pool.
+        assert_eq!(pinstrs.iter().filter(|i| matches!(i, PLabel(_))).count(), 2);
+        assert_eq!(pinstrs[1], PPush("_Lstr_0".into()));
+        assert_eq!(pinstrs[4], PPush("_Lstr_1".into()));
+        assert_eq!(pinstrs[5], PPush("_Lstr_0".into()));
+
+        let instrs = assemble(&pinstrs).unwrap();
+        // The pool follows halt: "hi" at 7, "yo" at 7 + 3 + 2 * 4 + 1.
+        assert_eq!(instrs[1], Push(Vloc(7)));
+        assert_eq!(instrs[4], Push(Vloc(19)));
+        assert_eq!(instrs[5], Push(Vloc(7)));
+        assert_eq!(instrs[6], Halt);
+        assert_eq!(&instrs[7..10], &[Push(Vi32(2)), Push(Vi32(0)), Alloc]);
+        assert_eq!(&instrs[10..14], &[Peek(0), Push(Vi32(0)), Push(Vi32('h' as i32)), Set]);
+        assert_eq!(instrs[18], Ret);
+        assert_eq!(&instrs[19..22], &[Push(Vi32(2)), Push(Vi32(0)), Alloc]);
+        assert_eq!(instrs[28], Push(Vi32('o' as i32)));
+    }
+
+    #[test]
+    fn test_string_pool_same_literal() {
+        let pinstrs = expand_src(".string \"x\"\n.string \"x\"").unwrap();
+        assert_eq!(pinstrs.iter().filter(|i| matches!(i, PLabel(_))).count(), 1);
+        assert_eq!(pinstrs[0], pinstrs[1]);
+    }
+
+    #[test]
+    fn test_data_string() {
+        let src = r#"push 1
+                     .datastring "hi"
+                     get
+                     .datastring "yo"
+                     .datastring "hi"
+                     .datastring ""
+                     halt"#;
+        let (expanded, data) = expand_with_data(&parse_numbered(src).unwrap(), Endianness::Big).unwrap();
+        // Each distinct string once in the data: its length, then its
+        // characters.
+        let chars = |s: &str| s.chars().map(|c| Vi32(c as i32)).collect::<Vec<_>>();
+        assert_eq!(data.values, [vec![Vi32(2)], chars("hi"), vec![Vi32(2)], chars("yo"), vec![Vi32(0)]].concat());
+        assert_eq!(data.symbols, vec![("_Ldata_0".to_string(), 0), ("_Ldata_1".to_string(), 3), ("_Ldata_2".to_string(), 6)]
+            .into_iter().collect());
+        // Each use is the data array and the string's index, on its line.
+        let pinstrs: Vec<PInstr> = expanded.iter().map(|(_, i)| i.clone()).collect();
+        assert_eq!(pinstrs, [PI(Push(Vi32(1))), PI(Var(0)), PI(Push(Vi32(0))), PI(Get), PI(Var(0)), PI(Push(Vi32(3))),
+                             PI(Var(0)), PI(Push(Vi32(0))), PI(Var(0)), PI(Push(Vi32(6))), PI(Halt)]);
+        assert_eq!(expanded[6].0, 5);
+        // `expand` has nowhere to put the data.
+        assert_eq!(expand_src(src), Err(error(2, ".datastring needs a data segment, which only an object file has")));
+        assert_eq!(expand_with_data(&parse_numbered(".string \"x\"").unwrap(), Endianness::Little).unwrap().1,
+                   DataSegment::default());
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let src = "push Lend; branch\n.byte 0x01 0x02\n.i32 258\nLend:\nhalt";
+        let instrs = assemble(&expand_src(src).unwrap()).unwrap();
+        // Each data byte takes one pc slot: 2 instructions, then 6 bytes.
+        assert_eq!(instrs[0], Push(Vloc(8)));
+        assert_eq!(&instrs[2..8], &[Byte(1), Byte(2), Byte(0), Byte(0), Byte(1), Byte(2)]);
+        assert_eq!(instrs[8], Halt);
+        // In the byte order of the operands around it.
+        let (expanded, _) = expand_with_data(&parse_numbered(src).unwrap(), Endianness::Little).unwrap();
+        let instrs = crate::assemble::assemble_numbered(&expanded).unwrap().0;
+        assert_eq!(&instrs[4..8], &[Byte(2), Byte(1), Byte(0), Byte(0)]);
+        let bytes: Vec<u8> = instrs[..8].iter().flat_map(|i| i.to_bytes_with(Endianness::Little)).collect();
+        assert_eq!(bytes[..6], [0x00, 0x04, 8, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_jumptable() {
+        // Entry `i` is at `Ltable + i`: `peek` the index, add it to the
+        // table's address, and call the entry there.
+        let src = "push 1\npush Ltable; peek 1; binary +; call\nhalt\n\
+                   Ltable: .jumptable Lzero Lone Ltwo\nLafter:\n\
+                   Lzero: push 0; ret\nLone: push 1; ret\nLtwo: push 2; ret";
+        let expanded = expand_src(src).unwrap();
+        let (instrs, symbols) = crate::assemble::assemble_with_symbols(&expanded).unwrap();
+        assert_eq!(symbols["Ltable"].pc, 6);
+        assert_eq!(instrs[6..9], [Push(Vloc(9)), Push(Vloc(11)), Push(Vloc(13))]);
+        // The table takes one pc per entry.
+        assert_eq!(symbols["Lafter"].pc, 9);
+        assert_eq!(instrs[11], Push(Vi32(1)));
+
+        assert_eq!(expand_src("Lx: .jumptable Lx Ly Lz\nLz:").unwrap_err(),
+                   error(1, "undefined label 'Ly' in .jumptable"));
+        assert!(parse_numbered(".jumptable").is_err());
+        assert!(parse_numbered(".jumptable L1 2").is_err());
+    }
+
+    #[test]
+    fn test_align() {
+        // 4 header bytes + 6 (push) + 1 (pop) = 11, so 1 byte pads to 12.
+        let instrs = assemble(&expand_src("push 1\npop\n.align 4\nhalt").unwrap()).unwrap();
+        assert_eq!(&instrs[2..4], &[Byte(0), Halt]);
+        // ... and 5 bytes pad to 16.
+        let instrs = assemble(&expand_src("push 1\npop\n.align 8\nhalt").unwrap()).unwrap();
+        assert_eq!(&instrs[2..7], &[Byte(0); 5]);
+        assert_eq!(instrs[7], Halt);
+        let offset = crate::byte_len(&instrs[..7]);
+        assert_eq!((HEADER_BYTES + offset) % 8, 0);
+        // Already aligned: the header alone is 4 bytes.
+        assert_eq!(expand_src(".align 4\nhalt").unwrap(), vec![PI(Halt)]);
+        assert_eq!(expand_src("pop\n.align 1\nhalt").unwrap(), vec![PI(Pop), PI(Halt)]);
+        // Offsets carry on from one .align to the next: 4 + 1 pads to
+        // 8, then 8 + 1 + 6 to 16.
+        let instrs = assemble(&expand_src("pop\n.align 8\npop\npush 1\n.align 16\nhalt").unwrap()).unwrap();
+        assert_eq!(instrs.iter().filter(|i| **i == Byte(0)).count(), 3 + 1);
+        assert_eq!((HEADER_BYTES + crate::byte_len(&instrs[..instrs.len() - 2])) % 16, 0);
+        for n in [0, 3, 8192, 2147483647] {
+            assert_eq!(expand_src(&format!("halt\n.align {}", n)).unwrap_err(),
+                       error(2, &format!(".align {}: alignment must be a power of two up to 4096", n)));
+        }
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = temp_dir("include");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.s"), "setframe 0\npush Lsquare\n.include \"lib/square.s\"\nhalt\n").unwrap();
+        fs::write(dir.join("lib/square.s"), "Lsquare:\nvar 0; var 0\nBINARY *\nret\n").unwrap();
+        let numbered = parse_file(&dir.join("main.s"), true).unwrap();
+        assert_eq!(numbered.iter().map(|(n, _)| *n).collect::<Vec<_>>(), vec![1, 2, 1, 2, 2, 3, 4, 4]);
+        let pinstrs: Vec<PInstr> = expand(&numbered).unwrap().into_iter().map(|(_, i)| i).collect();
+        let instrs = assemble(&pinstrs).unwrap();
+        assert_eq!(instrs[1], Push(Vloc(2)));
+        assert_eq!(instrs[4], Binary(crate::isa::Binop::Mul));
+        assert!(parse_file(&dir.join("main.s"), false).is_err());
+
+        let (numbered, sources) = parse_sources(&dir.join("main.s"), true).unwrap();
+        assert_eq!(numbered.iter().map(|(n, _)| *n).collect::<Vec<_>>(), vec![1, 2, 4, 5, 5, 6, 7, 8]);
+        assert_eq!(sources.files, vec![dir.join("main.s"), dir.join("lib/square.s")]);
+        let located: Vec<_> = (0..=9).map(|n| sources.locate(n)).collect();
+        assert_eq!(located, vec![None, Some((0, 1)), Some((0, 2)), Some((0, 3)), Some((1, 1)), Some((1, 2)),
+                                 Some((1, 3)), Some((1, 4)), Some((0, 4)), None]);
+        // The text as written, before opcodes are lowercased.
+        assert_eq!((sources.len(), sources.text(6), sources.text(8)), (8, Some("BINARY *"), Some("halt")));
+        assert_eq!((sources.text(0), sources.text(9)), (None, None));
+
+        // Both files define the label.
+        fs::write(dir.join("dup.s"), "Lsquare:\n.include \"lib/square.s\"\n").unwrap();
+        let numbered = parse_file(&dir.join("dup.s"), true).unwrap();
+        let pinstrs: Vec<PInstr> = expand(&numbered).unwrap().into_iter().map(|(_, i)| i).collect();
+        assert_eq!(assemble(&pinstrs).unwrap_err(), "label 'Lsquare' is defined more than once");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_all_sources() {
+        let dir = temp_dir("all-sources");
+        let (main, runtime) = (dir.join("main.s"), dir.join("runtime.s"));
+        fs::write(&main, "push Ldouble\ncall\nhalt\n").unwrap();
+        fs::write(&runtime, "Ldouble:\nret\n").unwrap();
+        let (numbered, sources) = parse_all_sources(&[&main, &runtime], false, 1).unwrap();
+        assert_eq!(numbered.iter().map(|(n, _)| *n).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(sources.file_line(4), Some((runtime.as_path(), 1)));
+        let instrs = crate::assemble::assemble_numbered(&expand(&numbered).unwrap()).unwrap().0;
+        assert_eq!(instrs[0], Push(Vloc(3)));
+
+        // A label both define, named in both.
+        fs::write(&main, "push 1\nLdouble:\nhalt\n").unwrap();
+        let (numbered, sources) = parse_all_sources(&[&runtime, &main], false, 1).unwrap();
+        let err = crate::assemble::assemble_numbered(&expand(&numbered).unwrap()).unwrap_err();
+        assert_eq!(Diagnostic::locate_error(&sources, &main, &err).to_string(),
+                   format!("{}:2: error: label 'Ldouble' is defined more than once (first defined at {}:1)",
+                           main.display(), runtime.display()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = temp_dir("include-errors");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("a.s"), ".include \"lib/b.s\"\n").unwrap();
+        fs::write(dir.join("lib/b.s"), "pop\n.include \"../a.s\"\n").unwrap();
+        let err = parse_file(&dir.join("a.s"), false).unwrap_err();
+        assert!(err.to_string().starts_with("include cycle: "), "{}", err);
+        assert!(err.to_string().ends_with("a.s"), "{}", err);
+
+        fs::write(dir.join("a.s"), "pop\n.include \"missing.s\"\n").unwrap();
+        let err = parse_file(&dir.join("a.s"), false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("missing.s"), "{}", err);
+
+        fs::write(dir.join("a.s"), "pop\n  peek x\n").unwrap();
+        let err = parse_file(&dir.join("a.s"), false).unwrap_err();
+        assert!(err.to_string().ends_with("a.s:2:8: could not parse integer 'x'\n  peek x\n       ^"), "{}", err);
+
+        assert_eq!(expand_src(".include \"a.s\"").unwrap_err(),
+                   error(1, ".include is only supported when reading files (see parse_file)"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_errors() {
+        let dir = temp_dir("max-errors");
+        fs::write(dir.join("a.s"), "psh 1\npop\npeek x\n.include \"b.s\"\nhalt 3\n").unwrap();
+        fs::write(dir.join("b.s"), "push 1\n  frob\n").unwrap();
+        let errors = |max| {
+            let err = parse_file_max_errors(&dir.join("a.s"), false, max).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Other);
+            let errors = err.into_inner().unwrap().downcast::<ParseErrors>().unwrap().errors;
+            errors.into_iter().map(|e| (e.path.file_name().unwrap().to_str().unwrap().to_string(), e.line))
+                .collect::<Vec<_>>()
+        };
+        let all = vec![("a.s".to_string(), 1), ("a.s".to_string(), 3), ("b.s".to_string(), 2), ("a.s".to_string(), 5)];
+        assert_eq!(errors(0), all);
+        assert_eq!(errors(4), all);
+        assert_eq!(errors(2), all[..2]);
+        assert_eq!(errors(1), all[..1]);
+
+        // Each error shows its line, as a single error always has.
+        let err = parse_file_max_errors(&dir.join("a.s"), false, 3).unwrap_err().to_string();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(lines.len(), 9, "{}", err);
+        assert!(lines[0].ends_with("a.s:1:1: could not parse instruction 'psh 1'; did you mean 'push'?"), "{}", err);
+        assert_eq!(lines[1..3], ["psh 1", "^"]);
+        assert!(lines[3].ends_with("a.s:3:6: could not parse integer 'x'"), "{}", err);
+        assert!(lines[6].ends_with("b.s:2:3: could not parse instruction 'frob'"), "{}", err);
+
+        fs::write(dir.join("a.s"), "push 1\nhalt\n").unwrap();
+        assert_eq!(parse_file_max_errors(&dir.join("a.s"), false, 0).unwrap(), parse_file(&dir.join("a.s"), false).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_recovery() {
+        let dir = temp_dir("recovery");
+        let parse_errors = |src: &str| {
+            fs::write(dir.join("a.s"), src).unwrap();
+            let err = parse_file_max_errors(&dir.join("a.s"), false, 0).unwrap_err();
+            *err.into_inner().unwrap().downcast::<ParseErrors>().unwrap()
+        };
+        // The label of the bad line is kept, so the later duplicate
+        // is still found, and the bad instruction still takes a pc.
+        let errors = parse_errors("push 1\nLloop: psh 1\npush Lloop\nLloop:\nhalt\n");
+        assert_eq!(errors.errors.iter().map(|e| e.line).collect::<Vec<_>>(), [2]);
+        assert_eq!(errors.recovered[..3], [(1, PI(Push(Vi32(1)))), (2, PLabel("Lloop".into())), (2, PI(Halt))]);
+        assert_eq!(errors.later_error().unwrap().to_string(), "line 4: label 'Lloop' is defined more than once");
+
+        // Lines after the bad one keep their numbers.
+        let errors = parse_errors(".equ N 1\npeek x\npop\n.equ N 2\n");
+        assert_eq!(errors.later_error().unwrap().to_string(), "line 4: constant 'N' is defined more than once");
+
+        // Every bad statement of a line is reported.
+        let errors = parse_errors("push 1; psh 2; peek x\nhalt\n");
+        assert_eq!(errors.errors.iter().map(|e| e.error.column).collect::<Vec<_>>(), [Some(8), Some(20)]);
+        assert_eq!(errors.later_error(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Yields `data`, then fails.
+    struct Failing(&'static [u8]);
+
+    impl io::Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset"));
+            }
+            let n = buf.len().min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let path = Path::new("net/prog.s");
+        let numbered = parse_reader(&b"push 1\npop\n"[..], path, false).unwrap();
+        assert_eq!(numbered, vec![(1, PI(Push(Vi32(1)))), (2, PI(Pop))]);
+
+        let err = parse_reader(BufReader::new(Failing(b"push 1\npush 2\n")), path, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(err.to_string(), "net/prog.s:3: connection reset");
+
+        let err = parse_reader(&b"push 1\npush \xff\n"[..], path, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "net/prog.s:2: stream did not contain valid UTF-8");
+
+        let dir = temp_dir("read-errors");
+        fs::write(dir.join("bad.s"), b"halt\n\xc3\n").unwrap();
+        let err = parse_file(&dir.join("bad.s"), false).unwrap_err();
+        assert!(err.to_string().ends_with("bad.s:2: stream did not contain valid UTF-8"), "{}", err);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_equ() {
+        let src = "push SIZE\npush 0\nalloc\n.equ SIZE 256\n.equ FLAG false\npush FLAG";
+        assert_eq!(expand_src(src).unwrap(),
+                   vec![PI(Push(Vi32(256))), PI(Push(Vi32(0))), PI(Alloc), PI(Push(Vbool(false)))]);
+        assert_eq!(expand_src(".equ N 1\n.equ N 1").unwrap_err(),
+                   ExpandError { previous: Some(1), ..error(2, "constant 'N' is defined more than once") });
+        assert_eq!(expand_src(".equ N 1\npush M").unwrap_err(), error(2, "undefined constant 'M'"));
+        assert!(assemble(&PInstr::parse_line("push N").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_define() {
+        assert_eq!(parse_define("STACK=256"), Ok((0, PDirective(Equ("STACK".into(), Vi32(256))))));
+        assert_eq!(parse_define("DEBUG"), Ok((0, PDirective(Equ("DEBUG".into(), Vi32(1))))));
+        assert_eq!(parse_define("ON=true"), Ok((0, PDirective(Equ("ON".into(), Vbool(true))))));
+        assert!(parse_define("Lf=1").is_err());
+        assert!(parse_define("N=x").is_err());
+        assert!(parse_define("=1").is_err());
+
+        let defined = |src: &str| {
+            let mut numbered = vec![parse_define("N=2").unwrap()];
+            numbered.extend(parse_numbered(src).unwrap());
+            expand(&numbered).map(|out| out.into_iter().map(|(_, i)| i).collect::<Vec<_>>())
+        };
+        assert_eq!(defined("push N; halt"), Ok(vec![PI(Push(Vi32(2))), PI(Halt)]));
+        assert_eq!(defined("halt\n.equ N 3"), Err(error(2, "constant 'N' is already defined on the command line")));
+    }
+
+    #[test]
+    fn test_times() {
+        assert_eq!(expand_src(".times 3 { pop }").unwrap(), vec![PI(Pop); 3]);
+        assert_eq!(expand_src("halt; .times 0 { pop }").unwrap(), vec![PI(Halt)]);
+        let nested = ".times 2 {\n push 1\n .times 2 { pop }\n}";
+        let numbered = parse_numbered(nested).unwrap();
+        let expanded = expand(&numbered).unwrap();
+        assert_eq!(expanded.iter().map(|(_, i)| i.clone()).collect::<Vec<_>>(),
+                   vec![PI(Push(Vi32(1))), PI(Pop), PI(Pop), PI(Push(Vi32(1))), PI(Pop), PI(Pop)]);
+        assert_eq!(expanded.iter().map(|(n, _)| *n).collect::<Vec<_>>(), vec![2, 3, 3, 2, 3, 3]);
+
+        // Each copy of a loop gets its own labels.
+        let pinstrs = expand_src(".times 2 { .while; push false; .do; .endwhile }").unwrap();
+        assert!(assemble(&pinstrs).is_ok());
+        assert!(pinstrs.contains(&PLabel("_Lwhile_1".into())));
+    }
+
+    #[test]
+    fn test_times_errors() {
+        assert_eq!(expand_src("pop\n.times 2 {\npop").unwrap_err(),
+                   error(2, ".times block is never closed with }"));
+        assert_eq!(expand_src("pop }").unwrap_err(), error(1, "} without a matching .times"));
+        assert_eq!(expand_src(".times 2 {\nLx:\n}").unwrap_err(),
+                   error(2, "label 'Lx' defined inside a .times block"));
+        // Too big to unroll, alone or nested.
+        assert_eq!(expand_src("pop\n.times 4294967295 {\npop\n}").unwrap_err(),
+                   error(2, ".times block (lines 2-4) unrolls to more than 1048576 statements"));
+        assert_eq!(expand_src(".times 1025 {\n.times 1024 {\npop\n}\n}\nhalt").unwrap_err(),
+                   error(1, ".times block (lines 1-5) unrolls to more than 1048576 statements"));
+        assert_eq!(expand_src(".times 1048576 { }").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_scoped_labels() {
+        // The same countdown loop in two functions, with the same names.
+        let src = "Lf:
+                   .Lloop:
+                   var 0; push 1; binary -; store 0
+                   var 0; push 0; binary <; push .Lloop; branch
+                   ret
+                   Lg:
+                   .Lloop:
+                   push .Lloop; branch
+                   _L1:
+                   push .Lloop
+                   ret";
+        let pinstrs = expand_src(src).unwrap();
+        assert!(pinstrs.contains(&PLabel("Lf.Lloop".into())));
+        assert!(pinstrs.contains(&PLabel("Lg.Lloop".into())));
+        let instrs = assemble(&pinstrs).unwrap();
+        assert_eq!(instrs[7], Push(Vloc(0)));
+        assert_eq!(instrs[10], Push(Vloc(10)));
+        // `_` labels don't open a scope.
+        assert_eq!(instrs[12], Push(Vloc(10)));
+    }
+
+    #[test]
+    fn test_scoped_label_errors() {
+        assert_eq!(expand_src("push .Lx").unwrap_err(),
+                   error(1, "scoped label '.Lx' is not after any label"));
+        assert_eq!(expand_src("Lf:\n.Lx:\nLg:\npush .Lx").unwrap_err(),
+                   error(4, "undefined scoped label 'Lg.Lx'"));
+        assert_eq!(expand_src("Lf:\n.Lx:\n.Lx:").unwrap_err(),
+                   error(3, "scoped label 'Lf.Lx' is defined twice"));
+        assert!(assemble(&PInstr::parse_line("Lf:; .Lx:; push .Lx").unwrap()).is_err());
+        assert_eq!(expand_src("Lf:\n.Lx:\nLg:\npush .Lx+1").unwrap_err(),
+                   error(4, "undefined scoped label 'Lg.Lx'"));
+    }
+
+    #[test]
+    fn test_label_offsets() {
+        // Offsets from scoped and `_` labels resolve like those from
+        // any other label.
+        let src = "Lf:\npush .Ltable+1\nbranch\n.Ltable:\npush 1\npush 2\npush _L1-2\n_L1:\nhalt";
+        let instrs = assemble(&expand_src(src).unwrap()).unwrap();
+        assert_eq!(instrs[0], Push(Vloc(3)));
+        assert_eq!(instrs[4], Push(Vloc(3)));
+    }
+
+    #[test]
+    fn test_unbalanced_while() {
+        assert_eq!(expand_src("push 1\n.while\npush true\n.do\n.while\n.do\n.endwhile").unwrap_err(),
+                   error(2, ".while loop is never closed with .endwhile"));
+        assert_eq!(expand_src(".while\npush true\n.endwhile").unwrap_err(),
+                   error(1, ".while loop has no .do"));
+        assert_eq!(expand_src("halt\n.endwhile").unwrap_err(),
+                   error(2, ".endwhile without a matching .while"));
+        assert_eq!(expand_src(".break").unwrap_err(),
+                   error(1, ".break outside of a .while loop"));
+        assert_eq!(expand_src(".while\n.break").unwrap_err(),
+                   error(2, ".break in a .while condition"));
+        assert!(assemble(&PInstr::parse_line(".while").unwrap()).is_err());
+    }
+}
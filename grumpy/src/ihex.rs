@@ -0,0 +1,242 @@
+//! Intel HEX, the text format that flashing tools read: a line per
+//! record, `:`, then in hex the record's data length, 16-bit address,
+//! type, data and a checksum that makes its bytes sum to zero.
+
+use crate::disassemble::decode_with;
+use crate::object::{ObjectError, ObjectFile};
+use crate::Endianness;
+use std::{error, fmt};
+
+/// A record of data bytes at its address.
+const DATA: u8 = 0x00;
+
+/// The record that ends the file.
+const END_OF_FILE: u8 = 0x01;
+
+/// A record giving bits 4 to 19 of the addresses of the data after it.
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+
+/// A record giving the start address of 8086 code, which is ignored.
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+
+/// A record giving the high 16 bits of the addresses of the data after it.
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+
+/// A record giving the start address of 32-bit code, which is ignored.
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Errors raised while decoding Intel HEX, at a (1-based) line of the
+/// text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IhexError {
+    /// A line that is not a record: it does not start with `:`, is not
+    /// all hex digits, or its length does not match its length byte.
+    BadRecord { line: usize },
+    /// A record whose bytes do not sum to zero.
+    BadChecksum { line: usize, expected: u8, found: u8 },
+    /// A record of a type that is not in the format.
+    UnknownType { line: usize, record_type: u8 },
+    /// A record after the end-of-file record.
+    AfterEndOfFile { line: usize },
+    /// No end-of-file record.
+    NoEndOfFile,
+    /// Data missing at (or given twice for) an address, when the data
+    /// must be one run of bytes from address 0.
+    Gap { address: u32 },
+}
+
+impl fmt::Display for IhexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IhexError::BadRecord { line } => write!(f, "line {}: not an Intel HEX record", line),
+            IhexError::BadChecksum { line, expected, found } =>
+                write!(f, "line {}: checksum is {:02X}, expected {:02X}", line, found, expected),
+            IhexError::UnknownType { line, record_type } =>
+                write!(f, "line {}: unknown record type {:02X}", line, record_type),
+            IhexError::AfterEndOfFile { line } => write!(f, "line {}: record after the end of file", line),
+            IhexError::NoEndOfFile => write!(f, "no end-of-file record"),
+            IhexError::Gap { address } => write!(f, "no single data byte at address {:#x}", address),
+        }
+    }
+}
+
+impl error::Error for IhexError {}
+
+/// Append the record of `record_type` at `address` holding `data`.
+fn record(text: &mut String, record_type: u8, address: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+    text.push(':');
+    for b in bytes {
+        text.push_str(&format!("{:02X}", b));
+    }
+    text.push('\n');
+}
+
+/// The bytes of a record, `:` and then hex digits, if it is one whose
+/// length byte matches its length. The checksum is not checked.
+fn record_bytes(text: &str) -> Option<Vec<u8>> {
+    let hex = text.strip_prefix(':')?;
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let bytes: Vec<u8> = (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect();
+    match bytes.first() {
+        Some(&len) if bytes.len() == 5 + len as usize => Some(bytes),
+        _ => None,
+    }
+}
+
+/// Encode `bytes` as Intel HEX, from address 0, in data records of up to
+/// `bytes_per_record` bytes (commonly 16 or 32; at least 1), then the
+/// end-of-file record. Past 64 KiB, an extended linear address record
+/// starts each 64 KiB block, and no record crosses into the next.
+pub fn encode(bytes: &[u8], bytes_per_record: u8) -> String {
+    let per_record = bytes_per_record.max(1) as usize;
+    let mut text = String::new();
+    let mut at = 0;
+    while at < bytes.len() {
+        if at > 0 && at % 0x1_0000 == 0 {
+            record(&mut text, EXTENDED_LINEAR_ADDRESS, 0, &((at >> 16) as u16).to_be_bytes());
+        }
+        let len = per_record.min(bytes.len() - at).min(0x1_0000 - at % 0x1_0000);
+        record(&mut text, DATA, at as u16, &bytes[at..at + len]);
+        at += len;
+    }
+    record(&mut text, END_OF_FILE, 0, &[]);
+    text
+}
+
+/// Decode Intel HEX written by `encode`, or by another tool as long as
+/// its data is one run of bytes from address 0, in any order. Blank
+/// lines and whitespace around records are ignored, as are start
+/// address records.
+pub fn decode(text: &str) -> Result<Vec<u8>, IhexError> {
+    let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+    let mut base = 0u32;
+    let mut ended = false;
+    for (n, line) in text.lines().enumerate() {
+        let (line, text) = (n + 1, line.trim());
+        if text.is_empty() {
+            continue;
+        }
+        if ended {
+            return Err(IhexError::AfterEndOfFile { line });
+        }
+        let bytes = record_bytes(text).ok_or(IhexError::BadRecord { line })?;
+        let (body, found) = bytes.split_at(bytes.len() - 1);
+        let expected = body.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg();
+        if found[0] != expected {
+            return Err(IhexError::BadChecksum { line, expected, found: found[0] });
+        }
+        let address = u16::from_be_bytes([body[1], body[2]]) as u32;
+        let data = &body[4..];
+        let word = || match *data {
+            [high, low] => Ok(u16::from_be_bytes([high, low]) as u32),
+            _ => Err(IhexError::BadRecord { line }),
+        };
+        match body[3] {
+            DATA => runs.push((base.wrapping_add(address), data.to_vec())),
+            END_OF_FILE => ended = true,
+            EXTENDED_SEGMENT_ADDRESS => base = word()? << 4,
+            EXTENDED_LINEAR_ADDRESS => base = word()? << 16,
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => (),
+            record_type => return Err(IhexError::UnknownType { line, record_type }),
+        }
+    }
+    if !ended {
+        return Err(IhexError::NoEndOfFile);
+    }
+    runs.sort_by_key(|(address, _)| *address);
+    let mut bytes = Vec::new();
+    for (address, data) in runs {
+        if address as usize != bytes.len() {
+            return Err(IhexError::Gap { address: (bytes.len() as u32).min(address) });
+        }
+        bytes.extend(data);
+    }
+    Ok(bytes)
+}
+
+/// A program as Intel HEX, for flashing: its entry pc and instructions
+/// in the headerless layout (see `ObjectFile::to_legacy_bytes`), in
+/// records of `bytes_per_record` bytes.
+pub fn encode_ihex(program: &ObjectFile, endianness: Endianness, bytes_per_record: u8) -> String {
+    encode(&program.to_legacy_bytes(endianness), bytes_per_record)
+}
+
+/// Read a program written by `encode_ihex`.
+pub fn decode_ihex(text: &str, endianness: Endianness) -> Result<ObjectFile, ObjectError> {
+    let bytes = decode(text).map_err(ObjectError::Ihex)?;
+    let (entry, instrs) = decode_with(&bytes, endianness).map_err(ObjectError::Decode)?;
+    Ok(ObjectFile { entry, instrs, ..ObjectFile::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::PInstr;
+
+    fn program() -> ObjectFile {
+        let pinstrs = PInstr::parse_line("push Lf; call; halt; Lf:; push 2; push -3; binary *; ret").unwrap();
+        let (instrs, symbols) = crate::assemble::assemble_with_symbols(&pinstrs).unwrap();
+        ObjectFile { symbols: Default::default(), ..ObjectFile::from_program(instrs, &symbols) }
+    }
+
+    #[test]
+    fn test_golden() {
+        let golden = include_str!("../tests/golden/program.hex");
+        assert_eq!(encode_ihex(&program(), Endianness::Big, 16), golden);
+        assert_eq!(decode_ihex(golden, Endianness::Big).unwrap(), program());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let program = program();
+        for e in [Endianness::Big, Endianness::Little] {
+            for per_record in [1, 16, 32, 255] {
+                let text = encode_ihex(&program, e, per_record);
+                assert!(text.lines().all(|l| l.len() <= 11 + 2 * per_record as usize), "{}", text);
+                assert_eq!(decode_ihex(&text, e).unwrap(), program);
+            }
+        }
+        assert_eq!(encode_ihex(&program, Endianness::Big, 32).lines().count(), 2);
+
+        // Past 64 KiB, with records in any order and blank lines.
+        let bytes: Vec<u8> = (0..0x1_0010u32).map(|n| n as u8).collect();
+        let text = encode(&bytes, 32);
+        assert!(text.contains("\n:020000040001F9\n:10000000"), "{}", &text[text.len() - 100..]);
+        assert_eq!(decode(&text).unwrap(), bytes);
+        let mut lines: Vec<&str> = text.lines().collect();
+        lines.swap(0, 1);
+        lines.insert(2, "");
+        assert_eq!(decode(&lines.join("\n")).unwrap(), bytes);
+        lines.remove(0);
+        assert_eq!(decode(&lines.join("\n")), Err(IhexError::Gap { address: 0x20 }));
+        assert_eq!(decode(":00000001FF\n"), Ok(vec![]));
+        assert_eq!(encode(&[], 16), ":00000001FF\n");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let golden = include_str!("../tests/golden/program.hex");
+        let bad_checksum = golden.replace("0DD6", "0DD7");
+        assert_eq!(decode(&bad_checksum), Err(IhexError::BadChecksum { line: 2, expected: 0xD6, found: 0xD7 }));
+        assert_eq!(decode_ihex(&bad_checksum, Endianness::Big).unwrap_err().to_string(),
+                   "line 2: checksum is D7, expected D6");
+        assert_eq!(decode(&golden.replace(":0B", "0B")), Err(IhexError::BadRecord { line: 2 }));
+        assert_eq!(decode(&golden.replace(":0B", ":0C")), Err(IhexError::BadRecord { line: 2 }));
+        assert_eq!(decode(&golden.replace("0DD6", "0DD")), Err(IhexError::BadRecord { line: 2 }));
+        assert_eq!(decode(&golden.replace(":00000001FF\n", "")), Err(IhexError::NoEndOfFile));
+        assert_eq!(decode(&format!("{}:00000001FF\n", golden)), Err(IhexError::AfterEndOfFile { line: 4 }));
+        assert_eq!(decode(":00000006FA\n:00000001FF\n"), Err(IhexError::UnknownType { line: 1, record_type: 6 }));
+        // The data must start at address 0.
+        assert_eq!(decode(":01000100AA54\n:00000001FF\n"), Err(IhexError::Gap { address: 0 }));
+        // Whole records that are not a program.
+        assert!(matches!(decode_ihex(":0100000000FF\n:00000001FF\n", Endianness::Big), Err(ObjectError::Decode(_))));
+    }
+}
@@ -1,13 +1,16 @@
-use self::{Binop::*, Instr::*, PInstr::*, Unop::*, Val::*};
-use crate::{ParseError, ToBytes};
-use std::fmt::{self, Display};
-use std::str::FromStr;
+use self::{Binop::*, Directive::*, Instr::*, PInstr::*, Unop::*, Val::*};
+use crate::{DecodeError, Endianness::{self, *}, FromBytes, ParseError, ParseErrorKind, Profile, ToBytes};
+use alloc::{format, string::{String, ToString}, vec, vec::Vec};
+use core::fmt::{self, Display};
+use core::convert::{TryFrom, TryInto};
+use core::str::FromStr;
 
 /// Heap addresses.
 pub type Address = usize;
 
 /// GrumpyVM values.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Val {
     // Value types that may appear in GrumpyVM programs:
     /// The unit value.
@@ -16,7 +19,7 @@ pub enum Val {
     Vi32(i32),
     /// Booleans.
     Vbool(bool),
-    /// Stack or instruction locations.
+    /// Stack or instruction locations, written `@n`.
     Vloc(u32),
     /// The undefined value.
     Vundef,
@@ -25,12 +28,33 @@ pub enum Val {
     // implementation, and may not appear in GrumpyVM programs:
     /// Metadata for heap objects that span multiple values.
     Vsize(i32),
-    /// Pointers to heap locations.
-    Vaddr(Address),
+    /// Pointers to heap locations. Serialized as a u64, whatever the
+    /// width of `usize`.
+    Vaddr(#[cfg_attr(feature = "serde", serde(with = "address_u64"))] Address),
+}
+
+/// Serialize addresses as u64, so that data written on a 64-bit host
+/// reads back on a 32-bit one when the addresses fit.
+#[cfg(feature = "serde")]
+mod address_u64 {
+    use super::Address;
+    use alloc::format;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use core::convert::TryFrom;
+
+    pub fn serialize<S: Serializer>(address: &Address, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(*address as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+        let n = u64::deserialize(deserializer)?;
+        Address::try_from(n).map_err(|_| D::Error::custom(format!("address {} does not fit in usize", n)))
+    }
 }
 
 /// GrumpyVM native instructions.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instr {
     /// Push(v): Push value v onto the stack.
     Push(Val),
@@ -65,8 +89,159 @@ pub enum Instr {
     Branch,
     /// Halt the machine.
     Halt,
+    /// A raw data byte, written to the output as-is with no opcode.
+    /// Occupies one pc slot like any instruction, but is not meant to
+    /// be executed. Produced by the `.byte` and `.i32` directives.
+    Byte(u8),
+}
+
+/// The first byte of each encoded instruction (but for `Byte`, which
+/// has none). The values are part of the binary format, and must never
+/// change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    Push = 0x00,
+    Pop = 0x01,
+    Peek = 0x02,
+    Unary = 0x03,
+    Binary = 0x04,
+    Swap = 0x05,
+    Alloc = 0x06,
+    Set = 0x07,
+    Get = 0x08,
+    Var = 0x09,
+    Store = 0x0A,
+    SetFrame = 0x0B,
+    Call = 0x0C,
+    Ret = 0x0D,
+    Branch = 0x0E,
+    Halt = 0x0F,
+}
+
+impl Opcode {
+    /// Every opcode, in numeric order.
+    pub const ALL: [Opcode; 16] = [
+        Opcode::Push, Opcode::Pop, Opcode::Peek, Opcode::Unary, Opcode::Binary, Opcode::Swap,
+        Opcode::Alloc, Opcode::Set, Opcode::Get, Opcode::Var, Opcode::Store, Opcode::SetFrame,
+        Opcode::Call, Opcode::Ret, Opcode::Branch, Opcode::Halt,
+    ];
+
+    /// The opcode encoded as `byte`, if any.
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        INSTRS.get(byte as usize).map(|spec| spec.opcode)
+    }
+
+    /// How the instructions with this opcode are written and encoded.
+    pub fn spec(self) -> &'static InstrSpec {
+        &INSTRS[self as usize]
+    }
+}
+
+/// The mnemonics in `INSTRS`, in the same order.
+const MNEMONICS: [&str; INSTRS.len()] = {
+    let mut mnemonics = [""; INSTRS.len()];
+    let mut i = 0;
+    while i < INSTRS.len() {
+        mnemonics[i] = INSTRS[i].mnemonic;
+        i += 1;
+    }
+    mnemonics
+};
+
+/// The kinds of operand an instruction takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKind {
+    None,
+    /// A value, for `push`.
+    Value,
+    /// A u32, such as a stack offset.
+    U32,
+    Unop,
+    Binop,
+}
+
+/// What an instruction works on, for analyses that treat alike the
+/// instructions of a kind (see `Instr::category`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InstrCategory {
+    /// Moves values on the stack or in the current frame: `push`,
+    /// `pop`, `peek`, `swap`, `var`, `store` and `setframe`.
+    Stack,
+    /// `unary` and `binary`.
+    Arithmetic,
+    /// Allocates, reads or writes heap arrays: `alloc`, `set`, `get`.
+    Memory,
+    /// Changes the pc other than by one: `call`, `ret`, `branch` and
+    /// `halt`.
+    ControlFlow,
+    /// A data byte, which is not an instruction at all.
+    Misc,
+}
+
+/// An instruction's operand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    None,
+    Value(Val),
+    U32(u32),
+    Unop(Unop),
+    Binop(Binop),
+}
+
+impl Operand {
+    pub fn kind(&self) -> OperandKind {
+        match self {
+            Operand::None => OperandKind::None,
+            Operand::Value(_) => OperandKind::Value,
+            Operand::U32(_) => OperandKind::U32,
+            Operand::Unop(_) => OperandKind::Unop,
+            Operand::Binop(_) => OperandKind::Binop,
+        }
+    }
+}
+
+/// How an instruction is written (its mnemonic, then its operand, if
+/// any) and encoded (its opcode, then its operand).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstrSpec {
+    pub mnemonic: &'static str,
+    pub opcode: Opcode,
+    pub operand: OperandKind,
 }
 
+/// Every instruction, indexed by opcode. The parser, `Display` and the
+/// encoder and decoder all work from this table, and `Instr::parts` and
+/// `Instr::from_parts` tie it to the variants of `Instr`.
+pub const INSTRS: [InstrSpec; 16] = [
+    InstrSpec { mnemonic: "push", opcode: Opcode::Push, operand: OperandKind::Value },
+    InstrSpec { mnemonic: "pop", opcode: Opcode::Pop, operand: OperandKind::None },
+    InstrSpec { mnemonic: "peek", opcode: Opcode::Peek, operand: OperandKind::U32 },
+    InstrSpec { mnemonic: "unary", opcode: Opcode::Unary, operand: OperandKind::Unop },
+    InstrSpec { mnemonic: "binary", opcode: Opcode::Binary, operand: OperandKind::Binop },
+    InstrSpec { mnemonic: "swap", opcode: Opcode::Swap, operand: OperandKind::None },
+    InstrSpec { mnemonic: "alloc", opcode: Opcode::Alloc, operand: OperandKind::None },
+    InstrSpec { mnemonic: "set", opcode: Opcode::Set, operand: OperandKind::None },
+    InstrSpec { mnemonic: "get", opcode: Opcode::Get, operand: OperandKind::None },
+    InstrSpec { mnemonic: "var", opcode: Opcode::Var, operand: OperandKind::U32 },
+    InstrSpec { mnemonic: "store", opcode: Opcode::Store, operand: OperandKind::U32 },
+    InstrSpec { mnemonic: "setframe", opcode: Opcode::SetFrame, operand: OperandKind::U32 },
+    InstrSpec { mnemonic: "call", opcode: Opcode::Call, operand: OperandKind::None },
+    InstrSpec { mnemonic: "ret", opcode: Opcode::Ret, operand: OperandKind::None },
+    InstrSpec { mnemonic: "branch", opcode: Opcode::Branch, operand: OperandKind::None },
+    InstrSpec { mnemonic: "halt", opcode: Opcode::Halt, operand: OperandKind::None },
+];
+
+/// The tag byte of each kind of value, after `Opcode::Push`. Like
+/// opcodes, these must never change.
+pub const TAG_UNIT: u8 = 0x00;
+pub const TAG_I32: u8 = 0x01;
+pub const TAG_TRUE: u8 = 0x02;
+pub const TAG_FALSE: u8 = 0x03;
+pub const TAG_LOC: u8 = 0x04;
+pub const TAG_UNDEF: u8 = 0x05;
+pub const TAG_SIZE: u8 = 0x06;
+pub const TAG_ADDR: u8 = 0x07;
+
 /// Program labels.
 pub type Label = String;
 
@@ -74,37 +249,266 @@ pub type Label = String;
 /// for labels. GrumpyVM cannot execute these directly -- they must
 /// first be translated by the assembler to native instructions.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PInstr {
     /// Label the next instruction.
     PLabel(Label),
     /// Push a label onto the stack.
     PPush(Label),
+    /// Push a label's location plus an offset (`push Lfoo+4`), e.g. to
+    /// index into a jump table. The label may be scoped (`push .Lx+4`),
+    /// until `expand` qualifies it as it does a `PRel`.
+    PPushOffset(Label, i32),
+    /// Push a scoped label (`push .Lname`), one defined as `.Lname:`
+    /// after the same top-level label as this push. The same name may
+    /// be defined once per scope, so a snippet using scoped labels can
+    /// be pasted into several functions unchanged. `expand::expand`
+    /// turns it into a `PPush`, which names one label program-wide.
+    PRel(Label),
+    /// Push the value of a constant defined with `.equ`.
+    PPushConst(String),
+    /// Export a label so that other modules may reference it when
+    /// linked (see `link::link`). Emits no instruction.
+    PGlobal(Label),
+    /// Declare a label private to its module. Labels are local unless
+    /// declared `.global`, so this only documents intent.
+    PLocal(Label),
+    /// A directive that must be expanded (see `expand::expand`) before
+    /// the program can be assembled.
+    PDirective(Directive),
     /// Native machine instruction.
     PI(Instr),
 }
 
+/// Assembler directives that `expand::expand` lowers into ordinary
+/// pseudo-instructions.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Directive {
+    /// Start a loop. The code up to the matching `.do` is the loop
+    /// condition, and must leave a bool on the stack.
+    While,
+    /// End a loop's condition and start its body.
+    Do,
+    /// End a loop, jumping back to re-test its condition.
+    EndWhile,
+    /// Jump to the end of the innermost enclosing loop.
+    Break,
+    /// `.string "text"`: push the address of a routine that returns the
+    /// text as a new heap array of character codes. Identical strings
+    /// share one routine.
+    Str(String),
+    /// `.datastring "text"`: push the data array and the index of the
+    /// text in it (its length, then its character codes), which only an
+    /// object file carries (see `expand::expand_with_data`). Identical
+    /// strings share one copy.
+    DataStr(String),
+    /// `.byte 0x01 2 ...`: emit each byte as data (see `Instr::Byte`).
+    Bytes(Vec<u8>),
+    /// `.i32 n`: emit the four bytes of `n` as data, in the output's
+    /// byte order.
+    I32(i32),
+    /// `.align n`: pad with zero data bytes until the offset in the
+    /// object file (counting its 4-byte header) is a multiple of `n`.
+    Align(u32),
+    /// `.include "path"`: the lines of another file, read in place of
+    /// this one by `expand::parse_file`.
+    Include(String),
+    /// `.times n {`: repeat everything up to the matching `}` n times.
+    Times(u32),
+    /// `}`: close a `.times` block.
+    EndTimes,
+    /// `.equ NAME value`: define a constant, which `push NAME` pushes.
+    /// Unlike a label, which stands for a location in the program, a
+    /// constant is replaced by its value as written.
+    Equ(String, Val),
+    /// `.jumptable L0 L1 ...`: push each label in turn, a block of one
+    /// `push` per label, so that entry `i` of a table at `T` is the
+    /// instruction at `T + i`. Every label must be defined.
+    JumpTable(Vec<Label>),
+}
+
 /// Unary operators.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Unop {
     /// Boolean negation.
-    Neg,
+    Neg = 0x00,
 }
 
 /// Binary operators.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Binop {
     /// i32 addition.
-    Add,
+    Add = 0x00,
     /// i32 multiplication.
-    Mul,
+    Mul = 0x01,
     /// i32 subtraction.
-    Sub,
+    Sub = 0x02,
     /// i32 division (raises an error on divide by zero).
-    Div,
+    Div = 0x03,
     /// Returns true if one i32 is less than another, otherwise false.
-    Lt,
+    Lt = 0x04,
     /// Returns true if one i32 is equal another, otherwise false.
-    Eq,
+    Eq = 0x05,
+}
+
+// An operator's discriminant is its byte in the binary format, after
+// `Opcode::Unary` or `Opcode::Binary`.
+impl Unop {
+    /// Every unary operator, in numeric order.
+    pub const ALL: [Unop; 1] = [Neg];
+
+    /// The operator encoded as `byte`, if any.
+    pub fn from_byte(byte: u8) -> Option<Unop> {
+        Unop::ALL.iter().copied().find(|u| *u as u8 == byte)
+    }
+}
+
+impl Binop {
+    /// Every binary operator, in numeric order.
+    pub const ALL: [Binop; 6] = [Add, Mul, Sub, Div, Lt, Eq];
+
+    /// The operator encoded as `byte`, if any.
+    pub fn from_byte(byte: u8) -> Option<Binop> {
+        Binop::ALL.iter().copied().find(|b| *b as u8 == byte)
+    }
+}
+
+impl Val {
+    /// Whether this is one of the values used internally by the VM
+    /// (`Vsize`, `Vaddr`), which may not appear in GrumpyVM programs.
+    ///
+    /// The parser never produces these and `assemble` rejects programs
+    /// that push them. They still have an encoding of their own (tags
+    /// 0x06 and 0x07), so VM state holding them can be serialized.
+    /// Making this a type-level guarantee would mean giving `Instr::Push`
+    /// a separate program-value type; that is the cleaner long-term
+    /// design, but it changes `Instr` for every user of the crate.
+    pub fn is_internal(&self) -> bool {
+        matches!(self, Vsize(_) | Vaddr(_))
+    }
+}
+
+impl Instr {
+    /// The opcode and operand of the instruction, or `None` for `Byte`,
+    /// which has neither.
+    pub fn parts(&self) -> Option<(Opcode, Operand)> {
+        let parts = match *self {
+            Push(v) => (Opcode::Push, Operand::Value(v)),
+            Pop => (Opcode::Pop, Operand::None),
+            Peek(n) => (Opcode::Peek, Operand::U32(n)),
+            Unary(u) => (Opcode::Unary, Operand::Unop(u)),
+            Binary(b) => (Opcode::Binary, Operand::Binop(b)),
+            Swap => (Opcode::Swap, Operand::None),
+            Alloc => (Opcode::Alloc, Operand::None),
+            Set => (Opcode::Set, Operand::None),
+            Get => (Opcode::Get, Operand::None),
+            Var(n) => (Opcode::Var, Operand::U32(n)),
+            Store(n) => (Opcode::Store, Operand::U32(n)),
+            SetFrame(n) => (Opcode::SetFrame, Operand::U32(n)),
+            Call => (Opcode::Call, Operand::None),
+            Ret => (Opcode::Ret, Operand::None),
+            Branch => (Opcode::Branch, Operand::None),
+            Halt => (Opcode::Halt, Operand::None),
+            Byte(_) => return None,
+        };
+        Some(parts)
+    }
+
+    /// The instruction with the given opcode and operand, or `None` if
+    /// the opcode takes another kind of operand.
+    pub fn from_parts(opcode: Opcode, operand: Operand) -> Option<Instr> {
+        let i = match (opcode, operand) {
+            (Opcode::Push, Operand::Value(v)) => Push(v),
+            (Opcode::Pop, Operand::None) => Pop,
+            (Opcode::Peek, Operand::U32(n)) => Peek(n),
+            (Opcode::Unary, Operand::Unop(u)) => Unary(u),
+            (Opcode::Binary, Operand::Binop(b)) => Binary(b),
+            (Opcode::Swap, Operand::None) => Swap,
+            (Opcode::Alloc, Operand::None) => Alloc,
+            (Opcode::Set, Operand::None) => Set,
+            (Opcode::Get, Operand::None) => Get,
+            (Opcode::Var, Operand::U32(n)) => Var(n),
+            (Opcode::Store, Operand::U32(n)) => Store(n),
+            (Opcode::SetFrame, Operand::U32(n)) => SetFrame(n),
+            (Opcode::Call, Operand::None) => Call,
+            (Opcode::Ret, Operand::None) => Ret,
+            (Opcode::Branch, Operand::None) => Branch,
+            (Opcode::Halt, Operand::None) => Halt,
+            _ => return None,
+        };
+        Some(i)
+    }
+
+    /// The name the instruction is written with (`"push"`, ...), or
+    /// `".byte"` for a data byte.
+    pub fn mnemonic(&self) -> &'static str {
+        self.spec().map_or(".byte", |spec| spec.mnemonic)
+    }
+
+    /// Every instruction's mnemonic, in opcode order, e.g. for
+    /// completing or correcting what a user typed.
+    pub fn all_mnemonics() -> &'static [&'static str] {
+        &MNEMONICS
+    }
+
+    /// How the instruction is written and encoded; `None` for `Byte`.
+    pub fn spec(&self) -> Option<&'static InstrSpec> {
+        self.parts().map(|(opcode, _)| opcode.spec())
+    }
+
+    /// The integer operand of `peek`, `var`, `store` and `setframe`.
+    pub fn operand(&self) -> Option<u32> {
+        match self {
+            Peek(n) | Var(n) | Store(n) | SetFrame(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// The value a `push` pushes.
+    pub fn value(&self) -> Option<Val> {
+        match self {
+            Push(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// What kind of instruction this is.
+    pub fn category(&self) -> InstrCategory {
+        match self {
+            Push(_) | Pop | Peek(_) | Swap | Var(_) | Store(_) | SetFrame(_) => InstrCategory::Stack,
+            Unary(_) | Binary(_) => InstrCategory::Arithmetic,
+            Alloc | Set | Get => InstrCategory::Memory,
+            Call | Ret | Branch | Halt => InstrCategory::ControlFlow,
+            Byte(_) => InstrCategory::Misc,
+        }
+    }
+
+    /// How many values the instruction pops off the stack, and how many
+    /// it then pushes, for analyses that track the depth of the stack.
+    /// `peek` and `var` read without popping, and `swap` pops and
+    /// pushes two. `alloc` pops a size and an initial value and pushes
+    /// the array's address; `set` pops an array, an index and a value,
+    /// and `get` an array and an index. `setframe` pushes the old frame
+    /// pointer; `call` pops its target and pushes the return address.
+    /// `ret` is modelled as popping the return value, the return address
+    /// and the saved frame pointer and pushing the return value back for
+    /// the caller, though it also drops whatever else is in its frame.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Push(_) | Peek(_) | Var(_) | SetFrame(_) => (0, 1),
+            Pop | Store(_) => (1, 0),
+            Unary(_) | Call => (1, 1),
+            Binary(_) | Alloc | Get => (2, 1),
+            Swap => (2, 2),
+            Set => (3, 0),
+            Branch => (2, 0),
+            Ret => (3, 1),
+            Halt | Byte(_) => (0, 0),
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////
@@ -138,32 +542,69 @@ impl Display for Val {
             Vunit    => write!(f, "tt"),
             Vi32(i)  => write!(f, "{}", i),
             Vbool(b) => write!(f, "{}", b),
-            Vloc(u)  => write!(f, "{}", u),
+            Vloc(u)  => write!(f, "@{}", u),
             Vundef   => write!(f, "undef"),
-            _ => Err(fmt::Error)
+            Vsize(i) => write!(f, "size({})", i),
+            Vaddr(a) => write!(f, "addr({:#x})", a),
         }
     }
 }
 
 impl Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self, self.parts()) {
+            (Byte(b), _) => write!(f, ".byte {:#04x}", b),
+            (_, Some((opcode, Operand::None))) => write!(f, "{}", opcode.spec().mnemonic),
+            (_, Some((opcode, operand))) => write!(f, "{} {}", opcode.spec().mnemonic, operand),
+            (_, None) => unreachable!("only Byte has no opcode"),
+        }
+    }
+}
+
+impl Display for Operand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Push(v)     => write!(f, "push {}", v),
-            Pop         => write!(f, "pop"),
-            Peek(u)     => write!(f, "peek {}", u),
-            Unary(u)    => write!(f, "unary {}", u),
-            Binary(b)   => write!(f, "binary {}", b),
-            Swap        => write!(f, "swap"),
-            Alloc       => write!(f, "alloc"),
-            Set         => write!(f, "set"),
-            Get         => write!(f, "get"),
-            Var(u)      => write!(f, "var {}", u),
-            Store(u)    => write!(f, "store {}", u),
-            SetFrame(u) => write!(f, "setframe {}", u),
-            Call        => write!(f, "call"),
-            Ret         => write!(f, "ret"),
-            Branch      => write!(f, "branch"),
-            Halt        => write!(f, "halt"),
+            Operand::None => Ok(()),
+            Operand::Value(v) => write!(f, "{}", v),
+            Operand::U32(n) => write!(f, "{}", n),
+            Operand::Unop(u) => write!(f, "{}", u),
+            Operand::Binop(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+impl Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            While    => write!(f, ".while"),
+            Do       => write!(f, ".do"),
+            EndWhile => write!(f, ".endwhile"),
+            Break    => write!(f, ".break"),
+            Str(s)   => {
+                write!(f, ".string ")?;
+                write_string_literal(f, s)
+            }
+            DataStr(s) => {
+                write!(f, ".datastring ")?;
+                write_string_literal(f, s)
+            }
+            Bytes(bs) => {
+                write!(f, ".byte")?;
+                bs.iter().try_for_each(|b| write!(f, " {:#04x}", b))
+            }
+            I32(i)   => write!(f, ".i32 {}", i),
+            Align(n) => write!(f, ".align {}", n),
+            Include(path) => {
+                write!(f, ".include ")?;
+                write_string_literal(f, path)
+            }
+            Times(n) => write!(f, ".times {} {{", n),
+            EndTimes => write!(f, "}}"),
+            Equ(name, v) => write!(f, ".equ {} {}", name, v),
+            JumpTable(labels) => {
+                write!(f, ".jumptable")?;
+                labels.iter().try_for_each(|l| write!(f, " {}", l))
+            }
         }
     }
 }
@@ -173,6 +614,12 @@ impl Display for PInstr {
         match self {
             PLabel(lbl) => write!(f, "{}:", lbl),
             PPush(lbl)  => write!(f, "push {}", lbl),
+            PPushOffset(lbl, n) => write!(f, "push {}{:+}", lbl, n),
+            PRel(lbl)   => write!(f, "push .{}", lbl),
+            PPushConst(name) => write!(f, "push {}", name),
+            PGlobal(lbl) => write!(f, ".global {}", lbl),
+            PLocal(lbl) => write!(f, ".local {}", lbl),
+            PDirective(d) => write!(f, "{}", d),
             PI(instr)   => write!(f, "{}", instr)
         }
     }
@@ -188,7 +635,7 @@ impl FromStr for Unop {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "neg" => Ok(Neg),
-            _ => Err(ParseError("Unop Parse Error".to_string()))
+            _ => Err(ParseError::new(ParseErrorKind::UnaryOperator, s))
         }
     }
 }
@@ -204,7 +651,7 @@ impl FromStr for Binop {
             "/" => Ok(Div),
             "<" => Ok(Lt),
             "==" => Ok(Eq),
-            _ => Err(ParseError("Binop Parse Error".to_string()))
+            _ => Err(ParseError::new(ParseErrorKind::BinaryOperator, s))
         }
     }
 }
@@ -218,11 +665,16 @@ impl FromStr for Val {
             "undef" => Ok(Vundef),
             "true" => Ok(Vbool(true)),
             "false" => Ok(Vbool(false)),
+            _ if s.starts_with('@') => s[1..].parse::<u32>()
+                .map(Vloc)
+                .map_err(|_| ParseError::new(ParseErrorKind::Value, s)),
+            // Numbers too large for an i32 were read as locations before
+            // `@` existed, and still are.
             _ => match s.parse::<i32>() {
-                Ok(T) => Ok(Vi32(T)),
-                Err(T) => match s.parse::<u32>(){
-                    Ok(E) => Ok(Vloc(E)),
-                    Err(E) => Err(ParseError("Val Parse Error".to_string()))
+                Ok(i) => Ok(Vi32(i)),
+                Err(_) => match s.parse::<u32>(){
+                    Ok(u) => Ok(Vloc(u)),
+                    Err(_) => Err(ParseError::new(ParseErrorKind::Value, s))
                 }
             }
 
@@ -234,45 +686,154 @@ impl FromStr for Instr {
     type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split = s.split_whitespace();
-        let split : Vec<&str> = split.collect();
-        match split[0] {
-            "push" => Ok(Push(Val::from_str(split[1])?)),
-            "pop" => Ok(Pop),
-            "peek" => Ok(Peek(split[1].parse::<u32>()?)),
-            "unary" => Ok(Unary(Unop::from_str(split[1]).unwrap())),
-            "binary" => Ok(Binary(Binop::from_str(split[1]).unwrap())),
-            "swap" => Ok(Swap),
-            "alloc" => Ok(Alloc),
-            "set" => Ok(Set),
-            "get" => Ok(Get),
-            "var" => Ok(Var(split[1].parse::<u32>()?)),
-            "store" => Ok(Store(split[1].parse::<u32>()?)),
-            "setframe" => Ok(SetFrame(split[1].parse::<u32>()?)),
-            "call" => Ok(Call),
-            "ret" => Ok(Ret),
-            "branch" => Ok(Branch),
-            "halt" => Ok(Halt),
-            _ => Err(ParseError("Instr Parse Error".to_string()))
+        let tokens = tokenize(s);
+        let token = |i| token(s, &tokens, i);
+        let unknown = || ParseError::new(ParseErrorKind::Instruction, s.trim()).at(token(0).0)
+            .suggest(closest_mnemonic(token(0).1));
+        let spec = INSTRS.iter().find(|spec| spec.mnemonic == token(0).1).ok_or_else(unknown)?;
+        if spec.operand != OperandKind::None && tokens.len() < 2 {
+            return Err(arity(spec.mnemonic, 1, 0).at(token(1).0));
+        }
+        let operand = match spec.operand {
+            OperandKind::None => Operand::None,
+            OperandKind::Value => Operand::Value(parse_token(token(1), Val::from_str)?),
+            OperandKind::U32 => Operand::U32(parse_token(token(1), parse_u32)?),
+            OperandKind::Unop => Operand::Unop(parse_token(token(1), Unop::from_str)?),
+            OperandKind::Binop => Operand::Binop(parse_token(token(1), Binop::from_str)?),
+        };
+        let i = Instr::from_parts(spec.opcode, operand).ok_or_else(unknown)?;
+        let expected = if spec.operand == OperandKind::None { 0 } else { 1 };
+        match tokens.len() - 1 {
+            found if found == expected => Ok(i),
+            found => Err(arity(i.mnemonic(), expected, found).at(token(expected + 1).0)),
         }
     }
 }
 
-fn parse_label(s: &str) -> Result<Label, ParseError> {
+impl FromStr for Directive {
     type Err = ParseError;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            ".while" => Ok(While),
+            ".do" => Ok(Do),
+            ".endwhile" => Ok(EndWhile),
+            ".break" => Ok(Break),
+            _ => Err(ParseError::new(ParseErrorKind::Directive, s))
+        }
+    }
+}
+
+/// Split a statement into its whitespace-separated tokens, each with
+/// the byte offset it starts at. A double-quoted string literal is a
+/// single token, spaces and all.
+pub fn tokenize(s: &str) -> Vec<(usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c.is_whitespace() {
+            if let Some(start) = start.take() {
+                tokens.push((start, &s[start..i]));
+            }
+            continue;
+        } else if c == '"' {
+            in_string = true;
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        tokens.push((start, &s[start..]));
+    }
+    tokens
+}
+
+/// The `i`th of the `tokens` of statement `s`, or, if it has fewer, an
+/// empty token at its end, which no operand parses as, so that a
+/// missing operand is an error rather than a panic.
+fn token<'a>(s: &str, tokens: &[(usize, &'a str)], i: usize) -> (usize, &'a str) {
+    tokens.get(i).copied().unwrap_or((s.trim_end().len(), ""))
+}
+
+/// The mnemonic within two edits of `word`, the first of the closest
+/// if several are, to suggest for a typo.
+fn closest_mnemonic(word: &str) -> Option<&'static str> {
+    MNEMONICS.iter()
+        .map(|m| (edit_distance(word, m), *m))
+        .filter(|(d, _)| *d <= 2)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, m)| m)
+}
+
+/// The Levenshtein distance between `a` and `b`, in chars: the fewest
+/// insertions, deletions and substitutions turning one into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // The distances from the prefix of `a` so far to each prefix of `b`.
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + if ca == *cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// An error for an instruction given `found` operands where it takes
+/// `expected`.
+fn arity(mnemonic: &str, expected: usize, found: usize) -> ParseError {
+    ParseError::new(ParseErrorKind::Arity { expected, found }, mnemonic)
+}
+
+/// Parse a token with `parse`, locating any error at the token.
+fn parse_token<T>((column, token): (usize, &str), parse: impl FnOnce(&str) -> Result<T, ParseError>)
+                  -> Result<T, ParseError> {
+    parse(token).map_err(|e| e.at(column))
+}
+
+/// Parse an integer operand.
+fn parse_int<T: FromStr>(s: &str) -> Result<T, ParseError> {
+    s.parse::<T>().map_err(|_| ParseError::new(ParseErrorKind::Integer, s))
+}
+
+/// Parse a u32 operand, such as a stack offset or a count. An integer
+/// out of its range is reported as such, not as something that is not
+/// an integer at all.
+fn parse_u32(s: &str) -> Result<u32, ParseError> {
+    parse_int::<u32>(s).map_err(|e| {
+        let digits = s.strip_prefix('-').unwrap_or(s);
+        if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+            ParseError::new(ParseErrorKind::Unsigned { max: u32::MAX.into() }, s)
+        } else {
+            e
+        }
+    })
+}
+
+fn parse_label(s: &str) -> Result<Label, ParseError> {
     let split = s.split_whitespace();
     let split : Vec<&str> = split.collect();
 
     if split.len() != 1 {
-        return Err(ParseError("Parse Label Error, Too little or too many inputs".to_string()))
+        return Err(ParseError::new(ParseErrorKind::Label, s.trim()))
     }
 
-    for (i, letter) in s.chars().enumerate(){
-        if i == 0 && letter != 'L'{
-            if letter != '_' || s.chars().nth(1).unwrap() != 'L'{
-                return Err(ParseError("ParseLabel Error".to_string()));
-            }
+    for (i, letter) in s.char_indices(){
+        if i == 0 && letter != 'L' && (letter != '_' || !s[1..].starts_with('L')){
+            return Err(ParseError::new(ParseErrorKind::Label, s));
         }
         if i == s.len()-1 && letter == ':'{
             let mut tmp = s.chars();
@@ -280,30 +841,327 @@ fn parse_label(s: &str) -> Result<Label, ParseError> {
             return Ok(tmp.as_str().to_string());
         }
         if i != 0 && !letter.is_ascii_alphanumeric(){
-            return Err(ParseError("ParseLabel Error".to_string()));
+            return Err(ParseError::new(ParseErrorKind::Label, s));
         }
     }
-    return Ok(s.to_string());
+    Ok(s.to_string())
+}
+
+/// Whether `s` can be written as a label in source, as `parse_label`
+/// reads it: not scoped (`Lf.Lx`) or generated (`_Lwhile_0`) names.
+pub fn is_label_name(s: &str) -> bool {
+    parse_label(s).as_deref() == Ok(s)
+}
+
+/// Parse the name of a constant: a letter or `_`, then letters, digits
+/// and `_`s. Names that could be labels are not allowed, nor are names
+/// that are values (`true`, `tt`, ...).
+pub(crate) fn parse_constant(s: &str) -> Result<String, ParseError> {
+    let mut chars = s.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && parse_label(s).is_err()
+        && Val::from_str(s).is_err();
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(ParseError::new(ParseErrorKind::Constant, s))
+    }
+}
+
+/// Parse a data byte, written in decimal or as `0x` hex.
+fn parse_byte(s: &str) -> Result<u8, ParseError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16)
+            .map_err(|_| ParseError::new(ParseErrorKind::Integer, s)),
+        None => parse_int::<u8>(s),
+    }
 }
 
 impl FromStr for PInstr {
     type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split = s.split_whitespace();
-        let split : Vec<&str> = split.collect();
+        if s.trim() == "}" {
+            return Ok(PDirective(EndTimes));
+        }
+        let tokens = tokenize(s);
+        // The rest of the statement from the i'th token, with its column.
+        let rest = |i: usize| match tokens.get(i) {
+            Some(&(column, _)) => (column, s[column..].trim_end()),
+            None => (s.trim_end().len(), ""),
+        };
+        let token = |i| token(s, &tokens, i);
+        match token(0).1 {
+            ".string" => return Ok(PDirective(Str(parse_token(rest(1), parse_string_literal)?))),
+            ".datastring" => return Ok(PDirective(DataStr(parse_token(rest(1), parse_string_literal)?))),
+            ".include" => return Ok(PDirective(Include(parse_token(rest(1), parse_string_literal)?))),
+            ".times" if s.trim_end().ends_with('{') => {
+                let (column, n) = rest(1);
+                let n = n.strip_suffix('{').unwrap_or(n).trim_end();
+                return Ok(PDirective(Times(parse_token((column, n), parse_u32)?)));
+            }
+            _ => (),
+        }
+        let split: Vec<&str> = tokens.iter().map(|&(_, token)| token).collect();
 
-        match split[0] {
-            "push" => match parse_label(split[1]){
-                Ok(Label) => Ok(PPush(parse_label(split[1])?)),
-                Err(e) => Ok(PI(Instr::from_str(s)?))
+        match token(0).1 {
+            ".global" if split.len() == 2 => Ok(PGlobal(parse_token(tokens[1], parse_label)?)),
+            ".local" if split.len() == 2 => Ok(PLocal(parse_token(tokens[1], parse_label)?)),
+            ".byte" if split.len() > 1 => Ok(PDirective(Bytes(
+                tokens[1..].iter().map(|&b| parse_token(b, parse_byte)).collect::<Result<_, _>>()?
+            ))),
+            ".jumptable" if split.len() > 1 => Ok(PDirective(JumpTable(
+                tokens[1..].iter().map(|&l| parse_token(l, parse_label)).collect::<Result<_, _>>()?
+            ))),
+            ".i32" if split.len() == 2 => Ok(PDirective(I32(parse_token(tokens[1], parse_int::<i32>)?))),
+            ".align" if split.len() == 2 => Ok(PDirective(Align(parse_token(tokens[1], parse_u32)?))),
+            ".equ" if split.len() == 3 => Ok(PDirective(Equ(
+                parse_token(tokens[1], parse_constant)?,
+                parse_token(tokens[2], Val::from_str)?,
+            ))),
+            l if l.starts_with(".L") && l.ends_with(':') && split.len() == 1 =>
+                Ok(PLabel(format!(".{}", parse_token((tokens[0].0 + 1, &l[1..]), parse_label)?))),
+            d if d.starts_with('.') && split.len() == 1 => Ok(PDirective(parse_token(tokens[0], Directive::from_str)?)),
+            "push" => match parse_label(token(1).1){
+                Ok(label) => Ok(PPush(label)),
+                Err(_) if split.len() == 2 && split[1].starts_with(".L") && split[1].contains(['+', '-']) =>
+                    Ok(parse_push_offset(tokens[1])?),
+                Err(_) if split.len() == 2 && split[1].starts_with('.') =>
+                    Ok(PRel(parse_token((tokens[1].0 + 1, &split[1][1..]), parse_label)?)),
+                Err(_) if split.len() == 2 && parse_constant(split[1]).is_ok() =>
+                    Ok(PPushConst(split[1].to_string())),
+                Err(_) if split.len() == 2 && (split[1].starts_with('L') || split[1].starts_with("_L")) =>
+                    Ok(parse_push_offset(tokens[1])?),
+                Err(_) => Ok(PI(Instr::from_str(s)?))
             },
-            _ => match parse_label(split[0]){
-                Ok(Label) => Ok(PLabel(parse_label(split[0])?)),
-                Err(e) => Ok(PI(Instr::from_str(s)?))
+            _ => match parse_label(token(0).1){
+                Ok(label) => Ok(PLabel(label)),
+                Err(_) => Ok(PI(Instr::from_str(s)?))
+            }
+        }
+    }
+}
+
+/// Parse the operand of `push Lfoo+4`, at `column`: any label `push`
+/// takes, or a scoped one (`.Lx`), then a signed offset.
+fn parse_push_offset((column, operand): (usize, &str)) -> Result<PInstr, ParseError> {
+    let at = operand.find(['+', '-'])
+        .ok_or_else(|| ParseError::new(ParseErrorKind::Label, operand).at(column))?;
+    let (label, offset) = operand.split_at(at);
+    let label = match label.strip_prefix('.') {
+        Some(name) => format!(".{}", parse_token((column + 1, name), parse_label)?),
+        None => parse_token((column, label), parse_label)?,
+    };
+    Ok(PPushOffset(label, parse_token((column + at, offset), parse_int::<i32>)?))
+}
+
+impl PInstr {
+    /// Parse one line of assembly into zero or more pseudo-instructions.
+    /// Instructions on the same line are separated by `;`, and anything
+    /// after `//` is a comment. Blank lines parse to an empty vector.
+    /// A label may start a statement, as in `Lloop: push 1`, and names
+    /// the instruction after it.
+    ///
+    /// Errors give the column of the offending token in `line`.
+    pub fn parse_line(line: &str) -> Result<Vec<PInstr>, ParseError> {
+        statements(line).map(|s| parse_statement(line, s)).collect()
+    }
+
+    /// Like `parse_line`, but carry on past statements that do not
+    /// parse, returning those that do together with an error for each
+    /// that does not. A bad statement that is neither a directive nor a
+    /// label is replaced by a `halt`, so that the labels after it keep
+    /// the pcs they would have had.
+    ///
+    /// The result is only good for finding more errors in the rest of
+    /// the program (see `expand::parse_file_max_errors`); a program
+    /// with any errors must never be assembled or written out.
+    pub fn parse_line_recovering(line: &str) -> (Vec<PInstr>, Vec<ParseError>) {
+        let mut pinstrs = Vec::new();
+        let mut errors = Vec::new();
+        for s in statements(line) {
+            match parse_statement(line, s) {
+                Ok(i) => pinstrs.push(i),
+                Err(e) => {
+                    let s = s.trim();
+                    if !s.starts_with('.') && !s.ends_with(':') && s != "}" {
+                        pinstrs.push(PI(Instr::Halt));
+                    }
+                    errors.push(e);
+                }
+            }
+        }
+        (pinstrs, errors)
+    }
+
+    /// Whether this defines a label (`Lname:`).
+    pub fn is_label(&self) -> bool {
+        matches!(self, PLabel(_))
+    }
+
+    /// Whether this assembles to one instruction, and so takes up a pc.
+    /// Labels and declarations take up none, and a directive emits what
+    /// it expands to (see `expand::expand`), if anything, not itself.
+    pub fn emits_instruction(&self) -> bool {
+        match self {
+            PPush(_) | PPushOffset(..) | PRel(_) | PPushConst(_) | PI(_) => true,
+            PLabel(_) | PGlobal(_) | PLocal(_) | PDirective(_) => false,
+        }
+    }
+}
+
+/// The statements of a line that `PInstr::parse_line` parses one by
+/// one, as slices of the line.
+fn statements(line: &str) -> impl Iterator<Item = &str> {
+    split_statements(line).0
+        .into_iter()
+        .flat_map(split_label)
+        .flat_map(split_braces)
+        .filter(|s| !s.trim().is_empty())
+}
+
+/// Parse statement `s`, a slice of `line`, giving errors the column in
+/// the line.
+fn parse_statement(line: &str, s: &str) -> Result<PInstr, ParseError> {
+    PInstr::from_str(s).map_err(|e| {
+        let start = s.as_ptr() as usize - line.as_ptr() as usize;
+        let column = start + e.column.unwrap_or(0);
+        e.at(column)
+    })
+}
+
+/// Split a line into its `;`-separated statements and its trailing
+/// `//` comment, if any. Separators inside string literals don't count.
+fn split_statements(line: &str) -> (Vec<&str>, Option<&str>) {
+    let bytes = line.as_bytes();
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, &c) in bytes.iter().enumerate() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == b'\\' {
+                escaped = true;
+            } else if c == b'"' {
+                in_string = false;
+            }
+        } else if c == b'"' {
+            in_string = true;
+        } else if c == b';' {
+            statements.push(&line[start..i]);
+            start = i + 1;
+        } else if c == b'/' && bytes.get(i + 1) == Some(&b'/') {
+            statements.push(&line[start..i]);
+            return (statements, Some(&line[i..]));
+        }
+    }
+    statements.push(&line[start..]);
+    (statements, None)
+}
+
+/// Split label definitions off the front of a statement, as in
+/// `Lloop: push 1`, so that each label and what follows parse on their
+/// own. The pieces are slices of the statement, in order.
+fn split_label(statement: &str) -> Vec<&str> {
+    let start = statement.len() - statement.trim_start().len();
+    let end = statement[start..].find(char::is_whitespace).map_or(statement.len(), |i| start + i);
+    if !statement[start..end].ends_with(':') || statement[end..].trim().is_empty() {
+        return vec![statement];
+    }
+    let mut parts = vec![&statement[..end]];
+    parts.extend(split_label(&statement[end..]));
+    parts
+}
+
+/// Split the braces of `.times` blocks from the statements around them,
+/// so that `.times 2 { pop }` is the statements `.times 2 {`, `pop`
+/// and `}`.
+fn split_braces(statement: &str) -> Vec<&str> {
+    if [".string", ".datastring", ".include"].iter().any(|d| statement.trim_start().starts_with(d)) {
+        return vec![statement];
+    }
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (i, c) in statement.char_indices() {
+        match c {
+            '{' => {
+                parts.push(&statement[start..=i]);
+                start = i + 1;
             }
+            '}' => {
+                parts.push(&statement[start..i]);
+                parts.push("}");
+                start = i + 1;
+            }
+            _ => (),
         }
     }
+    parts.push(&statement[start..]);
+    parts
+}
+
+/// Lowercase the opcode of every instruction on a line, leaving
+/// operands and label definitions alone (label case matters). Running a
+/// line through this before `PInstr::parse_line` accepts mixed-case
+/// opcodes such as `PUSH 3` or `Halt`.
+pub fn lowercase_opcodes(line: &str) -> String {
+    let (statements, comment) = split_statements(line);
+    let code = statements.iter()
+        .map(|statement| split_label(statement).into_iter().flat_map(split_braces).map(|seg| {
+            let start = seg.len() - seg.trim_start().len();
+            let end = seg[start..].find(char::is_whitespace).map_or(seg.len(), |i| start + i);
+            let word = &seg[start..end];
+            if word.ends_with(':') {
+                seg.to_string()
+            } else {
+                format!("{}{}{}", &seg[..start], word.to_ascii_lowercase(), &seg[end..])
+            }
+        }).collect::<String>())
+        .collect::<Vec<String>>()
+        .join(";");
+    code + comment.unwrap_or("")
+}
+
+/// Parse a double-quoted string literal, with `\\`, `\"`, `\n` and
+/// `\t` escapes.
+fn parse_string_literal(s: &str) -> Result<String, ParseError> {
+    let err = || ParseError::new(ParseErrorKind::StringLiteral, s);
+    let inner = s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).ok_or_else(err)?;
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\\') => out.push('\\'),
+                Some('"') => out.push('"'),
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                _ => return Err(err()),
+            },
+            '"' => return Err(err()),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+/// Write a string as a double-quoted literal that `parse_string_literal`
+/// reads back.
+fn write_string_literal(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '\\' => write!(f, "\\\\")?,
+            '"' => write!(f, "\\\"")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
 }
 
 /// Test to_string and from_string implementations (to_string comes
@@ -312,10 +1170,27 @@ impl FromStr for PInstr {
 fn test_isa_parse() -> Result<(), ParseError> {
     assert_eq!(PLabel("Ltest".into()), PLabel("Ltest".into()).to_string().parse()?);
     assert_eq!(PPush("Ltest".into()), PPush("Ltest".into()).to_string().parse()?);
+    assert_eq!(PRel("Ltest".into()), PRel("Ltest".into()).to_string().parse()?);
+    assert_eq!(PPushConst("N".into()), PPushConst("N".into()).to_string().parse()?);
+    assert_eq!(PLabel(".Ltest".into()), PLabel(".Ltest".into()).to_string().parse()?);
+    for n in [4, -2, 0] {
+        for label in ["Ltest", "_L1", ".Ltest"] {
+            let p = PPushOffset(label.into(), n);
+            assert_eq!(p, p.to_string().parse()?);
+        }
+    }
+    assert_eq!(PGlobal("Ltest".into()), PGlobal("Ltest".into()).to_string().parse()?);
+    assert_eq!(PLocal("Ltest".into()), PLocal("Ltest".into()).to_string().parse()?);
+    for d in [While, Do, EndWhile, Break, Str("a \"b\"; \\ // c\n".into()), DataStr("{x}".into()),
+              Bytes(vec![0, 0x7f, 0xff]), I32(-2), Align(8), Times(3), EndTimes,
+              Include("lib/a b.s".into()), Equ("SIZE_2".into(), Vi32(-3)),
+              Equ("YES".into(), Vbool(true)), JumpTable(vec!["L0".into(), "L1".into()])] {
+        assert_eq!(PDirective(d.clone()), PDirective(d).to_string().parse()?);
+    }
     let pinstrs: Vec<PInstr> = vec![Push(Vi32(123)), Pop, Peek(45), Unary(Neg),
 				    Binary(Lt), Swap, Alloc, Set, Get, Var(65),
 				    Store(5), Call, Ret, Branch, Halt]
-	.into_iter().map(|x| PI(x)).collect();
+	.into_iter().map(PI).collect();
     for pinstr in pinstrs {
 	assert_eq!(pinstr, pinstr.to_string().parse()?);
     }
@@ -326,78 +1201,460 @@ fn test_isa_parse() -> Result<(), ParseError> {
 // ToBytes trait implementations
 ////////////////////////////////////////////////////////////////////////
 
+impl ToBytes for u16 {
+    fn encode_into_with(&self, buf: &mut Vec<u8>, endianness: Endianness) {
+        match endianness{
+            Big => buf.extend_from_slice(&self.to_be_bytes()),
+            Little => buf.extend_from_slice(&self.to_le_bytes()),
+        }
+    }
+
+    fn byte_len(&self) -> usize {
+        2
+    }
+}
+
 impl ToBytes for u32 {
-    fn to_bytes(&self) -> Vec<u8> {
-        return self.to_be_bytes().to_vec();
+    fn encode_into_with(&self, buf: &mut Vec<u8>, endianness: Endianness) {
+        match endianness{
+            Big => buf.extend_from_slice(&self.to_be_bytes()),
+            Little => buf.extend_from_slice(&self.to_le_bytes()),
+        }
+    }
+
+    fn byte_len(&self) -> usize {
+        4
+    }
+}
+
+impl ToBytes for u64 {
+    fn encode_into_with(&self, buf: &mut Vec<u8>, endianness: Endianness) {
+        match endianness{
+            Big => buf.extend_from_slice(&self.to_be_bytes()),
+            Little => buf.extend_from_slice(&self.to_le_bytes()),
+        }
+    }
+
+    fn byte_len(&self) -> usize {
+        8
     }
 }
 
 impl ToBytes for i32 {
-    fn to_bytes(&self) -> Vec<u8> {
-        return self.to_be_bytes().to_vec();
+    fn encode_into_with(&self, buf: &mut Vec<u8>, endianness: Endianness) {
+        match endianness{
+            Big => buf.extend_from_slice(&self.to_be_bytes()),
+            Little => buf.extend_from_slice(&self.to_le_bytes()),
+        }
+    }
+
+    fn byte_len(&self) -> usize {
+        4
     }
 }
 
 impl ToBytes for Unop {
-    fn to_bytes(&self) -> Vec<u8> {
-        return vec![0x00]
+    fn encode_into_with(&self, buf: &mut Vec<u8>, _: Endianness) {
+        buf.push(*self as u8)
+    }
+
+    fn byte_len(&self) -> usize {
+        1
     }
 }
 
 impl ToBytes for Binop {
-    fn to_bytes(&self) -> Vec<u8> {
-        match self{
-            Add => return vec![0x00],
-            Mul => return vec![0x01],
-            Sub => return vec![0x02],
-            Div => return vec![0x03],
-            Lt => return vec![0x04],
-            Eq => return vec![0x05]
-        }
+    fn encode_into_with(&self, buf: &mut Vec<u8>, _: Endianness) {
+        buf.push(*self as u8)
+    }
+
+    fn byte_len(&self) -> usize {
+        1
     }
 }
 
 impl ToBytes for Val {
-    fn to_bytes(&self) -> Vec<u8> {
+    fn encode_into_with(&self, buf: &mut Vec<u8>, e: Endianness) {
+        self.encode_into_profile(buf, e, Profile::Fixed)
+    }
+
+    fn byte_len(&self) -> usize {
+        match self {
+            Vi32(_) | Vloc(_) | Vsize(_) => 5,
+            Vaddr(_) => 9,
+            Vunit | Vbool(_) | Vundef => 1,
+        }
+    }
+}
+
+impl Val {
+    /// Append the encoding, with the given operand profile, to `buf`.
+    pub fn encode_into_profile(&self, buf: &mut Vec<u8>, e: Endianness, profile: Profile) {
+        buf.push(self.tag());
         match self{
-            Vunit => return vec![0x00],
-            Vi32(i) => return [vec![0x01], i32::to_bytes(i)].concat(),
-            Vbool(b) => {
-                if *b == true{
-                    return vec![0x02]
-                }
-                else{
-                    return vec![0x03]
-                }
-            },
-            Vloc(u) => return [vec![0x04], u32::to_bytes(u)].concat(),
-            Vundef => return vec![0x05],
-            Vsize(i32) => return vec![0x11],
-            Vaddr(Address) => return vec![0x11],
+            Vi32(i) | Vsize(i) => i.encode_operand(buf, e, profile),
+            Vloc(u) => u.encode_operand(buf, e, profile),
+            Vaddr(a) => (*a as u64).encode_operand(buf, e, profile),
+            Vunit | Vbool(_) | Vundef => (),
+        }
+    }
+
+    /// The tag byte (`TAG_UNIT`, ...) that starts the encoding.
+    pub fn tag(&self) -> u8 {
+        match self {
+            Vunit => TAG_UNIT,
+            Vi32(_) => TAG_I32,
+            Vbool(true) => TAG_TRUE,
+            Vbool(false) => TAG_FALSE,
+            Vloc(_) => TAG_LOC,
+            Vundef => TAG_UNDEF,
+            Vsize(_) => TAG_SIZE,
+            Vaddr(_) => TAG_ADDR,
+        }
+    }
+
+    /// Decode a value encoded with the given operand profile.
+    pub fn from_bytes_profile(bytes: &[u8], e: Endianness, profile: Profile)
+                              -> Result<(Self, usize), DecodeError> {
+        match tag(bytes, "value")? {
+            TAG_UNIT => Ok((Vunit, 1)),
+            TAG_I32 => operand(bytes, e, profile).map(|(i, n)| (Vi32(i), n)),
+            TAG_TRUE => Ok((Vbool(true), 1)),
+            TAG_FALSE => Ok((Vbool(false), 1)),
+            TAG_LOC => operand(bytes, e, profile).map(|(u, n)| (Vloc(u), n)),
+            TAG_UNDEF => Ok((Vundef, 1)),
+            TAG_SIZE => operand(bytes, e, profile).map(|(i, n)| (Vsize(i), n)),
+            TAG_ADDR => operand::<u64>(bytes, e, profile).map(|(a, n)| (Vaddr(a as Address), n)),
+            opcode => Err(DecodeError::UnknownOpcode { what: "value", opcode }),
         }
     }
 }
 
 impl ToBytes for Instr {
-    fn to_bytes(&self) -> Vec<u8> {
-        match self{
-            Push(v) => return [vec![0x00], Val::to_bytes(v)].concat(),
-            Pop => return vec![0x01],
-            Peek(v) => return [vec![0x02], u32::to_bytes(v)].concat(),
-            Unary(v) => return [vec![0x03], Unop::to_bytes(v)].concat(),
-            Binary(b) => return [vec![0x04], Binop::to_bytes(b)].concat(),
-            Swap => return vec![0x05],
-            Alloc => return vec![0x06],
-            Set => return vec![0x07],
-            Get => return vec![0x08],
-            Var(v) => return [vec![0x09], u32::to_bytes(v)].concat(),
-            Store(v) => return [vec![0x0A], u32::to_bytes(v)].concat(),
-            SetFrame(v) => return [vec![0x0B], u32::to_bytes(v)].concat(),
-            Call => return vec![0x0C],
-            Ret => return vec![0x0D],
-            Branch => return vec![0x0E],
-            Halt => return vec![0x0F],
+    fn encode_into_with(&self, buf: &mut Vec<u8>, e: Endianness) {
+        self.encode_into_profile(buf, e, Profile::Fixed)
+    }
+
+    fn byte_len(&self) -> usize {
+        match self.parts() {
+            Some((_, operand)) => 1 + operand.byte_len(),
+            None => 1,
+        }
+    }
+}
+
+/// A sequence of instructions encodes as their encodings back to back,
+/// into one buffer sized by `byte_len`.
+impl ToBytes for [Instr] {
+    fn encode_into_with(&self, buf: &mut Vec<u8>, e: Endianness) {
+        for i in self {
+            i.encode_into_with(buf, e);
+        }
+    }
+
+    fn byte_len(&self) -> usize {
+        crate::byte_len(self)
+    }
+}
+
+impl ToBytes for Vec<Instr> {
+    fn encode_into_with(&self, buf: &mut Vec<u8>, e: Endianness) {
+        self.as_slice().encode_into_with(buf, e)
+    }
+
+    fn byte_len(&self) -> usize {
+        self.as_slice().byte_len()
+    }
+}
+
+impl Instr {
+    /// Append the encoding, with the given operand profile, to `buf`.
+    pub fn encode_into_profile(&self, buf: &mut Vec<u8>, e: Endianness, profile: Profile) {
+        buf.push(self.opcode());
+        if let Some((_, operand)) = self.parts() {
+            operand.encode_into_profile(buf, e, profile);
+        }
+    }
+
+    /// The first byte of the encoding: the opcode, or the data byte of
+    /// a `Byte`.
+    pub fn opcode(&self) -> u8 {
+        match (self, self.parts()) {
+            (Byte(b), _) => *b,
+            (_, parts) => parts.map_or(0, |(opcode, _)| opcode as u8),
+        }
+    }
+
+    /// The opcode an encoded instruction starting with `byte` has, if
+    /// any (see `Opcode::from_byte`).
+    pub fn from_opcode(byte: u8) -> Option<Opcode> {
+        Opcode::from_byte(byte)
+    }
+
+    /// Decode an instruction encoded with the given operand profile.
+    pub fn from_bytes_profile(bytes: &[u8], e: Endianness, profile: Profile)
+                              -> Result<(Self, usize), DecodeError> {
+        let opcode = tag(bytes, "instruction")?;
+        let unknown = DecodeError::UnknownOpcode { what: "instruction", opcode };
+        let spec = Opcode::from_byte(opcode).ok_or(unknown)?.spec();
+        let (operand, n) = Operand::from_bytes_profile(spec.operand, &bytes[1..], e, profile)?;
+        let i = Instr::from_parts(spec.opcode, operand).ok_or(unknown)?;
+        Ok((i, n + 1))
+    }
+}
+
+impl Operand {
+    /// The length of the fixed-width encoding.
+    fn byte_len(&self) -> usize {
+        match self {
+            Operand::None => 0,
+            Operand::Value(v) => v.byte_len(),
+            Operand::U32(_) => 4,
+            Operand::Unop(_) | Operand::Binop(_) => 1,
+        }
+    }
+
+    fn encode_into_profile(&self, buf: &mut Vec<u8>, e: Endianness, profile: Profile) {
+        match self {
+            Operand::None => (),
+            Operand::Value(v) => v.encode_into_profile(buf, e, profile),
+            Operand::U32(n) => n.encode_operand(buf, e, profile),
+            Operand::Unop(u) => u.encode_into_with(buf, e),
+            Operand::Binop(b) => b.encode_into_with(buf, e),
+        }
+    }
+
+    /// Decode an operand of the given kind from the start of `bytes`.
+    fn from_bytes_profile(kind: OperandKind, bytes: &[u8], e: Endianness, profile: Profile)
+                          -> Result<(Operand, usize), DecodeError> {
+        match kind {
+            OperandKind::None => Ok((Operand::None, 0)),
+            OperandKind::Value => Val::from_bytes_profile(bytes, e, profile).map(|(v, n)| (Operand::Value(v), n)),
+            OperandKind::U32 => u32::decode_operand(bytes, e, profile).map(|(x, n)| (Operand::U32(x), n)),
+            OperandKind::Unop => Unop::from_bytes_with(bytes, e).map(|(u, n)| (Operand::Unop(u), n)),
+            OperandKind::Binop => Binop::from_bytes_with(bytes, e).map(|(b, n)| (Operand::Binop(b), n)),
+        }
+    }
+}
+
+/// Integer operands, which the varint profile encodes as LEB128.
+trait IntOperand: ToBytes + FromBytes {
+    /// The name used in decoding errors.
+    const WHAT: &'static str;
+    /// Whether the varint is signed LEB128, carrying an i64 in its u64.
+    const SIGNED: bool = false;
+
+    fn to_varint(&self) -> u64;
+
+    fn from_varint(n: u64) -> Option<Self>;
+
+    fn encode_operand(&self, buf: &mut Vec<u8>, e: Endianness, profile: Profile) {
+        match profile {
+            Profile::Fixed => self.encode_into_with(buf, e),
+            Profile::Varint => write_leb128(buf, self.to_varint(), Self::SIGNED),
+        }
+    }
+
+    fn decode_operand(bytes: &[u8], e: Endianness, profile: Profile) -> Result<(Self, usize), DecodeError> {
+        match profile {
+            Profile::Fixed => Self::from_bytes_with(bytes, e),
+            Profile::Varint => {
+                let (n, len) = read_leb128(bytes, Self::SIGNED, Self::WHAT)?;
+                Self::from_varint(n).map(|x| (x, len)).ok_or(DecodeError::Overflow { what: Self::WHAT })
+            }
+        }
+    }
+}
+
+impl IntOperand for u32 {
+    const WHAT: &'static str = "u32";
+
+    fn to_varint(&self) -> u64 {
+        u64::from(*self)
+    }
+
+    fn from_varint(n: u64) -> Option<Self> {
+        u32::try_from(n).ok()
+    }
+}
+
+impl IntOperand for u64 {
+    const WHAT: &'static str = "u64";
+
+    fn to_varint(&self) -> u64 {
+        *self
+    }
+
+    fn from_varint(n: u64) -> Option<Self> {
+        Some(n)
+    }
+}
+
+impl IntOperand for i32 {
+    const WHAT: &'static str = "i32";
+    const SIGNED: bool = true;
+
+    fn to_varint(&self) -> u64 {
+        i64::from(*self) as u64
+    }
+
+    fn from_varint(n: u64) -> Option<Self> {
+        i32::try_from(n as i64).ok()
+    }
+}
+
+/// Append `n` as LEB128, seven bits a byte with the high bit set on all
+/// but the last. Signed LEB128 stops once the rest is sign extension.
+fn write_leb128(buf: &mut Vec<u8>, n: u64, signed: bool) {
+    let mut n = n;
+    loop {
+        let b = (n & 0x7f) as u8;
+        n = if signed { ((n as i64) >> 7) as u64 } else { n >> 7 };
+        let done = if signed {
+            (n == 0 && b & 0x40 == 0) || (n == u64::MAX && b & 0x40 != 0)
+        } else {
+            n == 0
+        };
+        if done {
+            buf.push(b);
+            return;
         }
+        buf.push(b | 0x80);
+    }
+}
+
+/// Read a LEB128 varint of at most 64 bits (sign-extended if `signed`)
+/// from the start of `bytes`, with its length.
+fn read_leb128(bytes: &[u8], signed: bool, what: &'static str) -> Result<(u64, usize), DecodeError> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    for (i, &b) in bytes.iter().enumerate() {
+        let low = u64::from(b & 0x7f);
+        if shift >= 64 || (shift > 57 && low >> (64 - shift) != 0) {
+            return Err(DecodeError::Overflow { what });
+        }
+        n |= low << shift;
+        shift += 7;
+        if b & 0x80 == 0 {
+            if signed && shift < 64 && b & 0x40 != 0 {
+                n |= u64::MAX << shift;
+            }
+            return Ok((n, i + 1));
+        }
+    }
+    Err(DecodeError::Truncated { what, needed: bytes.len() + 1, available: bytes.len() })
+}
+
+/// The first `N` bytes of `bytes`, or an error naming `what` was cut short.
+fn take<const N: usize>(bytes: &[u8], what: &'static str) -> Result<[u8; N], DecodeError> {
+    bytes.get(..N)
+        .map(|b| b.try_into().unwrap())
+        .ok_or(DecodeError::Truncated { what, needed: N, available: bytes.len() })
+}
+
+/// The tag byte at the start of `bytes`.
+fn tag(bytes: &[u8], what: &'static str) -> Result<u8, DecodeError> {
+    Ok(take::<1>(bytes, what)?[0])
+}
+
+/// Decode the integer operand of a one-byte tag, counting the tag in
+/// its length.
+fn operand<T: IntOperand>(bytes: &[u8], e: Endianness, profile: Profile) -> Result<(T, usize), DecodeError> {
+    T::decode_operand(&bytes[1..], e, profile).map(|(x, n)| (x, n + 1))
+}
+
+impl FromBytes for u16 {
+    fn from_bytes_with(bytes: &[u8], endianness: Endianness) -> Result<(Self, usize), DecodeError> {
+        let b = take(bytes, "u16")?;
+        match endianness {
+            Big => Ok((u16::from_be_bytes(b), 2)),
+            Little => Ok((u16::from_le_bytes(b), 2)),
+        }
+    }
+}
+
+impl FromBytes for u32 {
+    fn from_bytes_with(bytes: &[u8], endianness: Endianness) -> Result<(Self, usize), DecodeError> {
+        let b = take(bytes, "u32")?;
+        match endianness {
+            Big => Ok((u32::from_be_bytes(b), 4)),
+            Little => Ok((u32::from_le_bytes(b), 4)),
+        }
+    }
+}
+
+impl FromBytes for u64 {
+    fn from_bytes_with(bytes: &[u8], endianness: Endianness) -> Result<(Self, usize), DecodeError> {
+        let b = take(bytes, "u64")?;
+        match endianness {
+            Big => Ok((u64::from_be_bytes(b), 8)),
+            Little => Ok((u64::from_le_bytes(b), 8)),
+        }
+    }
+}
+
+impl FromBytes for i32 {
+    fn from_bytes_with(bytes: &[u8], endianness: Endianness) -> Result<(Self, usize), DecodeError> {
+        let b = take(bytes, "i32")?;
+        match endianness {
+            Big => Ok((i32::from_be_bytes(b), 4)),
+            Little => Ok((i32::from_le_bytes(b), 4)),
+        }
+    }
+}
+
+impl FromBytes for Unop {
+    fn from_bytes_with(bytes: &[u8], _: Endianness) -> Result<(Self, usize), DecodeError> {
+        let opcode = tag(bytes, "unop")?;
+        Unop::from_byte(opcode).map(|u| (u, 1)).ok_or(DecodeError::UnknownOpcode { what: "unop", opcode })
+    }
+}
+
+impl FromBytes for Binop {
+    fn from_bytes_with(bytes: &[u8], _: Endianness) -> Result<(Self, usize), DecodeError> {
+        let opcode = tag(bytes, "binop")?;
+        Binop::from_byte(opcode).map(|b| (b, 1)).ok_or(DecodeError::UnknownOpcode { what: "binop", opcode })
+    }
+}
+
+impl FromBytes for Val {
+    fn from_bytes_with(bytes: &[u8], e: Endianness) -> Result<(Self, usize), DecodeError> {
+        Val::from_bytes_profile(bytes, e, Profile::Fixed)
+    }
+}
+
+/// Decode the whole of `bytes` as one `T`.
+fn decode_exact<T: FromBytes>(bytes: &[u8], what: &'static str) -> Result<T, DecodeError> {
+    match T::from_bytes(bytes)? {
+        (x, n) if n == bytes.len() => Ok(x),
+        (_, n) => Err(DecodeError::TrailingBytes { what, extra: bytes.len() - n }),
+    }
+}
+
+// Decode a big-endian value that takes up the whole slice.
+impl TryFrom<&[u8]> for Val {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        decode_exact(bytes, "value")
+    }
+}
+
+// Decode a big-endian instruction that takes up the whole slice:
+// `Instr::try_from(&bytes[..])`.
+impl TryFrom<&[u8]> for Instr {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        decode_exact(bytes, "instruction")
+    }
+}
+
+// Data bytes (`Byte`) have no opcode, so they cannot be told apart from
+// instructions and are never decoded.
+impl FromBytes for Instr {
+    fn from_bytes_with(bytes: &[u8], e: Endianness) -> Result<(Self, usize), DecodeError> {
+        Instr::from_bytes_profile(bytes, e, Profile::Fixed)
     }
 }
 
@@ -417,7 +1674,7 @@ mod tests {
     #[test]
     fn unit_test1(){
         assert_eq!(Instr::from_str("push 12").unwrap(), Push(Vi32(12)));
-        assert_eq!(Binop::from_str("+").unwrap(), Binop::from(Add));
+        assert_eq!(Binop::from_str("+").unwrap(), Add);
     }
     #[test]
     fn unit_test2(){
@@ -429,4 +1686,665 @@ mod tests {
         assert_eq!(Instr::from_str("push 700").unwrap(), Push(Vi32(700)));
         assert_eq!(Val::to_bytes(&Vi32(700)), vec![1,0,0,2,188]);
     }
+    #[test]
+    fn test_endianness(){
+        assert_eq!(Push(Vi32(1)).to_bytes(), vec![0x00, 0x01, 0, 0, 0, 1]);
+        assert_eq!(Push(Vi32(1)).to_bytes_with(Big), vec![0x00, 0x01, 0, 0, 0, 1]);
+        assert_eq!(Push(Vi32(1)).to_bytes_with(Little), vec![0x00, 0x01, 1, 0, 0, 0]);
+        assert_eq!(Var(0x0102).to_bytes_with(Little), vec![0x09, 2, 1, 0, 0]);
+        assert_eq!(Binary(Div).to_bytes_with(Little), vec![0x04, 0x03]);
+    }
+    #[test]
+    fn test_encode_into(){
+        let instrs = [Push(Vi32(-7)), Push(Vloc(9)), Push(Vaddr(3)), Peek(2), Binary(Lt),
+                      Unary(Neg), Halt, Byte(0xcc)];
+        for e in [Big, Little] {
+            let mut buf = vec![0xaa];
+            for i in &instrs {
+                i.encode_into_with(&mut buf, e);
+            }
+            let expected: Vec<u8> = std::iter::once(0xaa)
+                .chain(instrs.iter().flat_map(|i| i.to_bytes_with(e)))
+                .collect();
+            assert_eq!(buf, expected);
+        }
+        let mut buf = Vec::new();
+        7u32.encode_into(&mut buf);
+        assert_eq!(buf, 7u32.to_bytes());
+    }
+    #[test]
+    fn test_byte_len(){
+        let vals = [Vunit, Vi32(i32::MIN), Vbool(true), Vbool(false), Vloc(u32::MAX), Vundef,
+                    Vsize(-1), Vaddr(usize::MAX)];
+        let mut instrs: Vec<Instr> = vals.iter().map(|v| Push(*v)).collect();
+        instrs.extend([Pop, Peek(1), Unary(Neg), Swap, Alloc, Set, Get, Var(1),
+                       Store(1), SetFrame(1), Call, Ret, Branch, Halt, Byte(1)]);
+        instrs.extend([Add, Mul, Sub, Div, Lt, Eq].iter().map(|b| Binary(*b)));
+        for v in &vals {
+            assert_eq!(v.byte_len(), v.to_bytes().len(), "{:?}", v);
+        }
+        for i in &instrs {
+            let bytes = i.to_bytes();
+            assert_eq!(i.byte_len(), bytes.len(), "{:?}", i);
+            assert_eq!(bytes.capacity(), bytes.len(), "{:?}", i);
+        }
+        assert_eq!(crate::byte_len(&instrs), instrs.iter().map(|i| i.to_bytes().len()).sum::<usize>());
+        assert_eq!(Add.byte_len(), 1);
+        assert_eq!(0u32.byte_len(), 4);
+    }
+    #[test]
+    fn test_from_bytes_round_trip(){
+        let vals = [Vunit, Vi32(0), Vi32(-1), Vi32(i32::MIN), Vi32(i32::MAX), Vbool(true),
+                    Vbool(false), Vloc(0), Vloc(0x01020304), Vloc(u32::MAX), Vundef];
+        let mut instrs: Vec<Instr> = vals.iter().map(|v| Push(*v)).collect();
+        for u in [0, 7, 0x01020304, u32::MAX] {
+            instrs.extend([Peek(u), Var(u), Store(u), SetFrame(u)]);
+        }
+        instrs.extend([Unary(Neg), Pop, Swap, Alloc, Set, Get, Call, Ret, Branch, Halt]);
+        instrs.extend([Add, Mul, Sub, Div, Lt, Eq].iter().map(|b| Binary(*b)));
+
+        for e in [Big, Little] {
+            for v in &vals {
+                let bytes = v.to_bytes_with(e);
+                assert_eq!(Val::from_bytes_with(&bytes, e).unwrap(), (*v, bytes.len()));
+            }
+            for i in &instrs {
+                let bytes = i.to_bytes_with(e);
+                assert_eq!(Instr::from_bytes_with(&bytes, e).unwrap(), (*i, bytes.len()));
+                // Trailing bytes belong to whatever follows.
+                let padded = [bytes.clone(), vec![0x0F]].concat();
+                assert_eq!(Instr::from_bytes_with(&padded, e).unwrap(), (*i, bytes.len()));
+            }
+        }
+    }
+    #[test]
+    fn test_operand(){
+        assert_eq!(Peek(3).operand(), Some(3));
+        assert_eq!(Var(0).operand(), Some(0));
+        assert_eq!(Store(7).operand(), Some(7));
+        assert_eq!(SetFrame(u32::MAX).operand(), Some(u32::MAX));
+        for i in [Push(Vi32(4)), Push(Vloc(4)), Pop, Unary(Neg), Binary(Add), Swap, Alloc, Set, Get, Call, Ret,
+                  Branch, Halt, Byte(4)] {
+            assert_eq!(i.operand(), None, "{}", i);
+        }
+        assert_eq!(Push(Vloc(4)).value(), Some(Vloc(4)));
+        assert_eq!(Push(Vundef).value(), Some(Vundef));
+        assert_eq!(Peek(4).value(), None);
+        assert_eq!(Byte(4).value(), None);
+    }
+    #[test]
+    fn test_opcodes_distinct(){
+        use std::collections::HashSet;
+        // One of every instruction but `Byte`, whose first byte is data.
+        let instrs = [Push(Vunit), Pop, Peek(0), Unary(Neg), Binary(Add), Swap, Alloc, Set, Get, Var(0),
+                      Store(0), SetFrame(0), Call, Ret, Branch, Halt];
+        for i in instrs {
+            match i {
+                // Adding a variant fails to compile here until it is
+                // listed above.
+                Push(_) | Pop | Peek(_) | Unary(_) | Binary(_) | Swap | Alloc | Set | Get | Var(_) | Store(_)
+                | SetFrame(_) | Call | Ret | Branch | Halt | Byte(_) => (),
+            }
+            assert_eq!(i.to_bytes()[0], i.opcode());
+            assert_eq!(Instr::from_opcode(i.opcode()).map(|op| op as u8), Some(i.opcode()));
+        }
+        let opcodes: HashSet<u8> = instrs.iter().map(Instr::opcode).collect();
+        assert_eq!(opcodes.len(), instrs.len());
+        assert_eq!(opcodes, Opcode::ALL.iter().map(|op| *op as u8).collect());
+        assert_eq!(Byte(0x2a).opcode(), 0x2a);
+
+        let vals = [Vunit, Vi32(0), Vbool(true), Vbool(false), Vloc(0), Vundef, Vsize(0), Vaddr(0)];
+        let tags: HashSet<u8> = vals.iter().map(Val::tag).collect();
+        assert_eq!(tags.len(), vals.len());
+        for v in vals {
+            assert_eq!(v.to_bytes()[0], v.tag());
+        }
+        let binops: HashSet<u8> = Binop::ALL.iter().map(|b| *b as u8).collect();
+        assert_eq!(binops.len(), Binop::ALL.len());
+        for b in Binop::ALL {
+            assert_eq!(Binop::from_byte(b as u8), Some(b));
+            match b {
+                Add | Mul | Sub | Div | Lt | Eq => (),
+            }
+        }
+        for u in Unop::ALL {
+            assert_eq!(Unop::from_byte(u as u8), Some(u));
+            match u {
+                Neg => (),
+            }
+        }
+        assert_eq!(Opcode::from_byte(0x10), None);
+        assert_eq!(Binop::from_byte(0x06), None);
+    }
+    #[test]
+    fn test_instr_table(){
+        use std::collections::HashSet;
+        let sample = |kind| match kind {
+            OperandKind::None => Operand::None,
+            OperandKind::Value => Operand::Value(Vi32(-3)),
+            OperandKind::U32 => Operand::U32(3),
+            OperandKind::Unop => Operand::Unop(Neg),
+            OperandKind::Binop => Operand::Binop(Div),
+        };
+        let mut variants = HashSet::new();
+        for (n, spec) in INSTRS.iter().enumerate() {
+            assert_eq!(spec.opcode as usize, n, "{}", spec.mnemonic);
+            let i = Instr::from_parts(spec.opcode, sample(spec.operand)).unwrap();
+            // Every variant but `Byte` once; the match fails to compile
+            // when a variant is added, until it is handled here and in
+            // the table.
+            let variant = match i {
+                Push(_) => 0, Pop => 1, Peek(_) => 2, Unary(_) => 3, Binary(_) => 4, Swap => 5, Alloc => 6,
+                Set => 7, Get => 8, Var(_) => 9, Store(_) => 10, SetFrame(_) => 11, Call => 12, Ret => 13,
+                Branch => 14, Halt => 15, Byte(_) => unreachable!(),
+            };
+            assert!(variants.insert(variant), "{}", spec.mnemonic);
+            assert_eq!(i.spec(), Some(spec));
+            assert_eq!(i.parts(), Some((spec.opcode, sample(spec.operand))));
+            // The other kinds of operand do not fit.
+            for kind in [OperandKind::None, OperandKind::Value, OperandKind::U32, OperandKind::Unop, OperandKind::Binop] {
+                assert_eq!(Instr::from_parts(spec.opcode, sample(kind)).is_some(), kind == spec.operand);
+            }
+            let text = i.to_string();
+            assert!(text.starts_with(spec.mnemonic), "{}", text);
+            assert_eq!(Instr::from_str(&text).unwrap(), i);
+            let bytes = i.to_bytes();
+            assert_eq!((bytes[0], bytes.len()), (n as u8, i.byte_len()));
+            assert_eq!(Instr::from_bytes(&bytes).unwrap(), (i, bytes.len()));
+        }
+        assert_eq!(variants.len(), 16);
+        let mnemonics: HashSet<&str> = INSTRS.iter().map(|spec| spec.mnemonic).collect();
+        assert_eq!(mnemonics.len(), INSTRS.len());
+        assert_eq!(Byte(7).parts(), None);
+        assert_eq!(Byte(7).spec(), None);
+    }
+    #[test]
+    fn test_try_from(){
+        let vals = [Vunit, Vi32(-9), Vbool(true), Vbool(false), Vloc(9), Vundef, Vsize(9), Vaddr(0xdead_beef)];
+        let mut instrs: Vec<Instr> = vals.iter().map(|v| Push(*v)).collect();
+        instrs.extend([Pop, Peek(9), Unary(Neg), Swap, Alloc, Set, Get, Var(9), Store(9), SetFrame(9), Call, Ret,
+                       Branch, Halt]);
+        instrs.extend(Binop::ALL.iter().map(|b| Binary(*b)));
+        for i in instrs {
+            let bytes = i.to_bytes();
+            assert_eq!(Instr::try_from(bytes.as_slice()), Ok(i));
+            let padded = [bytes.clone(), vec![0x0F]].concat();
+            assert_eq!(Instr::try_from(padded.as_slice()),
+                       Err(DecodeError::TrailingBytes { what: "instruction", extra: 1 }), "{}", i);
+        }
+        for v in vals {
+            let bytes = v.to_bytes();
+            assert_eq!(Val::try_from(bytes.as_slice()), Ok(v));
+            let padded = [bytes.clone(), vec![0, 0]].concat();
+            assert_eq!(Val::try_from(padded.as_slice()), Err(DecodeError::TrailingBytes { what: "value", extra: 2 }));
+        }
+        assert_eq!(Instr::try_from(&[0x02, 0, 0][..]),
+                   Err(DecodeError::Truncated { what: "u32", needed: 4, available: 2 }));
+        assert_eq!(Val::try_from(&[][..]), Err(DecodeError::Truncated { what: "value", needed: 1, available: 0 }));
+        assert_eq!(DecodeError::TrailingBytes { what: "instruction", extra: 3 }.to_string(),
+                   "3 trailing bytes after instruction");
+    }
+    #[test]
+    fn test_slice_to_bytes(){
+        let instrs = vec![Push(Vi32(-2)), Push(Vaddr(1 << 40)), Peek(300), Binary(Lt), Unary(Neg), Halt];
+        for e in [Endianness::Big, Endianness::Little] {
+            let each: Vec<u8> = instrs.iter().flat_map(|i| i.to_bytes_with(e)).collect();
+            assert_eq!(instrs[..].to_bytes_with(e), each);
+            assert_eq!(instrs.to_bytes_with(e), each);
+            assert_eq!(instrs.byte_len(), each.len());
+        }
+        assert_eq!(instrs[..0].to_bytes(), Vec::<u8>::new());
+    }
+    #[test]
+    fn test_mnemonic(){
+        let instrs = [(Push(Vunit), "push"), (Pop, "pop"), (Peek(1), "peek"), (Unary(Neg), "unary"),
+                      (Binary(Mul), "binary"), (Swap, "swap"), (Alloc, "alloc"), (Set, "set"), (Get, "get"),
+                      (Var(1), "var"), (Store(1), "store"), (SetFrame(1), "setframe"), (Call, "call"),
+                      (Ret, "ret"), (Branch, "branch"), (Halt, "halt"), (Byte(1), ".byte")];
+        for (i, mnemonic) in instrs {
+            assert_eq!(i.mnemonic(), mnemonic);
+            assert!(i.to_string().starts_with(mnemonic), "{}", i);
+        }
+    }
+    #[test]
+    fn test_emits_instruction(){
+        let cases = [(PLabel("Lf".into()), true, false), (PPush("Lf".into()), false, true),
+                     (PPushOffset("Lf".into(), 2), false, true), (PRel("Lf".into()), false, true),
+                     (PPushConst("N".into()), false, true), (PGlobal("Lf".into()), false, false),
+                     (PLocal("Lf".into()), false, false), (PDirective(Directive::While), false, false),
+                     (PDirective(Directive::Str("ab".into())), false, false), (PI(Halt), false, true),
+                     (PI(Byte(7)), false, true)];
+        for (i, label, emits) in cases {
+            assert_eq!((i.is_label(), i.emits_instruction()), (label, emits), "{}", i);
+        }
+    }
+    #[test]
+    fn test_category(){
+        use InstrCategory::*;
+        let categories: Vec<InstrCategory> = [Peek(1), Binary(Mul), Get, Branch, Byte(7)].iter()
+            .map(Instr::category)
+            .collect();
+        assert_eq!(categories, [Stack, Arithmetic, Memory, ControlFlow, Misc]);
+        assert_eq!((SetFrame(0).category(), Unary(Neg).category(), Alloc.category(), Halt.category()),
+                   (Stack, Arithmetic, Memory, ControlFlow));
+    }
+
+    #[test]
+    fn test_stack_effect(){
+        let effects = [
+            (Push(Vi32(1)), (0, 1)), (Pop, (1, 0)), (Peek(2), (0, 1)), (Unary(Neg), (1, 1)),
+            (Binary(Add), (2, 1)), (Swap, (2, 2)), (Alloc, (2, 1)), (Set, (3, 0)), (Get, (2, 1)),
+            (Var(0), (0, 1)), (Store(0), (1, 0)), (SetFrame(1), (0, 1)), (Call, (1, 1)), (Ret, (3, 1)),
+            (Branch, (2, 0)), (Halt, (0, 0)), (Byte(7), (0, 0)),
+        ];
+        for (i, effect) in effects {
+            assert_eq!(i.stack_effect(), effect, "{}", i);
+        }
+        // Every opcode is in the table.
+        assert_eq!(effects.iter().filter(|(i, _)| i.parts().is_some()).count(), INSTRS.len());
+    }
+
+    #[test]
+    fn test_all_mnemonics(){
+        let mnemonics = Instr::all_mnemonics();
+        assert_eq!(mnemonics.len(), 16);
+        assert_eq!(mnemonics[..3], ["push", "pop", "peek"]);
+        for m in mnemonics {
+            match Instr::from_str(m) {
+                Ok(i) => assert_eq!(i.mnemonic(), *m),
+                // Recognized, but missing its operand.
+                Err(e) => assert_eq!((e.kind, e.token.as_str()), (ParseErrorKind::Arity { expected: 1, found: 0 }, *m)),
+            }
+        }
+        assert_eq!(Instr::from_str("halt").unwrap().mnemonic(), mnemonics[Opcode::Halt as usize]);
+    }
+    #[test]
+    fn test_did_you_mean(){
+        let err = |s: &str| Instr::from_str(s).unwrap_err();
+        assert_eq!(err("psh 3").suggestion, Some("push"));
+        assert_eq!(err("psh 3").to_string(), "could not parse instruction 'psh 3'; did you mean 'push'?");
+        assert_eq!(err("poop").suggestion, Some("pop"));
+        assert_eq!(err("  brnch").to_string(), "could not parse instruction 'brnch'; did you mean 'branch'?");
+        assert_eq!(err("frobnicate 1").suggestion, None);
+        assert_eq!(err("frobnicate 1").to_string(), "could not parse instruction 'frobnicate 1'");
+        assert_eq!(PInstr::parse_line("push 1; hlt").unwrap_err().suggestion, Some("halt"));
+        assert_eq!((edit_distance("", "pop"), edit_distance("kitten", "sitting"), edit_distance("set", "set")),
+                   (3, 3, 0));
+    }
+    #[test]
+    fn test_unsigned_operands(){
+        let err = |s: &str| Instr::from_str(s).unwrap_err();
+        let unsigned = ParseErrorKind::Unsigned { max: 4294967295 };
+        assert_eq!(err("peek -1"), ParseError::new(unsigned, "-1").at(5));
+        assert_eq!(err("peek -1").to_string(), "operand must be a non-negative integer up to 4294967295, found '-1'");
+        assert_eq!(err("var 4294967296"), ParseError::new(unsigned, "4294967296").at(4));
+        assert_eq!(err("var 4294967296").to_string(),
+                   "operand must be a non-negative integer up to 4294967295, found '4294967296'");
+        assert_eq!(err("store -99999999999999999999").kind, unsigned);
+        assert_eq!(Instr::from_str("setframe 4294967295"), Ok(SetFrame(u32::MAX)));
+        // Not integers at all.
+        for s in ["peek x", "peek -", "peek 1.5", "peek +-1"] {
+            assert_eq!(err(s).kind, ParseErrorKind::Integer, "{}", s);
+        }
+        assert_eq!(PInstr::from_str(".times -2 {").unwrap_err().kind, unsigned);
+    }
+    #[test]
+    fn test_label_before_instruction(){
+        assert_eq!(PInstr::parse_line("Lloop: push 1").unwrap(), vec![PLabel("Lloop".into()), PI(Push(Vi32(1)))]);
+        assert_eq!(PInstr::parse_line("La:\tLb: halt // done; really").unwrap(),
+                   vec![PLabel("La".into()), PLabel("Lb".into()), PI(Halt)]);
+        assert_eq!(PInstr::parse_line("pop; .Lnext: push Lloop; Lend: ret").unwrap(),
+                   vec![PI(Pop), PLabel(".Lnext".into()), PPush("Lloop".into()), PLabel("Lend".into()), PI(Ret)]);
+        assert_eq!(PInstr::parse_line("Lmsg: .string \"a; {b}\"").unwrap(),
+                   vec![PLabel("Lmsg".into()), PDirective(Str("a; {b}".into()))]);
+        assert_eq!(PInstr::parse_line("Ltop: .times 2 { pop }").unwrap(),
+                   PInstr::parse_line("Ltop:; .times 2 { pop }").unwrap());
+        // Errors point into the instruction.
+        let err = PInstr::parse_line("Lloop: psh 1").unwrap_err();
+        assert_eq!((err.column, err.suggestion), (Some(7), Some("push")));
+        assert_eq!(PInstr::parse_line("L-x: push 1").unwrap_err().column, Some(0));
+        assert_eq!(lowercase_opcodes("Lloop: PUSH 1; Lend: HALT"), "Lloop: push 1; Lend: halt");
+    }
+    #[test]
+    fn test_parse_line_recovering(){
+        let line = "Lloop: psh 1; pop; peek x; .tims 2 {; Lbad-: ; push Lloop";
+        let (pinstrs, errors) = PInstr::parse_line_recovering(line);
+        // The label on the bad statement's line is kept, and each bad
+        // instruction still takes up a pc.
+        assert_eq!(pinstrs, vec![PLabel("Lloop".into()), PI(Halt), PI(Pop), PI(Halt), PPush("Lloop".into())]);
+        let columns: Vec<Option<usize>> = errors.iter().map(|e| e.column).collect();
+        assert_eq!(columns, [Some(7), Some(24), Some(27), Some(38)]);
+        assert_eq!(errors[0], PInstr::parse_line(line).unwrap_err());
+
+        let good = "Lloop: push 1; pop";
+        assert_eq!(PInstr::parse_line_recovering(good), (PInstr::parse_line(good).unwrap(), vec![]));
+    }
+    #[test]
+    fn test_arity(){
+        let err = |s: &str| Instr::from_str(s).unwrap_err();
+        assert_eq!(err("pop 3"), ParseError::new(ParseErrorKind::Arity { expected: 0, found: 1 }, "pop").at(4));
+        assert_eq!(err("pop 3").to_string(), "'pop' takes 0 operands, found 1");
+        assert_eq!(err("peek").to_string(), "'peek' takes 1 operand, found 0");
+        assert_eq!(err("binary + 2 3").to_string(), "'binary' takes 1 operand, found 3");
+        assert_eq!(err("binary + 2 3").column, Some(9));
+        assert_eq!(err("  setframe").column, Some(10));
+        // An operand that does not parse is reported as such.
+        assert_eq!(err("peek x").kind, ParseErrorKind::Integer);
+        let err = PInstr::parse_line("push 1; halt now").unwrap_err();
+        assert_eq!((err.to_string(), err.column), ("'halt' takes 0 operands, found 1".to_string(), Some(13)));
+        assert!(PInstr::parse_line("push 1 2").is_err());
+    }
+    #[test]
+    fn test_opcode_values(){
+        // The binary format: these must never change.
+        let opcodes: Vec<(Opcode, u8)> = Opcode::ALL.iter().map(|op| (*op, *op as u8)).collect();
+        assert_eq!(opcodes, vec![
+            (Opcode::Push, 0x00), (Opcode::Pop, 0x01), (Opcode::Peek, 0x02), (Opcode::Unary, 0x03),
+            (Opcode::Binary, 0x04), (Opcode::Swap, 0x05), (Opcode::Alloc, 0x06), (Opcode::Set, 0x07),
+            (Opcode::Get, 0x08), (Opcode::Var, 0x09), (Opcode::Store, 0x0A), (Opcode::SetFrame, 0x0B),
+            (Opcode::Call, 0x0C), (Opcode::Ret, 0x0D), (Opcode::Branch, 0x0E), (Opcode::Halt, 0x0F),
+        ]);
+        assert_eq!([TAG_UNIT, TAG_I32, TAG_TRUE, TAG_FALSE, TAG_LOC, TAG_UNDEF, TAG_SIZE, TAG_ADDR],
+                   [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07]);
+        assert_eq!(Neg as u8, 0x00);
+        let binops: Vec<u8> = Binop::ALL.iter().map(|b| *b as u8).collect();
+        assert_eq!(binops, vec![0x00, 0x01, 0x02, 0x03, 0x04, 0x05]);
+        assert_eq!([Add, Mul, Sub, Div, Lt, Eq], Binop::ALL);
+    }
+    #[test]
+    fn test_from_bytes_errors(){
+        assert_eq!(Instr::from_bytes(&[]), Err(DecodeError::Truncated { what: "instruction", needed: 1, available: 0 }));
+        assert_eq!(Instr::from_bytes(&[0x00, 0x01, 0, 0]),
+                   Err(DecodeError::Truncated { what: "i32", needed: 4, available: 2 }));
+        assert_eq!(Instr::from_bytes(&[0x00]), Err(DecodeError::Truncated { what: "value", needed: 1, available: 0 }));
+        assert_eq!(Instr::from_bytes(&[0x10]),
+                   Err(DecodeError::UnknownOpcode { what: "instruction", opcode: 0x10 }));
+        assert_eq!(Instr::from_bytes(&[0x04, 0x06]),
+                   Err(DecodeError::UnknownOpcode { what: "binop", opcode: 0x06 }));
+        assert_eq!(Val::from_bytes(&[0x07, 0, 0, 0, 0]), Err(DecodeError::Truncated { what: "u64", needed: 8, available: 4 }));
+        assert_eq!(DecodeError::Truncated { what: "u64", needed: 8, available: 4 }.to_string(),
+                   "truncated u64 (needs 8 bytes, 4 left)");
+        assert_eq!(Val::from_bytes(&[0x11]),
+                   Err(DecodeError::UnknownOpcode { what: "value", opcode: 0x11 }));
+        assert_eq!(DecodeError::UnknownOpcode { what: "value", opcode: 0x11 }.to_string(),
+                   "unknown value opcode 0x11");
+    }
+    #[test]
+    fn test_varint_profile(){
+        let varint = |i: Instr| {
+            let mut buf = Vec::new();
+            i.encode_into_profile(&mut buf, Big, Profile::Varint);
+            assert_eq!(Instr::from_bytes_profile(&buf, Big, Profile::Varint), Ok((i, buf.len())), "{}", i);
+            buf
+        };
+        // Opcode and tag, then the operand.
+        for (n, len) in [(0, 1), (63, 1), (64, 2), (127, 2), (128, 2), (-1, 1), (-64, 1), (-65, 2),
+                         (8191, 2), (8192, 3), (i32::MAX, 5), (i32::MIN, 5)] {
+            assert_eq!(varint(Push(Vi32(n))).len(), 2 + len, "{}", n);
+            assert_eq!(varint(Push(Vsize(n))).len(), 2 + len, "{}", n);
+        }
+        for (n, len) in [(0, 1), (127, 1), (128, 2), (16383, 2), (16384, 3), (u32::MAX, 5)] {
+            assert_eq!(varint(Push(Vloc(n))).len(), 2 + len, "{}", n);
+            assert_eq!(varint(Var(n)).len(), 1 + len, "{}", n);
+            assert_eq!(varint(SetFrame(n)).len(), 1 + len, "{}", n);
+        }
+        assert_eq!(varint(Push(Vi32(-1))), vec![0x00, 0x01, 0x7f]);
+        assert_eq!(varint(Peek(300)), vec![0x02, 0xac, 0x02]);
+        assert_eq!(varint(Push(Vaddr(0xdead_beef))).len(), 7);
+        assert_eq!(varint(Binary(Eq)), Binary(Eq).to_bytes());
+
+        let decode = |bytes: &[u8]| Instr::from_bytes_profile(bytes, Big, Profile::Varint);
+        assert_eq!(decode(&[0x09, 0x80, 0x80, 0x80, 0x80, 0x10]), Err(DecodeError::Overflow { what: "u32" }));
+        assert_eq!(decode(&[0x00, 0x01, 0x80, 0x80, 0x80, 0x80, 0x08]), Err(DecodeError::Overflow { what: "i32" }));
+        assert_eq!(decode(&[0x00, 0x07, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]),
+                   Err(DecodeError::Overflow { what: "u64" }));
+        assert_eq!(decode(&[0x0A, 0x80]), Err(DecodeError::Truncated { what: "u32", needed: 2, available: 1 }));
+        assert_eq!(DecodeError::Overflow { what: "u32" }.to_string(), "varint too large for u32");
+    }
+    #[test]
+    fn test_internal_vals_encoding(){
+        let vals = [Vunit, Vi32(7), Vbool(true), Vbool(false), Vloc(7), Vundef,
+                    Vsize(7), Vsize(-1), Vaddr(7), Vaddr(0xdead_beef)];
+        for e in [Big, Little] {
+            let encoded: Vec<Vec<u8>> = vals.iter().map(|v| v.to_bytes_with(e)).collect();
+            // Injective: distinct values never share an encoding.
+            for (i, a) in encoded.iter().enumerate() {
+                for b in &encoded[i + 1..] {
+                    assert_ne!(a, b);
+                }
+            }
+            for (v, bytes) in vals.iter().zip(&encoded) {
+                assert_eq!(Val::from_bytes_with(bytes, e).unwrap(), (*v, bytes.len()));
+            }
+        }
+        assert_eq!(Vsize(3).to_bytes(), vec![0x06, 0, 0, 0, 3]);
+        assert_eq!(Vaddr(0x10).to_bytes(), vec![0x07, 0, 0, 0, 0, 0, 0, 0, 0x10]);
+    }
+    #[test]
+    fn test_display_internal_vals(){
+        assert_eq!(Vsize(3).to_string(), "size(3)");
+        assert_eq!(Vaddr(10).to_string(), "addr(0xa)");
+        assert_eq!(Push(Vaddr(0x7f)).to_string(), "push addr(0x7f)");
+    }
+    #[test]
+    fn test_parse_never_internal(){
+        for s in ["size(3)", "addr(0x7f)", "0", "-1", "4294967295", "tt", "undef", "true"] {
+            if let Ok(v) = Val::from_str(s) {
+                assert!(!v.is_internal(), "{} parsed to internal value {:?}", s, v);
+            }
+        }
+        assert!(Vsize(3).is_internal() && Vaddr(3).is_internal());
+        assert!(!Vloc(3).is_internal() && !Vi32(3).is_internal());
+    }
+    #[test]
+    fn test_parse_line_separators(){
+        assert_eq!(PInstr::parse_line("push 1; push 2; binary +").unwrap(),
+                   vec![PI(Push(Vi32(1))), PI(Push(Vi32(2))), PI(Binary(Add))]);
+        assert_eq!(PInstr::parse_line("Lloop: ; push Lloop;branch").unwrap(),
+                   vec![PLabel("Lloop".into()), PPush("Lloop".into()), PI(Branch)]);
+        assert_eq!(PInstr::parse_line("pop;").unwrap(), vec![PI(Pop)]);
+    }
+    #[test]
+    fn test_parse_line_comments(){
+        assert_eq!(PInstr::parse_line("push 1 // push 2; pop").unwrap(),
+                   vec![PI(Push(Vi32(1)))]);
+        assert_eq!(PInstr::parse_line("// just a comment").unwrap(), vec![]);
+        assert_eq!(PInstr::parse_line("   ").unwrap(), vec![]);
+        assert!(PInstr::parse_line("push 1; bogus").is_err());
+    }
+    #[test]
+    fn test_parse_string(){
+        assert_eq!(PInstr::parse_line(r#".string "a; b // c"; pop // d"#).unwrap(),
+                   vec![PDirective(Str("a; b // c".into())), PI(Pop)]);
+        assert_eq!(PInstr::parse_line(r#".string "say \"hi\"\n""#).unwrap(),
+                   vec![PDirective(Str("say \"hi\"\n".into()))]);
+        assert!(PInstr::parse_line(r#".string "open"#).is_err());
+        assert!(PInstr::parse_line(r#".string "a" "b""#).is_err());
+        assert!(PInstr::parse_line(r#".string "\q""#).is_err());
+        assert_eq!(lowercase_opcodes(r#".STRING "A; B" // C"#), r#".string "A; B" // C"#);
+    }
+    #[test]
+    fn test_parse_data(){
+        assert_eq!(PInstr::parse_line(".byte 0x01 2 0xFF; .i32 -5").unwrap(),
+                   vec![PDirective(Bytes(vec![1, 2, 255])), PDirective(I32(-5))]);
+        assert!(PInstr::parse_line(".byte 256").is_err());
+        assert!(PInstr::parse_line(".byte").is_err());
+        assert!(PInstr::parse_line(".i32 1 2").is_err());
+        assert_eq!(Byte(0xab).to_bytes_with(Little), vec![0xab]);
+        assert_eq!(Byte(0xab).to_string(), ".byte 0xab");
+    }
+    /// Every operand form survives a trip through its text, the way
+    /// the disassembler relies on.
+    #[test]
+    fn test_display_round_trip(){
+        let vals = [Vunit, Vundef, Vbool(true), Vbool(false), Vi32(0), Vi32(1), Vi32(-1),
+                    Vi32(i32::MIN), Vi32(i32::MAX), Vloc(i32::MAX as u32 + 1), Vloc(u32::MAX)];
+        for v in vals {
+            assert_eq!(v.to_string().parse::<Val>(), Ok(v), "{}", v);
+        }
+        let mut instrs: Vec<Instr> = vals.iter().map(|v| Push(*v)).collect();
+        for n in [0, 1, i32::MAX as u32, u32::MAX] {
+            instrs.extend([Peek(n), Var(n), Store(n), SetFrame(n)]);
+        }
+        instrs.extend([Add, Mul, Sub, Div, Lt, Eq].iter().map(|b| Binary(*b)));
+        instrs.extend([Unary(Neg), Pop, Swap, Alloc, Set, Get, Call, Ret, Branch, Halt]);
+        for i in instrs {
+            assert_eq!(i.to_string().parse::<Instr>(), Ok(i), "{}", i);
+            assert_eq!(PI(i).to_string().parse::<PInstr>(), Ok(PI(i)), "{}", i);
+        }
+        assert_eq!("2147483648".parse::<Val>(), Ok(Vloc(2147483648)));
+    }
+    #[test]
+    fn test_location_syntax(){
+        assert_eq!(Push(Vloc(5)).to_string(), "push @5");
+        assert_eq!(Push(Vloc(5)).to_string().parse::<Instr>(), Ok(Push(Vloc(5))));
+        assert_eq!(PI(Push(Vloc(5))).to_string().parse::<PInstr>(), Ok(PI(Push(Vloc(5)))));
+        assert_eq!("5".parse::<Val>(), Ok(Vi32(5)));
+        for bad in ["@", "@-1", "@x", "@4294967296", "@ 5"] {
+            assert_eq!(bad.parse::<Val>(), Err(ParseError::new(ParseErrorKind::Value, bad)), "{}", bad);
+        }
+    }
+    #[test]
+    fn test_parse_error_messages(){
+        let message = |line: &str| PInstr::parse_line(line).unwrap_err().to_string();
+        assert_eq!(message("push 1.5"), "could not parse value '1.5'");
+        assert_eq!(message("bogus 3"), "could not parse instruction 'bogus 3'");
+        assert_eq!(message("peek x"), "could not parse integer 'x'");
+        assert_eq!(message(".byte 0x1g"), "could not parse integer '0x1g'");
+        assert_eq!(message(".loop"), "could not parse directive '.loop'");
+        assert_eq!(message(".global L_x"), "could not parse label 'L_x'");
+        assert_eq!(message(".equ 9 1"), "could not parse constant name '9'");
+        assert_eq!(message(r#".string "a"#), r#"could not parse string literal '"a'"#);
+        assert_eq!(Binop::from_str("%").unwrap_err(),
+                   ParseError::new(ParseErrorKind::BinaryOperator, "%"));
+        assert_eq!(Unop::from_str("not").unwrap_err().to_string(),
+                   "could not parse unary operator 'not'");
+    }
+    #[test]
+    fn test_parse_error_columns(){
+        let column = |line: &str| PInstr::parse_line(line).unwrap_err().column;
+        assert_eq!(column("push 1.5"), Some(5));
+        assert_eq!(column("  pop; push 1; peek   x // y"), Some(22));
+        assert_eq!(column("push 1; bogus"), Some(8));
+        assert_eq!(column(".times 2 { pop; peek -1 }"), Some(21));
+        let err = PInstr::parse_line("pop;\tpush 1.5").unwrap_err();
+        assert_eq!(err.caret("pop;\tpush 1.5").unwrap(), "pop;\tpush 1.5\n    \t     ^");
+        assert_eq!(Val::from_str("x").unwrap_err().caret("x"), None);
+    }
+    #[test]
+    fn test_parse_adversarial(){
+        let inputs = ["", " ", "\t", "push", "push ", "pop extra", "unary", "unary %", "binary",
+                      "binary ^", "peek", "var", "store", "setframe", ":", "L", "_", "_L", "L:", "::",
+                      ".", ".L", ".L:", "@", "push @", "push @-1", "push L+", "push Lx-", "push .",
+                      ".equ", ".equ N", ".global", ".byte", ".i32", ".times", ".string", ".datastring", "\"",
+                      "push 99999999999999999999999", "peek 4294967296", ".i32 -2147483649",
+                      "push \u{e9}", "\u{e9}:", "_\u{e9}", "L\u{e9}"];
+        for input in inputs {
+            // Neither statement parsing nor line parsing may panic.
+            let _ = PInstr::from_str(input);
+            let _ = PInstr::parse_line(input);
+            assert!(Instr::from_str(input).is_err(), "{:?}", input);
+        }
+        assert!(PInstr::from_str("").is_err());
+        assert!(PInstr::from_str("  ").is_err());
+        for input in ["push", "unary", "binary %", "peek", "_", ".L", "@", "push @", "push L+",
+                      "push 99999999999999999999999", "\u{e9}:", "_\u{e9}"] {
+            assert!(PInstr::from_str(input).is_err(), "{:?}", input);
+        }
+        let err = PInstr::parse_line("pop; push").unwrap_err();
+        assert_eq!((err.kind, err.token.as_str(), err.column),
+                   (ParseErrorKind::Arity { expected: 1, found: 0 }, "push", Some(9)));
+        let err = PInstr::from_str("unary  ").unwrap_err();
+        assert_eq!((err.kind, err.column), (ParseErrorKind::Arity { expected: 1, found: 0 }, Some(5)));
+    }
+    #[test]
+    fn test_tokenize(){
+        assert_eq!(tokenize("push 1"), vec![(0, "push"), (5, "1")]);
+        assert_eq!(tokenize("  peek    3  "), vec![(2, "peek"), (10, "3")]);
+        assert_eq!(tokenize("\tbinary\t+"), vec![(1, "binary"), (8, "+")]);
+        assert_eq!(tokenize(""), vec![]);
+        assert_eq!(tokenize("   "), vec![]);
+        assert_eq!(tokenize(r#".string "a  b" x"#), vec![(0, ".string"), (8, r#""a  b""#), (15, "x")]);
+        assert_eq!(tokenize(r#".string "\" ""#), vec![(0, ".string"), (8, r#""\" ""#)]);
+        assert_eq!(tokenize("\u{e9}t\u{e9} 2"), vec![(0, "\u{e9}t\u{e9}"), (6, "2")]);
+    }
+    #[test]
+    fn test_operand_columns(){
+        let column = |s: &str| PInstr::from_str(s).unwrap_err().column;
+        assert_eq!(column("  store   x"), Some(10));
+        assert_eq!(column(".byte 1 2   0x100"), Some(12));
+        assert_eq!(column(".equ N  1.5"), Some(8));
+        assert_eq!(column("push  Lx+y"), Some(8));
+        assert_eq!(column(r#".string  "a" b"#), Some(9));
+        assert_eq!(column(".times x {"), Some(7));
+        assert_eq!(column("  pusj 1"), Some(2));
+    }
+    #[test]
+    fn test_parse_equ(){
+        assert_eq!(PInstr::parse_line(".equ STACK_SIZE 256; push STACK_SIZE").unwrap(),
+                   vec![PDirective(Equ("STACK_SIZE".into(), Vi32(256))),
+                        PPushConst("STACK_SIZE".into())]);
+        // Label-shaped names and values can't be constants.
+        assert_eq!(PInstr::parse_line("push LIMIT").unwrap(), vec![PPush("LIMIT".into())]);
+        assert!(PInstr::parse_line(".equ LIMIT 3").is_err());
+        assert!(PInstr::parse_line(".equ true 3").is_err());
+        assert!(PInstr::parse_line(".equ 3X 3").is_err());
+        assert!(PInstr::parse_line(".equ N").is_err());
+        assert!(PInstr::parse_line(".equ N Lf").is_err());
+    }
+    #[test]
+    fn test_parse_times(){
+        assert_eq!(PInstr::parse_line(".times 3 { pop }").unwrap(),
+                   vec![PDirective(Times(3)), PI(Pop), PDirective(EndTimes)]);
+        assert_eq!(PInstr::parse_line(".times 2{push 1; .times 2 {pop}}").unwrap(),
+                   vec![PDirective(Times(2)), PI(Push(Vi32(1))), PDirective(Times(2)), PI(Pop),
+                        PDirective(EndTimes), PDirective(EndTimes)]);
+        assert_eq!(PInstr::parse_line(r#".string "{}""#).unwrap(),
+                   vec![PDirective(Str("{}".into()))]);
+        assert!(PInstr::parse_line(".times -1 { pop }").is_err());
+        assert!(PInstr::parse_line(".times { pop }").is_err());
+        assert_eq!(lowercase_opcodes(".TIMES 2 {POP; Halt}"), ".times 2 {pop; halt}");
+    }
+    #[test]
+    fn test_lowercase_opcodes(){
+        assert!(PInstr::parse_line("PUSH 3").is_err());
+        assert_eq!(PInstr::parse_line(&lowercase_opcodes("PUSH 3")).unwrap(),
+                   vec![PI(Push(Vi32(3)))]);
+        assert_eq!(PInstr::parse_line(&lowercase_opcodes("Halt")).unwrap(), vec![PI(Halt)]);
+        assert_eq!(lowercase_opcodes("  Push Lmain; CALL"), "  push Lmain; call");
+        assert_eq!(lowercase_opcodes("LMain: ;Push LMain"), "LMain: ;push LMain");
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip(){
+        use serde_json::{from_str, to_string};
+        let vals = [Vunit, Vundef, Vbool(true), Vi32(-7), Vloc(u32::MAX), Vsize(2), Vaddr(0xdead_beef)];
+        let mut instrs: Vec<Instr> = vals.iter().map(|v| Push(*v)).collect();
+        instrs.extend([Peek(1), Var(2), Store(3), SetFrame(4), Unary(Neg), Byte(0xff)]);
+        instrs.extend([Add, Mul, Sub, Div, Lt, Eq].iter().map(|b| Binary(*b)));
+        instrs.extend([Pop, Swap, Alloc, Set, Get, Call, Ret, Branch, Halt]);
+        for i in &instrs {
+            assert_eq!(from_str::<Instr>(&to_string(i).unwrap()).unwrap(), *i);
+        }
+        let directives = vec![While, Do, EndWhile, Break, Str("hi \"x\"".to_string()), DataStr("yo".to_string()),
+                              Bytes(vec![0, 255]),
+                              I32(-1), Align(4), Include("lib.s".to_string()), Times(3), EndTimes,
+                              Equ("N".to_string(), Vi32(10)), JumpTable(vec!["La".to_string(), "Lb".to_string()])];
+        let mut pinstrs = vec![PLabel("Lf".to_string()), PPush("Lf".to_string()),
+                               PPushOffset("Lf".to_string(), -2), PRel(".Lx".to_string()),
+                               PPushConst("N".to_string()), PGlobal("Lf".to_string()),
+                               PLocal("Lg".to_string()), PI(Halt)];
+        pinstrs.extend(directives.into_iter().map(PDirective));
+        for i in &pinstrs {
+            assert_eq!(&from_str::<PInstr>(&to_string(i).unwrap()).unwrap(), i);
+        }
+
+        // Values are tagged with their variant's name.
+        assert_eq!(to_string(&Vunit).unwrap(), r#""Vunit""#);
+        assert_eq!(to_string(&Push(Vi32(-7))).unwrap(), r#"{"Push":{"Vi32":-7}}"#);
+        assert_eq!(to_string(&Binary(Lt)).unwrap(), r#"{"Binary":"Lt"}"#);
+        assert_eq!(to_string(&Vaddr(0xdead_beef)).unwrap(), r#"{"Vaddr":3735928559}"#);
+        assert!(from_str::<Val>(r#"{"Vaddr":-1}"#).is_err());
+        assert!(from_str::<Val>(r#"{"Vi64":1}"#).is_err());
+    }
 }
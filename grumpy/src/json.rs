@@ -0,0 +1,467 @@
+use crate::expand::Diagnostic;
+use crate::isa::{Instr, Instr::*, Val, Val::*};
+use crate::object::{DataSegment, LineTable, Metadata, ObjectFile, SymbolTable};
+use crate::Profile;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::{error, fmt};
+
+/// An error in a JSON program, at the given byte offset of the text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.message)
+    }
+}
+
+impl error::Error for JsonError {}
+
+fn error(offset: usize, message: &str) -> JsonError {
+    JsonError { offset, message: message.to_string() }
+}
+
+/// Write a program as JSON, for tools that would rather not decode
+/// object files. The text is an object with the keys
+///
+/// - `"entry"`: the entry pc, a number;
+/// - `"instrs"`: the instructions, in order;
+/// - `"symbols"`: present only if the program has symbols, an object
+///   mapping each label to its pc.
+///
+/// Each instruction is an object whose `"op"` is its mnemonic, as in
+/// assembly (`"push"`, `"binary"`, ..., and `"byte"` for a data byte),
+/// and
+///
+/// - for `push`, a `"type"` of `"unit"`, `"undef"`, `"i32"`, `"bool"`,
+///   `"loc"`, `"size"` or `"addr"`, and for all but the first two, a
+///   `"value"`, a number (or `true`/`false` for a bool);
+/// - for `peek`, `var`, `store`, `setframe` and `byte`, an `"arg"`
+///   number;
+/// - for `unary` and `binary`, an `"arg"` string, the operator as in
+///   assembly (`"neg"`, `"+"`, `"=="`, ...).
+///
+/// For example, `{"op": "push", "type": "loc", "value": 4}` is
+/// `push @4`. The profile and debug lines of an `ObjectFile` are not
+/// written. The output puts one instruction per line, so that it diffs
+/// well.
+pub fn to_json(program: &ObjectFile) -> String {
+    let mut out = format!("{{\n  \"entry\": {},\n  \"instrs\": [", program.entry);
+    for (n, i) in program.instrs.iter().enumerate() {
+        out.push_str(if n == 0 { "\n    " } else { ",\n    " });
+        write_instr(&mut out, i);
+    }
+    out.push_str(if program.instrs.is_empty() { "]" } else { "\n  ]" });
+    if !program.symbols.is_empty() {
+        out.push_str(",\n  \"symbols\": {");
+        for (n, (label, pc)) in program.symbols.iter().enumerate() {
+            out.push_str(if n == 0 { "\n    " } else { ",\n    " });
+            write_string(&mut out, label);
+            write!(out, ": {}", pc).unwrap();
+        }
+        out.push_str("\n  }");
+    }
+    out.push_str("\n}\n");
+    out
+}
+
+/// Write a diagnostic as one line of JSON, for editors: an object with
+/// the keys `"file"`, `"line"` and `"col"` (1-based numbers, or `null`
+/// where not known), `"severity"` (`"error"` or `"warning"`) and
+/// `"message"`. For example,
+///
+/// ```text
+/// {"file": "prog.s", "line": 5, "col": 3, "severity": "error", "message": "could not parse integer 'x'"}
+/// ```
+pub fn diagnostic_to_json(diagnostic: &Diagnostic) -> String {
+    let number = |n: Option<usize>| n.map_or("null".to_string(), |n| n.to_string());
+    let mut out = "{\"file\": ".to_string();
+    write_string(&mut out, &diagnostic.path.to_string_lossy());
+    write!(out, ", \"line\": {}, \"col\": {}, \"severity\": \"{}\", \"message\": ",
+           number(diagnostic.line), number(diagnostic.column), diagnostic.severity).unwrap();
+    write_string(&mut out, &diagnostic.message);
+    out.push('}');
+    out
+}
+
+fn write_instr(out: &mut String, i: &Instr) {
+    let op = match i {
+        Byte(_) => "byte".to_string(),
+        i => i.to_string().split(' ').next().unwrap().to_string(),
+    };
+    write!(out, "{{\"op\": \"{}\"", op).unwrap();
+    match i {
+        Push(v) => {
+            let (ty, value) = match v {
+                Vunit => ("unit", None),
+                Vundef => ("undef", None),
+                Vi32(n) => ("i32", Some(n.to_string())),
+                Vbool(b) => ("bool", Some(b.to_string())),
+                Vloc(n) => ("loc", Some(n.to_string())),
+                Vsize(n) => ("size", Some(n.to_string())),
+                Vaddr(a) => ("addr", Some(a.to_string())),
+            };
+            write!(out, ", \"type\": \"{}\"", ty).unwrap();
+            if let Some(value) = value {
+                write!(out, ", \"value\": {}", value).unwrap();
+            }
+        }
+        Peek(n) | Var(n) | Store(n) | SetFrame(n) => write!(out, ", \"arg\": {}", n).unwrap(),
+        Byte(b) => write!(out, ", \"arg\": {}", b).unwrap(),
+        Unary(u) => write!(out, ", \"arg\": \"{}\"", u).unwrap(),
+        Binary(b) => write!(out, ", \"arg\": \"{}\"", b).unwrap(),
+        _ => (),
+    }
+    out.push('}');
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Read a program written by `to_json`. Keys it does not know are
+/// ignored, so that later versions may add some. The program has the
+/// fixed-width profile and no debug lines.
+pub fn from_json(text: &str) -> Result<ObjectFile, JsonError> {
+    let mut parser = Parser { text, at: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.at < text.len() {
+        return Err(error(parser.at, "trailing characters after the program"));
+    }
+    let entry = value.field("entry")?.number()?;
+    let instrs = value.field("instrs")?.array()?.iter().map(read_instr).collect::<Result<_, _>>()?;
+    let mut symbols = SymbolTable::new();
+    if let Some(table) = value.get("symbols")? {
+        for (label, pc) in table.object()? {
+            symbols.insert(label.clone(), pc.number()?);
+        }
+    }
+    Ok(ObjectFile { entry, instrs, symbols, profile: Profile::Fixed, lines: LineTable::default(),
+                    metadata: Metadata::new(), data: DataSegment::default() })
+}
+
+fn read_instr(i: &Value) -> Result<Instr, JsonError> {
+    let op = i.field("op")?;
+    let arg = || i.field("arg");
+    Ok(match op.string()? {
+        "push" => Push(read_val(i)?),
+        "pop" => Pop,
+        "peek" => Peek(arg()?.number()?),
+        "unary" => Unary(operator(arg()?, "unary operator")?),
+        "binary" => Binary(operator(arg()?, "binary operator")?),
+        "swap" => Swap,
+        "alloc" => Alloc,
+        "set" => Set,
+        "get" => Get,
+        "var" => Var(arg()?.number()?),
+        "store" => Store(arg()?.number()?),
+        "setframe" => SetFrame(arg()?.number()?),
+        "call" => Call,
+        "ret" => Ret,
+        "branch" => Branch,
+        "halt" => Halt,
+        "byte" => Byte(arg()?.number()?),
+        s => return Err(error(op.at, &format!("unknown op '{}'", s))),
+    })
+}
+
+fn operator<T: FromStr>(arg: &Value, what: &str) -> Result<T, JsonError> {
+    let s = arg.string()?;
+    s.parse().map_err(|_| error(arg.at, &format!("unknown {} '{}'", what, s)))
+}
+
+fn read_val(i: &Value) -> Result<Val, JsonError> {
+    let ty = i.field("type")?;
+    let value = || i.field("value");
+    Ok(match ty.string()? {
+        "unit" => Vunit,
+        "undef" => Vundef,
+        "i32" => Vi32(value()?.number()?),
+        "bool" => match value()? {
+            Value { kind: Kind::Bool(b), .. } => Vbool(*b),
+            v => return Err(error(v.at, "expected true or false")),
+        },
+        "loc" => Vloc(value()?.number()?),
+        "size" => Vsize(value()?.number()?),
+        "addr" => Vaddr(value()?.number()?),
+        s => return Err(error(ty.at, &format!("unknown value type '{}'", s))),
+    })
+}
+
+/// A JSON value, with the offset it starts at.
+#[derive(Debug)]
+struct Value {
+    at: usize,
+    kind: Kind,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Null,
+    Bool(bool),
+    /// Programs hold only integers, so no other number is read.
+    Number(i128),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn object(&self) -> Result<&[(String, Value)], JsonError> {
+        match &self.kind {
+            Kind::Object(fields) => Ok(fields),
+            _ => Err(error(self.at, "expected an object")),
+        }
+    }
+
+    /// The value of a key of an object, if it has it.
+    fn get(&self, key: &str) -> Result<Option<&Value>, JsonError> {
+        Ok(self.object()?.iter().find(|(k, _)| k == key).map(|(_, v)| v))
+    }
+
+    fn field(&self, key: &str) -> Result<&Value, JsonError> {
+        self.get(key)?.ok_or_else(|| error(self.at, &format!("missing \"{}\"", key)))
+    }
+
+    fn array(&self) -> Result<&[Value], JsonError> {
+        match &self.kind {
+            Kind::Array(items) => Ok(items),
+            _ => Err(error(self.at, "expected an array")),
+        }
+    }
+
+    fn string(&self) -> Result<&str, JsonError> {
+        match &self.kind {
+            Kind::String(s) => Ok(s),
+            _ => Err(error(self.at, "expected a string")),
+        }
+    }
+
+    fn number<T: TryFrom<i128>>(&self) -> Result<T, JsonError> {
+        match self.kind {
+            Kind::Number(n) => T::try_from(n).map_err(|_| error(self.at, &format!("{} is out of range", n))),
+            _ => Err(error(self.at, "expected a number")),
+        }
+    }
+}
+
+/// A recursive descent parser for the JSON that `from_json` reads.
+struct Parser<'a> {
+    text: &'a str,
+    at: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.at).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.at += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(error(self.at, &format!("expected '{}'", c as char)));
+        }
+        self.at += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Value, JsonError> {
+        self.skip_whitespace();
+        let at = self.at;
+        let rest = &self.text[at..];
+        let kind = match self.peek() {
+            Some(b'{') => Kind::Object(self.list(b'}', |p| {
+                let key = p.string()?;
+                p.expect(b':')?;
+                Ok((key, p.value()?))
+            })?),
+            Some(b'[') => Kind::Array(self.list(b']', Parser::value)?),
+            Some(b'"') => Kind::String(self.string()?),
+            Some(b'-' | b'0'..=b'9') => {
+                let len = rest.find(|c: char| !(c == '-' || c.is_ascii_digit())).unwrap_or(rest.len());
+                if let Some(b'.' | b'e' | b'E') = rest.as_bytes().get(len) {
+                    return Err(error(at, "expected an integer"));
+                }
+                self.at += len;
+                Kind::Number(rest[..len].parse().map_err(|_| error(at, "invalid number"))?)
+            }
+            _ => {
+                let (word, kind) = vec![("true", Kind::Bool(true)), ("false", Kind::Bool(false)), ("null", Kind::Null)]
+                    .into_iter()
+                    .find(|(word, _)| rest.starts_with(word))
+                    .ok_or_else(|| error(at, "expected a value"))?;
+                self.at += word.len();
+                kind
+            }
+        };
+        Ok(Value { at, kind })
+    }
+
+    /// The comma-separated items of an object or array, from its opening
+    /// bracket to `close`.
+    fn list<T>(&mut self, close: u8, mut item: impl FnMut(&mut Self) -> Result<T, JsonError>)
+               -> Result<Vec<T>, JsonError> {
+        self.at += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(close) {
+            self.at += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.at += 1,
+                Some(c) if c == close => {
+                    self.at += 1;
+                    return Ok(items);
+                }
+                _ => return Err(error(self.at, &format!("expected ',' or '{}'", close as char))),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect(b'"')?;
+        let mut s = String::new();
+        let mut chars = self.text[self.at..].char_indices();
+        loop {
+            let (i, c) = chars.next().ok_or_else(|| error(self.text.len(), "unterminated string"))?;
+            match c {
+                '"' => {
+                    self.at += i + 1;
+                    return Ok(s);
+                }
+                '\\' => {
+                    let escape = match chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, '/')) => '/',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((j, 'u')) => {
+                            let hex = self.text.get(self.at + j + 1..self.at + j + 5);
+                            let c = hex.and_then(|h| u32::from_str_radix(h, 16).ok()).and_then(char::from_u32);
+                            chars.nth(3);
+                            c.ok_or_else(|| error(self.at + i, "invalid \\u escape"))?
+                        }
+                        _ => return Err(error(self.at + i, "invalid escape")),
+                    };
+                    s.push(escape);
+                }
+                c => s.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::PInstr;
+
+    /// A program using every instruction and value type.
+    fn program() -> ObjectFile {
+        let src = "Lmain:; push tt; push undef; push -5; push true; push Lend; peek 1; var 0; store 2;
+                   setframe 3; unary neg; binary +; binary *; binary -; binary /; binary <; binary ==;
+                   pop; swap; alloc; set; get; call; ret; branch; Lend:; halt";
+        let (mut instrs, symbols) = crate::assemble::assemble_with_symbols(&PInstr::parse_line(src).unwrap()).unwrap();
+        instrs.pop();
+        let entry = instrs.len() as u32;
+        instrs.extend([Byte(0xff), Push(Vsize(2)), Push(Vaddr(0xdead_beef))]);
+        ObjectFile {
+            entry,
+            instrs,
+            symbols: crate::object::symbol_table(&symbols),
+            profile: Profile::Fixed,
+            lines: LineTable::default(),
+            metadata: Metadata::new(),
+            data: DataSegment::default(),
+        }
+    }
+
+    #[test]
+    fn test_golden() {
+        let golden = include_str!("../tests/golden/program.json");
+        assert_eq!(to_json(&program()), golden);
+        assert_eq!(from_json(golden).unwrap(), program());
+
+        let bare = ObjectFile { symbols: SymbolTable::new(), instrs: vec![], ..program() };
+        let golden = include_str!("../tests/golden/empty.json");
+        assert_eq!(to_json(&bare), golden);
+        assert_eq!(from_json(golden).unwrap(), bare);
+    }
+
+    #[test]
+    fn test_diagnostic_to_json() {
+        use crate::expand::{parse_file, Diagnostic, ParseErrors};
+        use std::path::Path;
+        let dir = crate::temp_dir("json-diagnostic");
+        std::fs::write(dir.join("prog.s"), "push 1\n  peek x\n").unwrap();
+        let err = parse_file(&dir.join("prog.s"), false).unwrap_err();
+        let errors = err.into_inner().unwrap().downcast::<ParseErrors>().unwrap();
+        let diagnostic = errors.errors[0].diagnostic();
+        assert_eq!(diagnostic.to_string(), format!("{}:2:8: error: could not parse integer 'x'\n  peek x\n       ^",
+                                                   dir.join("prog.s").display()));
+        let json = diagnostic_to_json(&Diagnostic { path: "prog.s".into(), ..diagnostic });
+        assert_eq!(json, r#"{"file": "prog.s", "line": 2, "col": 8, "severity": "error", "message": "could not parse integer 'x'"}"#);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let warning = Diagnostic::warning(Path::new("a \"b\".s"), None, "no 'halt'\n");
+        assert_eq!(warning.to_string(), "a \"b\".s: warning: no 'halt'\n");
+        assert_eq!(diagnostic_to_json(&warning),
+                   r#"{"file": "a \"b\".s", "line": null, "col": null, "severity": "warning", "message": "no 'halt'\u000a"}"#);
+    }
+
+    #[test]
+    fn test_from_json() {
+        // Any layout, unknown keys and escapes.
+        let text = r#" {"instrs":[{"op":"push","type":"bool","value":false,"note":null},{"arg":"<","op":"binary"}],
+                        "version":[1,{}],"entry":2,"symbols":{"LA\"":1}} "#;
+        let o = from_json(text).unwrap();
+        assert_eq!((o.entry, o.instrs), (2, vec![Push(Vbool(false)), Binary(crate::isa::Binop::Lt)]));
+        assert_eq!(o.symbols.into_iter().collect::<Vec<_>>(), vec![("LA\"".to_string(), 1)]);
+
+        let err = |text: &str| from_json(text).unwrap_err().to_string();
+        assert_eq!(err(r#"{"entry": 0}"#), "byte 0: missing \"instrs\"");
+        assert_eq!(err(r#"{"entry": -1, "instrs": []}"#), "byte 10: -1 is out of range");
+        assert_eq!(err(r#"{"entry": 1.5, "instrs": []}"#), "byte 10: expected an integer");
+        assert_eq!(err(r#"{"entry": 0, "instrs": [{"op": "jump"}]}"#), "byte 31: unknown op 'jump'");
+        assert_eq!(err(r#"{"entry": 0, "instrs": [{"op": "peek"}]}"#), "byte 24: missing \"arg\"");
+        assert_eq!(err(r#"{"entry": 0, "instrs": [{"op": "binary", "arg": "%"}]}"#),
+                   "byte 48: unknown binary operator '%'");
+        assert_eq!(err(r#"{"entry": 0, "instrs": [{"op": "push", "type": "bool", "value": 1}]}"#),
+                   "byte 64: expected true or false");
+        assert_eq!(err(r#"{"entry": 0, "instrs": [{"op": "byte", "arg": 256}]}"#), "byte 46: 256 is out of range");
+        assert_eq!(err(r#"{"entry": 0, "instrs": [] "#), "byte 26: expected ',' or '}'");
+        assert_eq!(err(r#"{"entry": 0, "instrs": []} x"#), "byte 27: trailing characters after the program");
+        assert_eq!(err(r#"{"entry": 0, "instrs": [], "symbols": {"L": "x"}}"#), "byte 44: expected a number");
+        assert_eq!(err(r#"["#), "byte 1: expected a value");
+        assert_eq!(err(r#"{"a"#), "byte 3: unterminated string");
+    }
+}
@@ -1,37 +1,301 @@
 //! The Grumpy compiler.
+//!
+//! Without the default `std` feature, only `isa` and the types here are
+//! built, needing `core` and `alloc` but not `std`, so that a VM without
+//! an operating system can parse and encode programs.
 
 #![warn(clippy::all)]
-use std::{error, fmt, io, num};
+#![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
+extern crate alloc;
 
-// Declare 'isa' and 'assemble' as modules in the grumpy crate.
+use alloc::{format, string::{String, ToString}, vec::Vec};
+use core::{error, fmt};
+#[cfg(feature = "std")]
+use std::io;
+
+// Declare the modules of the grumpy crate. All but `isa` need `std`.
+#[cfg(feature = "std")]
 pub mod assemble;
+#[cfg(feature = "std")]
+pub mod base64;
+#[cfg(feature = "std")]
+pub mod compress;
+#[cfg(feature = "std")]
+pub mod diff;
+#[cfg(feature = "std")]
+pub mod disassemble;
+#[cfg(feature = "std")]
+pub mod emit;
+#[cfg(feature = "std")]
+pub mod expand;
+#[cfg(feature = "std")]
+pub mod ihex;
 pub mod isa;
+#[cfg(feature = "std")]
+pub mod json;
+#[cfg(feature = "std")]
+pub mod link;
+#[cfg(feature = "std")]
+pub mod object;
+#[cfg(feature = "std")]
+pub mod session;
+
+/// Byte order of multi-byte operands in the binary representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endianness {
+    /// Most significant byte first, as GrumpyVM expects (the default).
+    #[default]
+    Big,
+    /// Least significant byte first.
+    Little,
+}
+
+/// How integer operands (of `push`, `peek`, `var`, ...) are encoded.
+/// Opcodes and value tags are the same either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    /// Fixed width, in the byte order given alongside (the default, and
+    /// what GrumpyVM loads).
+    #[default]
+    Fixed,
+    /// LEB128 varints, signed for `i32`s, which take a byte for values
+    /// below 64 in magnitude. The byte order does not apply.
+    Varint,
+}
 
 // Trait for types that can be converted to a binary representation.
 pub trait ToBytes {
-    fn to_bytes(&self) -> Vec<u8>;
+    /// Encode with the default (big-endian) byte order.
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with(Endianness::Big)
+    }
+
+    /// Encode with the given byte order for multi-byte operands, into a
+    /// vector allocated once, at its final size.
+    fn to_bytes_with(&self, endianness: Endianness) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.byte_len());
+        self.encode_into_with(&mut buf, endianness);
+        buf
+    }
+
+    /// Append the big-endian encoding to `buf`, without allocating a
+    /// vector of its own.
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        self.encode_into_with(buf, Endianness::Big)
+    }
+
+    /// Append the encoding, with the given byte order, to `buf`.
+    fn encode_into_with(&self, buf: &mut Vec<u8>, endianness: Endianness);
+
+    /// The length of the encoding, which is the same for either byte
+    /// order. The default encodes and measures; implementations that
+    /// can, compute it without allocating.
+    fn byte_len(&self) -> usize {
+        // Not `to_bytes`, which sizes its vector with this.
+        let mut buf = Vec::new();
+        self.encode_into_with(&mut buf, Endianness::Big);
+        buf.len()
+    }
 }
 
-// A type for parse errors.
-#[derive(Debug)]
-pub struct ParseError(String);
+/// The total encoded length of a sequence of items, e.g. the
+/// instructions of a program.
+pub fn byte_len<T: ToBytes>(items: &[T]) -> usize {
+    items.iter().map(ToBytes::byte_len).sum()
+}
 
-impl fmt::Display for ParseError {
+// Trait for types that can be decoded from their binary representation.
+pub trait FromBytes: Sized {
+    /// Decode with the default (big-endian) byte order.
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, usize), DecodeError> {
+        Self::from_bytes_with(bytes, Endianness::Big)
+    }
+
+    /// Decode a value from the start of `bytes`, returning it together
+    /// with the number of bytes it took up. Trailing bytes are ignored.
+    fn from_bytes_with(bytes: &[u8], endianness: Endianness) -> Result<(Self, usize), DecodeError>;
+}
+
+/// Errors raised while decoding a binary representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended in the middle of the named item, which takes
+    /// `needed` bytes (at least, for a varint) where `available` were
+    /// left.
+    Truncated { what: &'static str, needed: usize, available: usize },
+    /// A tag byte that does not encode any variant of the named item.
+    UnknownOpcode { what: &'static str, opcode: u8 },
+    /// An object file header naming an entry pc past the end of the
+    /// program, as when it is read with the wrong byte order.
+    EntryOutOfRange { entry: u32, instrs: usize },
+    /// A varint with more bits than the named item holds.
+    Overflow { what: &'static str },
+    /// An object file whose stored checksum does not match its
+    /// contents, as when it was truncated or corrupted in transit.
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// Bytes left over after the named item, where it should have used
+    /// up the input.
+    TrailingBytes { what: &'static str, extra: usize },
+}
+
+impl fmt::Display for DecodeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        match self {
+            DecodeError::Truncated { what, needed, available } =>
+                write!(f, "truncated {} (needs {} bytes, {} left)", what, needed, available),
+            DecodeError::UnknownOpcode { what, opcode } =>
+                write!(f, "unknown {} opcode {:#04x}", what, opcode),
+            DecodeError::EntryOutOfRange { entry, instrs } =>
+                write!(f, "entry pc {} is past the end of the program ({} instructions)", entry, instrs),
+            DecodeError::Overflow { what } => write!(f, "varint too large for {}", what),
+            DecodeError::ChecksumMismatch { expected, actual } =>
+                write!(f, "checksum mismatch (stored {:#010x}, computed {:#010x})", expected, actual),
+            DecodeError::TrailingBytes { what, extra } => write!(f, "{} trailing bytes after {}", extra, what),
+        }
     }
 }
 
-impl error::Error for ParseError {}
+impl error::Error for DecodeError {}
 
-impl From<num::ParseIntError> for ParseError {
-    fn from(err: num::ParseIntError) -> Self {
-        ParseError(format!("{}", err))
+/// What a `ParseError` was trying to parse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnaryOperator,
+    BinaryOperator,
+    Value,
+    Integer,
+    Instruction,
+    Directive,
+    Label,
+    Constant,
+    StringLiteral,
+    /// An instruction (named by the token) given the wrong number of
+    /// operands.
+    Arity { expected: usize, found: usize },
+    /// An integer (the token) that is negative or above `max` where
+    /// only 0 to `max` will do, e.g. `peek -1`.
+    Unsigned { max: u64 },
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ParseErrorKind::UnaryOperator => "unary operator",
+            ParseErrorKind::BinaryOperator => "binary operator",
+            ParseErrorKind::Value => "value",
+            ParseErrorKind::Integer => "integer",
+            ParseErrorKind::Instruction => "instruction",
+            ParseErrorKind::Directive => "directive",
+            ParseErrorKind::Label => "label",
+            ParseErrorKind::Constant => "constant name",
+            ParseErrorKind::StringLiteral => "string literal",
+            ParseErrorKind::Arity { .. } => "operands",
+            ParseErrorKind::Unsigned { .. } => "unsigned integer",
+        };
+        write!(f, "{}", name)
     }
 }
 
+// A type for parse errors: what was being parsed, the offending input,
+// and (when known) the 0-based byte column it starts at in its line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub token: String,
+    pub column: Option<usize>,
+    /// What the token was probably meant to be, e.g. the mnemonic
+    /// closest to a misspelt one.
+    pub suggestion: Option<&'static str>,
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, token: &str) -> ParseError {
+        ParseError { kind, token: token.to_string(), column: None, suggestion: None }
+    }
+
+    /// Set the column of the offending token.
+    pub fn at(mut self, column: usize) -> ParseError {
+        self.column = Some(column);
+        self
+    }
+
+    /// Suggest what was meant instead, if anything.
+    pub fn suggest(mut self, suggestion: Option<&'static str>) -> ParseError {
+        self.suggestion = suggestion;
+        self
+    }
+
+    /// Show the source line with a caret under the offending token,
+    /// if its column is known. Tabs are kept so the caret lines up.
+    pub fn caret(&self, line: &str) -> Option<String> {
+        self.column.map(|c| {
+            let pad: String = line[..c].chars().map(|ch| if ch == '\t' { '\t' } else { ' ' }).collect();
+            format!("{}\n{}^", line, pad)
+        })
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::Arity { expected, found } => {
+                let s = if expected == 1 { "" } else { "s" };
+                write!(f, "'{}' takes {} operand{}, found {}", self.token, expected, s, found)?
+            }
+            ParseErrorKind::Unsigned { max } =>
+                write!(f, "operand must be a non-negative integer up to {}, found '{}'", max, self.token)?,
+            kind => write!(f, "could not parse {} '{}'", kind, self.token)?,
+        }
+        match self.suggestion {
+            Some(s) => write!(f, "; did you mean '{}'?", s),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+#[cfg(feature = "std")]
 impl From<ParseError> for io::Error {
     fn from(err: ParseError) -> Self {
-        io::Error::new(io::ErrorKind::Other, format!("{:?}", err))
+        io::Error::other(err.to_string())
+    }
+}
+
+/// A fresh directory for the files of one test.
+#[cfg(all(test, feature = "std"))]
+pub(crate) fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("grumpy-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Built by `cargo test -p grumpy --no-default-features`: the ISA
+/// parses, prints and encodes programs using only what a `no_std` build
+/// has.
+#[cfg(all(test, not(feature = "std")))]
+mod no_std {
+    use super::*;
+    use crate::isa::{Instr, PInstr};
+
+    #[test]
+    fn test_isa() {
+        let pinstrs = PInstr::parse_line("push 3; push @1; binary +; halt").unwrap();
+        let instrs: Vec<Instr> = pinstrs.iter().map(|i| match i {
+            PInstr::PI(i) => *i,
+            _ => unreachable!(),
+        }).collect();
+        let mut bytes = Vec::new();
+        for i in &instrs {
+            i.encode_into_with(&mut bytes, Endianness::Little);
+        }
+        assert_eq!(bytes.len(), byte_len(&instrs));
+        let (first, len) = Instr::from_bytes_with(&bytes, Endianness::Little).unwrap();
+        assert_eq!((first, len), (instrs[0], 6));
+        assert_eq!(instrs[1].to_string(), "push @1");
+        let err = PInstr::parse_line("peek x").unwrap_err();
+        assert_eq!((err.kind, err.column), (ParseErrorKind::Integer, Some(5)));
+        assert_eq!(err.to_string(), "could not parse integer 'x'");
     }
 }
@@ -0,0 +1,423 @@
+use crate::assemble::{apply_relocations, declared_visibility, Symbol, Symbols, Visibility};
+use crate::expand::Scopes;
+use crate::isa::{Label, PInstr, PInstr::*};
+use crate::object::{DataSegment, LineTable, Metadata, ObjectFile, RelocatableObject, SymbolTable};
+use crate::Profile;
+use std::collections::{HashMap, HashSet};
+use std::{error, fmt};
+
+/// Errors raised while renaming or combining pseudo-instruction programs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+    /// Two distinct labels were renamed to the same label.
+    Collision { first: Label, second: Label, renamed: Label },
+    /// A module referenced a label that it neither defines nor
+    /// imports from another module's exports.
+    Undefined { label: Label, module: usize },
+    /// Two modules exported the same label.
+    DuplicateExport { label: Label, first: usize, second: usize },
+    /// A module referenced a label that another module defines but
+    /// does not export.
+    NotExported { label: Label, module: usize, owner: usize },
+    /// A module's `.global`/`.local` declarations are inconsistent.
+    Visibility { module: usize, message: String },
+    /// A module's scoped labels (`.Lname`) are misused.
+    Scope { module: usize, message: String },
+    /// An object pushed a label that no object defines or exports.
+    Unresolved { label: Label, object: String },
+    /// Two objects exported the same label.
+    MultiplyDefined { label: Label, first: String, second: String },
+    /// An object's relocations could not be applied.
+    Relocation { object: String, message: String },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::Collision { first, second, renamed } =>
+                write!(f, "labels '{}' and '{}' both rename to '{}'", first, second, renamed),
+            LinkError::Undefined { label, module } =>
+                write!(f, "module {} references undefined label '{}'", module, label),
+            LinkError::DuplicateExport { label, first, second } =>
+                write!(f, "label '{}' is exported by both module {} and module {}",
+                       label, first, second),
+            LinkError::NotExported { label, module, owner } =>
+                write!(f, "module {} references label '{}', which is local to module {} \
+                           (declare it .global to export it)", module, label, owner),
+            LinkError::Visibility { module, message } | LinkError::Scope { module, message } =>
+                write!(f, "module {}: {}", module, message),
+            LinkError::Unresolved { label, object } =>
+                write!(f, "'{}' references undefined label '{}'", object, label),
+            LinkError::MultiplyDefined { label, first, second } =>
+                write!(f, "label '{}' is exported by both '{}' and '{}'", label, first, second),
+            LinkError::Relocation { object, message } => write!(f, "'{}': {}", object, message),
+        }
+    }
+}
+
+impl error::Error for LinkError {}
+
+/// Rename every label in a program. `f` is called once per distinct
+/// label, and the result is used at both its definition (`PLabel`) and
+/// its references (`PPush`, `PPushOffset`), so the program's control flow is unchanged.
+/// Nothing is modified if two distinct labels would get the same name.
+pub fn map_labels<F>(pinstrs: &mut [PInstr], mut f: F) -> Result<(), LinkError>
+where
+    F: FnMut(&Label) -> Label,
+{
+    let mut renames = HashMap::<Label, Label>::new();
+    let mut owners = HashMap::<Label, Label>::new();
+
+    for i in pinstrs.iter() {
+        let label = match i {
+            PLabel(l) | PPush(l) | PPushOffset(l, _) | PGlobal(l) | PLocal(l) => l,
+            PRel(_) | PPushConst(_) | PDirective(_) | PI(_) => continue,
+        };
+        if renames.contains_key(label) {
+            continue;
+        }
+        let renamed = f(label);
+        if let Some(first) = owners.get(&renamed) {
+            return Err(LinkError::Collision {
+                first: first.clone(),
+                second: label.clone(),
+                renamed,
+            });
+        }
+        owners.insert(renamed.clone(), label.clone());
+        renames.insert(label.clone(), renamed);
+    }
+
+    for i in pinstrs.iter_mut() {
+        match i {
+            PLabel(l) | PPush(l) | PPushOffset(l, _) | PGlobal(l) | PLocal(l) =>
+                *l = renames[l].clone(),
+            PRel(_) | PPushConst(_) | PDirective(_) | PI(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Prefix every label in a program, e.g. `Lmain` becomes `Llib_Lmain`
+/// under the prefix `"Llib_"`.
+pub fn prefix_labels(pinstrs: &mut [PInstr], prefix: &str) -> Result<(), LinkError> {
+    map_labels(pinstrs, |l| format!("{}{}", prefix, l))
+}
+
+/// Link several modules into one program. Modules are concatenated in
+/// order. Labels a module marks `.global` keep their names and may be
+/// referenced from any module; every other label is private to its
+/// module and is renamed so it cannot clash with the same name elsewhere.
+/// Scoped labels are resolved within each module first.
+pub fn link(modules: &[Vec<PInstr>]) -> Result<Vec<PInstr>, LinkError> {
+    let modules = modules.iter().enumerate()
+        .map(|(m, module)| resolve_scopes(module).map_err(|message| LinkError::Scope { module: m, message }))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut exports = HashMap::<Label, usize>::new();
+    let mut owners = HashMap::<&Label, usize>::new();
+    for (m, module) in modules.iter().enumerate() {
+        declared_visibility(module)
+            .map_err(|message| LinkError::Visibility { module: m, message })?;
+        for i in module {
+            if let PLabel(l) = i {
+                owners.entry(l).or_insert(m);
+            }
+            if let PGlobal(l) = i {
+                match exports.get(l) {
+                    Some(&first) if first != m => return Err(LinkError::DuplicateExport {
+                        label: l.clone(),
+                        first,
+                        second: m,
+                    }),
+                    _ => { exports.insert(l.clone(), m); }
+                }
+            }
+        }
+    }
+
+    let mut linked = Vec::new();
+    for (m, module) in modules.iter().enumerate() {
+        let defined: HashSet<&Label> = module.iter()
+            .filter_map(|i| match i { PLabel(l) => Some(l), _ => None })
+            .collect();
+        let mut undefined = None;
+        let mut module = module.clone();
+        // Private labels get a '_' after the first character, which
+        // parse_label never accepts, so they cannot clash with labels
+        // written in source.
+        map_labels(&mut module, |l| {
+            if exports.get(l) == Some(&m) {
+                l.clone()
+            } else if defined.contains(l) {
+                format!("_m{}_{}", m, l)
+            } else {
+                if !exports.contains_key(l) && undefined.is_none() {
+                    undefined = Some(l.clone());
+                }
+                l.clone()
+            }
+        })?;
+        if let Some(label) = undefined {
+            return Err(match owners.get(&label) {
+                Some(&owner) => LinkError::NotExported { label, module: m, owner },
+                None => LinkError::Undefined { label, module: m },
+            });
+        }
+        linked.extend(module);
+    }
+    Ok(linked)
+}
+
+/// Link relocatable objects into one program, laid out in order. As in
+/// `link`, an object's `.global` labels may be pushed from any object
+/// and its other labels only from itself, and the other labels are
+/// renamed `_m<n>_<label>` in the result's symbol table. The entry pc is
+/// the length of the program, as `assemble` gives it.
+pub fn link_objects(objects: &[RelocatableObject]) -> Result<ObjectFile, LinkError> {
+    let mut bases = Vec::new();
+    let mut end: u32 = 0;
+    for o in objects {
+        bases.push(end);
+        end += o.module.instrs.len() as u32;
+    }
+    let mut exports = HashMap::<&Label, (usize, u32)>::new();
+    for (m, o) in objects.iter().enumerate() {
+        for (label, sym) in o.module.symbols.iter().filter(|(_, sym)| sym.visibility == Visibility::Global) {
+            if let Some(&(first, _)) = exports.get(label) {
+                return Err(LinkError::MultiplyDefined {
+                    label: label.clone(),
+                    first: objects[first].name.clone(),
+                    second: o.name.clone(),
+                });
+            }
+            exports.insert(label, (m, bases[m] + sym.pc));
+        }
+    }
+
+    let mut instrs = Vec::new();
+    let mut symbols = SymbolTable::new();
+    for (m, o) in objects.iter().enumerate() {
+        // Where each label the object pushes ends up in the program.
+        let mut targets = Symbols::new();
+        for (_, label) in &o.module.relocations {
+            let pc = match (o.module.symbols.get(label), exports.get(label)) {
+                (Some(sym), _) => bases[m] + sym.pc,
+                (None, Some(&(_, pc))) => pc,
+                (None, None) => return Err(LinkError::Unresolved { label: label.clone(), object: o.name.clone() }),
+            };
+            targets.insert(label.clone(), Symbol { pc, visibility: Visibility::Global });
+        }
+        let mut code = o.module.instrs.clone();
+        apply_relocations(&mut code, &o.module.relocations, 0, &targets)
+            .map_err(|message| LinkError::Relocation { object: o.name.clone(), message })?;
+        instrs.extend(code);
+        for (label, sym) in &o.module.symbols {
+            let name = match sym.visibility {
+                Visibility::Global => label.clone(),
+                Visibility::Local => format!("_m{}_{}", m, label),
+            };
+            symbols.insert(name, bases[m] + sym.pc);
+        }
+    }
+    Ok(ObjectFile {
+        entry: end,
+        instrs,
+        symbols,
+        profile: Profile::Fixed,
+        lines: LineTable::default(),
+        metadata: Metadata::new(),
+        data: DataSegment::default(),
+    })
+}
+
+/// Replace a module's scoped labels by ordinary ones (see `Scopes`).
+fn resolve_scopes(module: &[PInstr]) -> Result<Vec<PInstr>, String> {
+    let mut scopes = Scopes::default();
+    let resolved = module.iter().enumerate()
+        .map(|(n, i)| scopes.resolve(n, i))
+        .collect::<Result<_, _>>()?;
+    scopes.finish().map_err(|(_, message)| message)?;
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::assemble;
+    use crate::isa::{Binop::*, Instr::*, Val::*};
+
+    fn program() -> Vec<PInstr> {
+        vec![PPush("Lmain".into()), PI(Call), PI(Halt),
+             PLabel("Lmain".into()), PPush("Lf".into()), PI(Ret),
+             PLabel("Lf".into()), PI(Ret)]
+    }
+
+    #[test]
+    fn test_prefix_labels() {
+        let mut p = program();
+        prefix_labels(&mut p, "Llib_").unwrap();
+        assert_eq!(p, vec![PPush("Llib_Lmain".into()), PI(Call), PI(Halt),
+                           PLabel("Llib_Lmain".into()), PPush("Llib_Lf".into()), PI(Ret),
+                           PLabel("Llib_Lf".into()), PI(Ret)]);
+    }
+
+    #[test]
+    fn test_swap_labels() {
+        let mut p = program();
+        map_labels(&mut p, |l| match l.as_str() {
+            "Lmain" => "Lf".to_string(),
+            "Lf" => "Lmain".to_string(),
+            _ => l.clone(),
+        }).unwrap();
+        assert_eq!(p, vec![PPush("Lf".into()), PI(Call), PI(Halt),
+                           PLabel("Lf".into()), PPush("Lmain".into()), PI(Ret),
+                           PLabel("Lmain".into()), PI(Ret)]);
+    }
+
+    #[test]
+    fn test_map_labels_collision() {
+        let mut p = program();
+        let err = map_labels(&mut p, |_| "Lsame".to_string()).unwrap_err();
+        assert_eq!(err, LinkError::Collision {
+            first: "Lmain".into(),
+            second: "Lf".into(),
+            renamed: "Lsame".into(),
+        });
+        // The program is left untouched on error.
+        assert_eq!(p, program());
+    }
+
+    fn parse(src: &str) -> Vec<PInstr> {
+        src.lines().flat_map(|l| PInstr::parse_line(l).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_link_cross_module_call() {
+        let main = parse("setframe 0; push Lmain; call; halt
+                          Lmain:; push 3; push Lsquare; setframe 1; swap; call; ret
+                          Lhelper:; ret");
+        let lib = parse(".global Lsquare
+                         Lsquare:; var 0; var 0; binary *; ret
+                         Lhelper:; ret");
+        let linked = link(&[main, lib]).unwrap();
+        // Both modules' private Lhelper survive under distinct names.
+        assert!(linked.contains(&PLabel("_m0_Lhelper".into())));
+        assert!(linked.contains(&PLabel("_m1_Lhelper".into())));
+        assert!(linked.contains(&PLabel("Lsquare".into())));
+
+        let instrs = assemble(&linked).unwrap();
+        assert_eq!(instrs[1], Push(Vloc(4)));
+        assert_eq!(instrs[5], Push(Vloc(11)));
+        assert_eq!(instrs[13], Binary(Mul));
+    }
+
+    #[test]
+    fn test_link_same_local_in_two_modules() {
+        let a = parse("push Lmain; call; halt; .local Lloop; Lloop:; push Lloop; ret");
+        let b = parse(".global Lmain; Lmain:; Lloop:; push Lloop; ret");
+        let instrs = assemble(&link(&[a, b]).unwrap()).unwrap();
+        assert_eq!(instrs[0], Push(Vloc(5)));
+        assert_eq!(instrs[3], Push(Vloc(3)));
+        assert_eq!(instrs[5], Push(Vloc(5)));
+    }
+
+    #[test]
+    fn test_link_scoped_labels() {
+        let a = parse("push Lg; call; push Lf; call; halt; Lf:; .Ldone:; push .Ldone; ret");
+        let b = parse(".global Lg; Lg:; .Ldone:; push .Ldone; ret");
+        let linked = link(&[a, b]).unwrap();
+        // Scopes are resolved before private labels are renamed.
+        assert!(linked.contains(&PLabel("_m0_Lf.Ldone".into())));
+        assert!(linked.contains(&PLabel("_m1_Lg.Ldone".into())));
+        let instrs = assemble(&linked).unwrap();
+        assert_eq!(instrs[0], Push(Vloc(7)));
+        assert_eq!(instrs[5], Push(Vloc(5)));
+        assert_eq!(instrs[7], Push(Vloc(7)));
+
+        let err = link(&[parse("push .Lx; halt")]).unwrap_err();
+        assert_eq!(err.to_string(), "module 0: scoped label '.Lx' is not after any label");
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let main = parse("push Lf; call; halt");
+        let err = link(&[main.clone(), parse("Lg:; ret")]).unwrap_err();
+        assert_eq!(err, LinkError::Undefined { label: "Lf".into(), module: 0 });
+
+        let err = link(&[main.clone(), parse(".local Lf; Lf:; ret")]).unwrap_err();
+        assert_eq!(err, LinkError::NotExported { label: "Lf".into(), module: 0, owner: 1 });
+        assert_eq!(err.to_string(), "module 0 references label 'Lf', which is local to \
+                                     module 1 (declare it .global to export it)");
+
+        let err = link(&[main.clone(), parse(".global Lf; .local Lf; Lf:; ret")]).unwrap_err();
+        assert_eq!(err, LinkError::Visibility {
+            module: 1,
+            message: "label 'Lf' is declared both local and global".into(),
+        });
+
+        let lib = parse(".global Lf; Lf:; ret");
+        let err = link(&[main, lib.clone(), lib]).unwrap_err();
+        assert_eq!(err, LinkError::DuplicateExport { label: "Lf".into(), first: 1, second: 2 });
+    }
+
+    /// Assemble a module to a relocatable object and back.
+    fn object(name: &str, src: &str) -> RelocatableObject {
+        let module = crate::assemble::assemble_relocatable(&parse(src)).unwrap();
+        let o = RelocatableObject { name: name.into(), module };
+        let bytes = o.encode(crate::Endianness::Big);
+        assert_eq!(RelocatableObject::from_bytes(&bytes, crate::Endianness::Big).unwrap(), o);
+        o
+    }
+
+    #[test]
+    fn test_link_objects() {
+        let sources = ["setframe 0; push Lmain; call; halt
+                        Lmain:; push 3; push Lsquare; setframe 1; swap; call
+                        push Ldouble; setframe 1; swap; call; ret",
+                       ".global Lsquare
+                        Lsquare:; var 0; var 0; binary *; ret",
+                       ".global Ldouble
+                        Ldouble:; var 0; push Ltwice; setframe 1; swap; call; ret
+                        Ltwice:; var 0; var 0; binary +; push Lsquare; pop; ret"];
+        let objects: Vec<RelocatableObject> = sources.iter().enumerate()
+            .map(|(m, src)| object(&format!("m{}.s", m), src))
+            .collect();
+        assert_eq!(objects[0].undefined().into_iter().collect::<Vec<_>>(), vec!["Ldouble", "Lsquare"]);
+        assert!(objects[1].undefined().is_empty());
+        let linked = link_objects(&objects).unwrap();
+
+        // The same as linking the sources and assembling the result.
+        let modules: Vec<Vec<PInstr>> = sources.iter().map(|src| parse(src)).collect();
+        let (mut instrs, symbols) = crate::assemble::assemble_with_symbols(&link(&modules).unwrap()).unwrap();
+        assert_eq!(instrs.pop(), Some(Push(Vloc(linked.entry))));
+        assert_eq!(linked.instrs, instrs);
+        assert_eq!(linked.symbols, crate::object::symbol_table(&symbols));
+        assert_eq!(linked.symbols["Lsquare"], 14);
+        assert_eq!(linked.instrs[5], Push(Vloc(14)));
+
+        // Every pushed location is in the program, and the file reads back.
+        for i in &linked.instrs {
+            if let Push(Vloc(n)) = i {
+                assert!(*n <= linked.entry, "{}", i);
+            }
+        }
+        let bytes = linked.encode(crate::Endianness::Big);
+        assert_eq!(ObjectFile::from_bytes(&bytes, crate::Endianness::Big).unwrap(), linked);
+    }
+
+    #[test]
+    fn test_link_objects_errors() {
+        let a = object("a.s", ".global Lf; Lf:; push Lg; ret");
+        let b = object("b.s", ".global Lf; Lf:; ret");
+        let c = object("c.s", "Lg:; ret");
+        assert_eq!(link_objects(&[a.clone(), b]).unwrap_err().to_string(),
+                   "label 'Lf' is exported by both 'a.s' and 'b.s'");
+        assert_eq!(link_objects(&[a, c]).unwrap_err(),
+                   LinkError::Unresolved { label: "Lg".into(), object: "a.s".into() });
+
+        let mut bad = object("bad.s", "push Lf; Lf:; ret");
+        bad.module.relocations[0].0 = 1;
+        assert_eq!(link_objects(&[bad]).unwrap_err().to_string(),
+                   "'bad.s': relocation for 'Lf' does not point at a push");
+    }
+}
@@ -0,0 +1,1854 @@
+use crate::base64::Base64Error;
+use crate::ihex::IhexError;
+use crate::compress;
+use crate::disassemble::{decode_with, decode_with_profile, DisassembleError};
+use crate::assemble::{Relocatable, Symbol, Symbols, Visibility};
+use crate::expand::Sources;
+use crate::isa::{Instr, Label, Val};
+use crate::{DecodeError, Endianness, FromBytes, Profile, ToBytes};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{error, fmt, str};
+
+/// The first four bytes of an object file.
+pub const MAGIC: [u8; 4] = *b"GRPY";
+
+/// The first four bytes of a relocatable object file.
+pub const RELOCATABLE_MAGIC: [u8; 4] = *b"GRPR";
+
+/// The format version written by `write_object`: the major version in
+/// the high byte, the minor version in the low byte. Readers accept any
+/// minor version of a major version they know.
+pub const VERSION: u16 = 0x0200;
+
+/// The oldest major version `ObjectFile::from_bytes` reads. Version 1
+/// is `MAGIC`, the version, the legacy layout and a CRC32 of the legacy
+/// layout, with no flags or sections.
+pub const OLDEST_VERSION: u8 = 1;
+
+/// Size of the magic and version that precede the legacy layout.
+const PREFIX_BYTES: usize = 6;
+
+/// Size of the flags that start the checksummed part of an object file.
+const FLAGS_BYTES: usize = 2;
+
+/// The flag marking an object file whose operands use `Profile::Varint`.
+const FLAG_VARINT: u16 = 0x0001;
+
+/// The flag marking an object file with a debug section.
+const FLAG_DEBUG: u16 = 0x0002;
+
+/// The flag marking an object file whose contents after the flags are
+/// compressed (see `compress`).
+const FLAG_COMPRESSED: u16 = 0x0004;
+
+/// The flag marking an object file with a metadata section.
+const FLAG_METADATA: u16 = 0x0008;
+
+/// The flag marking an object file with a data segment.
+const FLAG_DATA: u16 = 0x0010;
+
+/// Size of the checksum at the end of the file.
+const TRAILER_BYTES: usize = 4;
+
+/// Label addresses as stored in an object file.
+pub type SymbolTable = BTreeMap<String, u32>;
+
+/// Facts about how a program was built, such as its source file, as
+/// stored in the metadata section of an object file.
+pub type Metadata = BTreeMap<String, String>;
+
+/// Initialized data kept apart from the code, with labels of its own,
+/// such as the text of each `.datastring` (see `expand::expand`).
+///
+/// Before the program starts, if it has any data, the loader allocates
+/// one heap array of `values.len()` cells, stores the values in it in
+/// order, and pushes its address (a `Vaddr`) for the entry code to keep
+/// (see `DataSegment::load`). A data label is the index of its value in
+/// that array, so the program reads the value with `get` at the array's
+/// address and the label's index, rather than running code to build
+/// the array: in its first frame, where the address is `var 0`, with
+/// `var 0; push <index>; get`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DataSegment {
+    pub values: Vec<Val>,
+    /// Data labels and the indexes of their values.
+    pub symbols: SymbolTable,
+}
+
+impl DataSegment {
+    /// The heap and the stack the loader starts the program with: the
+    /// array of the values at address 0, laid out as `alloc` lays one
+    /// out (a `Vsize` of its length, then its cells), and its address
+    /// on the stack; or nothing at all if there is no data.
+    pub fn load(&self) -> (Vec<Val>, Vec<Val>) {
+        if self.values.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+        let mut heap = vec![Val::Vsize(self.values.len() as i32)];
+        heap.extend(&self.values);
+        (heap, vec![Val::Vaddr(0)])
+    }
+}
+
+/// The metadata `assem --metadata` records: the source file, the assembler's
+/// version, and the options it was run with (`options`, joined by
+/// spaces), under the keys `"source"`, `"assembler"` and `"options"`.
+/// Unless `reproducible`, the source is its absolute path and the time
+/// of the build, in seconds since the Unix epoch, is kept as `"time"`;
+/// otherwise the source is just its file name and there is no time,
+/// so that building the same input anywhere gives the same bytes.
+pub fn build_metadata(source: &Path, options: &[&str], reproducible: bool) -> Metadata {
+    let mut metadata = Metadata::new();
+    let source = if reproducible {
+        source.file_name().map_or_else(|| source.display().to_string(), |n| n.to_string_lossy().into_owned())
+    } else {
+        std::path::absolute(source).unwrap_or_else(|_| source.to_path_buf()).display().to_string()
+    };
+    metadata.insert("source".to_string(), source);
+    metadata.insert("assembler".to_string(), format!("grumpy {}", env!("CARGO_PKG_VERSION")));
+    metadata.insert("options".to_string(), options.join(" "));
+    if !reproducible {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        metadata.insert("time".to_string(), time.to_string());
+    }
+    metadata
+}
+
+/// The addresses of the labels in an assembler symbol table.
+pub fn symbol_table(symbols: &Symbols) -> SymbolTable {
+    symbols.iter().map(|(label, sym)| (label.clone(), sym.pc)).collect()
+}
+
+/// The source positions of a program's instructions, as stored in the
+/// debug section of an object file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineTable {
+    /// The source files, which the rows refer to by index.
+    pub files: Vec<String>,
+    /// `(pc, file, line)` rows in order of pc: the instructions from a
+    /// row's pc up to the next row's came from that line of that file.
+    pub rows: Vec<(u32, u32, u32)>,
+}
+
+impl LineTable {
+    /// The table for a program's line numbers (see
+    /// `assemble::line_numbers`), which count lines across the files
+    /// in `sources`.
+    pub fn new(lines: &[(u32, usize)], sources: &Sources) -> LineTable {
+        LineTable {
+            files: sources.files.iter().map(|f| f.display().to_string()).collect(),
+            rows: lines.iter()
+                .filter_map(|(pc, line)| sources.locate(*line).map(|(file, n)| (*pc, file as u32, n as u32)))
+                .collect(),
+        }
+    }
+
+    /// The file and line that the instruction at `pc` came from. Every
+    /// pc past the last row's is on its line, so callers should check
+    /// that `pc` is in the program.
+    pub fn line_for_pc(&self, pc: u32) -> Option<(&str, u32)> {
+        let row = self.rows.partition_point(|(start, _, _)| *start <= pc).checked_sub(1)?;
+        let (_, file, line) = self.rows[row];
+        Some((self.files.get(file as usize)?, line))
+    }
+}
+
+/// How `read_object` reads a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReadOptions {
+    /// Read a headerless file (see `write_legacy`).
+    pub legacy: bool,
+    /// Don't verify the checksum, to salvage what is left of a damaged
+    /// file. The trailer is still expected.
+    pub skip_checksum: bool,
+}
+
+/// What `ObjectFile::encode_with` leaves out of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteOptions {
+    /// Leave out the symbol section and the labels of the data segment.
+    pub strip_symbols: bool,
+    /// Leave out the debug section.
+    pub strip_debug: bool,
+    /// Leave out the metadata section.
+    pub strip_metadata: bool,
+}
+
+impl WriteOptions {
+    /// Leave out everything but the code and data, so that no label or
+    /// file name is shipped.
+    pub const STRIP_ALL: WriteOptions = WriteOptions { strip_symbols: true, strip_debug: true, strip_metadata: true };
+}
+
+/// Errors raised while reading an object file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectError {
+    /// The file does not start with `MAGIC`.
+    BadMagic { found: Vec<u8> },
+    /// The file was written by an incompatible version of the format,
+    /// or a version was asked for that cannot be written.
+    UnsupportedVersion { major: u8, minor: u8 },
+    /// An older version of the format, asked for when writing, cannot
+    /// hold part of the program.
+    NotInVersion { major: u8, what: &'static str },
+    /// The header or instructions could not be decoded.
+    Decode(DisassembleError),
+    /// A symbol name, at the given byte offset, is not UTF-8.
+    BadSymbolName { offset: usize },
+    /// A relocatable object's field, at the given byte offset, holds a
+    /// value it cannot have.
+    BadField { offset: usize, what: &'static str },
+    /// The text of a program (see `base64::decode_base64`) is not base64.
+    Base64(Base64Error),
+    /// The text of a program (see `ihex::decode_ihex`) is not Intel HEX.
+    Ihex(IhexError),
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectError::BadMagic { found } =>
+                write!(f, "not a GrumpyVM object file (starts with {:02x?}, expected {:02x?})",
+                       found, MAGIC),
+            ObjectError::UnsupportedVersion { major, minor } =>
+                write!(f, "unsupported object file version {}.{} (max supported {})",
+                       major, minor, VERSION >> 8),
+            ObjectError::NotInVersion { major, what } =>
+                write!(f, "object file version {} cannot hold {}", major, what),
+            ObjectError::Decode(e) => write!(f, "{}", e),
+            ObjectError::BadSymbolName { offset } => write!(f, "byte {:#x}: symbol name is not UTF-8", offset),
+            ObjectError::BadField { offset, what } => write!(f, "byte {:#x}: invalid {}", offset, what),
+            ObjectError::Base64(e) => write!(f, "{}", e),
+            ObjectError::Ihex(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for ObjectError {}
+
+/// Errors raised by `ObjectFile::replace_instr` and `ObjectFile::patch`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchError {
+    /// There is no instruction at the pc.
+    OutOfRange { pc: u32, len: usize },
+    /// The new instruction does not encode to as many bytes as the one
+    /// it replaces.
+    SizeMismatch { pc: u32, old: usize, new: usize },
+    /// The new instruction pushes a location that is not in the program.
+    BadTarget { pc: u32, target: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::OutOfRange { pc, len } =>
+                write!(f, "pc {} is out of range (the program has {} instructions)", pc, len),
+            PatchError::SizeMismatch { pc, old, new } =>
+                write!(f, "pc {}: new instruction is {} bytes, the old one {}", pc, new, old),
+            PatchError::BadTarget { pc, target } =>
+                write!(f, "pc {}: new instruction pushes location {}, which is out of range", pc, target),
+        }
+    }
+}
+
+impl error::Error for PatchError {}
+
+/// A way in which a decoded program is unfit to load (see
+/// `verify_object`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    /// The entry pc is past the end of the program.
+    EntryOutOfRange { entry: u32, len: usize },
+    /// The entry pc is a data byte, not an instruction.
+    EntryInData { entry: u32 },
+    /// The instruction at `pc` pushes a location past the end of the
+    /// program.
+    TargetOutOfRange { pc: u32, target: u32 },
+    /// The instruction at `pc` pushes a value that only the VM may
+    /// create (a `Vsize` or `Vaddr`).
+    InternalValue { pc: u32, value: Val },
+    /// A symbol's address is past the end of the program.
+    SymbolOutOfRange { label: Label, pc: u32 },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::EntryOutOfRange { entry, len } =>
+                write!(f, "entry pc {} is past the end of the program ({} instructions)", entry, len),
+            VerifyError::EntryInData { entry } => write!(f, "entry pc {} is a data byte", entry),
+            VerifyError::TargetOutOfRange { pc, target } =>
+                write!(f, "pc {}: pushes location {}, past the end of the program", pc, target),
+            VerifyError::InternalValue { pc, value } =>
+                write!(f, "pc {}: pushes {}, which programs may not contain", pc, value),
+            VerifyError::SymbolOutOfRange { label, pc } =>
+                write!(f, "symbol '{}' at pc {} is past the end of the program", label, pc),
+        }
+    }
+}
+
+impl error::Error for VerifyError {}
+
+/// A patch made by `ObjectFile::patch`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patched {
+    /// The instruction the patch replaced.
+    pub old: Instr,
+    /// How many bytes the instructions after the patch moved by in the
+    /// encoded file; 0 if the new instruction is the old one's size.
+    pub shift: isize,
+}
+
+/// The headerless layout GrumpyVM loads: the entry pc, then the
+/// instructions.
+pub fn write_legacy(entry: u32, instrs: &[Instr], endianness: Endianness) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(entry.byte_len() + instrs.byte_len());
+    entry.encode_into_with(&mut bytes, endianness);
+    instrs.encode_into_with(&mut bytes, endianness);
+    bytes
+}
+
+/// Write the layout of `write_legacy` to `out` as it is encoded, the
+/// header and then each instruction, without building it in memory.
+/// Every piece is written whole (`write_all`), however little `out`
+/// takes at a time, as a pipe may.
+pub fn write_legacy_to<W: Write>(out: &mut W, entry: u32, instrs: &[Instr], endianness: Endianness)
+                                 -> io::Result<()> {
+    out.write_all(&entry.to_bytes_with(endianness))?;
+    let mut buf = Vec::new();
+    for i in instrs {
+        buf.clear();
+        i.encode_into_with(&mut buf, endianness);
+        out.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+/// An object file: `MAGIC`, `VERSION`, u16 flags, the legacy layout,
+/// the symbol section, its size in bytes as a u32, and a CRC32 of
+/// everything after `VERSION`. Without `symbols` the symbol section is
+/// left out (its size is 0). The section is a u32 count, then an
+/// address, a u32 name length and the UTF-8 name of each symbol.
+pub fn write_object(entry: u32, instrs: &[Instr], symbols: Option<&SymbolTable>,
+                    endianness: Endianness) -> Vec<u8> {
+    write_object_with(entry, instrs, symbols, endianness, Profile::Fixed)
+}
+
+/// Like `write_object`, with the instructions' operands encoded in the
+/// given profile, which the flags record. The header and symbols are
+/// always fixed width.
+pub fn write_object_with(entry: u32, instrs: &[Instr], symbols: Option<&SymbolTable>,
+                         endianness: Endianness, profile: Profile) -> Vec<u8> {
+    write(entry, instrs, Sections { symbols, ..Sections::default() }, endianness, profile)
+}
+
+/// The sections that `write` adds after the code, if present.
+#[derive(Clone, Copy, Default)]
+struct Sections<'a> {
+    symbols: Option<&'a SymbolTable>,
+    lines: Option<&'a LineTable>,
+    metadata: Option<&'a Metadata>,
+    data: Option<&'a DataSegment>,
+}
+
+/// Like `write_object_with`, adding a debug section after the symbol
+/// size if there are `lines`, and setting `FLAG_DEBUG`. The section is
+/// a u32 count and the u32 length and UTF-8 bytes of each file name,
+/// then a u32 count and the u32 pc, file index and line of each row.
+/// Its size in bytes follows it as a u32. Likewise `metadata`, if any,
+/// is a section after that, with `FLAG_METADATA`: a u32 count, then the
+/// key and value of each pair, as names are written. Last comes the
+/// `data` segment, if any, with `FLAG_DATA`: a u32 count and the
+/// encoded values, then the data labels as a symbol section has them,
+/// with indexes for addresses.
+fn write(entry: u32, instrs: &[Instr], sections: Sections<'_>, endianness: Endianness, profile: Profile)
+         -> Vec<u8> {
+    let Sections { symbols, lines, metadata, data } = sections;
+    // Room for all but the sections' contents: varint operands are no
+    // longer than fixed width ones.
+    let mut bytes = Vec::with_capacity(PREFIX_BYTES + FLAGS_BYTES + entry.byte_len() + crate::byte_len(instrs)
+                                       + 2 * 4 + TRAILER_BYTES);
+    bytes.extend(MAGIC);
+    VERSION.encode_into_with(&mut bytes, endianness);
+    let mut flags = match profile { Profile::Fixed => 0, Profile::Varint => FLAG_VARINT };
+    if lines.is_some() {
+        flags |= FLAG_DEBUG;
+    }
+    if metadata.is_some() {
+        flags |= FLAG_METADATA;
+    }
+    if data.is_some() {
+        flags |= FLAG_DATA;
+    }
+    flags.encode_into_with(&mut bytes, endianness);
+    entry.encode_into_with(&mut bytes, endianness);
+    for i in instrs {
+        i.encode_into_profile(&mut bytes, endianness, profile);
+    }
+    let start = bytes.len();
+    if let Some(symbols) = symbols {
+        write_symbols(&mut bytes, symbols, endianness);
+    }
+    ((bytes.len() - start) as u32).encode_into_with(&mut bytes, endianness);
+    if let Some(lines) = lines {
+        let start = bytes.len();
+        write_lines(&mut bytes, lines, endianness);
+        ((bytes.len() - start) as u32).encode_into_with(&mut bytes, endianness);
+    }
+    if let Some(metadata) = metadata {
+        let start = bytes.len();
+        write_metadata(&mut bytes, metadata, endianness);
+        ((bytes.len() - start) as u32).encode_into_with(&mut bytes, endianness);
+    }
+    if let Some(data) = data {
+        let start = bytes.len();
+        write_data(&mut bytes, data, endianness);
+        ((bytes.len() - start) as u32).encode_into_with(&mut bytes, endianness);
+    }
+    crc32(&bytes[PREFIX_BYTES..]).encode_into_with(&mut bytes, endianness);
+    bytes
+}
+
+/// Append the contents of a symbol section (see `write_object`).
+fn write_symbols(bytes: &mut Vec<u8>, symbols: &SymbolTable, endianness: Endianness) {
+    (symbols.len() as u32).encode_into_with(bytes, endianness);
+    for (name, pc) in symbols {
+        pc.encode_into_with(bytes, endianness);
+        write_name(bytes, name, endianness);
+    }
+}
+
+/// Append the contents of a debug section (see `write`).
+fn write_lines(bytes: &mut Vec<u8>, lines: &LineTable, endianness: Endianness) {
+    (lines.files.len() as u32).encode_into_with(bytes, endianness);
+    for file in &lines.files {
+        write_name(bytes, file, endianness);
+    }
+    (lines.rows.len() as u32).encode_into_with(bytes, endianness);
+    for (pc, file, line) in &lines.rows {
+        pc.encode_into_with(bytes, endianness);
+        file.encode_into_with(bytes, endianness);
+        line.encode_into_with(bytes, endianness);
+    }
+}
+
+/// Append the contents of a metadata section (see `write`).
+fn write_metadata(bytes: &mut Vec<u8>, metadata: &Metadata, endianness: Endianness) {
+    (metadata.len() as u32).encode_into_with(bytes, endianness);
+    for (key, value) in metadata {
+        write_name(bytes, key, endianness);
+        write_name(bytes, value, endianness);
+    }
+}
+
+/// Append the contents of a data segment (see `write`).
+fn write_data(bytes: &mut Vec<u8>, data: &DataSegment, endianness: Endianness) {
+    (data.values.len() as u32).encode_into_with(bytes, endianness);
+    for v in &data.values {
+        v.encode_into_with(bytes, endianness);
+    }
+    write_symbols(bytes, &data.symbols, endianness);
+}
+
+/// Read an object file written by `write_object`, returning its entry
+/// pc, instructions and symbols (empty if it has none).
+pub fn read_object(bytes: &[u8], endianness: Endianness, options: ReadOptions)
+                   -> Result<(u32, Vec<Instr>, SymbolTable), ObjectError> {
+    let o = ObjectFile::read_with(bytes, endianness, options)?;
+    Ok((o.entry, o.instrs, o.symbols))
+}
+
+/// The parts of an object file's checksummed payload.
+struct Parts<'a> {
+    profile: Profile,
+    /// The legacy layout.
+    code: &'a [u8],
+    /// The symbol section, with its offset in the file.
+    symbols: (&'a [u8], usize),
+    /// The debug section, if there is one, with its offset.
+    debug: Option<(&'a [u8], usize)>,
+    /// The metadata section, if there is one, with its offset.
+    metadata: Option<(&'a [u8], usize)>,
+    /// The data segment, if there is one, with its offset.
+    data: Option<(&'a [u8], usize)>,
+}
+
+/// The payload of an object file (see `payload`) as it was before
+/// compression, without `FLAG_COMPRESSED`, if the flags have it.
+/// Offsets in errors about what it holds are then offsets into it.
+fn expand(payload: &[u8], endianness: Endianness) -> Result<Cow<'_, [u8]>, ObjectError> {
+    let (flags, _) = u16::from_bytes_with(payload, endianness).map_err(|error| {
+        ObjectError::Decode(DisassembleError { offset: PREFIX_BYTES, instr: None, error })
+    })?;
+    if flags & FLAG_COMPRESSED == 0 {
+        return Ok(Cow::Borrowed(payload));
+    }
+    let mut expanded = Vec::new();
+    (flags & !FLAG_COMPRESSED).encode_into_with(&mut expanded, endianness);
+    let contents = compress::decompress(&payload[FLAGS_BYTES..]).map_err(|e| {
+        ObjectError::BadField { offset: PREFIX_BYTES + FLAGS_BYTES + e.offset, what: "compressed data" }
+    })?;
+    expanded.extend(contents);
+    Ok(Cow::Owned(expanded))
+}
+
+/// Compress an object file written by `write`, setting
+/// `FLAG_COMPRESSED` and checksumming what is written.
+fn compress_object(bytes: &[u8], endianness: Endianness) -> Vec<u8> {
+    let payload = &bytes[PREFIX_BYTES..bytes.len() - TRAILER_BYTES];
+    let (flags, _) = u16::from_bytes_with(payload, endianness).expect("write writes the flags");
+    let mut compressed = bytes[..PREFIX_BYTES].to_vec();
+    (flags | FLAG_COMPRESSED).encode_into_with(&mut compressed, endianness);
+    compressed.extend(compress::compress(&payload[FLAGS_BYTES..]));
+    crc32(&compressed[PREFIX_BYTES..]).encode_into_with(&mut compressed, endianness);
+    compressed
+}
+
+/// Split the payload of an object file (see `expand`) into its parts.
+fn parts(payload: &[u8], endianness: Endianness) -> Result<Parts<'_>, ObjectError> {
+    let error = |offset, error| ObjectError::Decode(DisassembleError { offset, instr: None, error });
+    let (flags, _) = u16::from_bytes_with(payload, endianness).map_err(|e| error(PREFIX_BYTES, e))?;
+    if flags & !(FLAG_VARINT | FLAG_DEBUG | FLAG_COMPRESSED | FLAG_METADATA | FLAG_DATA) != 0 {
+        return Err(ObjectError::BadField { offset: PREFIX_BYTES, what: "flags" });
+    }
+    let profile = if flags & FLAG_VARINT != 0 { Profile::Varint } else { Profile::Fixed };
+    // The sections end the payload, each followed by its size.
+    let mut end = payload.len();
+    let data = match flags & FLAG_DATA {
+        0 => None,
+        _ => Some(section(payload, &mut end, endianness, "data segment")?),
+    };
+    let metadata = match flags & FLAG_METADATA {
+        0 => None,
+        _ => Some(section(payload, &mut end, endianness, "metadata section")?),
+    };
+    let debug = match flags & FLAG_DEBUG {
+        0 => None,
+        _ => Some(section(payload, &mut end, endianness, "debug section")?),
+    };
+    let symbols = section(payload, &mut end, endianness, "symbol section")?;
+    Ok(Parts { profile, code: &payload[FLAGS_BYTES..end], symbols, debug, metadata, data })
+}
+
+/// The section of a payload that ends, followed by its u32 size, at
+/// `end`, with its offset in the file. Moves `end` to its start.
+fn section<'a>(payload: &'a [u8], end: &mut usize, endianness: Endianness, what: &'static str)
+               -> Result<(&'a [u8], usize), ObjectError> {
+    let error = |offset, error| ObjectError::Decode(DisassembleError { offset, instr: None, error });
+    let truncated = |offset, needed, available| error(offset, DecodeError::Truncated { what, needed, available });
+    let footer = end.checked_sub(4).filter(|n| *n >= FLAGS_BYTES)
+        .ok_or_else(|| truncated(PREFIX_BYTES + FLAGS_BYTES, 4, end.saturating_sub(FLAGS_BYTES)))?;
+    let (size, _) = u32::from_bytes_with(&payload[footer..], endianness).map_err(|e| error(PREFIX_BYTES + footer, e))?;
+    let start = footer.checked_sub(size as usize).filter(|n| *n >= FLAGS_BYTES)
+        .ok_or_else(|| truncated(PREFIX_BYTES + footer, size as usize, footer - FLAGS_BYTES))?;
+    *end = start;
+    Ok((&payload[start..footer], PREFIX_BYTES + start))
+}
+
+/// Check the magic, version and checksum of a file, returning its major
+/// version, from `oldest` to the current one, and what is between the
+/// version and the checksum.
+fn payload(bytes: &[u8], magic: [u8; 4], oldest: u8, endianness: Endianness, options: ReadOptions)
+           -> Result<(u8, &[u8]), ObjectError> {
+    if bytes.get(..magic.len()) != Some(&magic[..]) {
+        let found = bytes.iter().take(magic.len()).copied().collect();
+        return Err(ObjectError::BadMagic { found });
+    }
+    let error = |offset, error| ObjectError::Decode(DisassembleError { offset, instr: None, error });
+    let (version, _) = u16::from_bytes_with(&bytes[magic.len()..], endianness)
+        .map_err(|e| error(magic.len(), e))?;
+    let major = (version >> 8) as u8;
+    if !(oldest..=(VERSION >> 8) as u8).contains(&major) {
+        return Err(ObjectError::UnsupportedVersion { major, minor: version as u8 });
+    }
+    let trailer = match bytes.len().checked_sub(TRAILER_BYTES) {
+        Some(n) if n >= PREFIX_BYTES => n,
+        _ => {
+            let truncated = DecodeError::Truncated {
+                what: "object file",
+                needed: PREFIX_BYTES + TRAILER_BYTES,
+                available: bytes.len(),
+            };
+            return Err(error(PREFIX_BYTES, truncated));
+        }
+    };
+    let payload = &bytes[PREFIX_BYTES..trailer];
+    if !options.skip_checksum {
+        let (expected, _) = u32::from_bytes_with(&bytes[trailer..], endianness)
+            .map_err(|e| error(trailer, e))?;
+        let actual = crc32(payload);
+        if expected != actual {
+            return Err(error(trailer, DecodeError::ChecksumMismatch { expected, actual }));
+        }
+    }
+    Ok((major, payload))
+}
+
+/// Reads the fields of part of a file in order. `base` is where the
+/// part starts in the file, so that errors give file offsets.
+struct Fields<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    base: usize,
+    endianness: Endianness,
+}
+
+impl<'a> Fields<'a> {
+    fn new(bytes: &'a [u8], base: usize, endianness: Endianness) -> Fields<'a> {
+        Fields { bytes, at: 0, base, endianness }
+    }
+
+    fn error(&self, error: DecodeError) -> ObjectError {
+        ObjectError::Decode(DisassembleError { offset: self.base + self.at, instr: None, error })
+    }
+
+    fn read<T: FromBytes>(&mut self) -> Result<T, ObjectError> {
+        let (value, len) = T::from_bytes_with(&self.bytes[self.at..], self.endianness)
+            .map_err(|e| self.error(e))?;
+        self.at += len;
+        Ok(value)
+    }
+
+    fn byte(&mut self, what: &'static str) -> Result<u8, ObjectError> {
+        let b = *self.bytes.get(self.at).ok_or_else(|| self.error(DecodeError::Truncated { what, needed: 1, available: 0 }))?;
+        self.at += 1;
+        Ok(b)
+    }
+
+    /// A u32 length, then that many bytes of UTF-8.
+    fn name(&mut self) -> Result<String, ObjectError> {
+        let len = self.read::<u32>()? as usize;
+        let name = self.bytes.get(self.at..self.at + len)
+            .ok_or_else(|| {
+                let available = self.bytes.len() - self.at;
+                self.error(DecodeError::Truncated { what: "symbol name", needed: len, available })
+            })?;
+        let name = str::from_utf8(name).map_err(|_| ObjectError::BadSymbolName { offset: self.base + self.at })?;
+        self.at += len;
+        Ok(name.to_string())
+    }
+}
+
+/// Write a name as `Fields::name` reads it.
+fn write_name(bytes: &mut Vec<u8>, name: &str, endianness: Endianness) {
+    (name.len() as u32).encode_into_with(bytes, endianness);
+    bytes.extend(name.as_bytes());
+}
+
+/// Decode a symbol section that starts `offset` bytes into the file.
+fn read_symbols(section: &[u8], offset: usize, endianness: Endianness) -> Result<SymbolTable, ObjectError> {
+    let mut symbols = SymbolTable::new();
+    if section.is_empty() {
+        return Ok(symbols);
+    }
+    let mut fields = Fields::new(section, offset, endianness);
+    for _ in 0..fields.read::<u32>()? {
+        let pc = fields.read::<u32>()?;
+        symbols.insert(fields.name()?, pc);
+    }
+    Ok(symbols)
+}
+
+/// Decode a debug section that starts `offset` bytes into the file.
+fn read_lines(section: &[u8], offset: usize, endianness: Endianness) -> Result<LineTable, ObjectError> {
+    let mut fields = Fields::new(section, offset, endianness);
+    let files = (0..fields.read::<u32>()?).map(|_| fields.name()).collect::<Result<Vec<_>, _>>()?;
+    let mut rows = Vec::new();
+    for _ in 0..fields.read::<u32>()? {
+        let pc = fields.read::<u32>()?;
+        let file = fields.read::<u32>()?;
+        if file as usize >= files.len() {
+            return Err(ObjectError::BadField { offset: offset + fields.at - 4, what: "file index" });
+        }
+        rows.push((pc, file, fields.read::<u32>()?));
+    }
+    Ok(LineTable { files, rows })
+}
+
+/// Decode a metadata section that starts `offset` bytes into the file.
+fn read_metadata(section: &[u8], offset: usize, endianness: Endianness) -> Result<Metadata, ObjectError> {
+    let mut fields = Fields::new(section, offset, endianness);
+    let mut metadata = Metadata::new();
+    for _ in 0..fields.read::<u32>()? {
+        let key = fields.name()?;
+        metadata.insert(key, fields.name()?);
+    }
+    Ok(metadata)
+}
+
+/// Decode a data segment that starts `offset` bytes into the file.
+fn read_data(section: &[u8], offset: usize, endianness: Endianness) -> Result<DataSegment, ObjectError> {
+    let mut fields = Fields::new(section, offset, endianness);
+    let values = (0..fields.read::<u32>()?).map(|_| fields.read::<Val>()).collect::<Result<Vec<_>, _>>()?;
+    let mut symbols = SymbolTable::new();
+    for _ in 0..fields.read::<u32>()? {
+        let index = fields.read::<u32>()?;
+        if index as usize >= values.len() {
+            return Err(ObjectError::BadField { offset: offset + fields.at - 4, what: "data index" });
+        }
+        symbols.insert(fields.name()?, index);
+    }
+    Ok(DataSegment { values, symbols })
+}
+
+/// Rewrite an object file without its symbol, debug and metadata
+/// sections, and without the labels of its data segment. The code is
+/// copied byte for byte, and the flags but for `FLAG_DEBUG` and
+/// `FLAG_METADATA`; a compressed file is compressed again. A version 1
+/// file, which has no sections, is copied whole.
+pub fn strip_symbols(bytes: &[u8], endianness: Endianness) -> Result<Vec<u8>, ObjectError> {
+    let payload = match payload(bytes, MAGIC, OLDEST_VERSION, endianness, ReadOptions::default())? {
+        (1, _) => return Ok(bytes.to_vec()),
+        (_, payload) => payload,
+    };
+    let expanded = expand(payload, endianness)?;
+    let parts = parts(&expanded, endianness)?;
+    let code_end = FLAGS_BYTES + parts.code.len();
+    let mut stripped = bytes[..PREFIX_BYTES].to_vec();
+    let (flags, _) = u16::from_bytes_with(&expanded, endianness).expect("expand reads the flags");
+    (flags & !(FLAG_DEBUG | FLAG_METADATA)).encode_into_with(&mut stripped, endianness);
+    stripped.extend(&expanded[FLAGS_BYTES..code_end]);
+    0u32.encode_into_with(&mut stripped, endianness);
+    if let Some((section, offset)) = parts.data {
+        let data = DataSegment { symbols: SymbolTable::new(), ..read_data(section, offset, endianness)? };
+        let start = stripped.len();
+        write_data(&mut stripped, &data, endianness);
+        ((stripped.len() - start) as u32).encode_into_with(&mut stripped, endianness);
+    }
+    crc32(&stripped[PREFIX_BYTES..]).encode_into_with(&mut stripped, endianness);
+    if let Cow::Owned(_) = expanded {
+        return Ok(compress_object(&stripped, endianness));
+    }
+    Ok(stripped)
+}
+
+/// Read an object file from `reader` and write it to `writer` without
+/// its symbol, debug and metadata sections, as `strip_symbols` does. A
+/// file that does not decode is an `InvalidData` error wrapping the
+/// `ObjectError`.
+pub fn strip_file<R: Read, W: Write>(mut reader: R, mut writer: W, endianness: Endianness) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let stripped = strip_symbols(&bytes, endianness).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    writer.write_all(&stripped)
+}
+
+/// Check that a decoded program, such as one read from a file of
+/// unknown origin, is safe to load: its entry pc is an instruction or
+/// the end of the program, every location it pushes and every symbol
+/// is within the program, and it pushes no `Vsize` or `Vaddr`. Decoding
+/// has already rejected anything that is not an instruction; this
+/// checks what decodes but cannot run. Every failure is returned, in
+/// the order of its pc.
+pub fn verify_object(object: &ObjectFile) -> Result<(), Vec<VerifyError>> {
+    let len = object.instrs.len();
+    let mut errors = Vec::new();
+    match object.instrs.get(object.entry as usize) {
+        _ if object.entry as usize > len => errors.push(VerifyError::EntryOutOfRange { entry: object.entry, len }),
+        Some(Instr::Byte(_)) => errors.push(VerifyError::EntryInData { entry: object.entry }),
+        _ => (),
+    }
+    for (pc, i) in object.instrs.iter().enumerate() {
+        let pc = pc as u32;
+        match *i {
+            Instr::Push(Val::Vloc(target)) if target as usize > len =>
+                errors.push(VerifyError::TargetOutOfRange { pc, target }),
+            Instr::Push(value @ (Val::Vsize(_) | Val::Vaddr(_))) => errors.push(VerifyError::InternalValue { pc, value }),
+            _ => (),
+        }
+    }
+    let mut symbols: Vec<(&String, &u32)> = object.symbols.iter().filter(|(_, &pc)| pc as usize > len).collect();
+    symbols.sort_by_key(|&(_, &pc)| pc);
+    errors.extend(symbols.into_iter().map(|(label, &pc)| VerifyError::SymbolOutOfRange { label: label.clone(), pc }));
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+/// A linked program, as written by `write_object`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectFile {
+    /// The entry pc in the header.
+    pub entry: u32,
+    pub instrs: Vec<Instr>,
+    /// Label addresses; empty if the file has no symbol section.
+    pub symbols: SymbolTable,
+    /// How the instructions' operands are encoded.
+    pub profile: Profile,
+    /// Source positions; empty if the file has no debug section.
+    pub lines: LineTable,
+    /// How the program was built (see `build_metadata`); empty if the
+    /// file has no metadata section.
+    pub metadata: Metadata,
+    /// Initialized data; empty if the file has no data segment.
+    pub data: DataSegment,
+}
+
+impl ObjectFile {
+    /// The program the assembler produced (see
+    /// `assemble::assemble_with_symbols`): its instructions, with the
+    /// entry pc (the number of instructions before it) pushed last, and
+    /// its labels. The entry pc is 0, and every instruction kept, if the
+    /// last instruction is not that push, as for a program that ends
+    /// with a `push @n` of its own.
+    pub fn from_program(mut instrs: Vec<Instr>, symbols: &Symbols) -> ObjectFile {
+        let entry = match instrs.last() {
+            Some(&Instr::Push(Val::Vloc(pc))) if pc as usize == instrs.len() - 1 => {
+                instrs.pop();
+                pc
+            }
+            _ => 0,
+        };
+        ObjectFile {
+            entry,
+            instrs,
+            symbols: symbol_table(symbols),
+            profile: Profile::Fixed,
+            lines: LineTable::default(),
+            metadata: Metadata::new(),
+            data: DataSegment::default(),
+        }
+    }
+
+    /// Drop the symbols, lines and metadata, and the labels of the data,
+    /// leaving what the program needs to run.
+    pub fn strip(&mut self) {
+        self.symbols.clear();
+        self.lines = LineTable::default();
+        self.metadata.clear();
+        self.data.symbols.clear();
+    }
+
+    /// Replace the instruction at `pc` with `instr`, which must encode
+    /// to as many bytes in the file's profile, so that `encode` changes
+    /// only those bytes and the checksum. Returns the old instruction.
+    pub fn replace_instr(&mut self, pc: u32, instr: Instr) -> Result<Instr, PatchError> {
+        let old = self.instrs.get(pc as usize).ok_or(PatchError::OutOfRange { pc, len: self.instrs.len() })?;
+        let (old_len, new_len) = (self.encoded_len(old), self.encoded_len(&instr));
+        if old_len != new_len {
+            return Err(PatchError::SizeMismatch { pc, old: old_len, new: new_len });
+        }
+        self.patch(pc, instr).map(|patched| patched.old)
+    }
+
+    /// Replace the instruction at `pc` with `instr`, of any size.
+    ///
+    /// Pcs count instructions, not bytes, so the entry pc, the symbols,
+    /// the lines and every `Vloc` stay valid however the size changes;
+    /// only the byte offsets of the instructions after `pc` move, by the
+    /// returned `shift`. A new instruction that pushes a location past
+    /// the end of the program is refused.
+    pub fn patch(&mut self, pc: u32, instr: Instr) -> Result<Patched, PatchError> {
+        let len = self.instrs.len();
+        if pc as usize >= len {
+            return Err(PatchError::OutOfRange { pc, len });
+        }
+        if let Instr::Push(Val::Vloc(target)) = instr {
+            if target as usize > len {
+                return Err(PatchError::BadTarget { pc, target });
+            }
+        }
+        let shift = self.encoded_len(&instr) as isize - self.encoded_len(&self.instrs[pc as usize]) as isize;
+        let old = std::mem::replace(&mut self.instrs[pc as usize], instr);
+        Ok(Patched { old, shift })
+    }
+
+    /// The size of `instr` encoded in the file's profile.
+    fn encoded_len(&self, instr: &Instr) -> usize {
+        let mut buf = Vec::new();
+        instr.encode_into_profile(&mut buf, Endianness::Big, self.profile);
+        buf.len()
+    }
+
+    /// The entry pc in the header.
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    pub fn instrs(&self) -> &[Instr] {
+        &self.instrs
+    }
+
+    /// The label addresses, if there are any to write a symbol section.
+    pub fn symbols(&self) -> Option<&SymbolTable> {
+        if self.symbols.is_empty() { None } else { Some(&self.symbols) }
+    }
+
+    /// The contents of the named section (`"symbols"`, `"debug"`,
+    /// `"metadata"` or `"data"`) as
+    /// `encode` writes them, without their size, or `None` if the file
+    /// has no such section.
+    pub fn raw_section(&self, name: &str, endianness: Endianness) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        match name {
+            "symbols" => write_symbols(&mut bytes, self.symbols()?, endianness),
+            "debug" if self.lines != LineTable::default() => write_lines(&mut bytes, &self.lines, endianness),
+            "metadata" if !self.metadata.is_empty() => write_metadata(&mut bytes, &self.metadata, endianness),
+            "data" if self.data != DataSegment::default() => write_data(&mut bytes, &self.data, endianness),
+            _ => return None,
+        }
+        Some(bytes)
+    }
+
+    /// Read a file written by `write` or `write_compressed` from `reader`. A file
+    /// that does not decode is an `InvalidData` error wrapping the
+    /// `ObjectError`.
+    pub fn read<R: Read>(mut reader: R, endianness: Endianness) -> io::Result<ObjectFile> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        ObjectFile::from_bytes(&bytes, endianness).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// The file `assem` writes by default, as `write_legacy` lays it
+    /// out: the entry pc, then the instructions, without the sections.
+    pub fn to_legacy_bytes(&self, endianness: Endianness) -> Vec<u8> {
+        write_legacy(self.entry, &self.instrs, endianness)
+    }
+
+    /// Write the file, as `encode` encodes it, to `out`.
+    pub fn write<W: Write>(&self, mut out: W, endianness: Endianness) -> io::Result<()> {
+        out.write_all(&self.encode(endianness))
+    }
+
+    /// Write the file, as `encode_version` encodes it, to `out`. A version
+    /// that cannot be written is an `InvalidInput` error wrapping the
+    /// `ObjectError`.
+    pub fn write_version<W: Write>(&self, mut out: W, major: u8, endianness: Endianness) -> io::Result<()> {
+        let bytes = self.encode_version(major, endianness)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        out.write_all(&bytes)
+    }
+
+    /// Encode in the given major version of the format, for tools that
+    /// read no newer one; `encode` writes the current version. Version 1
+    /// holds only the code, so the symbols, lines and metadata are left
+    /// out, and a varint profile or a data segment, which it cannot say
+    /// how to read or load, is an error.
+    pub fn encode_version(&self, major: u8, endianness: Endianness) -> Result<Vec<u8>, ObjectError> {
+        match major {
+            1 => {
+                if self.profile != Profile::Fixed {
+                    return Err(ObjectError::NotInVersion { major, what: "varint operands" });
+                }
+                if self.data != DataSegment::default() {
+                    return Err(ObjectError::NotInVersion { major, what: "a data segment" });
+                }
+                let mut bytes = MAGIC.to_vec();
+                0x0100u16.encode_into_with(&mut bytes, endianness);
+                bytes.extend(self.to_legacy_bytes(endianness));
+                crc32(&bytes[PREFIX_BYTES..]).encode_into_with(&mut bytes, endianness);
+                Ok(bytes)
+            }
+            2 => Ok(self.encode(endianness)),
+            major => Err(ObjectError::UnsupportedVersion { major, minor: 0 }),
+        }
+    }
+
+    /// Write the file, as `encode_compressed` encodes it, to `out`.
+    pub fn write_compressed<W: Write>(&self, mut out: W, endianness: Endianness) -> io::Result<()> {
+        out.write_all(&self.encode_compressed(endianness))
+    }
+
+    /// Encode as `encode` does, then compress everything after the flags
+    /// (see `compress`), setting a flag so that `from_bytes` and `read`
+    /// decompress it. The checksum is of the compressed bytes.
+    pub fn encode_compressed(&self, endianness: Endianness) -> Vec<u8> {
+        compress_object(&self.encode(endianness), endianness)
+    }
+
+    /// Encode with `write_object_with`, leaving out an empty symbol
+    /// section, and adding a debug section unless `lines` is empty, a
+    /// metadata section unless `metadata` is, and a data segment unless
+    /// `data` is.
+    pub fn encode(&self, endianness: Endianness) -> Vec<u8> {
+        self.encode_with(endianness, WriteOptions::default())
+    }
+
+    /// Like `encode`, leaving out the sections `options` strips. The
+    /// code is the same either way.
+    pub fn encode_with(&self, endianness: Endianness, options: WriteOptions) -> Vec<u8> {
+        let unlabelled;
+        let data = if options.strip_symbols && !self.data.symbols.is_empty() {
+            unlabelled = DataSegment { values: self.data.values.clone(), symbols: SymbolTable::new() };
+            &unlabelled
+        } else {
+            &self.data
+        };
+        let sections = Sections {
+            symbols: if self.symbols.is_empty() || options.strip_symbols { None } else { Some(&self.symbols) },
+            lines: if self.lines == LineTable::default() || options.strip_debug { None } else { Some(&self.lines) },
+            metadata: if self.metadata.is_empty() || options.strip_metadata { None } else { Some(&self.metadata) },
+            data: if *data == DataSegment::default() { None } else { Some(data) },
+        };
+        write(self.entry, &self.instrs, sections, endianness, self.profile)
+    }
+
+    /// The source file and line of the instruction at `pc`, if the file
+    /// has a debug section and `pc` is in the program.
+    pub fn line_for_pc(&self, pc: u32) -> Option<(&str, u32)> {
+        if pc as usize >= self.instrs.len() {
+            return None;
+        }
+        self.lines.line_for_pc(pc)
+    }
+
+    /// Decode a file written by `write_object` or `write_object_with`, or
+    /// in any major version from `OLDEST_VERSION` on (see `encode_version`).
+    pub fn from_bytes(bytes: &[u8], endianness: Endianness) -> Result<ObjectFile, ObjectError> {
+        ObjectFile::read_with(bytes, endianness, ReadOptions::default())
+    }
+
+    /// Decode the legacy layout of a version 1 file, without its header.
+    fn read_version_1(code: &[u8], endianness: Endianness) -> Result<ObjectFile, ObjectError> {
+        let (entry, instrs) = decode_with(code, endianness).map_err(|e| ObjectError::Decode(DisassembleError {
+            offset: e.offset + PREFIX_BYTES,
+            ..e
+        }))?;
+        Ok(ObjectFile { entry, instrs, ..ObjectFile::default() })
+    }
+
+    /// Like `from_bytes`, with the given options.
+    pub fn read_with(bytes: &[u8], endianness: Endianness, options: ReadOptions)
+                     -> Result<ObjectFile, ObjectError> {
+        if options.legacy {
+            let (entry, instrs) = decode_with(bytes, endianness).map_err(ObjectError::Decode)?;
+            return Ok(ObjectFile {
+                entry,
+                instrs,
+                symbols: SymbolTable::new(),
+                profile: Profile::Fixed,
+                lines: LineTable::default(),
+                metadata: Metadata::new(),
+                data: DataSegment::default(),
+            });
+        }
+        let payload = match payload(bytes, MAGIC, OLDEST_VERSION, endianness, options)? {
+            (1, code) => return ObjectFile::read_version_1(code, endianness),
+            (_, payload) => expand(payload, endianness)?,
+        };
+        let parts = parts(&payload, endianness)?;
+        let (entry, instrs) = decode_with_profile(parts.code, endianness, parts.profile)
+            .map_err(|e| ObjectError::Decode(DisassembleError {
+                offset: e.offset + PREFIX_BYTES + FLAGS_BYTES,
+                ..e
+            }))?;
+        let (section, offset) = parts.symbols;
+        let symbols = read_symbols(section, offset, endianness)?;
+        let lines = match parts.debug {
+            Some((section, offset)) => read_lines(section, offset, endianness)?,
+            None => LineTable::default(),
+        };
+        let metadata = match parts.metadata {
+            Some((section, offset)) => read_metadata(section, offset, endianness)?,
+            None => Metadata::new(),
+        };
+        let data = match parts.data {
+            Some((section, offset)) => read_data(section, offset, endianness)?,
+            None => DataSegment::default(),
+        };
+        Ok(ObjectFile { entry, instrs, symbols, profile: parts.profile, lines, metadata, data })
+    }
+}
+
+/// A module assembled without knowing where it will be loaded (see
+/// `assemble_relocatable`), for `link::link_objects` to combine with
+/// others. The name identifies the module in link errors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelocatableObject {
+    pub name: String,
+    pub module: Relocatable,
+}
+
+impl RelocatableObject {
+    /// The labels the module pushes but does not define, which another
+    /// module must export.
+    pub fn undefined(&self) -> BTreeSet<&Label> {
+        self.module.relocations.iter()
+            .map(|(_, label)| label)
+            .filter(|label| !self.module.symbols.contains_key(*label))
+            .collect()
+    }
+
+    /// Encode as `RELOCATABLE_MAGIC`, `VERSION`, the name, the number of
+    /// instructions and the instructions, the symbols (a count, then
+    /// the address, visibility byte (0 local, 1 global) and name of
+    /// each), the relocations (a count, then the instruction index and
+    /// label of each), and a CRC32 of everything after `VERSION`. Names
+    /// are a u32 length and UTF-8 bytes.
+    pub fn encode(&self, endianness: Endianness) -> Vec<u8> {
+        let mut bytes = RELOCATABLE_MAGIC.to_vec();
+        VERSION.encode_into_with(&mut bytes, endianness);
+        write_name(&mut bytes, &self.name, endianness);
+        (self.module.instrs.len() as u32).encode_into_with(&mut bytes, endianness);
+        for i in &self.module.instrs {
+            i.encode_into_with(&mut bytes, endianness);
+        }
+        (self.module.symbols.len() as u32).encode_into_with(&mut bytes, endianness);
+        for (label, sym) in &self.module.symbols {
+            sym.pc.encode_into_with(&mut bytes, endianness);
+            bytes.push(match sym.visibility { Visibility::Local => 0, Visibility::Global => 1 });
+            write_name(&mut bytes, label, endianness);
+        }
+        (self.module.relocations.len() as u32).encode_into_with(&mut bytes, endianness);
+        for (index, label) in &self.module.relocations {
+            (*index as u32).encode_into_with(&mut bytes, endianness);
+            write_name(&mut bytes, label, endianness);
+        }
+        crc32(&bytes[PREFIX_BYTES..]).encode_into_with(&mut bytes, endianness);
+        bytes
+    }
+
+    /// Decode a file written by `encode`.
+    pub fn from_bytes(bytes: &[u8], endianness: Endianness) -> Result<RelocatableObject, ObjectError> {
+        let (_, payload) = payload(bytes, RELOCATABLE_MAGIC, (VERSION >> 8) as u8, endianness, ReadOptions::default())?;
+        let mut fields = Fields::new(payload, PREFIX_BYTES, endianness);
+        let name = fields.name()?;
+        let instrs = (0..fields.read::<u32>()?).map(|_| fields.read::<Instr>()).collect::<Result<_, _>>()?;
+        let mut symbols = Symbols::new();
+        for _ in 0..fields.read::<u32>()? {
+            let pc = fields.read::<u32>()?;
+            let visibility = match fields.byte("visibility")? {
+                0 => Visibility::Local,
+                1 => Visibility::Global,
+                _ => return Err(ObjectError::BadField { offset: PREFIX_BYTES + fields.at - 1, what: "visibility" }),
+            };
+            symbols.insert(fields.name()?, Symbol { pc, visibility });
+        }
+        let relocations = (0..fields.read::<u32>()?)
+            .map(|_| Ok((fields.read::<u32>()? as usize, fields.name()?)))
+            .collect::<Result<_, _>>()?;
+        Ok(RelocatableObject { name, module: Relocatable { instrs, relocations, symbols } })
+    }
+}
+
+/// The CRC-32 (IEEE 802.3) of `bytes`, as used by zip and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::{Instr::*, Val::*};
+
+    fn program() -> Vec<Instr> {
+        vec![Push(Vi32(3)), Push(Vloc(3)), Branch, Halt]
+    }
+
+    #[test]
+    fn test_write_read() {
+        for e in [Endianness::Big, Endianness::Little] {
+            let bytes = write_object(4, &program(), None, e);
+            assert_eq!(&bytes[..4], b"GRPY");
+            assert_eq!(read_object(&bytes, e, ReadOptions::default()).unwrap(), (4, program(), SymbolTable::new()));
+        }
+        let bytes = write_object(4, &program(), None, Endianness::Big);
+        assert_eq!(&bytes[4..12], &[2, 0, 0, 0, 0, 0, 0, 4]);
+    }
+
+    #[test]
+    fn test_reject_text_file() {
+        let err = read_object(b"push 1\nhalt\n", Endianness::Big, ReadOptions::default()).unwrap_err();
+        assert_eq!(err, ObjectError::BadMagic { found: b"push".to_vec() });
+        assert_eq!(err.to_string(),
+                   "not a GrumpyVM object file (starts with [70, 75, 73, 68], expected [47, 52, 50, 59])");
+        assert!(read_object(b"GR", Endianness::Big, ReadOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_reject_version() {
+        let mut bytes = write_object(4, &program(), None, Endianness::Big);
+        bytes[4] = 3;
+        let err = read_object(&bytes, Endianness::Big, ReadOptions::default()).unwrap_err();
+        assert_eq!(err, ObjectError::UnsupportedVersion { major: 3, minor: 0 });
+        assert_eq!(err.to_string(), "unsupported object file version 3.0 (max supported 2)");
+        bytes[4] = 0;
+        assert_eq!(read_object(&bytes, Endianness::Big, ReadOptions::default()).unwrap_err(),
+                   ObjectError::UnsupportedVersion { major: 0, minor: 0 });
+        // A newer minor version is still readable.
+        bytes[4] = 2;
+        bytes[5] = 7;
+        assert_eq!(read_object(&bytes, Endianness::Big, ReadOptions::default()).unwrap(), (4, program(), SymbolTable::new()));
+        let err = read_object(&bytes[..5], Endianness::Big, ReadOptions::default()).unwrap_err();
+        assert_eq!(err, ObjectError::Decode(DisassembleError {
+            offset: 4,
+            instr: None,
+            error: DecodeError::Truncated { what: "u16", needed: 2, available: 1 },
+        }));
+    }
+
+    #[test]
+    fn test_legacy() {
+        let legacy = write_legacy(4, &program(), Endianness::Big);
+        assert!(read_object(&legacy, Endianness::Big, ReadOptions::default()).is_err());
+        assert_eq!(read_object(&legacy, Endianness::Big, ReadOptions { legacy: true, ..ReadOptions::default() }).unwrap(), (4, program(), SymbolTable::new()));
+        // Decode errors are located in the file being read.
+        let mut bytes = write_object(4, &program(), None, Endianness::Big);
+        bytes[12] = 0x42;
+        let salvage = ReadOptions { skip_checksum: true, ..ReadOptions::default() };
+        assert_eq!(read_object(&bytes, Endianness::Big, salvage).unwrap_err().to_string(),
+                   "byte 0xc (instr #0): unknown instruction opcode 0x42");
+    }
+
+    /// A writer that takes at most `limit` bytes per call, as a pipe or
+    /// a full socket buffer may.
+    struct Short {
+        bytes: Vec<u8>,
+        limit: usize,
+        calls: usize,
+    }
+
+    impl Write for Short {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.limit);
+            self.bytes.extend(&buf[..n]);
+            self.calls += 1;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_legacy_to() {
+        for e in [Endianness::Big, Endianness::Little] {
+            let expected = write_legacy(4, &program(), e);
+            for limit in [1, 3, 64] {
+                let mut out = Short { bytes: Vec::new(), limit, calls: 0 };
+                write_legacy_to(&mut out, 4, &program(), e).unwrap();
+                assert_eq!(out.bytes, expected, "{}", limit);
+                assert!(out.calls >= expected.len() / limit, "{}", limit);
+            }
+        }
+        // A writer that takes nothing fails rather than dropping output.
+        let mut full = Short { bytes: Vec::new(), limit: 0, calls: 0 };
+        let err = write_legacy_to(&mut full, 4, &program(), Endianness::Big).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn test_from_program() {
+        let o = ObjectFile::from_program([program(), vec![Push(Vloc(4))]].concat(), &Symbols::new());
+        assert_eq!((o.entry, o.instrs), (4, program()));
+        // With no entry pc pushed, nothing is dropped, even a push of a
+        // location at the very end.
+        let o = ObjectFile::from_program(program(), &Symbols::new());
+        assert_eq!((o.entry, o.instrs), (0, program()));
+        let trailing = [program(), vec![Push(Vloc(2))]].concat();
+        let o = ObjectFile::from_program(trailing.clone(), &Symbols::new());
+        assert_eq!((o.entry, o.instrs), (0, trailing.clone()));
+        // As the assembler leaves it, with its count pushed after.
+        let pinstrs = crate::isa::PInstr::parse_line("push 3; push @3; branch; halt; push @2").unwrap();
+        let (instrs, symbols) = crate::assemble::assemble_with_symbols(&pinstrs).unwrap();
+        let o = ObjectFile::from_program(instrs, &symbols);
+        assert_eq!((o.entry, o.instrs), (5, trailing));
+        assert_eq!(ObjectFile::from_program(vec![], &Symbols::new()).instrs, []);
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let bytes = write_object(4, &program(), None, Endianness::Big);
+        assert_eq!(&bytes[bytes.len() - 4..], &crc32(&bytes[6..bytes.len() - 4]).to_be_bytes());
+
+        // Flip a bit in the middle of an operand: the file still decodes,
+        // but not to the program that was written.
+        let mut corrupt = bytes.clone();
+        corrupt[17] ^= 0x01;
+        let err = read_object(&corrupt, Endianness::Big, ReadOptions::default()).unwrap_err();
+        let actual = crc32(&corrupt[6..corrupt.len() - 4]);
+        assert_eq!(err, ObjectError::Decode(DisassembleError {
+            offset: bytes.len() - 4,
+            instr: None,
+            error: DecodeError::ChecksumMismatch { expected: crc32(&bytes[6..bytes.len() - 4]), actual },
+        }));
+        assert!(err.to_string().starts_with("byte 0x1e: checksum mismatch (stored 0x"), "{}", err);
+        let salvage = ReadOptions { skip_checksum: true, ..ReadOptions::default() };
+        assert_eq!(read_object(&corrupt, Endianness::Big, salvage).unwrap().1[0], Push(Vi32(2)));
+
+        // A file cut short after a whole instruction.
+        let err = read_object(&bytes[..bytes.len() - 1], Endianness::Big, ReadOptions::default()).unwrap_err();
+        assert!(matches!(err, ObjectError::Decode(DisassembleError {
+            error: DecodeError::ChecksumMismatch { .. }, ..
+        })), "{}", err);
+        let err = read_object(&bytes[..8], Endianness::Big, ReadOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), "byte 0x6: truncated object file (needs 10 bytes, 8 left)");
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbols: SymbolTable = vec![("Lmain".to_string(), 0), ("_Lwhile_0".to_string(), 2),
+                                        ("Lf.Lx".to_string(), 4)].into_iter().collect();
+        for e in [Endianness::Big, Endianness::Little] {
+            let bytes = write_object(4, &program(), Some(&symbols), e);
+            assert_eq!(read_object(&bytes, e, ReadOptions::default()).unwrap(), (4, program(), symbols.clone()));
+
+            // Stripping leaves the code alone.
+            let stripped = strip_symbols(&bytes, e).unwrap();
+            assert_eq!(stripped, write_object(4, &program(), None, e));
+            assert_eq!(stripped[..26], bytes[..26]);
+            assert_eq!(read_object(&stripped, e, ReadOptions::default()).unwrap().2, SymbolTable::new());
+        }
+
+        // An empty table is written, but reads the same as none.
+        let empty = write_object(4, &program(), Some(&SymbolTable::new()), Endianness::Big);
+        assert_eq!(empty.len(), write_object(4, &program(), None, Endianness::Big).len() + 4);
+        assert_eq!(read_object(&empty, Endianness::Big, ReadOptions::default()).unwrap().2, SymbolTable::new());
+    }
+
+    #[test]
+    fn test_symbol_errors() {
+        let symbols: SymbolTable = vec![("Lé".to_string(), 0)].into_iter().collect();
+        let mut bytes = write_object(4, &program(), Some(&symbols), Endianness::Big);
+        // The second byte of the name, after the entry pc, code, count,
+        // address and length.
+        let at = 8 + 4 + 14 + 4 + 4 + 4 + 1;
+        bytes[at] = 0xFF;
+        let salvage = ReadOptions { skip_checksum: true, ..ReadOptions::default() };
+        assert_eq!(read_object(&bytes, Endianness::Big, salvage).unwrap_err(),
+                   ObjectError::BadSymbolName { offset: at - 1 });
+        // A section size larger than the file.
+        let n = bytes.len();
+        bytes[n - 8..n - 4].copy_from_slice(&1000u32.to_be_bytes());
+        assert_eq!(read_object(&bytes, Endianness::Big, salvage).unwrap_err().to_string(),
+                   format!("byte {:#x}: truncated symbol section (needs 1000 bytes, {} left)", n - 8, n - 16));
+    }
+
+    #[test]
+    fn test_symbol_table() {
+        let pinstrs = crate::isa::PInstr::parse_line("Lstart:; push Lend; branch; Lend:; halt").unwrap();
+        let (_, symbols) = crate::assemble::assemble_with_symbols(&pinstrs).unwrap();
+        assert_eq!(symbol_table(&symbols), vec![("Lend".to_string(), 2), ("Lstart".to_string(), 0)].into_iter().collect());
+    }
+
+    #[test]
+    fn test_relocatable() {
+        let pinstrs = crate::isa::PInstr::parse_line(".global Lf; Lf:; ret; push Lg").unwrap();
+        let module = crate::assemble::assemble_relocatable(&pinstrs).unwrap();
+        let o = RelocatableObject { name: "m".into(), module };
+        assert_eq!(o.undefined().into_iter().collect::<Vec<_>>(), vec!["Lg"]);
+        for e in [Endianness::Big, Endianness::Little] {
+            assert_eq!(RelocatableObject::from_bytes(&o.encode(e), e).unwrap(), o);
+        }
+        let mut bytes = o.encode(Endianness::Big);
+        assert!(read_object(&bytes, Endianness::Big, ReadOptions::default()).is_err());
+
+        // The visibility byte: after the name, the instructions, the
+        // symbol count and the address.
+        let at = 6 + 5 + 4 + 7 + 4 + 4;
+        bytes[at] = 7;
+        let n = bytes.len();
+        let crc = crc32(&bytes[6..n - 4]);
+        bytes[n - 4..].copy_from_slice(&crc.to_be_bytes());
+        assert_eq!(RelocatableObject::from_bytes(&bytes, Endianness::Big).unwrap_err().to_string(),
+                   format!("byte {:#x}: invalid visibility", at));
+    }
+
+    #[test]
+    fn test_varint_profile() {
+        // A loop summing 1..=10, typical of small programs: mostly small
+        // operands.
+        let src = "push 0; push 1; Lloop:; peek 0; push 11; binary <; push Lbody; branch; halt;
+                   Lbody:; swap; peek 1; binary +; swap; push 1; binary +; push Lloop; push true; branch";
+        let pinstrs = crate::isa::PInstr::parse_line(src).unwrap();
+        let mut instrs = crate::assemble::assemble(&pinstrs).unwrap();
+        instrs.pop();
+        let entry = instrs.len() as u32;
+
+        let fixed = write_object(entry, &instrs, None, Endianness::Big);
+        let varint = write_object_with(entry, &instrs, None, Endianness::Big, Profile::Varint);
+        // The code shrinks by over a third; the rest of the file is the same.
+        let rest = fixed.len() - crate::byte_len(&instrs);
+        let (fixed_code, varint_code) = (fixed.len() - rest, varint.len() - rest);
+        assert!(3 * varint_code < 2 * fixed_code, "{} vs {} bytes", varint_code, fixed_code);
+        for e in [Endianness::Big, Endianness::Little] {
+            let bytes = write_object_with(entry, &instrs, None, e, Profile::Varint);
+            let o = ObjectFile::from_bytes(&bytes, e).unwrap();
+            assert_eq!((o.entry, &o.instrs, o.profile), (entry, &instrs, Profile::Varint));
+            assert_eq!(o.encode(e), bytes);
+        }
+
+        // The flags, not the reader, choose the profile.
+        let mut bytes = varint.clone();
+        bytes[7] = 0;
+        let salvage = ReadOptions { skip_checksum: true, ..ReadOptions::default() };
+        assert!(read_object(&bytes, Endianness::Big, salvage).is_err());
+        bytes[7] = 0x20;
+        assert_eq!(read_object(&bytes, Endianness::Big, salvage).unwrap_err(),
+                   ObjectError::BadField { offset: 6, what: "flags" });
+
+        // Stripping keeps the profile.
+        let symbols: SymbolTable = vec![("Lloop".to_string(), 2)].into_iter().collect();
+        let with_symbols = write_object_with(entry, &instrs, Some(&symbols), Endianness::Big, Profile::Varint);
+        assert_eq!(strip_symbols(&with_symbols, Endianness::Big).unwrap(), varint);
+    }
+
+    /// Run `o` from pc 0 to `halt` as GrumpyVM does, after loading its
+    /// data, returning the value on top of the stack. Only the
+    /// instructions the tests need are run, in the first frame.
+    fn run(o: &ObjectFile) -> Val {
+        let (heap, mut stack) = o.data.load();
+        let mut pc = 0;
+        loop {
+            let top = stack.len().wrapping_sub(1);
+            match o.instrs[pc] {
+                Push(v) => stack.push(v),
+                Var(i) => stack.push(stack[i as usize]),
+                Binary(crate::isa::Binop::Add) => match stack.split_off(top - 1)[..] {
+                    [Vi32(a), Vi32(b)] => stack.push(Vi32(a + b)),
+                    ref operands => panic!("binary + of {:?}", operands),
+                },
+                Get => match stack.split_off(top - 1)[..] {
+                    [Vaddr(a), Vi32(i)] => stack.push(heap[a + 1 + i as usize]),
+                    ref operands => panic!("get of {:?}", operands),
+                },
+                Halt => return stack[top],
+                i => panic!("'{}' is not run here", i),
+            }
+            pc += 1;
+        }
+    }
+
+    #[test]
+    fn test_data_segment() {
+        // Reads the value at Lsecond from the table the loader pushed.
+        let src = "var 0; push 1; get; halt";
+        let (instrs, symbols) = crate::assemble::assemble_with_symbols(&crate::isa::PInstr::parse_line(src).unwrap()).unwrap();
+        let data = DataSegment {
+            values: vec![Val::Vi32(-7), Val::Vbool(true), Val::Vunit, Val::Vaddr(1 << 40)],
+            symbols: vec![("Lfirst".to_string(), 0), ("Lsecond".to_string(), 1)].into_iter().collect(),
+        };
+        let o = ObjectFile { data: data.clone(), ..ObjectFile::from_program(instrs, &symbols) };
+        for e in [Endianness::Big, Endianness::Little] {
+            let bytes = o.encode(e);
+            let read = ObjectFile::from_bytes(&bytes, e).unwrap();
+            assert_eq!(read, o);
+            assert_eq!((&read.instrs, &read.data), (&o.instrs, &data));
+            assert_eq!(ObjectFile::from_bytes(&o.encode_compressed(e), e).unwrap(), o);
+            let section = o.raw_section("data", e).unwrap();
+            let end = bytes.len() - TRAILER_BYTES - 4;
+            assert_eq!(&bytes[end - section.len()..end], &section[..]);
+            // Stripping keeps the data, but not its labels.
+            let stripped = ObjectFile::from_bytes(&strip_symbols(&bytes, e).unwrap(), e).unwrap();
+            assert_eq!((stripped.data.values, stripped.data.symbols), (data.values.clone(), SymbolTable::new()));
+        }
+        assert_eq!(ObjectFile::from_program(vec![], &Symbols::new()).raw_section("data", Endianness::Big), None);
+        assert_eq!(run(&ObjectFile::from_bytes(&o.encode(Endianness::Little), Endianness::Little).unwrap()),
+                   Val::Vbool(true));
+        assert_eq!(DataSegment::default().load(), (vec![], vec![]));
+
+        // A label past the end of the data.
+        let mut bytes = o.encode(Endianness::Big);
+        let at = bytes.len() - TRAILER_BYTES - 4 - (4 + 4 + 7) - (4 + 4 + 6);
+        assert_eq!(&bytes[at..at + 4], &[0, 0, 0, 0]);
+        bytes[at + 3] = 4;
+        let salvage = ReadOptions { skip_checksum: true, ..ReadOptions::default() };
+        assert_eq!(read_object(&bytes, Endianness::Big, salvage).unwrap_err(),
+                   ObjectError::BadField { offset: at, what: "data index" });
+    }
+
+    #[test]
+    fn test_string_data() {
+        // The length of one string plus a character of another, read at
+        // runtime from the data the object file carries.
+        let src = ".datastring \"hi\"\nget\n.datastring \"yo\"\npush 1; binary +\nget\nbinary +\nhalt";
+        let (expanded, data) = crate::expand::expand_with_data(&crate::expand::parse_numbered(src).unwrap(), Endianness::Big).unwrap();
+        let (instrs, symbols) = crate::assemble::assemble_numbered(&expanded).unwrap();
+        let o = ObjectFile { data, ..ObjectFile::from_program(instrs, &symbols) };
+        for e in [Endianness::Big, Endianness::Little] {
+            let read = ObjectFile::from_bytes(&o.encode_compressed(e), e).unwrap();
+            assert_eq!(read.data.symbols["_Ldata_1"], 3);
+            assert_eq!(run(&read), Val::Vi32(2 + 'y' as i32));
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        use crate::isa::{Instr::*, Val::*};
+        let program = vec![Push(Vloc(3)), Call, Halt, Push(Vi32(2)), Ret];
+        let fixtures: [(&[u8], u8); 2] = [(include_bytes!("../tests/fixtures/v1.o"), 1),
+                                          (include_bytes!("../tests/fixtures/v2.o"), 2)];
+        for (bytes, major) in fixtures {
+            assert_eq!(bytes[4], major);
+            let o = ObjectFile::from_bytes(bytes, Endianness::Big).unwrap();
+            assert_eq!((o.entry, &o.instrs), (5, &program), "version {}", major);
+            assert_eq!(o.encode_version(major, Endianness::Big).unwrap(), bytes);
+            // Either reads into the same program, written as the newest.
+            assert_eq!(ObjectFile::from_bytes(&o.encode(Endianness::Big), Endianness::Big).unwrap(), o);
+        }
+        let v2 = ObjectFile::from_bytes(fixtures[1].0, Endianness::Big).unwrap();
+        assert_eq!(v2.symbols(), Some(&vec![("Lf".to_string(), 3)].into_iter().collect()));
+        assert_eq!(v2.line_for_pc(4), Some(("call.s", 2)));
+
+        // Version 1 keeps only the code.
+        for e in [Endianness::Big, Endianness::Little] {
+            let v1 = v2.encode_version(1, e).unwrap();
+            assert_eq!(ObjectFile::from_bytes(&v1, e).unwrap(),
+                       ObjectFile { entry: 5, instrs: program.clone(), ..ObjectFile::default() });
+            assert_eq!(strip_symbols(&v1, e).unwrap(), v1);
+            let mut written = Vec::new();
+            v2.write_version(&mut written, 1, e).unwrap();
+            assert_eq!(written, v1);
+        }
+        let varint = ObjectFile { profile: Profile::Varint, ..v2.clone() };
+        assert_eq!(varint.encode_version(1, Endianness::Big),
+                   Err(ObjectError::NotInVersion { major: 1, what: "varint operands" }));
+        let data = ObjectFile { data: DataSegment { values: vec![Val::Vunit], ..DataSegment::default() }, ..v2.clone() };
+        assert_eq!(data.encode_version(1, Endianness::Big).unwrap_err().to_string(),
+                   "object file version 1 cannot hold a data segment");
+        assert_eq!(v2.encode_version(3, Endianness::Big), Err(ObjectError::UnsupportedVersion { major: 3, minor: 0 }));
+        assert_eq!(v2.write_version(Vec::new(), 0, Endianness::Big).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // A damaged version 1 file fails its checksum.
+        let mut damaged = fixtures[0].0.to_vec();
+        damaged[PREFIX_BYTES + 5] ^= 1;
+        assert!(matches!(ObjectFile::from_bytes(&damaged, Endianness::Big),
+                         Err(ObjectError::Decode(DisassembleError { error: DecodeError::ChecksumMismatch { .. }, .. }))));
+        // Relocatable objects have only ever had version 2.
+        let relocatable = RelocatableObject { name: "m".into(), module: crate::assemble::assemble_relocatable(&[]).unwrap() };
+        let mut bytes = relocatable.encode(Endianness::Big);
+        bytes[4] = 1;
+        assert_eq!(RelocatableObject::from_bytes(&bytes, Endianness::Big),
+                   Err(ObjectError::UnsupportedVersion { major: 1, minor: 0 }));
+    }
+
+    #[test]
+    fn test_strip() {
+        let pinstrs = crate::isa::PInstr::parse_line("push Lf; call; halt; Lf:; push 2; ret").unwrap();
+        let (instrs, symbols) = crate::assemble::assemble_with_symbols(&pinstrs).unwrap();
+        let full = ObjectFile {
+            lines: LineTable { files: vec!["a.s".into()], rows: vec![(0, 0, 1), (3, 0, 2)] },
+            metadata: build_metadata(Path::new("a.s"), &[], true),
+            data: DataSegment {
+                values: vec![Val::Vi32(1)],
+                symbols: vec![("Ldata".to_string(), 0)].into_iter().collect(),
+            },
+            ..ObjectFile::from_program(instrs, &symbols)
+        };
+        let mut stripped = full.clone();
+        stripped.strip();
+        assert_eq!((stripped.symbols(), &stripped.lines, stripped.metadata.len()), (None, &LineTable::default(), 0));
+        assert_eq!((stripped.entry, &stripped.instrs, &stripped.data.values), (full.entry, &full.instrs, &full.data.values));
+        assert!(stripped.data.symbols.is_empty());
+
+        let code = |bytes: &[u8]| bytes[PREFIX_BYTES + FLAGS_BYTES..][..4 + full.instrs.byte_len()].to_vec();
+        for e in [Endianness::Big, Endianness::Little] {
+            let bytes = full.encode(e);
+            assert_eq!(full.encode_with(e, WriteOptions::default()), bytes);
+            let all = full.encode_with(e, WriteOptions::STRIP_ALL);
+            assert_eq!(all, stripped.encode(e));
+            assert_eq!(all, strip_symbols(&bytes, e).unwrap());
+            assert_eq!(code(&all), code(&bytes));
+            // Each section can be left out on its own.
+            let no_debug = WriteOptions { strip_debug: true, ..WriteOptions::default() };
+            assert_eq!(ObjectFile::from_bytes(&full.encode_with(e, no_debug), e).unwrap(),
+                       ObjectFile { lines: LineTable::default(), ..full.clone() });
+            let no_metadata = WriteOptions { strip_metadata: true, ..WriteOptions::default() };
+            assert_eq!(ObjectFile::from_bytes(&full.encode_with(e, no_metadata), e).unwrap(),
+                       ObjectFile { metadata: Metadata::new(), ..full.clone() });
+
+            let mut out = Vec::new();
+            strip_file(&bytes[..], &mut out, e).unwrap();
+            assert_eq!(out, all);
+            assert_eq!(strip_file(&bytes[1..], Vec::new(), e).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        // The stripped file still disassembles, with labels made up.
+        let bytes = stripped.encode(Endianness::Big);
+        let (entry, text) = crate::disassemble::disassemble_object(&bytes, Endianness::Big).unwrap();
+        assert_eq!(entry, 5);
+        assert_eq!(text, "push L3\ncall\nhalt\nL3:\npush 2\nret\nL5:\n");
+        let (_, named) = crate::disassemble::disassemble_object(&full.encode(Endianness::Big), Endianness::Big).unwrap();
+        assert_eq!(named.replace("Lf", "L3"), text);
+    }
+
+    #[test]
+    fn test_patch() {
+        let pinstrs = crate::isa::PInstr::parse_line("push Lf; call; halt; Lf:; push 2; ret").unwrap();
+        let (instrs, symbols) = crate::assemble::assemble_with_symbols(&pinstrs).unwrap();
+        let program = ObjectFile {
+            lines: LineTable { files: vec!["a.s".into()], rows: vec![(0, 0, 1), (3, 0, 2)] },
+            ..ObjectFile::from_program(instrs, &symbols)
+        };
+        let e = Endianness::Big;
+        let before = program.encode(e);
+
+        // A patch of the same size changes only its bytes and the CRC.
+        let mut patched = program.clone();
+        assert_eq!(patched.replace_instr(3, Push(Vi32(-7))), Ok(Push(Vi32(2))));
+        let after = patched.encode(e);
+        let at = PREFIX_BYTES + FLAGS_BYTES + 4 + program.instrs[..3].byte_len();
+        let body = before.len() - 4;
+        assert_eq!(after.len(), before.len());
+        assert_eq!((&after[..at], &after[at + 6..body]), (&before[..at], &before[at + 6..body]));
+        assert_eq!(after[at..at + 6], Push(Vi32(-7)).to_bytes_with(e)[..]);
+        assert_ne!(after[body..], before[body..]);
+        assert_eq!(ObjectFile::from_bytes(&after, e).unwrap(), patched);
+        assert_eq!(patched.replace_instr(2, Push(Vi32(0))), Err(PatchError::SizeMismatch { pc: 2, old: 1, new: 6 }));
+
+        // One of another size moves what follows, but no pc.
+        let mut patched = program.clone();
+        assert_eq!(patched.patch(2, Push(Vi32(0))), Ok(Patched { old: Halt, shift: 5 }));
+        assert_eq!(patched.patch(0, Push(Vloc(5))).unwrap().shift, 0);
+        let after = ObjectFile::from_bytes(&patched.encode(e), e).unwrap();
+        assert_eq!(after.instrs, [Push(Vloc(5)), Call, Push(Vi32(0)), Push(Vi32(2)), Ret]);
+        assert_eq!((after.entry, &after.symbols, &after.lines), (program.entry, &program.symbols, &program.lines));
+        assert_eq!(patched.patch(2, Halt).unwrap().shift, -5);
+
+        // A shorter operand under varints is a size change too.
+        let mut varint = ObjectFile { profile: Profile::Varint, ..program.clone() };
+        assert_eq!(varint.replace_instr(3, Push(Vi32(1000))), Err(PatchError::SizeMismatch { pc: 3, old: 3, new: 4 }));
+        assert_eq!(varint.patch(3, Push(Vi32(1000))).unwrap().shift, 1);
+
+        let mut patched = program.clone();
+        assert_eq!(patched.patch(5, Halt), Err(PatchError::OutOfRange { pc: 5, len: 5 }));
+        assert_eq!(patched.replace_instr(9, Halt), Err(PatchError::OutOfRange { pc: 9, len: 5 }));
+        assert_eq!(patched.patch(0, Push(Vloc(6))), Err(PatchError::BadTarget { pc: 0, target: 6 }));
+        assert_eq!(patched, program);
+        assert_eq!(PatchError::OutOfRange { pc: 5, len: 5 }.to_string(),
+                   "pc 5 is out of range (the program has 5 instructions)");
+        assert_eq!(PatchError::SizeMismatch { pc: 2, old: 1, new: 6 }.to_string(),
+                   "pc 2: new instruction is 6 bytes, the old one 1");
+    }
+
+    #[test]
+    fn test_verify_object() {
+        let pinstrs = crate::isa::PInstr::parse_line("push Lf; call; halt; Lf:; push 2; ret").unwrap();
+        let (instrs, symbols) = crate::assemble::assemble_with_symbols(&pinstrs).unwrap();
+        let program = ObjectFile::from_program(instrs, &symbols);
+        assert_eq!(verify_object(&program), Ok(()));
+        // A label at the end of the program is in range.
+        let end = ObjectFile { symbols: vec![("Lend".to_string(), 5)].into_iter().collect(), ..program.clone() };
+        assert_eq!(verify_object(&end), Ok(()));
+
+        let broken = |f: &dyn Fn(&mut ObjectFile)| {
+            let mut object = program.clone();
+            f(&mut object);
+            verify_object(&object).unwrap_err()
+        };
+        assert_eq!(broken(&|o| o.entry = 6), [VerifyError::EntryOutOfRange { entry: 6, len: 5 }]);
+        assert_eq!(broken(&|o| { o.entry = 1; o.instrs[1] = Byte(0x0c) }), [VerifyError::EntryInData { entry: 1 }]);
+        assert_eq!(broken(&|o| o.instrs[0] = Push(Vloc(9))), [VerifyError::TargetOutOfRange { pc: 0, target: 9 }]);
+        assert_eq!(broken(&|o| o.instrs[3] = Push(Vsize(2))), [VerifyError::InternalValue { pc: 3, value: Vsize(2) }]);
+        assert_eq!(broken(&|o| o.instrs[3] = Push(Vaddr(7))), [VerifyError::InternalValue { pc: 3, value: Vaddr(7) }]);
+        assert_eq!(broken(&|o| { o.symbols.insert("Lg".into(), 40); }),
+                   [VerifyError::SymbolOutOfRange { label: "Lg".into(), pc: 40 }]);
+
+        // Every failure is reported, in pc order.
+        let errors = broken(&|o| {
+            o.entry = 7;
+            o.instrs[4] = Push(Vloc(6));
+            o.instrs[2] = Push(Vaddr(0));
+            o.symbols.insert("Lz".into(), 8);
+            o.symbols.insert("La".into(), 9);
+        });
+        assert_eq!(errors, [VerifyError::EntryOutOfRange { entry: 7, len: 5 },
+                            VerifyError::InternalValue { pc: 2, value: Vaddr(0) },
+                            VerifyError::TargetOutOfRange { pc: 4, target: 6 },
+                            VerifyError::SymbolOutOfRange { label: "Lz".into(), pc: 8 },
+                            VerifyError::SymbolOutOfRange { label: "La".into(), pc: 9 }]);
+        assert_eq!(errors[2].to_string(), "pc 4: pushes location 6, past the end of the program");
+
+        // A file that decodes can still fail.
+        let bytes = ObjectFile { entry: 2, instrs: vec![Push(Vsize(1)), Halt], ..ObjectFile::default() }.encode(Endianness::Big);
+        let read = ObjectFile::from_bytes(&bytes, Endianness::Big).unwrap();
+        assert_eq!(verify_object(&read), Err(vec![VerifyError::InternalValue { pc: 0, value: Vsize(1) }]));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let pinstrs = crate::isa::PInstr::parse_line("push Lf; call; halt; Lf:; push 2; ret").unwrap();
+        let (instrs, symbols) = crate::assemble::assemble_with_symbols(&pinstrs).unwrap();
+        let plain = ObjectFile::from_program(instrs, &symbols);
+        let source = Path::new("tests/fib.s");
+        let metadata = build_metadata(source, &["--little-endian"], true);
+        assert_eq!(metadata, vec![("assembler".to_string(), format!("grumpy {}", env!("CARGO_PKG_VERSION"))),
+                                  ("options".to_string(), "--little-endian".to_string()),
+                                  ("source".to_string(), "fib.s".to_string())].into_iter().collect());
+        let o = ObjectFile { metadata, ..plain.clone() };
+        for e in [Endianness::Big, Endianness::Little] {
+            let bytes = o.encode(e);
+            assert_eq!(ObjectFile::from_bytes(&bytes, e).unwrap(), o);
+            assert_eq!(ObjectFile::from_bytes(&o.encode_compressed(e), e).unwrap(), o);
+            // Readers that want only the program find it as before.
+            assert_eq!(read_object(&bytes, e, ReadOptions::default()).unwrap(),
+                       (plain.entry, plain.instrs.clone(), plain.symbols.clone()));
+            assert_eq!(ObjectFile::from_bytes(&strip_symbols(&bytes, e).unwrap(), e).unwrap().metadata, Metadata::new());
+            let section = o.raw_section("metadata", e).unwrap();
+            let end = bytes.len() - TRAILER_BYTES - 4;
+            assert_eq!(&bytes[end - section.len()..end], &section[..]);
+        }
+        assert_eq!(plain.raw_section("metadata", Endianness::Big), None);
+
+        // Two reproducible builds, even from elsewhere, are identical.
+        let again = ObjectFile { metadata: build_metadata(Path::new("/elsewhere/fib.s"), &["--little-endian"], true),
+                                 ..plain.clone() };
+        assert_eq!(again.encode(Endianness::Big), o.encode(Endianness::Big));
+        // Others record where and when.
+        let full = build_metadata(source, &[], false);
+        assert!(Path::new(&full["source"]).is_absolute(), "{}", full["source"]);
+        assert!(full["time"].parse::<u64>().unwrap() > 1_600_000_000);
+        assert_eq!(full["options"], "");
+    }
+
+    #[test]
+    fn test_compressed() {
+        // Repetitive, as generated code is.
+        let mut instrs = Vec::new();
+        for n in 0..200 {
+            instrs.extend([Instr::Push(Val::Vi32(n % 3)), Instr::Store(1), Instr::Push(Val::Vloc(2)), Instr::Call]);
+        }
+        instrs.push(Instr::Halt);
+        let symbols: SymbolTable = vec![("Lf".to_string(), 2)].into_iter().collect();
+        let lines = LineTable { files: vec!["a.s".into()], rows: vec![(0, 0, 1)] };
+        let o = ObjectFile { entry: 800, instrs, symbols, profile: Profile::Fixed, lines, metadata: Metadata::new(),
+                             data: DataSegment::default() };
+        for e in [Endianness::Big, Endianness::Little] {
+            let plain = o.encode(e);
+            let compressed = o.encode_compressed(e);
+            assert!(compressed.len() * 4 < plain.len(), "{} of {}", compressed.len(), plain.len());
+            assert_eq!(ObjectFile::from_bytes(&compressed, e).unwrap(), o);
+            let mut written = Vec::new();
+            o.write_compressed(&mut written, e).unwrap();
+            assert_eq!(written, compressed);
+            assert_eq!(ObjectFile::read(&written[..], e).unwrap(), o);
+            // Uncompressed files read as they always have.
+            assert_eq!(ObjectFile::from_bytes(&plain, e).unwrap(), o);
+            // Stripping keeps the file compressed.
+            let stripped = strip_symbols(&compressed, e).unwrap();
+            assert_eq!(stripped, compress_object(&strip_symbols(&plain, e).unwrap(), e));
+            assert_eq!(ObjectFile::from_bytes(&stripped, e).unwrap().instrs, o.instrs);
+        }
+
+        // The flag says compressed, but what follows is not, though the
+        // checksum matches.
+        let mut garbage = o.encode(Endianness::Big);
+        garbage[PREFIX_BYTES + 1] |= FLAG_COMPRESSED as u8;
+        let n = garbage.len() - TRAILER_BYTES;
+        let crc = crc32(&garbage[PREFIX_BYTES..n]);
+        garbage[n..].copy_from_slice(&crc.to_be_bytes());
+        let err = ObjectFile::from_bytes(&garbage, Endianness::Big).unwrap_err();
+        assert!(matches!(err, ObjectError::BadField { what: "compressed data", .. }), "{:?}", err);
+        assert_eq!(ObjectFile::read(&garbage[..], Endianness::Big).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // A damaged compressed file fails its checksum like any other.
+        let mut damaged = o.encode_compressed(Endianness::Big);
+        damaged[PREFIX_BYTES + FLAGS_BYTES + 3] ^= 0xff;
+        assert!(matches!(ObjectFile::from_bytes(&damaged, Endianness::Big),
+                         Err(ObjectError::Decode(DisassembleError { error: DecodeError::ChecksumMismatch { .. }, .. }))));
+    }
+
+    #[test]
+    fn test_object_file_io() {
+        let pinstrs = crate::isa::PInstr::parse_line("push Lf; call; halt; Lf:; push 2; ret").unwrap();
+        let (instrs, symbols) = crate::assemble::assemble_with_symbols(&pinstrs).unwrap();
+        let o = ObjectFile::from_program(instrs, &symbols);
+        assert_eq!((o.entry(), o.instrs().len()), (5, 5));
+        assert_eq!(o.symbols(), Some(&vec![("Lf".to_string(), 3)].into_iter().collect()));
+        let legacy = o.to_legacy_bytes(Endianness::Big);
+        assert_eq!(legacy[..4], [0, 0, 0, 5]);
+        assert_eq!(legacy[4..], o.instrs.to_bytes()[..]);
+        assert_eq!(decode_with(&legacy, Endianness::Big).unwrap(), (o.entry, o.instrs.clone()));
+
+        let lines = LineTable { files: vec!["a.s".into()], rows: vec![(0, 0, 1), (3, 0, 2)] };
+        let variants = [o.clone(),
+                        ObjectFile { symbols: SymbolTable::new(), ..o.clone() },
+                        ObjectFile { lines: lines.clone(), ..o.clone() },
+                        ObjectFile { symbols: SymbolTable::new(), lines, profile: Profile::Varint, ..o.clone() }];
+        for o in &variants {
+            for e in [Endianness::Big, Endianness::Little] {
+                let mut bytes = Vec::new();
+                o.write(&mut bytes, e).unwrap();
+                assert_eq!(bytes, o.encode(e));
+                let read = ObjectFile::read(&bytes[..], e).unwrap();
+                assert_eq!(&read, o);
+                let mut again = Vec::new();
+                read.write(&mut again, e).unwrap();
+                assert_eq!(again, bytes);
+                assert_eq!(ObjectFile::read(&again[..], e).unwrap(), read);
+
+                // The sections are where the file says they are.
+                let symbols = read.raw_section("symbols", e);
+                let debug = read.raw_section("debug", e);
+                let sections = [debug.clone(), symbols.clone()];
+                let mut end = bytes.len() - TRAILER_BYTES;
+                for section in sections.iter().flatten() {
+                    let size = u32::from_bytes_with(&bytes[end - 4..], e).unwrap().0 as usize;
+                    assert_eq!(&bytes[end - 4 - size..end - 4], &section[..]);
+                    end -= 4 + size;
+                }
+                assert_eq!(symbols.is_some(), read.symbols().is_some());
+                assert_eq!(debug.is_some(), read.lines != LineTable::default());
+                assert_eq!(read.raw_section("code", e), None);
+            }
+        }
+
+        let err = ObjectFile::read(&b"GRPY"[..], Endianness::Big).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.get_ref().unwrap().downcast_ref::<ObjectError>().is_some());
+    }
+
+    #[test]
+    fn test_lines() {
+        let dir = crate::temp_dir("lines");
+        std::fs::write(dir.join("main.s"), "push Lsquare\npush 3\nsetframe 1\n\
+                                           .include \"square.s\"\n.while; push false; .do; .endwhile\nhalt\n").unwrap();
+        std::fs::write(dir.join("square.s"), "Lsquare:\n  var 0\n  var 0; binary *\n  ret\n").unwrap();
+        let (numbered, sources) = crate::expand::parse_sources(&dir.join("main.s"), false).unwrap();
+        let numbered = crate::expand::expand(&numbered).unwrap();
+        let lines = LineTable::new(&crate::assemble::line_numbers(&numbered).unwrap(), &sources);
+        let pinstrs: Vec<_> = numbered.into_iter().map(|(_, i)| i).collect();
+        let (mut instrs, symbols) = crate::assemble::assemble_with_symbols(&pinstrs).unwrap();
+        instrs.pop();
+        let entry = instrs.len() as u32;
+        let o = ObjectFile { entry, instrs, symbols: symbol_table(&symbols), profile: Profile::Fixed, lines,
+                             metadata: Metadata::new(), data: DataSegment::default() };
+
+        let (main, square) = (dir.join("main.s").display().to_string(), dir.join("square.s").display().to_string());
+        for e in [Endianness::Big, Endianness::Little] {
+            let bytes = o.encode(e);
+            let read = ObjectFile::from_bytes(&bytes, e).unwrap();
+            assert_eq!(read, o);
+            // Lsquare (at 3) emits nothing, so its body is on the lines
+            // after it; the loop is all on line 5.
+            let expected = vec![(0, &main, 1), (2, &main, 3), (3, &square, 2), (4, &square, 3), (5, &square, 3),
+                                (6, &square, 4), (7, &main, 5), (13, &main, 5), (14, &main, 6)];
+            for (pc, file, line) in expected {
+                assert_eq!(read.line_for_pc(pc), Some((file.as_str(), line)), "pc {}", pc);
+            }
+            assert_eq!(read.line_for_pc(15), None);
+
+            // Stripping drops the debug section with the symbols.
+            let stripped = ObjectFile::from_bytes(&strip_symbols(&bytes, e).unwrap(), e).unwrap();
+            assert_eq!((stripped.lines, stripped.instrs), (LineTable::default(), o.instrs.clone()));
+        }
+
+        // Without a debug section there are no lines.
+        let bare = ObjectFile { lines: LineTable::default(), ..o.clone() };
+        assert_eq!(ObjectFile::from_bytes(&bare.encode(Endianness::Big), Endianness::Big).unwrap().line_for_pc(0), None);
+        // A row naming a file that is not in the table.
+        let mut bytes = o.encode(Endianness::Big);
+        let n = bytes.len();
+        let at = n - 4 - 4 - 8;
+        bytes[at..at + 4].copy_from_slice(&7u32.to_be_bytes());
+        let salvage = ReadOptions { skip_checksum: true, ..ReadOptions::default() };
+        assert_eq!(read_object(&bytes, Endianness::Big, salvage).unwrap_err(),
+                   ObjectError::BadField { offset: at, what: "file index" });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use crate::assemble::assemble;
+use crate::expand::{expand, parse_numbered, Numbered};
+use crate::isa::Instr;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// A region of source text starting at a top-level label, together with
+/// the result of parsing it. Line numbers are relative to the region.
+#[derive(Debug, Clone)]
+struct Region {
+    text: String,
+    pinstrs: Vec<Numbered>,
+    /// The number of instructions the region assembled to, once its
+    /// directives were lowered with the rest of the program.
+    count: usize,
+}
+
+/// State kept between assemblies of the same (edited) program, so that
+/// only the functions whose source changed are parsed again.
+///
+/// The source is split into regions at top-level labels (labels that do
+/// not start with `_`), which for compiler output means one region per
+/// function. Label resolution always runs over the whole program, so the
+/// output is identical to assembling the source from scratch.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    regions: HashMap<u64, Region>,
+    reparsed: usize,
+}
+
+impl Session {
+    /// A session with nothing cached.
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    /// The number of regions that had to be parsed by the assembly that
+    /// produced this session.
+    pub fn reparsed(&self) -> usize {
+        self.reparsed
+    }
+
+    /// The number of instructions in the program this session
+    /// assembled, without the entry pc pushed after them.
+    pub fn instructions(&self) -> usize {
+        self.regions.values().map(|r| r.count).sum()
+    }
+
+    /// Assemble `src`, reusing the parse of every region whose text is
+    /// unchanged since the assembly that produced `self`. Returns the
+    /// assembled program and the session to use for the next edit.
+    pub fn assemble(self, src: &str) -> Result<(Vec<Instr>, Session), String> {
+        let mut next = Session::new();
+        let mut order = Vec::new();
+
+        for (start, text) in split_regions(src) {
+            let hash = hash_text(&text);
+            let cached = self.regions.get(&hash).filter(|r| r.text == text);
+            let region = match cached {
+                Some(r) => r.clone(),
+                None => {
+                    next.reparsed += 1;
+                    parse_region(text)?
+                }
+            };
+            next.regions.insert(hash, region);
+            order.push((start, hash));
+        }
+
+        let total = order.iter().map(|(_, h)| next.regions[h].pinstrs.len()).sum();
+        let mut numbered = Vec::with_capacity(total);
+        for (start, h) in &order {
+            numbered.extend(next.regions[h].pinstrs.iter().map(|(n, i)| (start + n - 1, i.clone())));
+        }
+        let expanded = expand(&numbered).map_err(|e| e.to_string())?;
+
+        // Each lowered statement keeps the line it came from, which
+        // places it in a region.
+        let starts: Vec<usize> = order.iter().map(|(start, _)| *start).collect();
+        let mut counts = vec![0; order.len()];
+        for (line, i) in &expanded {
+            if i.emits_instruction() {
+                counts[starts.partition_point(|s| s <= line).saturating_sub(1)] += 1;
+            }
+        }
+        for ((_, h), count) in order.iter().zip(counts) {
+            if let Some(region) = next.regions.get_mut(h) {
+                region.count = count;
+            }
+        }
+
+        let pinstrs: Vec<_> = expanded.into_iter().map(|(_, i)| i).collect();
+        let instrs = assemble(&pinstrs)?;
+        Ok((instrs, next))
+    }
+}
+
+fn hash_text(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn parse_region(text: String) -> Result<Region, String> {
+    let pinstrs = parse_numbered(&text).map_err(|e| e.to_string())?;
+    Ok(Region { text, pinstrs, count: 0 })
+}
+
+/// Whether a line begins with the definition of a top-level label.
+fn starts_region(line: &str) -> bool {
+    let first = line.split([';', '/']).next().unwrap_or("").trim();
+    first.starts_with('L') && first.ends_with(':')
+}
+
+/// Split source text into regions, each beginning at a top-level label
+/// (except the first, which holds whatever precedes the first label).
+/// Each region is paired with the line number it starts at.
+fn split_regions(src: &str) -> Vec<(usize, String)> {
+    let mut regions = vec![(1, String::new())];
+    for (n, line) in src.lines().enumerate() {
+        if starts_region(line) {
+            regions.push((n + 1, String::new()));
+        }
+        let region = &mut regions.last_mut().unwrap().1;
+        region.push_str(line);
+        region.push('\n');
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ToBytes;
+
+    const PRELUDE: &str = "setframe 0\npush Lmain\ncall\nhalt\n";
+    const MAIN: &str = "Lmain:\npush 5\npush Lf\nsetframe 2\nswap\ncall\nret\n";
+    const F: &str = "Lf:\nvar 0\npush 0\nbinary ==\npush _L1\nbranch\npush Lg\nret\n_L1:\npush 1\nret\n";
+    const G: &str = "Lg:\npush 2\nret\n";
+    const H: &str = "Lh:\npush Lf\npop\npush 7\nret\n";
+
+    fn bytes(instrs: &[Instr]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(crate::byte_len(instrs));
+        for i in instrs {
+            i.encode_into(&mut buf);
+        }
+        buf
+    }
+
+    fn cold(src: &str) -> Vec<u8> {
+        let pinstrs: Vec<_> = expand(&parse_numbered(src).unwrap()).unwrap()
+            .into_iter()
+            .map(|(_, i)| i)
+            .collect();
+        bytes(&assemble(&pinstrs).unwrap())
+    }
+
+    #[test]
+    fn test_session_matches_cold_assembly() {
+        let src = [PRELUDE, MAIN, F, G].concat();
+        let (instrs, session) = Session::new().assemble(&src).unwrap();
+        assert_eq!(session.reparsed(), 4);
+        assert_eq!(bytes(&instrs), cold(&src));
+        assert_eq!(session.instructions(), instrs.len() - 1);
+
+        // Insert a function.
+        let src = [PRELUDE, MAIN, H, F, G].concat();
+        let (instrs, session) = session.assemble(&src).unwrap();
+        assert_eq!(session.reparsed(), 1);
+        assert_eq!(bytes(&instrs), cold(&src));
+
+        // Reorder functions.
+        let src = [PRELUDE, G, MAIN, F, H].concat();
+        let (instrs, session) = session.assemble(&src).unwrap();
+        assert_eq!(session.reparsed(), 0);
+        assert_eq!(bytes(&instrs), cold(&src));
+
+        // Delete a function.
+        let src = [PRELUDE, G, MAIN, F].concat();
+        let (instrs, session) = session.assemble(&src).unwrap();
+        assert_eq!(session.reparsed(), 0);
+        assert_eq!(bytes(&instrs), cold(&src));
+
+        // Edit one line of a function.
+        let src = [PRELUDE, G.replace("push 2", "push 3").as_str(), MAIN, F].concat();
+        let (instrs, session) = session.assemble(&src).unwrap();
+        assert_eq!(session.reparsed(), 1);
+        assert_eq!(bytes(&instrs), cold(&src));
+        assert_eq!(session.instructions(), instrs.len() - 1);
+    }
+
+    #[test]
+    fn test_session_parse_error() {
+        let src = [PRELUDE, MAIN, "Lf:\nbogus\n"].concat();
+        assert!(Session::new().assemble(&src).is_err());
+    }
+
+    #[test]
+    fn test_session_expands_directives() {
+        let src = [PRELUDE, MAIN, F, G, "Lw:\n.while\npush true\n.do\n.endwhile\nret\n"].concat();
+        let (instrs, session) = Session::new().assemble(&src).unwrap();
+        assert_eq!(bytes(&instrs), cold(&src));
+        // Six statements, but the loop lowers to seven instructions and
+        // labels, which take up no pcs, and then there is the `ret`.
+        assert_eq!(session.instructions(), instrs.len() - 1);
+        assert_eq!(session.regions.values().find(|r| r.text.starts_with("Lw:")).unwrap().count, 8);
+
+        let src = [PRELUDE, MAIN, F, G, "Lw:\n.while\npush true\nret\n"].concat();
+        assert_eq!(Session::new().assemble(&src).unwrap_err(),
+                   "line 27: .while loop is never closed with .endwhile");
+    }
+}
@@ -0,0 +1,72 @@
+//! Allocation counts for encoding a large program with `to_bytes` and
+//! with `encode_into`. Run with `--nocapture` to see the counts and
+//! timings.
+
+use grumpy::isa::{Binop::*, Instr, Instr::*, Val::*};
+use grumpy::ToBytes;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Run `f`, returning its result and the number of allocations it made.
+fn counting<T>(name: &str, f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let result = f();
+    let allocs = ALLOCS.load(Ordering::Relaxed) - before;
+    println!("{}: {} allocations in {:?}", name, allocs, start.elapsed());
+    (result, allocs)
+}
+
+#[test]
+fn encode_into_allocates_per_buffer_not_per_instruction() {
+    let program: Vec<Instr> = [Push(Vi32(1)), Push(Vloc(2)), Binary(Add), Var(3), Pop]
+        .iter()
+        .cycle()
+        .take(1_000_000)
+        .copied()
+        .collect();
+
+    let (old, old_allocs) = counting("to_bytes", || {
+        let mut out = Vec::new();
+        for i in &program {
+            out.extend(i.to_bytes());
+        }
+        out
+    });
+    let (new, new_allocs) = counting("encode_into", || {
+        let mut out = Vec::new();
+        for i in &program {
+            i.encode_into(&mut out);
+        }
+        out
+    });
+
+    assert_eq!(old, new);
+    assert!(old_allocs >= program.len());
+    // Only the output buffer's growth allocates.
+    assert!(new_allocs < 64, "{} allocations", new_allocs);
+}
@@ -0,0 +1,11 @@
+#ifndef PROGRAM_H
+#define PROGRAM_H
+
+static const unsigned char PROGRAM[] = {
+    0x00, 0x00, 0x00, 0x07, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0x0c, 0x0f,
+    0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x01, 0xff, 0xff, 0xff, 0xfd,
+    0x04, 0x01, 0x0d,
+};
+static const unsigned long PROGRAM_LEN = 27;
+
+#endif